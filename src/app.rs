@@ -3,28 +3,62 @@
 
 use crate::{
     app_state::AppState,
-    cli::Args,
-    err_util::{eprintln_with_date, println_with_date, IgnoreErr, LogErr, OptionAnd},
-    hotkeys::{HotKeyAction, HotKeys},
-    lastfm::LastFM,
-    listenbrainz::ListenBrainz,
+    auto_dj::{self, AutoDjSource},
+    bad_files::BadFiles,
+    bookmarks::{self, Bookmark},
+    cli::{Args, BookmarkCommand, Command, PlaylistCommand, ScrobblesCommand},
+    config::Config,
+    cover_art, crash,
+    cue::{self, CueFactory},
+    decoder::DeviceLatency,
+    dir_memory::{DirMemory, DirPosition},
+    discord::DiscordPresence,
+    dsp::{self, DspSettings, ReplayGainMode},
+    err_util::{
+        debugln_with_date, eprintln_with_date, println_with_date, IgnoreErr, LogErr, OptionAnd,
+    },
+    error_popup::{self, ErrorSeverity},
+    headphones, history,
+    hotkeys::{self, HotKeyAction, HotKeys},
+    listenbrainz::ListenBrainzServer,
+    logger,
+    lyrics::LyricsNotifier,
     media_controls::MediaControls,
-    player::{self, PlaybackState, PlayerResponse, PlayerTx, PositionCallback, PositionCallbackId},
-    playlist_man,
+    meta_cache::MetaCache,
+    other_players,
+    player::{
+        self, JumpTarget, PlaybackState, PlayerResponse, PlayerTx, PositionCallback,
+        PositionCallbackId, PositionCallbackMarker,
+    },
+    playlist_man::{self, PlaylistEndAction, ScanObserver},
     popup::Popup,
+    project_info, rating, report,
+    scrobble_filter::ScrobbleFilter,
+    scrobble_retry::{self, RetrySchedule},
+    scrobbler::{self, ScrobbleArtist, ScrobbleTrack, ScrobbleWorker, Scrobbler},
+    share,
     show_file::show_file,
+    signals::SignalAction,
+    silence,
+    startup_report::{self, StartupReport},
     stream_base::{Track, TrackMeta},
+    subsonic, symphonia_stream,
     sys_vol::SysVol,
-    thread_util,
-    tray_icon::{TrayIcon, TrayIconImageType, TrayMenuItem},
+    systemd, thread_util,
+    tray_icon::{TrayIcon, TrayIconImageType, TrayIconStyle, TrayMenuItem},
 };
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use path_absolutize::Absolutize;
 use souvlaki::{MediaControlEvent, SeekDirection};
 use std::{
     path::{Path, PathBuf},
-    sync::{mpsc::Receiver, Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
     thread::JoinHandle,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 pub struct App {
@@ -35,93 +69,557 @@ pub struct App {
     meta: TrackMeta,
     hotkeys: HotKeys,
     tray: TrayIcon,
-    listenbrainz: Option<ListenBrainz>,
-    lastfm: Option<LastFM>,
+    scrobblers: ScrobbleDispatcher,
     state: AppState,
     popup: Popup,
     media_controls: Option<MediaControls>,
-    last_seek_position: Option<Duration>,
+    discord: Option<DiscordPresence>,
+    lyrics: Option<LyricsNotifier>,
+    auto_dj: Option<AutoDjSource>,
+    playlist_end: PlaylistEndAction,
+    cover_art: Option<PathBuf>,
+    cover_art_tx: Option<Sender<(Arc<str>, TrackMeta)>>,
+    workers: Workers,
+    scan_cancel_flag: Option<Arc<AtomicBool>>,
+    pending_bookmark_name: Option<String>,
+    meta_cache: MetaCache,
+    karaoke_strength: f32,
+    replay_gain: Option<ReplayGainMode>,
+    auto_gain: bool,
+    scrobble_min_duration: Duration,
+    // so only this pause is undone when the headphones are back
+    paused_by_unplug: bool,
+    paused_by_other_player: bool,
+    // when konik is started again without arguments
+    second_instance_action: Option<HotKeyAction>,
+    dir_memory: Option<DirMemory>,
+    steps: Steps,
+    bad_files: BadFiles,
 }
 
-const VOL_STEP: f64 = 0.01;
+// the playlist changes that read and write the playlist files, done without locking the app
+enum PlaylistJob {
+    Undo { playlist_index: Option<usize> },
+    OpenShare { source: String },
+}
+
+// the channels of the threads that do the slow work without locking the app
+#[derive(Default)]
+struct Workers {
+    rating_tx: Option<Sender<(Track, u8)>>,
+    playlist_job_tx: Option<Sender<PlaylistJob>>,
+}
+
+struct ScanReporter {
+    cancel_flag: Arc<AtomicBool>,
+    popup: Popup,
+    player: PlayerTx,
+    sent_tracks: Vec<Track>,
+    // of the playlist that is being replaced
+    playlist_index: Option<usize>,
+    // for the process that has sent the paths
+    reply_tx: Option<Sender<Result<String>>>,
+    // the last played track of the opened folder
+    resume: Option<DirPosition>,
+}
+
+impl ScanObserver for ScanReporter {
+    fn is_cancelled(&self) -> bool {
+        return self.cancel_flag.load(Ordering::Relaxed);
+    }
+
+    fn progress(&mut self, files_scanned: usize) {
+        self.popup.show(&format!(
+            "scanned {} files…",
+            playlist_man::format_count(files_scanned)
+        ));
+    }
+
+    // a chunk must not reach the playlist after the scan was cancelled
+    fn tracks_found(&mut self, tracks: Vec<Track>, cue_factory: &CueFactory) {
+        if self.is_cancelled() {
+            return;
+        }
+        let is_first_chunk = self.sent_tracks.is_empty();
+        self.sent_tracks.extend(tracks.iter().cloned());
+        if is_first_chunk {
+            playlist_man::backup_playlist(self.playlist_index).ignore_err();
+            if self.is_cancelled() {
+                return;
+            }
+            playlist_man::save_playlist(&tracks).ignore_err();
+        } else {
+            playlist_man::append_to_playlist(&tracks).ignore_err();
+        }
+        if self.is_cancelled() {
+            return;
+        }
+        if is_first_chunk {
+            self.player.stop();
+            let resume_index = self.resume_index(&tracks);
+            self.player.set_playlist(tracks, Some(cue_factory.clone()));
+            self.play_from(resume_index);
+        } else {
+            self.player.append_tracks(tracks, cue_factory.clone());
+        }
+    }
+}
+
+impl ScanReporter {
+    fn reply(&self, result: Result<String>) {
+        if let Some(reply_tx) = &self.reply_tx {
+            reply_tx.send(result).ignore_err();
+        }
+    }
+
+    // the remembered track is played as soon as it's found
+    fn resume_index(&self, tracks: &[Track]) -> Option<usize> {
+        let resume = self.resume.as_ref()?;
+        return tracks.iter().position(|track| resume.matches(track));
+    }
+
+    fn play_from(&mut self, resume_index: Option<usize>) {
+        let Some(index) = resume_index else {
+            self.player.play(Some(0));
+            return;
+        };
+        self.player.play(Some(index));
+        if let Some(resume) = self.resume.take() {
+            if resume.position_ms > 0 {
+                self.player.seek_to(resume.position());
+            }
+        }
+        self.popup.show("resumed from the last played track");
+    }
+
+    // the scan can be cancelled at any moment, so it's checked before every step
+    fn finish(&mut self, tracks: Vec<Track>, cue_factory: CueFactory) {
+        if self.is_cancelled() {
+            return;
+        }
+        if tracks.is_empty() {
+            eprintln_with_date("the track list is empty");
+            self.reply(Err(anyhow!("no tracks found")));
+            return;
+        }
+        self.reply(Ok(format!(
+            "{} tracks in the playlist",
+            playlist_man::format_count(tracks.len())
+        )));
+
+        if self.sent_tracks.is_empty() {
+            if self.is_cancelled() {
+                return;
+            }
+            playlist_man::backup_playlist(self.playlist_index).ignore_err();
+            if self.is_cancelled() {
+                return;
+            }
+            playlist_man::save_playlist(&tracks).ignore_err();
+            if self.is_cancelled() {
+                return;
+            }
+            self.player.stop();
+            let resume_index = self.resume_index(&tracks);
+            self.player.set_playlist(tracks, Some(cue_factory));
+            self.play_from(resume_index);
+        } else if tracks != self.sent_tracks {
+            if self.is_cancelled() {
+                return;
+            }
+            // the streamed order may differ from the final one, e.g. for smart playlists
+            playlist_man::save_playlist(&tracks).ignore_err();
+            if self.is_cancelled() {
+                return;
+            }
+            let resume_index = self.resume_index(&tracks);
+            self.player.update_playlist(tracks, cue_factory);
+            if resume_index.is_some() {
+                self.play_from(resume_index);
+            }
+        }
+    }
+}
+
+// Sends the same call to every scrobbling service.
+// Each service has its own worker, so a slow one does not hold up the rest
+// and the app is never locked while the calls are made.
+struct ScrobbleDispatcher {
+    workers: Vec<ScrobbleWorker>,
+    listenbrainz_servers: Vec<ListenBrainzServer>,
+    filter: ScrobbleFilter,
+    artist: ScrobbleArtist,
+    initialized: bool,
+    // wakes up the retries after something may have been queued
+    retry_tx: Option<Sender<()>>,
+}
+
+impl ScrobbleDispatcher {
+    const fn new(
+        listenbrainz_servers: Vec<ListenBrainzServer>,
+        filter: ScrobbleFilter,
+        artist: ScrobbleArtist,
+    ) -> Self {
+        return Self {
+            workers: Vec::new(),
+            listenbrainz_servers,
+            filter,
+            artist,
+            initialized: false,
+            retry_tx: None,
+        };
+    }
+
+    // the scrobblers are not needed until something is played
+    fn init(&mut self) {
+        if self.initialized {
+            return;
+        }
+        self.initialized = true;
+        self.workers = scrobbler::all_useable(&self.listenbrainz_servers)
+            .into_iter()
+            .map(ScrobbleWorker::start)
+            .collect();
+    }
+
+    fn is_empty(&mut self) -> bool {
+        self.init();
+        return self.workers.is_empty();
+    }
+
+    // The results of the successful calls are sent to the returned channel
+    // as soon as each service is done, the errors are logged.
+    // The channel is closed after all services are done.
+    fn dispatch<T, F>(&mut self, action: &str, f: F) -> Receiver<T>
+    where
+        T: Send + 'static,
+        F: Fn(&mut dyn Scrobbler) -> Result<T> + Send + Sync + 'static,
+    {
+        self.init();
+        let (tx, rx) = mpsc::channel();
+        let f = Arc::new(f);
+        for worker in &self.workers {
+            let f = f.clone();
+            let tx = tx.clone();
+            let action = action.to_string();
+            worker.send(move |scrobbler| {
+                let result = f(scrobbler)
+                    .with_context(|| format!("{} {action} failed", scrobbler.title()))
+                    .to_option();
+                if let Some(result) = result {
+                    tx.send(result).ok();
+                }
+            });
+        }
+        return rx;
+    }
+
+    // the configured rules decide whether and how the track is sent
+    fn dispatch_track<F>(&mut self, action: &str, filename: &str, track: ScrobbleTrack, f: F)
+    where
+        F: Fn(&mut dyn Scrobbler, &ScrobbleTrack) -> Result<()> + Send + Sync + 'static,
+    {
+        let Some(track) = self.filter.apply(filename, track) else {
+            debugln_with_date(format!("{action} skipped by the rules: {filename}"));
+            return;
+        };
+        self.dispatch(action, move |s| f(s, &track));
+        self.wake_retry();
+    }
+
+    fn wake_retry(&self) {
+        if let Some(retry_tx) = &self.retry_tx {
+            retry_tx.send(()).ok();
+        }
+    }
+
+    // the workers are not created again after this
+    fn take_workers(&mut self) -> Vec<ScrobbleWorker> {
+        self.initialized = true;
+        return std::mem::take(&mut self.workers);
+    }
+
+    // Waits for the queued requests, but not longer than the given time.
+    // The unsent scrobbles are already saved, so they are sent on the next run.
+    fn shutdown(workers: Vec<ScrobbleWorker>, timeout: Duration) {
+        if workers.is_empty() {
+            return;
+        }
+        let handles: Vec<_> = workers.into_iter().map(ScrobbleWorker::close).collect();
+        let (tx, rx) = mpsc::channel();
+        thread_util::thread("scrobblers shutdown", move || {
+            for handle in handles {
+                handle.join().to_anyhow().ignore_err();
+            }
+            tx.send(()).ignore_err();
+        });
+        if rx.recv_timeout(timeout).is_err() {
+            eprintln_with_date("the scrobbling services did not respond in time");
+        }
+    }
+}
+
+fn scrobble_track(artist: &str, title: &str, meta: &TrackMeta) -> ScrobbleTrack {
+    return ScrobbleTrack {
+        artist: artist.to_string(),
+        album: meta.album.clone(),
+        title: title.to_string(),
+        number: meta.track,
+        duration: meta.duration,
+        mbids: meta.mbids.clone(),
+    };
+}
+
+const DEFAULT_VOL_STEP_PERCENT: f64 = 1.0;
 const POS_CALLBACK_NOW_PLAYING: PositionCallbackId = 0;
 const POS_NOW_PLAYING_SECS: f64 = 5.0;
 const POS_CALLBACK_SCROBBLE: PositionCallbackId = 1;
 const POS_SCROBBLE_SECS: f64 = 5.0;
 const POS_CALLBACK_HL_END: PositionCallbackId = 2;
 const POS_HL_END_SECS: f64 = 0.5;
-const POS_MIN_DURATION_TO_SCROBBLE: Duration = Duration::from_secs(30);
-const DEFAULT_SEEK_LENGTH: Duration = Duration::from_secs(5);
+const POS_CALLBACK_COMPLETED: PositionCallbackId = 3;
+const POS_COMPLETED_SECS: f64 = 1.0;
+const POS_MIN_DURATION_TO_SCROBBLE_SECS: f64 = 30.0;
+const DEFAULT_SEEK_STEP_SECS: f64 = 5.0;
+const DEFAULT_LARGE_SEEK_STEP_SECS: f64 = 60.0;
+// the time for the scrobbling services to finish their requests on exit
+const SCROBBLERS_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the shorter tracks are not scrobbled
+fn scrobble_min_duration(secs: Option<f64>) -> Duration {
+    return Duration::try_from_secs_f64(secs.unwrap_or(POS_MIN_DURATION_TO_SCROBBLE_SECS))
+        .unwrap_or_default();
+}
+
+// how much the hotkeys and MPRIS change the volume and the position
+struct Steps {
+    // 0..1
+    volume: f64,
+    seek: Duration,
+    large_seek: Duration,
+}
+
+impl Steps {
+    fn new(config: &Config) -> Self {
+        let volume = config
+            .volume_step_percent
+            .unwrap_or(DEFAULT_VOL_STEP_PERCENT)
+            .clamp(0.1, 100.0);
+        let seek = |secs: Option<f64>, default| {
+            return Duration::try_from_secs_f64(secs.unwrap_or(default))
+                .unwrap_or_else(|_| Duration::from_secs_f64(default));
+        };
+        return Self {
+            volume: volume / 100.0,
+            seek: seek(config.seek_step_secs, DEFAULT_SEEK_STEP_SECS),
+            large_seek: seek(config.large_seek_step_secs, DEFAULT_LARGE_SEEK_STEP_SECS),
+        };
+    }
+}
+
+// auto DJ keeps playing by default if it's configured
+fn playlist_end_action(config: &Config) -> PlaylistEndAction {
+    if let Some(action) = config.playlist_end {
+        return action;
+    }
+    if config.auto_dj.is_some() {
+        return PlaylistEndAction::AutoDj;
+    }
+    return PlaylistEndAction::Stop;
+}
+
+// zero or less means never
+fn release_after_pause(secs: Option<f64>) -> Option<Duration> {
+    return match secs {
+        None => Some(player::DEFAULT_RELEASE_AFTER_PAUSE),
+        Some(secs) if secs > 0.0 => Some(Duration::from_secs_f64(secs)),
+        Some(_) => None,
+    };
+}
+
+// by default the track is scrobbled right before its end,
+// otherwise at the given percent or time, whichever comes first;
+// only the time that was actually listened counts
+fn scrobble_callback(config: &Config) -> PositionCallback {
+    let from_end = PositionCallbackMarker::SecsFromEnd(Duration::from_secs_f64(POS_SCROBBLE_SECS));
+    if config.scrobble_percent.is_none() && config.scrobble_max_secs.is_none() {
+        return PositionCallback {
+            id: POS_CALLBACK_SCROBBLE,
+            marker: PositionCallbackMarker::Listened(Box::new(from_end)),
+        };
+    }
+    let mut markers = vec![from_end];
+    if let Some(percent) = config.scrobble_percent {
+        markers.push(PositionCallbackMarker::Fraction(percent / 100.0));
+    }
+    if let Some(secs) = config.scrobble_max_secs {
+        markers.push(PositionCallbackMarker::SecsFromStart(
+            Duration::try_from_secs_f64(secs).unwrap_or_default(),
+        ));
+    }
+    return PositionCallback {
+        id: POS_CALLBACK_SCROBBLE,
+        marker: PositionCallbackMarker::Listened(Box::new(PositionCallbackMarker::Earliest(
+            markers,
+        ))),
+    };
+}
+
+fn dsp_settings(
+    state: &AppState,
+    karaoke_strength: f32,
+    replay_gain: Option<ReplayGainMode>,
+    auto_gain: bool,
+) -> DspSettings {
+    return DspSettings {
+        karaoke_strength: state.karaoke.then_some(karaoke_strength),
+        replay_gain,
+        auto_gain,
+    };
+}
 
 impl App {
-    pub fn new_args(&self, args: &Args, cur_dir: &Path) {
-        self.play_paths(&args.paths, cur_dir);
+    // The result is sent when the command is done, e.g. after the given paths are scanned.
+    // The receiver is disconnected if the scan was cancelled by a newer one.
+    pub fn new_args(&mut self, args: &Args, cur_dir: &Path) -> Receiver<Result<String>> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        match &args.command {
+            Some(Command::Rate { stars }) => self.user_action_rate(*stars),
+            Some(Command::Jump { number }) => self.user_action_jump(*number),
+            Some(Command::PlayFile { path }) => self.user_action_play_file(path, cur_dir),
+            Some(Command::RandomDir) => self.user_action_random_dir(),
+            Some(Command::Love) => self.user_action_love(),
+            Some(Command::Bookmark { command }) => match command {
+                BookmarkCommand::Add { name } => self.user_action_add_bookmark(name),
+                BookmarkCommand::Jump { name } => self.user_action_jump_to_bookmark(name),
+                BookmarkCommand::List => {}
+            },
+            Some(Command::Playlist {
+                command: PlaylistCommand::Prune,
+            }) => self.player.prune_playlist(),
+            Some(Command::Playlist {
+                command: PlaylistCommand::Undo,
+            }) => self.user_action_undo_playlist(),
+            Some(Command::Open { source }) => self.user_action_open_share(source, cur_dir),
+            Some(Command::Scrobbles {
+                command: ScrobblesCommand::Flush,
+            }) => {
+                self.flush_scrobbles();
+            }
+            Some(Command::Tag { command }) => self.tags_edited(&command.paths(), cur_dir),
+            _ if args.paths.is_empty() => {
+                if let Some(action) = self.second_instance_action {
+                    self.process_hotkey(action);
+                }
+            }
+            _ => {
+                self.play_paths(&args.paths, cur_dir, Some(reply_tx));
+                return reply_rx;
+            }
+        }
+        reply_tx.send(Ok(String::new())).ignore_err();
+        return reply_rx;
     }
 
-    fn play_paths(&self, paths: &[String], cur_dir: &Path) {
-        let (tracks, cue_factory) = playlist_man::collect_tracks(paths, cur_dir);
-        if tracks.is_empty() {
-            return;
+    fn play_paths(
+        &mut self,
+        paths: &[String],
+        cur_dir: &Path,
+        reply_tx: Option<Sender<Result<String>>>,
+    ) {
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        if let Some(prev_cancel_flag) = self.scan_cancel_flag.replace(cancel_flag.clone()) {
+            prev_cancel_flag.store(true, Ordering::Relaxed);
         }
 
-        playlist_man::save_playlist(&tracks).ignore_err();
-        self.player.stop();
-        self.player.set_playlist(tracks, Some(cue_factory));
-        self.player.play(Some(0));
+        let mut reporter = ScanReporter {
+            cancel_flag,
+            popup: self.popup.clone(),
+            player: self.player.clone_sender(),
+            sent_tracks: Vec::new(),
+            playlist_index: self.state.playlist_index,
+            reply_tx,
+            resume: self.dir_memory.as_mut().and_then(|dir_memory| {
+                let paths: Vec<_> = paths
+                    .iter()
+                    .map(|path| playlist_man::resolve_path(path, cur_dir))
+                    .collect();
+                let paths: Vec<_> = paths.iter().map(PathBuf::as_path).collect();
+                return dir_memory.open(&paths);
+            }),
+        };
+        let paths = paths.to_vec();
+        let cur_dir = cur_dir.to_path_buf();
+        thread_util::thread("playlist scanner", move || {
+            if let Some((tracks, cue_factory)) =
+                playlist_man::collect_tracks(&paths, &cur_dir, &mut reporter)
+            {
+                reporter.finish(tracks, cue_factory);
+            }
+        });
     }
 
     pub fn quit(&self) {
         self.user_action_quit();
     }
 
-    fn init_playlist(&self, paths: &[String], cur_dir: &Path) {
-        let tracks;
-        let auto_play;
-        let playlist_index;
-        let cue_factory;
-        if paths.is_empty() {
-            match playlist_man::load_playlist() {
-                Ok(loaded_tracks) => tracks = loaded_tracks,
-                Err(e) => {
-                    e.log();
-                    tracks = vec![];
-                }
+    fn init_playlist(&mut self, paths: &[String], cur_dir: &Path, prune: bool) {
+        if !paths.is_empty() {
+            self.play_paths(paths, cur_dir, None);
+            return;
+        }
+
+        let mut tracks = match playlist_man::load_playlist() {
+            Ok(loaded_tracks) => loaded_tracks,
+            Err(e) => {
+                e.log();
+                vec![]
             }
-            auto_play = false;
-            playlist_index = if tracks.is_empty() {
-                None
-            } else {
-                Some(self.state.playlist_index.unwrap_or(0))
-            };
-            cue_factory = None;
-        } else {
-            (tracks, cue_factory) = {
-                let (tracks, cue_factory) = playlist_man::collect_tracks(paths, cur_dir);
-                (tracks, Some(cue_factory))
-            };
-            auto_play = true;
-            playlist_index = if tracks.is_empty() { None } else { Some(0) };
-            if !tracks.is_empty() {
+        };
+        if prune {
+            let count = tracks.len();
+            let playlist_index;
+            (tracks, playlist_index) =
+                playlist_man::prune_tracks(tracks, self.state.playlist_index);
+            if tracks.len() != count {
+                playlist_man::backup_playlist(self.state.playlist_index).ignore_err();
                 playlist_man::save_playlist(&tracks).ignore_err();
+                self.state.playlist_index = playlist_index;
+                self.state.save_later();
             }
         }
         if tracks.is_empty() {
             eprintln_with_date("the track list is empty");
         }
 
-        self.player.set_playlist(tracks, cue_factory);
+        let playlist_index = if tracks.is_empty() {
+            None
+        } else {
+            Some(self.state.playlist_index.unwrap_or(0))
+        };
+        self.player.set_playlist(tracks, None);
         if let Some(playlist_index) = playlist_index {
-            if auto_play {
-                self.player.play(Some(playlist_index));
-            } else {
-                self.player.load_meta(playlist_index);
-            }
+            self.player.load_meta(playlist_index);
+        }
+    }
+
+    // receives false from each service that had nothing to send
+    fn flush_scrobbles(&mut self) -> Receiver<bool> {
+        return self.scrobblers.dispatch("flush", |s| s.flush());
+    }
+
+    // the scrobblers are not created just to retry, they exist after something was played
+    fn retry_scrobbles(&mut self) -> Option<Receiver<bool>> {
+        if !self.scrobblers.initialized {
+            return None;
         }
+        return Some(self.flush_scrobbles());
     }
 
     fn set_playback_state(&mut self, state: PlaybackState, position: Option<Duration>) {
         match state {
             PlaybackState::Playing => {
+                self.scrobblers.init();
                 if !matches!(
                     self.tray.image_type(),
                     TrayIconImageType::Play | TrayIconImageType::PlayHL
@@ -130,11 +628,77 @@ impl App {
                 }
             }
             PlaybackState::Stopped => self.tray.stop(),
-            PlaybackState::Paused => self.tray.pause(),
+            PlaybackState::Paused => {
+                self.tray.pause();
+                self.remember_dir_position(position.unwrap_or_default());
+            }
         }
+        self.paused_by_unplug = false;
+        self.paused_by_other_player = false;
         self.media_controls
             .mut_map(|c| c.set_state(&state, position).ignore_err());
         self.playback_state = state;
+        self.publish_position(position);
+    }
+
+    fn remember_dir_position(&mut self, position: Duration) {
+        if let (Some(dir_memory), Some(track)) = (&mut self.dir_memory, &self.cur_track) {
+            dir_memory.set_position(track, position);
+        }
+    }
+
+    // for the integrations that follow the playback position
+    fn publish_position(&self, position: Option<Duration>) {
+        if let Some(discord) = &self.discord {
+            match self.playback_state {
+                PlaybackState::Playing => discord.set(&self.meta, position),
+                PlaybackState::Paused => discord.set(&self.meta, None),
+                PlaybackState::Stopped => discord.clear(),
+            }
+        }
+        if let Some(lyrics) = &self.lyrics {
+            if matches!(self.playback_state, PlaybackState::Playing) {
+                if position.is_some() {
+                    lyrics.set_position(position);
+                }
+            } else {
+                lyrics.set_position(None);
+            }
+        }
+    }
+
+    fn headphones_changed(&mut self, plugged: bool, resume: bool) {
+        if plugged {
+            if resume
+                && self.paused_by_unplug
+                && matches!(self.playback_state, PlaybackState::Paused)
+            {
+                println_with_date("headphones are plugged in, resuming");
+                self.user_action_play();
+            }
+            return;
+        }
+        if matches!(self.playback_state, PlaybackState::Playing) {
+            println_with_date("headphones are unplugged, pausing");
+            self.user_action_pause();
+            self.paused_by_unplug = true;
+        }
+    }
+
+    // the playback is resumed only if it was paused because of the other player
+    fn other_player_changed(&mut self, is_playing: bool) {
+        if !is_playing {
+            if self.paused_by_other_player && matches!(self.playback_state, PlaybackState::Paused) {
+                println_with_date("other players have stopped, resuming");
+                self.user_action_play();
+            }
+            return;
+        }
+        if matches!(self.playback_state, PlaybackState::Playing) {
+            println_with_date("another player has started, pausing");
+            self.user_action_pause();
+            self.paused_by_other_player = true;
+        }
     }
 
     fn user_action_toggle_stop(&mut self) {
@@ -170,6 +734,106 @@ impl App {
         self.player.prev_dir();
     }
 
+    fn user_action_jump(&self, number: u64) {
+        let index = usize::try_from(number)
+            .unwrap_or(usize::MAX)
+            .saturating_sub(1);
+        self.player.jump_to(JumpTarget::Index(index));
+    }
+
+    fn user_action_play_file(&self, path: &str, cur_dir: &Path) {
+        let path = cur_dir.join(path);
+        let Some(path) = path.absolutize().to_option() else {
+            self.popup.show("invalid path");
+            return;
+        };
+        self.player
+            .jump_to(JumpTarget::File(path.to_string_lossy().to_string()));
+    }
+
+    // the new tags are shown if the current track was edited
+    fn tags_edited(&self, paths: &[String], cur_dir: &Path) {
+        for path in paths {
+            if let Some(path) = cur_dir.join(path).absolutize().to_option() {
+                self.player.reload_meta(&path.to_string_lossy());
+            }
+        }
+    }
+
+    fn playlist_ended(&self) {
+        match self.playlist_end {
+            PlaylistEndAction::Stop => self.popup.show("the playlist has ended"),
+            PlaylistEndAction::Repeat => {
+                self.popup.show("the playlist has ended, playing it again");
+                self.player.play(Some(0));
+            }
+            PlaylistEndAction::Shuffle => self.replay_shuffled(),
+            PlaylistEndAction::AutoDj => {
+                if let Some(source) = self.auto_dj {
+                    self.continue_with_auto_dj(source);
+                } else {
+                    self.popup
+                        .show("the playlist has ended, auto DJ is not configured");
+                }
+            }
+            PlaylistEndAction::Quit => {
+                self.popup.show("the playlist has ended, quitting");
+                self.user_action_quit();
+            }
+        }
+    }
+
+    // the previous order can be restored with "konik playlist undo"
+    // the playlist file is read and written in the background
+    fn replay_shuffled(&self) {
+        let player = self.player.clone_sender();
+        let popup = self.popup.clone();
+        let playlist_index = self.state.playlist_index;
+        thread_util::thread("shuffle", move || {
+            let Some(mut tracks) = playlist_man::load_playlist().to_option() else {
+                return;
+            };
+            if tracks.is_empty() {
+                return;
+            }
+            popup.show("the playlist has ended, playing it shuffled");
+            fastrand::shuffle(&mut tracks);
+            playlist_man::backup_playlist(playlist_index).ignore_err();
+            playlist_man::save_playlist(&tracks).ignore_err();
+            player.set_playlist(tracks, None);
+            player.play(Some(0));
+        });
+    }
+
+    // the lookup may take a while, so the tracks are appended when they are found
+    fn continue_with_auto_dj(&self, source: AutoDjSource) {
+        let player = self.player.clone_sender();
+        let popup = self.popup.clone();
+        let meta_cache = self.meta_cache.clone();
+        let last_track = self.cur_track.clone();
+        let last_meta = self.meta.clone();
+        thread_util::thread("auto DJ", move || {
+            let tracks = auto_dj::find_tracks(source, &meta_cache, last_track.as_ref(), &last_meta)
+                .with_context(|| format!("cannot get {} recommendations", source.title()))
+                .ok_or_default();
+            if tracks.is_empty() {
+                popup.show("the playlist has ended, no recommended tracks were found");
+                return;
+            }
+            popup.show(&format!(
+                "the playlist has ended, adding {} recommended tracks",
+                tracks.len()
+            ));
+            playlist_man::append_to_playlist(&tracks).ignore_err();
+            player.append_tracks(tracks, CueFactory::new());
+            player.next();
+        });
+    }
+
+    fn user_action_random_dir(&self) {
+        self.player.random_dir();
+    }
+
     fn user_action_stop(&mut self) {
         self.player.stop();
         self.set_playback_state(PlaybackState::Stopped, None);
@@ -177,6 +841,7 @@ impl App {
 
     fn user_action_quit(&self) {
         println_with_date("shutting down...");
+        systemd::notify_stopping();
         self.player.exit();
     }
 
@@ -234,34 +899,41 @@ impl App {
         match SysVol::new() {
             Ok(sys_vol) => self.process_sys_vol_result(sys_vol.modify_with_step(step)),
             Err(e) => e.context("cannot create system volume controller").log(),
-        };
+        }
     }
 
     fn user_action_sysvol_down(&self) {
-        self.change_volume(-VOL_STEP);
+        self.change_volume(-self.steps.volume);
     }
 
     fn user_action_sysvol_up(&self) {
-        self.change_volume(VOL_STEP);
+        self.change_volume(self.steps.volume);
     }
 
+    #[allow(clippy::cast_possible_truncation)]
     fn set_vol(&mut self, new_volume: f32, show_popup: bool) {
         let new_volume = new_volume.clamp(0.0, 1.0);
-        let steps_count = (new_volume / VOL_STEP as f32).round();
-        let new_volume = steps_count * VOL_STEP as f32;
+        let vol_step = self.steps.volume as f32;
+        let steps_count = (new_volume / vol_step).round();
+        let new_volume = (steps_count * vol_step).clamp(0.0, 1.0);
         self.state.volume = new_volume;
         self.player.set_volume(new_volume);
+        // the MPRIS clients read the volume from the state, not from the player
+        self.media_controls
+            .mut_map(|c| c.set_volume(new_volume).ignore_err());
         self.update_tray(show_popup);
-        self.state.save().ignore_err();
+        self.state.save_later();
     }
 
     fn user_action_vol_down(&mut self) {
-        let new_volume = self.state.volume - VOL_STEP as f32;
+        #[allow(clippy::cast_possible_truncation)]
+        let new_volume = self.state.volume - self.steps.volume as f32;
         self.set_vol(new_volume, true);
     }
 
     fn user_action_vol_up(&mut self) {
-        let new_volume = self.state.volume + VOL_STEP as f32;
+        #[allow(clippy::cast_possible_truncation)]
+        let new_volume = self.state.volume + self.steps.volume as f32;
         self.set_vol(new_volume, true);
     }
 
@@ -277,30 +949,222 @@ impl App {
         self.player.seek_to(position);
     }
 
-    fn user_action_open_uri(&self, uri_str: String) {
-        self.play_paths(&[uri_str], &PathBuf::new());
+    fn user_action_open_uri(&mut self, uri_str: String) {
+        self.play_paths(&[uri_str], &PathBuf::new(), None);
+    }
+
+    fn user_action_rate(&self, rating: u8) {
+        let Some(track) = &self.cur_track else {
+            self.popup.show("no file loaded");
+            return;
+        };
+        // the tags are written without locking the app
+        if let Some(tx) = &self.workers.rating_tx {
+            tx.send((track.clone(), rating)).ok();
+        }
+    }
+
+    fn track_rated(&mut self, track: &Track, rating: u8, result: Result<()>) {
+        match result {
+            Ok(()) => {
+                // another track may be playing by now
+                if self.cur_track.as_ref() == Some(track) {
+                    self.meta.rating = Some(rating);
+                }
+                self.popup
+                    .show(&format!("rated: {}", rating::stars(rating)));
+            }
+            Err(e) => {
+                self.popup.show("cannot rate the track");
+                e.log();
+            }
+        }
+    }
+
+    fn user_action_undo_playlist(&self) {
+        if let Some(tx) = &self.workers.playlist_job_tx {
+            tx.send(PlaylistJob::Undo {
+                playlist_index: self.state.playlist_index,
+            })
+            .ok();
+        }
     }
 
-    fn process_hotkey(&mut self, action: HotKeyAction) {
+    fn playlist_restored(&mut self, result: Result<(Vec<Track>, Option<usize>)>) {
+        match result {
+            Ok((tracks, playlist_index)) => {
+                // a running scan would replace the restored playlist
+                if let Some(cancel_flag) = self.scan_cancel_flag.take() {
+                    cancel_flag.store(true, Ordering::Relaxed);
+                }
+                self.state.playlist_index = playlist_index;
+                self.state.save_later();
+                self.popup.show(&format!(
+                    "restored the previous playlist ({} tracks)",
+                    playlist_man::format_count(tracks.len())
+                ));
+                self.player.set_playlist(tracks, None);
+                if let Some(playlist_index) = playlist_index {
+                    self.player.load_meta(playlist_index);
+                }
+            }
+            Err(e) => {
+                self.popup.show("cannot restore the previous playlist");
+                e.log();
+            }
+        }
+    }
+
+    fn user_action_open_share(&self, source: &str, cur_dir: &Path) {
+        // a file name is relative to the folder where the command was run
+        let source = if source.starts_with("share://") {
+            source.to_string()
+        } else {
+            cur_dir.join(source).to_string_lossy().to_string()
+        };
+        if let Some(tx) = &self.workers.playlist_job_tx {
+            tx.send(PlaylistJob::OpenShare { source }).ok();
+        }
+    }
+
+    // a running scan would replace the shared playlist
+    fn cancel_scan(&mut self) {
+        if let Some(cancel_flag) = self.scan_cancel_flag.take() {
+            cancel_flag.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn share_opened(&mut self, result: Result<(Vec<Track>, Option<usize>)>) {
+        match result {
+            Ok((tracks, playlist_index)) => {
+                self.state.playlist_index = playlist_index;
+                self.state.save_later();
+                self.popup.show(&format!(
+                    "opened the shared playlist ({} tracks)",
+                    playlist_man::format_count(tracks.len())
+                ));
+                self.player.set_playlist(tracks, None);
+                self.player.play(Some(playlist_index.unwrap_or(0)));
+            }
+            Err(e) => {
+                self.popup.show("cannot open the shared playlist");
+                e.log();
+            }
+        }
+    }
+
+    fn user_action_love(&mut self) {
+        let artist = self.scrobblers.artist.of(&self.meta).map(str::to_string);
+        let (Some(artist), Some(title)) = (artist, self.meta.title.clone()) else {
+            self.popup.show("cannot love: no artist or title");
+            return;
+        };
+        if self.scrobblers.is_empty() {
+            self.popup
+                .show("cannot love: no ListenBrainz, Last.fm or Libre.fm account");
+            return;
+        }
+        let text = format!("{artist} - {title}");
+        let results = self
+            .scrobblers
+            .dispatch("love call", move |s| s.love(&artist, &title));
+        self.scrobblers.wake_retry();
+        // shown once a service has accepted the request
+        let popup = self.popup.clone();
+        thread_util::thread("love result", move || {
+            if results.recv().is_ok() {
+                popup.show(&format!("loved: {text}"));
+            } else {
+                popup.show(&format!("cannot love: {text}"));
+            }
+        });
+    }
+
+    fn user_action_toggle_karaoke(&mut self) {
+        self.state.karaoke = !self.state.karaoke;
+        self.state.save_later();
+        self.player.set_dsp(dsp_settings(
+            &self.state,
+            self.karaoke_strength,
+            self.replay_gain,
+            self.auto_gain,
+        ));
+        self.popup.show(if self.state.karaoke {
+            "karaoke: on"
+        } else {
+            "karaoke: off"
+        });
+    }
+
+    fn user_action_add_bookmark(&mut self, name: &str) {
+        if self.cur_track.is_none() {
+            self.popup.show("no file loaded");
+            return;
+        }
+        // the bookmark is saved when the player reports the position
+        self.pending_bookmark_name = Some(name.to_string());
+        self.player.request_position();
+    }
+
+    fn save_pending_bookmark(&mut self, position: Duration) {
+        if let (Some(name), Some(track)) = (self.pending_bookmark_name.take(), &self.cur_track) {
+            match bookmarks::add(Bookmark::new(track, &name, position)) {
+                Ok(()) => self.popup.show(&format!(
+                    "bookmark added: {name} ({})",
+                    bookmarks::format_position(position)
+                )),
+                Err(e) => e.context("cannot add bookmark").log(),
+            }
+        }
+    }
+
+    fn user_action_jump_to_bookmark(&self, name: &str) {
+        let Some(track) = &self.cur_track else {
+            self.popup.show("no file loaded");
+            return;
+        };
+        match bookmarks::find(track, name) {
+            Ok(Some(bookmark)) => {
+                self.player.seek_to(bookmark.position());
+                self.popup.show(&format!("bookmark: {}", bookmark.name));
+            }
+            Ok(None) => self.popup.show(&format!("bookmark not found: {name}")),
+            Err(e) => e.context("cannot load bookmarks").log(),
+        }
+    }
+
+    pub fn process_hotkey(&mut self, action: HotKeyAction) {
         match action {
             HotKeyAction::StopPlay => self.user_action_toggle_stop(),
             HotKeyAction::Next => self.user_action_next(),
             HotKeyAction::Prev => self.user_action_prev(),
             HotKeyAction::NextDir => self.user_action_next_dir(),
             HotKeyAction::PrevDir => self.user_action_prev_dir(),
+            HotKeyAction::RandomDir => self.user_action_random_dir(),
             HotKeyAction::PauseToggle => self.user_action_toggle_pause(),
             HotKeyAction::SysVolDown => self.user_action_sysvol_down(),
             HotKeyAction::SysVolUp => self.user_action_sysvol_up(),
             HotKeyAction::VolDown => self.user_action_vol_down(),
             HotKeyAction::VolUp => self.user_action_vol_up(),
+            HotKeyAction::Rate(rating) => self.user_action_rate(rating),
+            HotKeyAction::ToggleKaraoke => self.user_action_toggle_karaoke(),
+            HotKeyAction::Love => self.user_action_love(),
+            HotKeyAction::SeekForward => self.user_action_seek_by(true, self.steps.seek),
+            HotKeyAction::SeekBackward => self.user_action_seek_by(false, self.steps.seek),
+            HotKeyAction::LargeSeekForward => {
+                self.user_action_seek_by(true, self.steps.large_seek);
+            }
+            HotKeyAction::LargeSeekBackward => {
+                self.user_action_seek_by(false, self.steps.large_seek);
+            }
         }
     }
 
-    fn update_tray(&mut self, show_popup: bool) {
+    fn update_tray(&self, show_popup: bool) {
         #[allow(clippy::cast_sign_loss)]
         let vol_percent = (self.state.volume * 100.0).round() as u8;
         if let Some(track) = &self.cur_track {
-            let path = Path::new(&track.filename);
+            let path = Path::new(&*track.filename);
             let dir_part = if let Some(dir) = path.parent() {
                 if let Some(dirname) = dir.file_name() {
                     dirname.to_string_lossy().to_string()
@@ -335,12 +1199,8 @@ impl App {
             );
             self.tray.set_tooltip(&tooltip);
 
-            self.media_controls
-                .mut_map(|c| c.set_metadata(&self.meta).ignore_err());
-            self.player.request_position(); // because set_volume resets the position
-
             if show_popup {
-                self.popup.show(&tooltip);
+                self.popup.announce(&tooltip, self.cover_art.as_deref());
             }
         } else {
             self.tray
@@ -348,58 +1208,74 @@ impl App {
         }
     }
 
+    // the cover is downloaded in the background if there's none on disk
+    fn update_cover_art(&mut self) {
+        let Some(track) = &self.cur_track else {
+            return;
+        };
+        self.cover_art = cover_art::find_local(&track.filename, &self.meta);
+        if self.cover_art.is_none() {
+            if let Some(tx) = &self.cover_art_tx {
+                tx.send((track.filename.clone(), self.meta.clone())).ok();
+            }
+        }
+    }
+
+    fn cover_art_fetched(&mut self, filename: &str, cover: PathBuf) {
+        if !self
+            .cur_track
+            .as_ref()
+            .is_some_and(|track| &*track.filename == filename)
+        {
+            return;
+        }
+        self.cover_art = Some(cover);
+        self.update_media_metadata();
+    }
+
+    // only when the tags or the cover change, since the MPRIS clients reset the position
+    fn update_media_metadata(&mut self) {
+        self.media_controls.mut_map(|c| {
+            c.set_metadata(&self.meta, self.cover_art.as_deref())
+                .ignore_err();
+        });
+        self.player.request_position(); // because set_metadata resets the position
+    }
+
     fn process_position_callback(&mut self, callback: &PositionCallback) {
-        if self.meta.duration > POS_MIN_DURATION_TO_SCROBBLE {
+        if self.meta.duration > self.scrobble_min_duration {
             let meta = &self.meta;
-            if let (Some(artist), Some(title)) = (&meta.artist, &meta.title) {
+            let filename = self
+                .cur_track
+                .as_ref()
+                .map(|track| track.filename.clone())
+                .unwrap_or_default();
+            if let (Some(artist), Some(title)) = (self.scrobblers.artist.of(meta), &meta.title) {
                 match callback.id {
                     POS_CALLBACK_NOW_PLAYING => {
-                        if let Some(listenbrainz) = &mut self.listenbrainz {
-                            listenbrainz
-                                .playing_now(artist, &meta.album, title, meta.track, meta.duration)
-                                .context("ListenBrainz playing now call failed")
-                                .ignore_err();
-                        }
-
-                        if let Some(lastfm) = &mut self.lastfm {
-                            lastfm
-                                .playing_now(
-                                    artist,
-                                    &meta.album,
-                                    title,
-                                    meta.track,
-                                    Some(meta.duration),
-                                )
-                                .context("Last.fm playing now call failed")
-                                .ignore_err();
-                        }
+                        self.scrobblers.dispatch_track(
+                            "playing now call",
+                            &filename,
+                            scrobble_track(artist, title, meta),
+                            |s, track| s.playing_now(track),
+                        );
                     }
                     POS_CALLBACK_SCROBBLE => {
-                        if self.last_seek_position.unwrap_or_default().is_zero() {
-                            if let Some(listenbrainz) = &mut self.listenbrainz {
-                                listenbrainz
-                                    .submit(artist, &meta.album, title, meta.track, meta.duration)
-                                    .context("ListenBrainz submit failed")
-                                    .ignore_err();
-                            }
-
-                            if let Some(lastfm) = &mut self.lastfm {
-                                lastfm
-                                    .scrobble(
-                                        artist,
-                                        &meta.album,
-                                        title,
-                                        meta.track,
-                                        Some(meta.duration),
-                                    )
-                                    .context("Last.fm scrobble failed")
-                                    .ignore_err();
-                            }
-                        }
+                        self.scrobblers.dispatch_track(
+                            "scrobble",
+                            &filename,
+                            scrobble_track(artist, title, meta),
+                            |s, track| s.scrobble(track),
+                        );
                     }
                     _ => {}
                 }
             }
+            match callback.id {
+                POS_CALLBACK_NOW_PLAYING => subsonic::report_playback(&filename, false),
+                POS_CALLBACK_SCROBBLE => subsonic::report_playback(&filename, true),
+                _ => {}
+            }
         }
 
         if callback.id == POS_CALLBACK_HL_END
@@ -407,6 +1283,35 @@ impl App {
         {
             self.tray.play();
         }
+
+        if callback.id == POS_CALLBACK_COMPLETED {
+            if let Some(track) = &self.cur_track {
+                history::add(track, &self.meta)
+                    .context("cannot add the track to the playback history")
+                    .ignore_err();
+            }
+        }
+    }
+
+    fn new_playlist_index(&mut self, playlist_index: usize, track: Track, user_navigation: bool) {
+        self.playlist_index = playlist_index;
+        self.cur_track = Some(track);
+        self.remember_dir_position(Duration::ZERO);
+        self.meta = TrackMeta::default();
+        self.cover_art = None;
+        if self.state.playlist_index != Some(playlist_index) {
+            self.state.playlist_index = Some(playlist_index);
+            self.state.save_later();
+        }
+        if !user_navigation && matches!(self.tray.image_type(), TrayIconImageType::Play) {
+            self.tray.play_hl();
+        }
+        // the cached tags are shown until the actual ones are read
+        if let Some(meta) = self.cur_track.as_ref().and_then(|t| self.meta_cache.get(t)) {
+            self.meta = meta;
+            self.update_tray(false);
+            self.update_media_metadata();
+        }
     }
 
     fn process_player_response(&mut self, resp: PlayerResponse) -> bool {
@@ -415,49 +1320,69 @@ impl App {
                 playlist_index,
                 track,
                 user_navigation,
-            } => {
-                self.playlist_index = playlist_index;
-                self.cur_track = Some(track);
-                self.meta = TrackMeta::default();
-                if self.state.playlist_index != Some(playlist_index) {
-                    self.state.playlist_index = Some(playlist_index);
-                    self.state.save().ignore_err();
-                }
-                self.last_seek_position = None;
-                if !user_navigation && matches!(self.tray.image_type(), TrayIconImageType::Play) {
-                    self.tray.play_hl();
+            } => self.new_playlist_index(playlist_index, track, user_navigation),
+            PlayerResponse::PlaylistEnded => self.playlist_ended(),
+            PlayerResponse::JumpTargetNotFound => {
+                self.popup.show("the track is not in the playlist");
+            }
+            PlayerResponse::PlaylistPruned { tracks, removed } => {
+                if removed > 0 {
+                    playlist_man::save_playlist(&tracks).ignore_err();
+                    self.popup.show(&format!(
+                        "removed {} missing files from the playlist",
+                        playlist_man::format_count(removed)
+                    ));
+                } else {
+                    self.popup.show("no missing files in the playlist");
                 }
             }
-            PlayerResponse::PlaylistEnded => {
-                self.popup.show("the playlist has ended");
+            PlayerResponse::TrackFailed { track, reason } => self.bad_files.add(&track, reason),
+            PlayerResponse::TooManyFailures { count } => {
+                error_popup::notify(
+                    ErrorSeverity::Critical,
+                    &format!("{count} tracks failed in a row, the playback is stopped"),
+                );
+            }
+            PlayerResponse::PlaylistUpdated { playlist_index } => {
+                if let Some(playlist_index) = playlist_index {
+                    self.playlist_index = playlist_index;
+                    if self.state.playlist_index != Some(playlist_index) {
+                        self.state.playlist_index = Some(playlist_index);
+                        self.state.save_later();
+                    }
+                    self.update_tray(false);
+                }
             }
             PlayerResponse::NewMeta {
                 meta,
                 user_navigation,
             } => {
-                self.meta = meta;
+                self.meta = *meta;
+                self.update_cover_art();
+                if let (Some(lyrics), Some(track)) = (&self.lyrics, &self.cur_track) {
+                    lyrics.set_track(track, &self.meta);
+                }
                 let state = self.playback_state.clone();
                 self.set_playback_state(state, Some(Duration::default()));
                 self.update_tray(user_navigation);
+                self.update_media_metadata();
             }
             PlayerResponse::PlaybackStateChanged { state, position } => {
                 self.set_playback_state(state, Some(position));
             }
             PlayerResponse::PositionRequested { position } => {
+                self.save_pending_bookmark(position);
                 self.set_playback_state(self.playback_state.clone(), Some(position));
             }
             PlayerResponse::Seeked { position } => {
                 let state = self.playback_state.clone();
-                self.last_seek_position = Some(position);
                 self.media_controls
                     .mut_map(|c| c.set_state(&state, Some(position)).ignore_err());
+                self.publish_position(Some(position));
             }
             PlayerResponse::PositionCallback { callback, .. } => {
                 self.process_position_callback(&callback);
             }
-            PlayerResponse::VolumeSet { volume } => {
-                self.media_controls.mut_map(|c| c.set_volume(volume));
-            }
             PlayerResponse::Exited => {
                 return false;
             }
@@ -477,10 +1402,10 @@ impl App {
             MediaControlEvent::Raise => self.update_tray(true),
             MediaControlEvent::Seek(dir) => match dir {
                 SeekDirection::Forward => {
-                    self.user_action_seek_by(true, DEFAULT_SEEK_LENGTH);
+                    self.user_action_seek_by(true, self.steps.seek);
                 }
                 SeekDirection::Backward => {
-                    self.user_action_seek_by(false, DEFAULT_SEEK_LENGTH);
+                    self.user_action_seek_by(false, self.steps.seek);
                 }
             },
             MediaControlEvent::SeekBy(dir, length) => match dir {
@@ -501,37 +1426,129 @@ pub struct AppHandle {
 }
 
 impl AppHandle {
+    // the player saves the audiobook positions before it exits
     pub fn wait(self) {
         self.player_thread.join().unwrap();
+        // the parts are taken out, so the hotkeys, the tray and MPRIS
+        // are not blocked while the parts are being stopped
         let mut app = self.app.lock().unwrap();
-        app.hotkeys.stop();
-        app.player.wait();
-        app.lastfm.take();
-        app.listenbrainz.take();
-        app.tray.shutdown();
+        let mut hotkeys = std::mem::replace(&mut app.hotkeys, HotKeys::new());
+        let player_thread = app.player.take_server_thread();
+        let state = app.state.clone();
+        let tray = std::mem::replace(&mut app.tray, TrayIcon::headless());
+        let scrobble_workers = app.scrobblers.take_workers();
+        let bad_files = app.bad_files.clone();
+        drop(app);
+
+        hotkeys.stop();
+        if let Some(player_thread) = player_thread {
+            player_thread.join().to_anyhow().ignore_err();
+        }
+        state.save().ignore_err();
+        bad_files.save();
+        ScrobbleDispatcher::shutdown(scrobble_workers, SCROBBLERS_SHUTDOWN_TIMEOUT);
+        tray.shutdown();
 
         // Unregistering media_controls may take almost 1 second
         // app.media_controls.take();
     }
 }
 
+fn debug_startup_step(started_at: Instant, step: &str) {
+    debugln_with_date(format!(
+        "startup: {step} ({} ms)",
+        started_at.elapsed().as_millis()
+    ));
+}
+
+// the scrobblers are created later, so the callbacks are always needed
+fn position_callbacks(config: &Config) -> Vec<PositionCallback> {
+    return vec![
+        PositionCallback::from_start(POS_CALLBACK_HL_END, POS_HL_END_SECS),
+        PositionCallback::from_end(POS_CALLBACK_COMPLETED, POS_COMPLETED_SECS),
+        PositionCallback::from_start(POS_CALLBACK_NOW_PLAYING, POS_NOW_PLAYING_SECS),
+        scrobble_callback(config),
+    ];
+}
+
+// the settings of the modules that are used by several threads
+fn init_modules(config: &Config) {
+    logger::init(config.log_levels.clone());
+    crash::install();
+    silence::init(&config.silence_split_dirs);
+    cue::init(config.cue_pregap);
+    symphonia_stream::init(config.video_files, config.audio_language.as_deref());
+}
+
+// returns the karaoke strength for the later toggles
+fn init_dsp(player: &PlayerTx, state: &AppState, config: &Config) -> f32 {
+    let karaoke_strength = config
+        .karaoke_strength
+        .unwrap_or(dsp::DEFAULT_KARAOKE_STRENGTH)
+        .clamp(0.0, 1.0);
+    player.set_dsp(dsp_settings(
+        state,
+        karaoke_strength,
+        config.replay_gain,
+        config.auto_gain,
+    ));
+    return karaoke_strength;
+}
+
+// the parts that need a desktop session, the headless mode goes without them
+fn start_desktop(
+    config: &Config,
+    headless: bool,
+    startup_report: &mut StartupReport,
+) -> Result<(TrayIcon, Popup)> {
+    if headless {
+        println_with_date("running in the headless mode");
+        return Ok((TrayIcon::headless(), Popup::headless()));
+    }
+    let tray_style = TrayIconStyle::detect(config.tray_high_contrast, config.tray_large_icons);
+    let tray = TrayIcon::new(tray_style).context("cannot create tray icon")?;
+    startup_report.check("tray", startup_report::check_tray());
+    startup_report.check("notifications", startup_report::check_notifications());
+    if let Some(quiet_hours) = &config.quiet_hours {
+        quiet_hours.validate().ignore_err();
+    }
+    return Ok((tray, Popup::new(config.quiet_hours.clone())));
+}
+
 pub fn start(cli_args: &Args, cur_dir: &Path) -> Result<AppHandle> {
-    let listenbrainz = ListenBrainz::useable_or_none();
-    let lastfm = LastFM::useable_or_none();
-    let position_callbacks = if listenbrainz.is_some() || lastfm.is_some() {
-        Some(vec![
-            PositionCallback::from_start(POS_CALLBACK_NOW_PLAYING, POS_NOW_PLAYING_SECS),
-            PositionCallback::from_end(POS_CALLBACK_SCROBBLE, POS_SCROBBLE_SECS),
-            PositionCallback::from_start(POS_CALLBACK_HL_END, POS_HL_END_SECS),
-        ])
-    } else {
-        None
-    };
-    let (player, dec_rx) = player::start_thread(position_callbacks);
-    let media_controls = MediaControls::new_if_available();
+    let started_at = Instant::now();
+    report::trim_logs();
+    let config = Config::load_or_default();
+    init_modules(&config);
+    let meta_cache = MetaCache::new();
+    let (player, dec_rx) = player::start_thread(
+        Some(position_callbacks(&config)),
+        config.low_latency,
+        config.readahead,
+        config.audiobook_dirs.clone(),
+        meta_cache.clone(),
+        DeviceLatency::new(&config.device_latency_ms),
+        release_after_pause(config.release_after_pause_secs),
+    );
+    debug_startup_step(started_at, "player started");
+
+    // registering on D-Bus may take a while, so do it while the rest is initialized
+    let media_controls_thread = thread_util::thread("media controls init", MediaControls::new);
+    let mut startup_report = StartupReport::new();
 
-    let state = AppState::load_or_default();
+    let mut state = AppState::load_or_default();
+    if let Some(note) = crash::take_previous_crash(&mut state) {
+        startup_report.note(note);
+    }
     player.set_volume(state.volume);
+    let karaoke_strength = init_dsp(&player, &state, &config);
+    let headless = cli_args.headless || config.headless;
+    let (tray, popup) = start_desktop(&config, headless, &mut startup_report)?;
+    debug_startup_step(started_at, "tray created");
+    startup_report.check("system volume", SysVol::new());
+    for server in &config.listenbrainz_servers {
+        startup_report.check("scrobblers", server.validate());
+    }
     let app = Arc::new(Mutex::new(App {
         player,
         playback_state: PlaybackState::default(),
@@ -539,33 +1556,88 @@ pub fn start(cli_args: &Args, cur_dir: &Path) -> Result<AppHandle> {
         cur_track: None,
         meta: TrackMeta::default(),
         hotkeys: HotKeys::new(),
-        tray: TrayIcon::new().context("cannot create tray icon")?,
-        listenbrainz,
-        lastfm,
+        tray,
+        scrobblers: ScrobbleDispatcher::new(
+            config.listenbrainz_servers.clone(),
+            ScrobbleFilter::new(&config.scrobble_rules),
+            config.scrobble_artist,
+        ),
         state,
-        popup: Popup::new(),
-        media_controls,
-        last_seek_position: None,
+        popup,
+        media_controls: None,
+        discord: None,
+        lyrics: None,
+        auto_dj: config.auto_dj,
+        playlist_end: playlist_end_action(&config),
+        cover_art: None,
+        cover_art_tx: None,
+        workers: Workers::default(),
+        scan_cancel_flag: None,
+        pending_bookmark_name: None,
+        meta_cache,
+        karaoke_strength,
+        replay_gain: config.replay_gain,
+        auto_gain: config.auto_gain,
+        scrobble_min_duration: scrobble_min_duration(config.scrobble_min_duration_secs),
+        paused_by_unplug: false,
+        paused_by_other_player: false,
+        second_instance_action: config
+            .second_instance_action
+            .unwrap_or(SignalAction::PauseToggle)
+            .hotkey_action(),
+        dir_memory: config.resume_dirs.then(DirMemory::load),
+        steps: Steps::new(&config),
+        bad_files: BadFiles::new(),
     }));
+    error_popup::init(
+        app.lock().unwrap().popup.clone(),
+        config.error_notifications,
+    );
 
+    app.lock()
+        .unwrap()
+        .init_playlist(&cli_args.paths, cur_dir, config.prune_playlist);
+    debug_startup_step(started_at, "playlist requested");
     set_tray_menu(&app);
-    start_hotkey_thread(&app).context("cannot start hotkey thread")?;
-    app.lock().unwrap().init_playlist(&cli_args.paths, cur_dir);
-    setup_media_controls(&app).context("cannot setup media controls")?;
+    if !headless {
+        startup_report.check(
+            "hotkeys",
+            start_hotkey_thread(&app, &config.hotkey_modifiers),
+        );
+        debug_startup_step(started_at, "hotkeys registered");
+    }
+    app.lock().unwrap().media_controls = startup_report.check(
+        "MPRIS",
+        media_controls_thread
+            .join()
+            .to_anyhow()
+            .and_then(|controls| controls),
+    );
+    startup_report.check("MPRIS", setup_media_controls(&app));
+    debug_startup_step(started_at, "media controls attached");
 
     let player_thread = start_player_response_thread(&app, dec_rx);
+    start_workers(&app);
+    start_integrations(&app, &config, &mut startup_report);
+    startup_report.publish(&app.lock().unwrap().popup);
 
     return Ok(AppHandle { app, player_thread });
 }
 
-fn start_hotkey_thread(app_arc: &Arc<Mutex<App>>) -> Result<()> {
+fn start_hotkey_thread(app_arc: &Arc<Mutex<App>>, modifier_names: &[String]) -> Result<()> {
+    // the same hotkeys can't be registered by several instances
+    if modifier_names.is_empty() && project_info::profile().is_some() {
+        println_with_date("no hotkey modifiers are set for this profile, the hotkeys are disabled");
+        return Ok(());
+    }
+    let modifiers = hotkeys::parse_modifiers(modifier_names)?;
     let app_arc = app_arc.clone();
     app_arc
         .clone()
         .lock()
         .unwrap()
         .hotkeys
-        .start(move |action| {
+        .start(modifiers, move |action| {
             let mut app = app_arc.lock().unwrap();
             app.process_hotkey(action);
         })
@@ -596,20 +1668,210 @@ fn start_player_response_thread(
     return t;
 }
 
+// The retries only run while something may be left unsent,
+// otherwise the thread sleeps until the next scrobble or love call.
+fn start_scrobble_retry_thread(app_arc: &Arc<Mutex<App>>) {
+    let (tx, rx) = mpsc::channel();
+    let mut app = app_arc.lock().unwrap();
+    app.scrobblers.retry_tx = Some(tx);
+    let listenbrainz_servers = app.scrobblers.listenbrainz_servers.clone();
+    drop(app);
+    let app_arc = app_arc.clone();
+    thread_util::thread("scrobble retry", move || {
+        // the scrobbles that are left from the previous run are sent right away
+        let mut is_active = scrobbler::has_pending(&listenbrainz_servers);
+        if is_active {
+            app_arc.lock().unwrap().scrobblers.init();
+        }
+        let mut schedule = RetrySchedule::new();
+        loop {
+            if !is_active {
+                if rx.recv().is_err() {
+                    return;
+                }
+                is_active = true;
+                schedule = RetrySchedule::new();
+            }
+            if rx.recv_timeout(scrobble_retry::POLL_INTERVAL) == Err(RecvTimeoutError::Disconnected)
+            {
+                return;
+            }
+            if schedule.is_due() {
+                let results = app_arc.lock().unwrap().retry_scrobbles();
+                // the app is not locked while the services respond
+                let had_pending = results
+                    .is_some_and(|results| return results.iter().any(|had_pending| had_pending));
+                schedule.retried(had_pending);
+                is_active = had_pending;
+            }
+        }
+    });
+}
+
+// the threads that do the slow work without locking the app
+fn start_workers(app_arc: &Arc<Mutex<App>>) {
+    start_scrobble_retry_thread(app_arc);
+    start_rating_thread(app_arc);
+    start_playlist_job_thread(app_arc);
+}
+
+// the shared playlist replaces the current one
+fn open_share(app_arc: &Arc<Mutex<App>>, source: &str) -> Result<(Vec<Track>, Option<usize>)> {
+    let (tracks, playlist_index) = share::open(source)?;
+    let mut app = app_arc.lock().unwrap();
+    app.cancel_scan();
+    let cur_playlist_index = app.state.playlist_index;
+    drop(app);
+    playlist_man::backup_playlist(cur_playlist_index).ignore_err();
+    playlist_man::save_playlist(&tracks).ignore_err();
+    return Ok((tracks, playlist_index));
+}
+
+fn start_playlist_job_thread(app_arc: &Arc<Mutex<App>>) {
+    let (tx, rx) = mpsc::channel::<PlaylistJob>();
+    app_arc.lock().unwrap().workers.playlist_job_tx = Some(tx);
+    let app_arc = app_arc.clone();
+    thread_util::thread("playlist jobs", move || {
+        for job in rx {
+            match job {
+                PlaylistJob::Undo { playlist_index } => {
+                    let result = playlist_man::restore_backup(playlist_index);
+                    app_arc.lock().unwrap().playlist_restored(result);
+                }
+                PlaylistJob::OpenShare { source } => {
+                    let result = open_share(&app_arc, &source);
+                    app_arc.lock().unwrap().share_opened(result);
+                }
+            }
+        }
+    });
+}
+
+fn start_rating_thread(app_arc: &Arc<Mutex<App>>) {
+    let (tx, rx) = mpsc::channel::<(Track, u8)>();
+    app_arc.lock().unwrap().workers.rating_tx = Some(tx);
+    let app_arc = app_arc.clone();
+    thread_util::thread("rating", move || {
+        while let Ok((track, rating)) = rx.recv() {
+            let result = rating::write(&track, rating);
+            app_arc.lock().unwrap().track_rated(&track, rating, result);
+        }
+    });
+}
+
+// the optional parts that are enabled in the config
+fn start_integrations(
+    app_arc: &Arc<Mutex<App>>,
+    config: &Config,
+    startup_report: &mut StartupReport,
+) {
+    if config.pause_on_headphones_unplug {
+        startup_report.check(
+            "headphones",
+            start_headphones_watcher(app_arc, config.resume_on_headphones_plug),
+        );
+    }
+    if config.pause_for_other_players {
+        startup_report.check("other players", start_other_players_watcher(app_arc));
+    }
+    let cover_art_tx = config
+        .fetch_cover_art
+        .then(|| start_cover_art_thread(app_arc));
+    let mut app = app_arc.lock().unwrap();
+    app.discord = config
+        .discord_client_id
+        .as_deref()
+        .map(DiscordPresence::new);
+    app.lyrics = config
+        .lyrics_notifications
+        .then(|| LyricsNotifier::new(app.popup.clone(), config.lyrics_provider.clone()));
+    app.cover_art_tx = cover_art_tx;
+}
+
+fn start_headphones_watcher(app_arc: &Arc<Mutex<App>>, resume: bool) -> Result<()> {
+    let app_arc = app_arc.clone();
+    return headphones::watch(move |plugged| {
+        app_arc.lock().unwrap().headphones_changed(plugged, resume);
+    });
+}
+
+fn start_other_players_watcher(app_arc: &Arc<Mutex<App>>) -> Result<()> {
+    let app_arc = app_arc.clone();
+    return other_players::watch(move |is_playing| {
+        app_arc.lock().unwrap().other_player_changed(is_playing);
+    });
+}
+
+// the covers are looked up one by one, since MusicBrainz limits the request rate
+fn start_cover_art_thread(app_arc: &Arc<Mutex<App>>) -> Sender<(Arc<str>, TrackMeta)> {
+    let (tx, rx) = mpsc::channel::<(Arc<str>, TrackMeta)>();
+    let app_arc = app_arc.clone();
+    thread_util::thread("cover art", move || {
+        while let Ok((filename, meta)) = rx.recv() {
+            match cover_art::fetch(&meta) {
+                Ok(Some(cover)) => app_arc.lock().unwrap().cover_art_fetched(&filename, cover),
+                Ok(None) => debugln_with_date(format!("no cover art found: {filename}")),
+                Err(e) => e.context("cannot fetch cover art").log(),
+            }
+        }
+    });
+    return tx;
+}
+
 fn set_tray_menu(app_arc: &Arc<Mutex<App>>) {
     let app = app_arc.lock().unwrap();
 
     app.tray.add_menu_item(|| {
         TrayMenuItem::new("Show current file", {
+            let app = app_arc.clone();
+            move || {
+                let filename = app
+                    .lock()
+                    .unwrap()
+                    .cur_track
+                    .ref_map(|t| return t.filename.clone());
+                // the file manager is called without locking the app
+                if let Some(filename) = filename {
+                    thread_util::thread("show file", move || {
+                        show_file(&filename).ignore_err();
+                    });
+                }
+            }
+        })
+    });
+
+    app.tray.add_menu_item(|| {
+        TrayMenuItem::new("Random folder", {
             let app = app_arc.clone();
             move || {
                 let app = app.lock().unwrap();
-                app.cur_track
-                    .ref_map(|t| show_file(&t.filename).ignore_err());
+                app.user_action_random_dir();
+            }
+        })
+    });
+
+    app.tray.add_menu_item(|| {
+        TrayMenuItem::new("Love", {
+            let app = app_arc.clone();
+            move || {
+                let mut app = app.lock().unwrap();
+                app.user_action_love();
             }
         })
     });
 
+    for rating in 1..=rating::MAX_RATING {
+        app.tray.add_menu_item(|| {
+            TrayMenuItem::new(&format!("Rate: {}", rating::stars(rating)), {
+                let app = app_arc.clone();
+                move || {
+                    let app = app.lock().unwrap();
+                    app.user_action_rate(rating);
+                }
+            })
+        });
+    }
+
     app.tray.add_menu_item(|| {
         TrayMenuItem::new("Exit", {
             let app = app_arc.clone();
@@ -622,8 +1884,9 @@ fn set_tray_menu(app_arc: &Arc<Mutex<App>>) {
 }
 
 fn setup_media_controls(app_arc: &Arc<Mutex<App>>) -> Result<()> {
-    let controls = &mut app_arc.lock().unwrap().media_controls;
-    if let Some(controls) = controls {
+    let app = &mut *app_arc.lock().unwrap();
+    let volume = app.state.volume;
+    if let Some(controls) = &mut app.media_controls {
         let app_arc = app_arc.clone();
         controls
             .attach(move |event| {
@@ -632,6 +1895,7 @@ fn setup_media_controls(app_arc: &Arc<Mutex<App>>) -> Result<()> {
             })
             .to_anyhow()
             .context("cannot attach media controls")?;
+        controls.set_volume(volume)?;
     }
     return Ok(());
 }
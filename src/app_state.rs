@@ -1,15 +1,46 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
+use std::{
+    sync::{Arc, Condvar, Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-use crate::{err_util::LogErr, project_file::ProjectFileJson};
+use crate::{
+    err_util::{IgnoreErr, LogErr},
+    project_file::{Migration, ProjectFileJson},
+    thread_util,
+};
+
+// the state changes often (e.g. on every volume step),
+// so the changes are collected and written at most once per this interval
+const SAVE_DELAY: Duration = Duration::from_secs(1);
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct AppState {
     pub playlist_index: Option<usize>,
     pub volume: f32,
+    #[serde(default)]
+    pub karaoke: bool,
+    // set when the player crashes, so the next start can tell about it
+    #[serde(default)]
+    pub crashed: bool,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    // 0: the state files from before the versioning have the same fields
+    Ok,
+];
+
+#[derive(Default)]
+struct Writer {
+    // the latest state that is not written yet
+    pending: Mutex<Option<AppState>>,
+    cond: Condvar,
 }
 
 impl Default for AppState {
@@ -17,10 +48,45 @@ impl Default for AppState {
         return Self {
             playlist_index: None,
             volume: 1.0,
+            karaoke: false,
+            crashed: false,
         };
     }
 }
 
+impl Writer {
+    fn run(&self) {
+        loop {
+            let pending = self.pending.lock().unwrap();
+            let pending = self
+                .cond
+                .wait_while(pending, |pending| return pending.is_none())
+                .unwrap();
+            drop(pending);
+            thread::sleep(SAVE_DELAY);
+            Self::write(&mut self.pending.lock().unwrap()).ignore_err();
+        }
+    }
+
+    // the lock is held while writing, so an older state never overwrites a newer one
+    fn write(pending: &mut Option<AppState>) -> Result<()> {
+        if let Some(state) = pending.take() {
+            AppState::file().save(&state)?;
+        }
+        return Ok(());
+    }
+}
+
+fn writer() -> &'static Arc<Writer> {
+    static WRITER: OnceLock<Arc<Writer>> = OnceLock::new();
+    return WRITER.get_or_init(|| {
+        let writer = Arc::new(Writer::default());
+        let thread_writer = writer.clone();
+        thread_util::thread("state writer", move || thread_writer.run());
+        return writer;
+    });
+}
+
 impl AppState {
     pub fn load_or_default() -> Self {
         return match Self::file().load() {
@@ -32,11 +98,28 @@ impl AppState {
         };
     }
 
+    // written right away, the pending changes are dropped
     pub fn save(&self) -> Result<()> {
-        return Self::file().save(&self);
+        let mut pending = writer().pending.lock().unwrap();
+        *pending = None;
+        let result = Self::file().save(self);
+        drop(pending);
+        return result;
+    }
+
+    // written in the background
+    pub fn save_later(&self) {
+        let writer = writer();
+        *writer.pending.lock().unwrap() = Some(self.clone());
+        writer.cond.notify_one();
+    }
+
+    // writes the changes that are still waiting for the background writer
+    pub fn flush() -> Result<()> {
+        return Writer::write(&mut writer().pending.lock().unwrap());
     }
 
     fn file() -> ProjectFileJson {
-        return ProjectFileJson::for_data("state.json", "state file");
+        return ProjectFileJson::for_state("state.json", "state file").with_migrations(MIGRATIONS);
     }
 }
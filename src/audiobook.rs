@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    err_util::IgnoreErr,
+    project_file::{Migration, ProjectFileJson},
+    stream_base::Track,
+};
+
+const AUDIOBOOK_EXTS: [&str; 1] = ["m4b"];
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Remembers the playback position of every audiobook file.
+pub struct Audiobooks {
+    dirs: Vec<PathBuf>,
+    positions: HashMap<String, u64>,
+    is_changed: bool,
+    last_save: Instant,
+}
+
+// chapters and CUE tracks are remembered separately
+fn track_key(track: &Track) -> String {
+    if let Some(index) = track.index {
+        return format!("{}#{index}", track.filename);
+    }
+    return track.filename.to_string();
+}
+
+const MIGRATIONS: &[Migration] = &[
+    // 0: the positions from before the versioning have the same format
+    Ok,
+];
+
+fn file() -> ProjectFileJson {
+    return ProjectFileJson::for_state("audiobook_positions.json", "audiobook positions")
+        .with_migrations(MIGRATIONS);
+}
+
+impl Audiobooks {
+    pub fn new(dirs: &[String]) -> Self {
+        let file = file();
+        let positions = if file.exists() {
+            file.load().ok_or_default()
+        } else {
+            HashMap::new()
+        };
+        return Self {
+            dirs: dirs.iter().map(PathBuf::from).collect(),
+            positions,
+            is_changed: false,
+            last_save: Instant::now(),
+        };
+    }
+
+    pub fn is_audiobook(&self, track: &Track) -> bool {
+        let path = Path::new(&*track.filename);
+        if let Some(ext) = path.extension() {
+            if AUDIOBOOK_EXTS.iter().any(|x| ext.eq_ignore_ascii_case(x)) {
+                return true;
+            }
+        }
+        return self.dirs.iter().any(|dir| path.starts_with(dir));
+    }
+
+    pub fn position(&self, track: &Track) -> Option<Duration> {
+        if !self.is_audiobook(track) {
+            return None;
+        }
+        return self
+            .positions
+            .get(&track_key(track))
+            .map(|ms| Duration::from_millis(*ms));
+    }
+
+    pub fn set_position(&mut self, track: &Track, position: Duration) {
+        if !self.is_audiobook(track) {
+            return;
+        }
+        let ms = u64::try_from(position.as_millis()).unwrap_or(u64::MAX);
+        if self.positions.insert(track_key(track), ms) != Some(ms) {
+            self.is_changed = true;
+        }
+    }
+
+    // the file was played till the end, so next time it will start from the beginning
+    pub fn forget_position(&mut self, track: &Track) {
+        if self.positions.remove(&track_key(track)).is_some() {
+            self.is_changed = true;
+            self.save();
+        }
+    }
+
+    pub fn save(&mut self) {
+        if self.is_changed {
+            file().save(&self.positions).ignore_err();
+            self.is_changed = false;
+        }
+        self.last_save = Instant::now();
+    }
+
+    pub fn save_periodically(&mut self) {
+        if self.last_save.elapsed() >= SAVE_INTERVAL {
+            self.save();
+        }
+    }
+}
@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    lastfm::LastFM,
+    listenbrainz::ListenBrainz,
+    meta_cache::MetaCache,
+    stream_base::{Track, TrackMeta},
+};
+
+const MAX_TRACKS: usize = 20;
+
+// where the next tracks are taken from when the playlist ends
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoDjSource {
+    // the recordings that ListenBrainz recommends to the user
+    Listenbrainz,
+    // the artists that Last.fm considers similar to the last played one
+    Lastfm,
+}
+
+impl AutoDjSource {
+    pub const fn title(self) -> &'static str {
+        return match self {
+            Self::Listenbrainz => "ListenBrainz",
+            Self::Lastfm => "Last.fm",
+        };
+    }
+}
+
+// the recommendations are matched by the recording MBIDs
+fn from_listenbrainz(meta_cache: &MetaCache) -> Result<Vec<Track>> {
+    let ranks: HashMap<String, usize> = ListenBrainz::recommended_recordings()?
+        .into_iter()
+        .enumerate()
+        .map(|(rank, mbid)| (mbid, rank))
+        .collect();
+    let mut found: Vec<(usize, Track)> = meta_cache
+        .find(|meta| {
+            meta.mbids
+                .recording
+                .as_ref()
+                .is_some_and(|mbid| ranks.contains_key(mbid))
+        })
+        .into_iter()
+        .filter_map(|(track, meta)| {
+            let rank = *ranks.get(meta.mbids.recording.as_ref()?)?;
+            return Some((rank, track));
+        })
+        .collect();
+    found.sort_by_key(|(rank, _)| *rank);
+    return Ok(found.into_iter().map(|(_, track)| track).collect());
+}
+
+// the similar artists are matched by their names, in random order
+fn from_lastfm(meta_cache: &MetaCache, last_meta: &TrackMeta) -> Result<Vec<Track>> {
+    let Some(artist) = &last_meta.artist else {
+        bail!("the last track has no artist");
+    };
+    let artists: HashSet<String> = LastFM::similar_artists(artist)?
+        .into_iter()
+        .map(|name| name.to_lowercase())
+        .collect();
+    let mut found: Vec<Track> = meta_cache
+        .find(|meta| {
+            meta.artist
+                .as_ref()
+                .is_some_and(|artist| artists.contains(&artist.to_lowercase()))
+        })
+        .into_iter()
+        .map(|(track, _)| track)
+        .collect();
+    fastrand::shuffle(&mut found);
+    return Ok(found);
+}
+
+// Picks the tracks to continue the playback with.
+// Only the tracks that were loaded before (and so are in the metadata cache) can be found.
+pub fn find_tracks(
+    source: AutoDjSource,
+    meta_cache: &MetaCache,
+    last_track: Option<&Track>,
+    last_meta: &TrackMeta,
+) -> Result<Vec<Track>> {
+    let mut tracks = match source {
+        AutoDjSource::Listenbrainz => from_listenbrainz(meta_cache)?,
+        AutoDjSource::Lastfm => from_lastfm(meta_cache, last_meta)?,
+    };
+    tracks.retain(|track| Some(track) != last_track);
+    tracks.truncate(MAX_TRACKS);
+    return Ok(tracks);
+}
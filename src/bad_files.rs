@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    err_util::{self, IgnoreErr},
+    error_popup::{self, ErrorSeverity},
+    project_file::ProjectFileJson,
+    project_info,
+    stream_base::Track,
+    thread_util,
+};
+
+// the failures that come together (e.g. a whole broken album) are reported in one notification
+const NOTIFY_DELAY: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Deserialize, Clone)]
+struct BadFile {
+    filename: String,
+    index: Option<usize>,
+    reason: String,
+    failed_at: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct Session {
+    files: Vec<BadFile>,
+    // not notified yet
+    #[serde(skip)]
+    unreported: usize,
+}
+
+// The files that could not be played since the player has started.
+// The report is saved by the notifier thread, so the caller does not wait for the disk.
+#[derive(Clone)]
+pub struct BadFiles {
+    session: Arc<Mutex<Session>>,
+}
+
+fn file() -> ProjectFileJson {
+    return ProjectFileJson::for_state("bad_files.json", "bad files report");
+}
+
+fn display_name(filename: &str) -> String {
+    return Path::new(filename).file_name().map_or_else(
+        || filename.to_string(),
+        |name| name.to_string_lossy().to_string(),
+    );
+}
+
+impl Session {
+    fn save(&self) {
+        file().save(self).ignore_err();
+    }
+
+    fn notification(&self) -> Option<String> {
+        return match self.unreported {
+            0 => None,
+            1 => self.files.last().map(|bad_file| {
+                return format!(
+                    "cannot play {}\n{}",
+                    display_name(&bad_file.filename),
+                    bad_file.reason
+                );
+            }),
+            count => Some(format!(
+                "{count} files could not be played, run \"{} errors\" to see why",
+                project_info::instance_name()
+            )),
+        };
+    }
+}
+
+impl BadFiles {
+    // the report of the previous run is replaced
+    pub fn new() -> Self {
+        let session = Session::default();
+        session.save();
+        return Self {
+            session: Arc::new(Mutex::new(session)),
+        };
+    }
+
+    pub fn add(&self, track: &Track, reason: String) {
+        let mut session = self.session.lock().unwrap();
+        session.files.push(BadFile {
+            filename: track.filename.to_string(),
+            index: track.index,
+            reason,
+            failed_at: err_util::get_now_str(),
+        });
+        session.unreported += 1;
+        let is_first = session.unreported == 1;
+        drop(session);
+        if !is_first {
+            return;
+        }
+        let session = self.session.clone();
+        thread_util::thread("bad files notifier", move || {
+            thread::sleep(NOTIFY_DELAY);
+            let mut locked = session.lock().unwrap();
+            let notification = locked.notification();
+            locked.unreported = 0;
+            let snapshot = locked.clone();
+            drop(locked);
+            snapshot.save();
+            if let Some(notification) = notification {
+                error_popup::notify(ErrorSeverity::Major, &notification);
+            }
+        });
+    }
+
+    // the failures that are not saved yet, e.g. on exit
+    pub fn save(&self) {
+        let snapshot = self.session.lock().unwrap().clone();
+        snapshot.save();
+    }
+}
+
+pub fn cli_print() {
+    let report = file();
+    let session: Session = if report.exists() {
+        report.load().ok_or_default()
+    } else {
+        Session::default()
+    };
+    if session.files.is_empty() {
+        println!("no files have failed to play");
+        return;
+    }
+    for bad_file in &session.files {
+        let index = bad_file
+            .index
+            .map(|index| format!(" #{index}"))
+            .unwrap_or_default();
+        println!(
+            "[{}] {}{index}\n    {}",
+            bad_file.failed_at, bad_file.filename, bad_file.reason
+        );
+    }
+}
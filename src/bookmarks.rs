@@ -0,0 +1,137 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    project_file::{Migration, ProjectFileJson},
+    stream_base::Track,
+};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Bookmark {
+    pub filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub name: String,
+    pub position_ms: u64,
+}
+
+impl Bookmark {
+    pub fn new(track: &Track, name: &str, position: Duration) -> Self {
+        return Self {
+            filename: track.filename.to_string(),
+            index: track.index,
+            name: name.to_string(),
+            position_ms: u64::try_from(position.as_millis()).unwrap_or(u64::MAX),
+        };
+    }
+
+    pub const fn position(&self) -> Duration {
+        return Duration::from_millis(self.position_ms);
+    }
+
+    fn is_for_track(&self, track: &Track) -> bool {
+        return *self.filename == *track.filename && self.index == track.index;
+    }
+}
+
+pub fn format_position(position: Duration) -> String {
+    let secs = position.as_secs();
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        return format!("{hours}:{mins:02}:{secs:02}");
+    }
+    return format!("{mins}:{secs:02}");
+}
+
+const MIGRATIONS: &[Migration] = &[
+    // 0: the bookmarks from before the versioning have the same fields
+    Ok,
+];
+
+fn file() -> ProjectFileJson {
+    return ProjectFileJson::for_data("bookmarks.json", "bookmarks").with_migrations(MIGRATIONS);
+}
+
+pub fn load() -> Result<Vec<Bookmark>> {
+    let file = file();
+    if !file.exists() {
+        return Ok(Vec::new());
+    }
+    return file.load();
+}
+
+fn save(bookmarks: &[Bookmark]) -> Result<()> {
+    return file().save(&bookmarks);
+}
+
+// a bookmark with the same name for the same track is replaced
+pub fn add(bookmark: Bookmark) -> Result<()> {
+    let mut bookmarks = load()?;
+    bookmarks.retain(|b| {
+        b.name != bookmark.name || b.filename != bookmark.filename || b.index != bookmark.index
+    });
+    bookmarks.push(bookmark);
+    bookmarks.sort_by(|a, b| {
+        a.filename
+            .cmp(&b.filename)
+            .then_with(|| a.index.cmp(&b.index))
+            .then_with(|| a.position_ms.cmp(&b.position_ms))
+    });
+    return save(&bookmarks);
+}
+
+// finds a bookmark of the track either by its name or by its 1-based number
+pub fn find(track: &Track, name_or_number: &str) -> Result<Option<Bookmark>> {
+    let bookmarks: Vec<Bookmark> = load()?
+        .into_iter()
+        .filter(|b| b.is_for_track(track))
+        .collect();
+    if let Some(bookmark) = bookmarks.iter().find(|b| b.name == name_or_number) {
+        return Ok(Some(bookmark.clone()));
+    }
+    let bookmark = name_or_number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_sub(1))
+        .and_then(|i| bookmarks.get(i))
+        .cloned();
+    return Ok(bookmark);
+}
+
+pub fn cli_print() -> Result<()> {
+    let bookmarks = load().context("cannot load bookmarks")?;
+    if bookmarks.is_empty() {
+        println!("There are no bookmarks.");
+        return Ok(());
+    }
+
+    let mut last_track = None;
+    let mut number = 0;
+    for bookmark in &bookmarks {
+        let track = (&bookmark.filename, bookmark.index);
+        if last_track != Some(track) {
+            if last_track.is_some() {
+                println!();
+            }
+            if let Some(index) = bookmark.index {
+                println!("{} (track {index})", bookmark.filename);
+            } else {
+                println!("{}", bookmark.filename);
+            }
+            last_track = Some(track);
+            number = 0;
+        }
+        number += 1;
+        println!(
+            "  {number:>3}. {:>8}  {}",
+            format_position(bookmark.position()),
+            bookmark.name
+        );
+    }
+    return Ok(());
+}
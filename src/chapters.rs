@@ -0,0 +1,442 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use lofty::{
+    file::TaggedFileExt,
+    probe::Probe,
+    tag::{ItemKey, ItemValue, TagType},
+};
+
+use crate::{silence, stream_man};
+
+const MP4_EXTS: [&str; 2] = ["m4b", "m4a"];
+const OGG_EXTS: [&str; 1] = ["ogg"];
+// e.g. "book.mp3" may have "book.chapters.txt" next to it
+const SIDECAR_SUFFIXES: [&str; 3] = [".chapters.txt", ".labels.txt", ".ffmetadata"];
+const FFMETADATA_HEADER: &str = ";FFMETADATA";
+// used by ffmpeg when TIMEBASE is not specified
+const FFMETADATA_DEFAULT_TIMEBASE: (u128, u128) = (1, 1_000_000_000);
+
+// Nero chapter timestamps are in 100ns units
+const NANOS_PER_NERO_TIME_UNIT: u64 = 100;
+
+pub struct Chapter {
+    pub start: Duration,
+    pub title: Option<String>,
+}
+
+fn has_ext(filename: &str, exts: &[&str]) -> bool {
+    return Path::new(filename)
+        .extension()
+        .is_some_and(|ext| exts.iter().any(|x| ext.eq_ignore_ascii_case(x)));
+}
+
+fn sidecar_file(filename: &str) -> Option<PathBuf> {
+    let path = Path::new(filename);
+    let stem = path.file_stem()?.to_str()?;
+    return SIDECAR_SUFFIXES
+        .iter()
+        .map(|suffix| path.with_file_name(format!("{stem}{suffix}")))
+        .find(|sidecar| sidecar.is_file());
+}
+
+pub fn is_supported_file(filename: &str) -> bool {
+    return stream_man::has_subtunes(filename)
+        || has_ext(filename, &MP4_EXTS)
+        || has_ext(filename, &OGG_EXTS)
+        || silence::is_enabled_for(filename)
+        || (stream_man::is_path_supported(filename) && sidecar_file(filename).is_some());
+}
+
+fn find_mp4_box(data: &[u8], box_type: [u8; 4]) -> Option<&[u8]> {
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let size = u32::from_be_bytes(data[pos..pos + 4].try_into().ok()?) as usize;
+        if size < 8 || pos + size > data.len() {
+            return None;
+        }
+        if data[pos + 4..pos + 8] == box_type {
+            return Some(&data[pos + 8..pos + size]);
+        }
+        pos += size;
+    }
+    return None;
+}
+
+fn read_mp4_moov(filename: &str) -> Result<Vec<u8>> {
+    let mut file = File::open(filename).with_context(|| format!("cannot open {filename}"))?;
+    let file_size = file.metadata().context("cannot get file size")?.len();
+    let mut pos = 0;
+    while pos + 8 <= file_size {
+        let mut header = [0; 8];
+        file.read_exact(&mut header)
+            .context("cannot read MP4 box header")?;
+        let mut size = u64::from(u32::from_be_bytes(header[0..4].try_into()?));
+        let mut header_size = 8;
+        if size == 1 {
+            let mut large_size = [0; 8];
+            file.read_exact(&mut large_size)
+                .context("cannot read MP4 box size")?;
+            size = u64::from_be_bytes(large_size);
+            header_size = 16;
+        } else if size == 0 {
+            size = file_size - pos;
+        }
+        // a corrupted size must not make us allocate past the end of the file
+        if size < header_size || size > file_size - pos {
+            bail!("invalid MP4 box size");
+        }
+        if &header[4..8] == b"moov" {
+            let mut moov = vec![0; usize::try_from(size - header_size)?];
+            file.read_exact(&mut moov)
+                .context("cannot read MP4 moov box")?;
+            return Ok(moov);
+        }
+        pos += size;
+        file.seek(SeekFrom::Start(pos))
+            .context("cannot seek to the next MP4 box")?;
+    }
+    bail!("no moov box found");
+}
+
+// chapters from the Nero "chpl" box
+fn read_mp4_chapters(filename: &str) -> Result<Vec<Chapter>> {
+    let moov = read_mp4_moov(filename)?;
+    let Some(chpl) = find_mp4_box(&moov, *b"udta").and_then(|udta| find_mp4_box(udta, *b"chpl"))
+    else {
+        return Ok(Vec::new());
+    };
+
+    let mut pos = if chpl.first() == Some(&1) { 8 } else { 4 };
+    let count = *chpl.get(pos).context("invalid chpl box")?;
+    pos += 1;
+    let mut chapters = Vec::new();
+    for _ in 0..count {
+        let start_bytes = chpl.get(pos..pos + 8).context("invalid chapter start")?;
+        let start = u64::from_be_bytes(start_bytes.try_into()?);
+        let title_len = *chpl.get(pos + 8).context("invalid chapter title")? as usize;
+        let title = chpl
+            .get(pos + 9..pos + 9 + title_len)
+            .context("invalid chapter title")?;
+        pos += 9 + title_len;
+        chapters.push(Chapter {
+            start: Duration::from_nanos(start.saturating_mul(NANOS_PER_NERO_TIME_UNIT)),
+            title: Some(String::from_utf8_lossy(title).trim().to_string()),
+        });
+    }
+    return Ok(chapters);
+}
+
+// either seconds or [[hh:]mm:]ss, with an optional fraction
+fn parse_time(s: &str) -> Option<Duration> {
+    let mut secs = 0.0;
+    for part in s.trim().split(':') {
+        secs = secs * 60.0 + part.parse::<f64>().ok()?;
+    }
+    return Duration::try_from_secs_f64(secs).ok();
+}
+
+// chapters from the CHAPTERxxx and CHAPTERxxxNAME comments
+fn read_vorbis_chapters(filename: &str) -> Result<Vec<Chapter>> {
+    let file = Probe::open(filename)
+        .with_context(|| format!("cannot open {filename}"))?
+        .read()
+        .with_context(|| format!("cannot read tags: {filename}"))?;
+    let Some(tag) = file.tag(TagType::VorbisComments) else {
+        return Ok(Vec::new());
+    };
+
+    let mut starts = BTreeMap::new();
+    let mut titles = BTreeMap::new();
+    for item in tag.items() {
+        if let (ItemKey::Unknown(key), ItemValue::Text(value)) = (item.key(), item.value()) {
+            let key = key.to_uppercase();
+            if let Some(num) = key.strip_prefix("CHAPTER") {
+                if let Some(num) = num.strip_suffix("NAME") {
+                    if let Ok(num) = num.parse::<usize>() {
+                        titles.insert(num, value.trim().to_string());
+                    }
+                } else if let (Ok(num), Some(start)) = (num.parse::<usize>(), parse_time(value)) {
+                    starts.insert(num, start);
+                }
+            }
+        }
+    }
+
+    let chapters = starts
+        .into_iter()
+        .map(|(num, start)| Chapter {
+            start,
+            title: titles.remove(&num),
+        })
+        .collect();
+    return Ok(chapters);
+}
+
+fn chapter_title(title: &str) -> Option<String> {
+    let title = title.trim();
+    if title.is_empty() {
+        return None;
+    }
+    return Some(title.to_string());
+}
+
+// chapters from an ffmpeg metadata file
+fn parse_ffmetadata(text: &str) -> Vec<Chapter> {
+    let mut chapters = Vec::new();
+    let mut is_chapter = false;
+    let mut timebase = FFMETADATA_DEFAULT_TIMEBASE;
+    let mut start = None;
+    let mut title = None;
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') {
+            if let Some(start) = start.take() {
+                chapters.push(Chapter {
+                    start,
+                    title: title.take(),
+                });
+            }
+            is_chapter = line.eq_ignore_ascii_case("[CHAPTER]");
+            timebase = FFMETADATA_DEFAULT_TIMEBASE;
+            title = None;
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if !is_chapter {
+            continue;
+        }
+        match key.trim().to_uppercase().as_str() {
+            "TIMEBASE" => {
+                if let Some((num, den)) = value.split_once('/') {
+                    if let (Ok(num), Ok(den)) = (num.trim().parse(), den.trim().parse()) {
+                        if den > 0 {
+                            timebase = (num, den);
+                        }
+                    }
+                }
+            }
+            "START" => {
+                // the chapter is skipped if the timestamp is too large
+                start = value
+                    .trim()
+                    .parse::<u128>()
+                    .ok()
+                    .and_then(|value| value.checked_mul(timebase.0))
+                    .and_then(|value| value.checked_mul(1_000_000_000))
+                    .map(|nanos| {
+                        let nanos = nanos / timebase.1;
+                        return Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX));
+                    });
+            }
+            "TITLE" => title = chapter_title(value),
+            _ => {}
+        }
+    }
+    if let Some(start) = start {
+        chapters.push(Chapter { start, title });
+    }
+    return chapters;
+}
+
+// Audacity labels ("start<TAB>end<TAB>title", in seconds)
+// or simple lists ("hh:mm:ss Title")
+fn parse_chapter_line(line: &str) -> Option<Chapter> {
+    let line = line.trim();
+    if line.contains('\t') {
+        let mut fields = line.split('\t');
+        let start = parse_time(fields.next()?)?;
+        return Some(Chapter {
+            start,
+            title: fields.nth(1).and_then(chapter_title),
+        });
+    }
+    let (time, title) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    return Some(Chapter {
+        start: parse_time(time)?,
+        title: chapter_title(title.trim_start_matches([' ', '-'])),
+    });
+}
+
+fn read_sidecar_chapters(path: &Path) -> Result<Vec<Chapter>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("cannot read {}", path.to_string_lossy()))?;
+    if text.trim_start().starts_with(FFMETADATA_HEADER) {
+        return Ok(parse_ffmetadata(&text));
+    }
+    return Ok(text.lines().filter_map(parse_chapter_line).collect());
+}
+
+pub fn read(filename: &str) -> Result<Vec<Chapter>> {
+    if stream_man::has_subtunes(filename) {
+        return stream_man::read_subtunes(filename);
+    }
+    // a sidecar file takes precedence over the embedded chapters
+    let mut chapters = if let Some(sidecar) = sidecar_file(filename) {
+        read_sidecar_chapters(&sidecar)?
+    } else if has_ext(filename, &MP4_EXTS) {
+        read_mp4_chapters(filename)?
+    } else if has_ext(filename, &OGG_EXTS) {
+        read_vorbis_chapters(filename)?
+    } else {
+        Vec::new()
+    };
+    // the recordings without chapters are split by the silence gaps
+    if chapters.len() < 2 && silence::is_enabled_for(filename) {
+        chapters = silence::read(filename)?;
+    }
+    chapters.sort_by_key(|chapter| chapter.start);
+    chapters.dedup_by_key(|chapter| chapter.start);
+    return Ok(chapters);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(box_type: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let size = u32::try_from(payload.len() + 8).unwrap();
+        let mut data = size.to_be_bytes().to_vec();
+        data.extend_from_slice(&box_type);
+        data.extend_from_slice(payload);
+        return data;
+    }
+
+    fn starts(chapters: &[Chapter]) -> Vec<Duration> {
+        return chapters.iter().map(|chapter| chapter.start).collect();
+    }
+
+    fn titles(chapters: &[Chapter]) -> Vec<Option<&str>> {
+        return chapters
+            .iter()
+            .map(|chapter| chapter.title.as_deref())
+            .collect();
+    }
+
+    #[test]
+    fn time_formats() {
+        assert_eq!(parse_time("5"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_time(" 1.5 "), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_time("02:03"), Some(Duration::from_secs(123)));
+        assert_eq!(
+            parse_time("1:02:03.25"),
+            Some(Duration::from_millis(3_723_250))
+        );
+    }
+
+    #[test]
+    fn invalid_times() {
+        assert_eq!(parse_time(""), None);
+        assert_eq!(parse_time("abc"), None);
+        assert_eq!(parse_time("1::2"), None);
+        assert_eq!(parse_time("-5"), None);
+    }
+
+    #[test]
+    fn ffmetadata() {
+        let text = ";FFMETADATA1\n\
+            title=Book\n\
+            [CHAPTER]\n\
+            TIMEBASE=1/1000\n\
+            START=0\n\
+            END=1500\n\
+            title=One\n\
+            [CHAPTER]\n\
+            START=1500000000\n\
+            [CHAPTER]\n\
+            TIMEBASE=1/10\n\
+            START=30\n\
+            title= Three \n";
+        let chapters = parse_ffmetadata(text);
+        assert_eq!(
+            starts(&chapters),
+            [
+                Duration::ZERO,
+                Duration::from_millis(1500),
+                Duration::from_secs(3)
+            ]
+        );
+        assert_eq!(titles(&chapters), [Some("One"), None, Some("Three")]);
+    }
+
+    #[test]
+    fn ffmetadata_ignores_other_sections() {
+        let text = ";FFMETADATA1\n[STREAM]\nSTART=100\ntitle=Stream\n";
+        assert!(parse_ffmetadata(text).is_empty());
+    }
+
+    #[test]
+    fn ffmetadata_overflow() {
+        let text = format!(
+            ";FFMETADATA1\n[CHAPTER]\nTIMEBASE={}/1\nSTART={}\n[CHAPTER]\nSTART=5\n",
+            u128::MAX,
+            u128::MAX
+        );
+        let chapters = parse_ffmetadata(&text);
+        assert_eq!(starts(&chapters), [Duration::from_nanos(5)]);
+    }
+
+    #[test]
+    fn audacity_labels() {
+        let chapter = parse_chapter_line("12.5\t20\tIntro").unwrap();
+        assert_eq!(chapter.start, Duration::from_millis(12500));
+        assert_eq!(chapter.title.as_deref(), Some("Intro"));
+
+        let chapter = parse_chapter_line("3\t4").unwrap();
+        assert_eq!(chapter.start, Duration::from_secs(3));
+        assert_eq!(chapter.title, None);
+    }
+
+    #[test]
+    fn simple_lines() {
+        let chapter = parse_chapter_line("01:02:03 - Part One").unwrap();
+        assert_eq!(chapter.start, Duration::from_secs(3723));
+        assert_eq!(chapter.title.as_deref(), Some("Part One"));
+
+        let chapter = parse_chapter_line("  10  ").unwrap();
+        assert_eq!(chapter.start, Duration::from_secs(10));
+        assert_eq!(chapter.title, None);
+
+        assert!(parse_chapter_line("Chapter 1").is_none());
+        assert!(parse_chapter_line("").is_none());
+    }
+
+    #[test]
+    fn mp4_boxes() {
+        let chpl = mp4_box(*b"chpl", &[1, 2, 3]);
+        let udta = mp4_box(*b"udta", &chpl);
+        let mut moov = mp4_box(*b"free", &[0; 4]);
+        moov.extend(udta);
+
+        let udta = find_mp4_box(&moov, *b"udta").unwrap();
+        assert_eq!(find_mp4_box(udta, *b"chpl"), Some([1, 2, 3].as_slice()));
+        assert_eq!(find_mp4_box(&moov, *b"chpl"), None);
+        assert_eq!(find_mp4_box(&moov, *b"meta"), None);
+    }
+
+    #[test]
+    fn invalid_mp4_boxes() {
+        assert_eq!(find_mp4_box(&[], *b"udta"), None);
+        assert_eq!(find_mp4_box(&[0, 0, 0], *b"udta"), None);
+
+        // the size is smaller than the header
+        let mut data = mp4_box(*b"udta", &[]);
+        data[3] = 4;
+        assert_eq!(find_mp4_box(&data, *b"udta"), None);
+
+        // the size is past the end of the data
+        let mut data = mp4_box(*b"udta", &[1, 2]);
+        data[3] = 100;
+        assert_eq!(find_mp4_box(&data, *b"udta"), None);
+    }
+}
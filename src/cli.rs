@@ -7,6 +7,8 @@ use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 
+use crate::err_util::LogLevel;
+
 #[derive(Parser, Serialize, Deserialize, Clone)]
 #[clap(author, about)]
 pub struct Args {
@@ -17,29 +19,277 @@ pub struct Args {
     #[clap(long, short = 'v')]
     pub version: bool,
 
+    /// Output verbosity
+    #[clap(long, value_enum, default_value_t = LogLevel::Info, global = true)]
+    pub log_level: LogLevel,
+
+    /// Run an independent instance with its own data folder, playlist and settings
+    #[clap(long, value_parser = parse_profile, global = true)]
+    pub profile: Option<String>,
+
+    /// Run as a systemd service (see install-service)
+    #[clap(long)]
+    pub daemon: bool,
+
+    /// Run without the tray icon, notifications and hotkeys (control it via MPRIS or the commands)
+    #[clap(long, alias = "no-tray")]
+    pub headless: bool,
+
     #[clap(value_parser)]
     pub paths: Vec<String>,
 }
 
+// the profile name is a part of the folder and D-Bus names
+fn parse_profile(profile: &str) -> Result<String, String> {
+    if profile.is_empty()
+        || !profile
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err("only latin letters, digits and underscores are allowed".to_string());
+    }
+    return Ok(profile.to_string());
+}
+
 #[derive(Subcommand, Serialize, Deserialize, Clone)]
 pub enum Command {
     /// Authenticate with Last.fm
     #[clap(name = "lastfm-auth")]
     LastFMAuth,
 
+    /// Authenticate with Libre.fm
+    #[clap(name = "librefm-auth")]
+    LibreFMAuth,
+
     /// Authenticate with ListenBrainz
     #[clap(name = "listenbrainz-auth")]
     ListenBrainzAuth,
 
-    /// Open the data folder
+    /// Print the config, data, state and cache folders and open the data folder
     #[clap(name = "data-folder")]
     DataFolder,
 
+    /// Print the files that could not be played since the player has started, with the reasons
+    Errors,
+
+    /// Print recently played tracks and play counts
+    History {
+        /// Maximum number of entries in each list
+        #[clap(long, short = 'n', default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Manage bookmarks within the currently playing track
+    Bookmark {
+        #[command(subcommand)]
+        command: BookmarkCommand,
+    },
+
+    /// Manage the stored playlist
+    Playlist {
+        #[command(subcommand)]
+        command: PlaylistCommand,
+    },
+
+    /// Play the track with the given number in the playlist
+    Jump {
+        /// Track number, starting from 1
+        #[clap(value_parser = clap::value_parser!(u64).range(1..))]
+        number: u64,
+    },
+
+    /// Play the file if it's in the playlist
+    #[clap(name = "play-file")]
+    PlayFile {
+        /// Path to the file
+        path: String,
+    },
+
+    /// Print a URI of the stored playlist that can be opened on another machine
+    Share {
+        /// Also save the URI to this file
+        #[clap(long, short = 'o')]
+        output: Option<String>,
+    },
+
+    /// Replace the playlist with a shared one
+    Open {
+        /// A share:// URI or a file with it
+        source: String,
+    },
+
+    /// Manage the scrobbles that were not sent yet
+    Scrobbles {
+        #[command(subcommand)]
+        command: ScrobblesCommand,
+    },
+
+    /// Jump to the first track of a random folder in the playlist
+    #[clap(name = "random-dir")]
+    RandomDir,
+
+    /// Love the currently playing track on Last.fm and ListenBrainz
+    Love,
+
+    /// Rate the currently playing track (1-5 stars)
+    Rate {
+        #[clap(value_parser = clap::value_parser!(u8).range(1..=5))]
+        stars: u8,
+    },
+
+    /// Print the lyrics of the current track
+    Lyrics,
+
+    /// Change the tags of audio files
+    Tag {
+        #[command(subcommand)]
+        command: TagCommand,
+    },
+
+    /// Browse the configured Subsonic server
+    Subsonic {
+        #[command(subcommand)]
+        command: SubsonicCommand,
+    },
+
     /// Print a short manual
     Readme,
 
+    /// Print the end of the player's log
+    Logs {
+        /// Number of lines
+        #[clap(long, short = 'n', default_value_t = 50)]
+        lines: usize,
+
+        /// Show the log file in the file manager instead
+        #[clap(long)]
+        show: bool,
+    },
+
+    /// Print the information for a bug report (version, config, audio devices, recent errors)
+    Report,
+
+    /// Print whether the player is running and which of its parts could not be started
+    Status,
+
     /// Print detailed version information
     Version,
+
+    /// Install the systemd user units, so the player is started by the first command
+    InstallService,
+}
+
+#[derive(Subcommand, Serialize, Deserialize, Clone)]
+pub enum BookmarkCommand {
+    /// Bookmark the current position
+    Add {
+        /// Bookmark name
+        name: String,
+    },
+
+    /// Print all bookmarks
+    List,
+
+    /// Jump to a bookmark of the current track
+    Jump {
+        /// Bookmark name or its number from the list
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Serialize, Deserialize, Clone)]
+pub enum PlaylistCommand {
+    /// Remove the files that no longer exist
+    Prune,
+
+    /// Restore the playlist that was replaced or pruned last
+    Undo,
+}
+
+#[derive(Subcommand, Serialize, Deserialize, Clone)]
+pub enum ScrobblesCommand {
+    /// Send the scrobbles that could not be sent before (e.g. while offline)
+    Flush,
+}
+
+#[derive(Subcommand, Serialize, Deserialize, Clone)]
+pub enum TagCommand {
+    /// Change the tags of a file, e.g. tag set song.mp3 artist="X" title="Y"
+    Set {
+        /// Path to the file
+        path: String,
+
+        /// Fields to change: artist, album, title, genre, track, track_total, disc, disc_total, year
+        /// (an empty value removes the field)
+        #[clap(required = true)]
+        changes: Vec<String>,
+    },
+
+    /// Set the same tags in several files, e.g. tag batch --set album="X" *.flac
+    Batch {
+        /// Field to change, can be repeated
+        #[clap(long = "set", short = 's', required = true)]
+        changes: Vec<String>,
+
+        /// Paths to the files
+        #[clap(required = true)]
+        paths: Vec<String>,
+    },
+}
+
+// the printed paths can be passed to konik to play the songs
+#[derive(Subcommand, Serialize, Deserialize, Clone)]
+pub enum SubsonicCommand {
+    /// Search the songs
+    Search {
+        /// Words from the artist, album or title
+        query: String,
+    },
+
+    /// Print all artists
+    Artists,
+
+    /// Print the albums of an artist
+    Artist {
+        /// Artist ID from the list of artists
+        id: String,
+    },
+
+    /// Print the songs of an album
+    Album {
+        /// Album ID, e.g. from subsonic:album:<ID>
+        id: String,
+    },
+}
+
+impl TagCommand {
+    pub fn paths(&self) -> Vec<String> {
+        return match self {
+            Self::Set { path, .. } => vec![path.clone()],
+            Self::Batch { paths, .. } => paths.clone(),
+        };
+    }
+}
+
+impl Command {
+    // remote commands are sent to the already running instance
+    pub const fn is_remote(&self) -> bool {
+        return matches!(
+            self,
+            Self::Rate { .. }
+                | Self::Jump { .. }
+                | Self::PlayFile { .. }
+                | Self::RandomDir
+                | Self::Love
+                | Self::Playlist { .. }
+                | Self::Scrobbles { .. }
+                | Self::Open { .. }
+                | Self::Tag { .. }
+                | Self::Bookmark {
+                    command: BookmarkCommand::Add { .. } | BookmarkCommand::Jump { .. }
+                }
+        );
+    }
 }
 
 pub fn read_line(prompt: &str) -> Result<String> {
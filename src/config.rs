@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auto_dj::AutoDjSource,
+    cue::CuePregap,
+    dsp::ReplayGainMode,
+    err_util::{LogErr, LogLevel},
+    error_popup::ErrorNotifications,
+    listenbrainz::ListenBrainzServer,
+    playlist_man::{PlaylistEndAction, SortOrder},
+    project_file::ProjectFileJson,
+    quiet::QuietHours,
+    scrobble_filter::ScrobbleRules,
+    scrobbler::ScrobbleArtist,
+    signals::SignalAction,
+    smart_playlist::SmartPlaylist,
+    subsonic::SubsonicServer,
+};
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Config {
+    pub low_latency: bool,
+    pub audiobook_dirs: Vec<String>,
+    pub silence_split_dirs: Vec<String>,
+    pub prune_playlist: bool,
+    pub readahead: bool,
+    pub karaoke_strength: Option<f32>,
+    pub sort_order: SortOrder,
+    pub scan_exclude: Vec<String>,
+    pub scan_max_depth: Option<usize>,
+    pub smart_playlists: HashMap<String, SmartPlaylist>,
+    pub scrobble_percent: Option<f64>,
+    pub scrobble_max_secs: Option<f64>,
+    pub scrobble_min_duration_secs: Option<f64>,
+    pub device_latency_ms: HashMap<String, u64>,
+    pub hotkey_modifiers: Vec<String>,
+    pub listenbrainz_servers: Vec<ListenBrainzServer>,
+    pub library_roots: HashMap<String, String>,
+    pub tray_high_contrast: Option<bool>,
+    pub tray_large_icons: Option<bool>,
+    pub release_after_pause_secs: Option<f64>,
+    pub scrobble_rules: ScrobbleRules,
+    pub use_keyring: bool,
+    pub discord_client_id: Option<String>,
+    pub fetch_cover_art: bool,
+    pub lyrics_provider: Option<String>,
+    pub lyrics_notifications: bool,
+    pub auto_dj: Option<AutoDjSource>,
+    pub scrobble_artist: ScrobbleArtist,
+    pub replay_gain: Option<ReplayGainMode>,
+    pub cue_pregap: CuePregap,
+    pub video_files: bool,
+    pub audio_language: Option<String>,
+    pub subsonic: Option<SubsonicServer>,
+    pub http_proxy: Option<String>,
+    pub http_connect_timeout_secs: Option<f64>,
+    pub http_read_timeout_secs: Option<f64>,
+    pub http_retries: Option<u32>,
+    pub log_levels: HashMap<String, LogLevel>,
+    pub error_notifications: ErrorNotifications,
+    pub signal_usr1: Option<SignalAction>,
+    pub signal_usr2: Option<SignalAction>,
+    pub pause_on_headphones_unplug: bool,
+    pub resume_on_headphones_plug: bool,
+    pub pause_for_other_players: bool,
+    pub headless: bool,
+    pub second_instance_action: Option<SignalAction>,
+    pub resume_dirs: bool,
+    pub quiet_hours: Option<QuietHours>,
+    pub auto_gain: bool,
+    pub seek_step_secs: Option<f64>,
+    pub large_seek_step_secs: Option<f64>,
+    pub volume_step_percent: Option<f64>,
+    pub playlist_end: Option<PlaylistEndAction>,
+}
+
+impl Config {
+    pub fn load_or_default() -> Self {
+        let file = Self::file();
+        if !file.exists() {
+            return Self::default();
+        }
+        return match file.load() {
+            Ok(config) => config,
+            Err(e) => {
+                e.log();
+                Self::default()
+            }
+        };
+    }
+
+    fn file() -> ProjectFileJson {
+        return ProjectFileJson::for_config("config.json", "config file");
+    }
+}
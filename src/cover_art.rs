@@ -0,0 +1,185 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use lofty::{
+    file::TaggedFileExt,
+    picture::{MimeType, PictureType},
+    probe::Probe,
+};
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    http,
+    project_file::{ProjectDir, ProjectFileString},
+    stream_base::TrackMeta,
+};
+
+const MUSICBRAINZ_SEARCH_URL: &str = "https://musicbrainz.org/ws/2/release/";
+const COVER_ART_ARCHIVE_URL: &str = "https://coverartarchive.org/release";
+// MusicBrainz allows one request per second
+const MUSICBRAINZ_DELAY: Duration = Duration::from_secs(1);
+const MAX_IMAGE_SIZE: u64 = 10 * 1024 * 1024;
+// marks the albums that have no cover online, so they are not looked up again
+const NOT_FOUND_EXT: &str = "none";
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    releases: Vec<SearchRelease>,
+}
+
+#[derive(Deserialize)]
+struct SearchRelease {
+    id: String,
+}
+
+fn dir() -> Option<PathBuf> {
+    return ProjectFileString::path_for(ProjectDir::Cache, "covers");
+}
+
+// the same cover is shared by all tracks of an album
+fn cache_key(meta: &TrackMeta) -> Option<String> {
+    if let Some(release) = &meta.mbids.release {
+        return Some(format!("mbid:{release}"));
+    }
+    let album = meta.album.as_deref()?;
+    let artist = meta.artist.as_deref().unwrap_or_default();
+    return Some(format!(
+        "{}\n{}",
+        artist.to_lowercase(),
+        album.to_lowercase()
+    ));
+}
+
+fn cache_base(meta: &TrackMeta) -> Option<PathBuf> {
+    let key = cache_key(meta)?;
+    return Some(dir()?.join(format!("{:x}", md5::compute(key))));
+}
+
+fn find_cached(base: &Path) -> Option<PathBuf> {
+    return ["jpg", "png"]
+        .into_iter()
+        .map(|ext| base.with_extension(ext))
+        .find(|path| path.is_file());
+}
+
+fn save(base: &Path, ext: &str, data: &[u8]) -> Result<PathBuf> {
+    let path = base.with_extension(ext);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    }
+    fs::write(&path, data).with_context(|| format!("cannot write {}", path.display()))?;
+    return Ok(path);
+}
+
+// the front cover is preferred, but any picture is better than nothing
+fn save_embedded(filename: &str, base: &Path) -> Option<PathBuf> {
+    let file = Probe::open(filename).ok()?.read().ok()?;
+    let pictures: Vec<_> = file.tags().iter().flat_map(|tag| tag.pictures()).collect();
+    let picture = pictures
+        .iter()
+        .find(|picture| picture.pic_type() == PictureType::CoverFront)
+        .or_else(|| pictures.first())?;
+    let ext = match picture.mime_type() {
+        Some(MimeType::Png) => "png",
+        _ => "jpg",
+    };
+    return save(base, ext, picture.data()).ok();
+}
+
+// the embedded or already downloaded cover
+pub fn find_local(filename: &str, meta: &TrackMeta) -> Option<PathBuf> {
+    let base = cache_base(meta)?;
+    if let Some(path) = find_cached(&base) {
+        return Some(path);
+    }
+    return save_embedded(filename, &base);
+}
+
+fn search_release(agent: &ureq::Agent, meta: &TrackMeta) -> Result<Option<String>> {
+    let Some(album) = &meta.album else {
+        return Ok(None);
+    };
+    let mut query = format!("release:\"{}\"", album.replace('"', ""));
+    if let Some(artist) = &meta.artist {
+        query = format!("{query} AND artist:\"{}\"", artist.replace('"', ""));
+    }
+    let url = Url::parse_with_params(
+        MUSICBRAINZ_SEARCH_URL,
+        &[("query", query.as_str()), ("fmt", "json"), ("limit", "1")],
+    )
+    .context("cannot build MusicBrainz URL")?;
+    let json = agent
+        .get(url.as_str())
+        .call()
+        .context("MusicBrainz search failed")?
+        .into_string()
+        .context("cannot read MusicBrainz response")?;
+    let response: SearchResponse =
+        serde_json::from_str(&json).context("cannot parse MusicBrainz response")?;
+    thread::sleep(MUSICBRAINZ_DELAY);
+    return Ok(response
+        .releases
+        .into_iter()
+        .next()
+        .map(|release| release.id));
+}
+
+fn download_front(agent: &ureq::Agent, release: &str) -> Result<Option<Vec<u8>>> {
+    let url = format!("{COVER_ART_ARCHIVE_URL}/{release}/front-500");
+    let response = match http::call(&agent.get(&url)) {
+        Ok(response) => response,
+        Err(ureq::Error::Status(404, _)) => return Ok(None),
+        Err(e) => return Err(e).context("cannot download the cover"),
+    };
+    let mut data = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_IMAGE_SIZE)
+        .read_to_end(&mut data)
+        .context("cannot read the cover")?;
+    return Ok(Some(data));
+}
+
+// Looks up the cover on MusicBrainz and Cover Art Archive.
+// The release ID from the tags is used if present, otherwise the release is searched.
+// Returns None if there's no cover; the network errors are not remembered.
+pub fn fetch(meta: &TrackMeta) -> Result<Option<PathBuf>> {
+    let Some(base) = cache_base(meta) else {
+        return Ok(None);
+    };
+    if let Some(path) = find_cached(&base) {
+        return Ok(Some(path));
+    }
+    if base.with_extension(NOT_FOUND_EXT).exists() {
+        return Ok(None);
+    }
+    let agent = http::new_agent();
+    let release = match &meta.mbids.release {
+        Some(release) => Some(release.clone()),
+        None => search_release(&agent, meta)?,
+    };
+    let data = match release {
+        Some(release) => download_front(&agent, &release)?,
+        None => None,
+    };
+    let Some(data) = data else {
+        save(&base, NOT_FOUND_EXT, &[])?;
+        return Ok(None);
+    };
+    let ext = if data.starts_with(b"\x89PNG") {
+        "png"
+    } else {
+        "jpg"
+    };
+    return save(&base, ext, &data).map(Some);
+}
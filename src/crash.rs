@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    backtrace::Backtrace,
+    panic::{self, PanicHookInfo},
+    sync::Mutex,
+    thread,
+};
+
+use crate::{
+    app_state::AppState,
+    err_util::{self, IgnoreErr, LogLevel},
+    error_popup,
+    project_file::ProjectFileString,
+    project_info,
+};
+
+type CrashHandler = Box<dyn Fn() + Send>;
+
+// the things that must be saved before the process is gone (e.g. the pending scrobbles)
+static HANDLERS: Mutex<Vec<CrashHandler>> = Mutex::new(Vec::new());
+
+fn file() -> ProjectFileString {
+    return ProjectFileString::for_data("crash.log", "crash report");
+}
+
+// The handlers are called from the panicking thread, which may hold any lock,
+// so they must not wait for the locks (e.g. use try_lock).
+pub fn on_crash<F>(f: F)
+where
+    F: Fn() + Send + 'static,
+{
+    HANDLERS.lock().unwrap().push(Box::new(f));
+}
+
+fn report(info: &PanicHookInfo) -> String {
+    let thread = thread::current();
+    let thread_name = thread.name().unwrap_or("unnamed");
+    return format!(
+        "[{}] {} {} crashed in thread \"{thread_name}\"\n{info}\n\nbacktrace:\n{}\n",
+        err_util::get_now_str(),
+        project_info::title(),
+        project_info::version(),
+        Backtrace::force_capture()
+    );
+}
+
+fn save_state() {
+    // the state file is saved on every change, so only the mark is added
+    AppState::flush().ignore_err();
+    let mut state = AppState::load_or_default();
+    state.crashed = true;
+    state.save().ignore_err();
+}
+
+fn handle_panic(info: &PanicHookInfo) {
+    let report = report(info);
+    let file = file();
+    file.save(&report).ignore_err();
+    err_util::log_line(LogLevel::Error, "crash", &report);
+
+    if let Ok(handlers) = HANDLERS.try_lock() {
+        for handler in handlers.iter() {
+            handler();
+        }
+    }
+    save_state();
+
+    let location = file
+        .filename()
+        .map(|filename| filename.to_string_lossy().to_string())
+        .unwrap_or_default();
+    error_popup::notify_now(&format!(
+        "{} has crashed, the report is saved to {location}",
+        project_info::title()
+    ));
+}
+
+// The panics of all threads are reported,
+// because a panic in a background thread may go unnoticed otherwise.
+pub fn install() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        handle_panic(info);
+        default_hook(info);
+    }));
+}
+
+// the previous run has crashed, so the user may want to continue where it stopped
+pub fn take_previous_crash(state: &mut AppState) -> Option<String> {
+    if !state.crashed {
+        return None;
+    }
+    state.crashed = false;
+    state.save().ignore_err();
+    let location = file()
+        .filename()
+        .map(|filename| filename.to_string_lossy().to_string())
+        .unwrap_or_default();
+    return Some(format!(
+        "the previous run has crashed (see {location}), press Play to resume"
+    ));
+}
@@ -6,33 +6,158 @@ use std::{
     fs,
     path::{Path, PathBuf},
     str::FromStr,
-    sync::Arc,
-    time::Duration,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{bail, Context, Result};
 use cuna::{track::Track, Cuna};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    err_util::{eprintln_with_date, LogErr},
-    stream_base::TrackMeta,
+    chapters::{self, Chapter},
+    err_util::{eprintln_with_date, IgnoreErr, LogErr},
+    project_file::{Migration, ProjectFileJson},
+    stream_base::{MusicBrainzIds, ReplayGain, TrackMeta},
 };
 
 const SOURCE_EXTS: [&str; 1] = ["flac"];
 
+static PREGAP: OnceLock<CuePregap> = OnceLock::new();
+static SHEET_CACHE: OnceLock<Mutex<SheetCache>> = OnceLock::new();
+
+// where the pregap of a track (between its INDEX 00 and INDEX 01) is played
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CuePregap {
+    // at the end of the previous track
+    #[default]
+    AppendToPrevious,
+    // at the start of the track itself
+    PrependToNext,
+    // not at all
+    Skip,
+}
+
+pub fn init(pregap: CuePregap) {
+    PREGAP.set(pregap).ok();
+}
+
+#[derive(Serialize, Deserialize)]
 struct CueTrack {
     index: usize,
     start: Duration,
+    // the previous track ends here, it's not the same as the start if the pregap is skipped
+    boundary: Duration,
     duration: Option<Duration>,
     meta: TrackMeta,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct CueSheet {
     tracks: Vec<CueTrack>,
     pub source_filename: String,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+struct SheetCacheEntry {
+    mtime: SystemTime,
+    pregap: CuePregap,
+    sheet: Arc<CueSheet>,
+}
+
+// Remembers the parsed CUE sheets across runs, so big collections are opened quickly.
+// An entry is used only while the modification time of its file stays the same.
+struct SheetCache {
+    entries: HashMap<String, SheetCacheEntry>,
+    is_changed: bool,
+}
+
+const SHEET_CACHE_MIGRATIONS: &[Migration] = &[
+    // 0: the cache from before the versioning has the same entries
+    Ok,
+];
+
+fn sheet_cache_file() -> ProjectFileJson {
+    return ProjectFileJson::for_cache("cue_cache.json", "CUE sheet cache")
+        .with_migrations(SHEET_CACHE_MIGRATIONS);
+}
+
+// the file is loaded once, and the lock is only held while the entries are accessed
+fn with_sheet_cache<T>(f: impl FnOnce(&mut SheetCache) -> T) -> T {
+    let cache = SHEET_CACHE.get_or_init(|| {
+        let file = sheet_cache_file();
+        let entries = if file.exists() {
+            file.load().ok_or_default()
+        } else {
+            HashMap::new()
+        };
+        return Mutex::new(SheetCache {
+            entries,
+            is_changed: false,
+        });
+    });
+    let mut guard = cache.lock().unwrap();
+    let result = f(&mut guard);
+    drop(guard);
+    return result;
+}
+
+fn file_mtime(filename: &str) -> Option<SystemTime> {
+    return fs::metadata(filename).and_then(|meta| meta.modified()).ok();
+}
+
+// the sheets of the removed files are dropped
+pub fn save_sheet_cache() {
+    let Some(mut entries) = with_sheet_cache(|cache| {
+        if !cache.is_changed {
+            return None;
+        }
+        cache.is_changed = false;
+        return Some(cache.entries.clone());
+    }) else {
+        return;
+    };
+    entries.retain(|filename, _| Path::new(filename).exists());
+    sheet_cache_file().save(&entries).ignore_err();
+}
+
+// cuna puts all REM lines together,
+// so the ones inside the TRACK blocks are collected here separately
+struct CueComments {
+    global: Vec<String>,
+    tracks: HashMap<usize, Vec<String>>,
+}
+
+impl CueComments {
+    fn parse(s: &str) -> Self {
+        let mut comments = Self {
+            global: Vec::new(),
+            tracks: HashMap::new(),
+        };
+        let mut cur_track: Option<usize> = None;
+        for line in s.trim_start_matches('\u{feff}').lines() {
+            let line = line.trim();
+            let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            if keyword.eq_ignore_ascii_case("TRACK") {
+                cur_track = rest.split_whitespace().next().and_then(|n| n.parse().ok());
+            } else if keyword.eq_ignore_ascii_case("REM") {
+                let comment = rest.trim().to_string();
+                match cur_track {
+                    Some(index) => comments.tracks.entry(index).or_default().push(comment),
+                    None => comments.global.push(comment),
+                }
+            }
+        }
+        return comments;
+    }
+
+    fn for_track(&self, index: usize) -> &[String] {
+        return self.tracks.get(&index).map_or(&[], Vec::as_slice);
+    }
+}
+
 impl CueSheet {
     fn is_supported_file(filename: &str) -> bool {
         let len = filename.len();
@@ -112,35 +237,77 @@ impl CueSheet {
         return None;
     }
 
+    fn load_cached(filename: &str) -> Result<Arc<Self>> {
+        let pregap = PREGAP.get().copied().unwrap_or_default();
+        let mtime = file_mtime(filename);
+        if let Some(mtime) = mtime {
+            let cached = with_sheet_cache(|cache| {
+                return cache
+                    .entries
+                    .get(filename)
+                    .filter(|entry| entry.mtime == mtime && entry.pregap == pregap)
+                    .map(|entry| entry.sheet.clone());
+            });
+            // the source file may have been renamed since
+            if let Some(sheet) = cached.filter(|sheet| Path::new(&sheet.source_filename).exists()) {
+                return Ok(sheet);
+            }
+        }
+        let sheet = Arc::new(Self::new(filename)?);
+        if let Some(mtime) = mtime {
+            with_sheet_cache(|cache| {
+                cache.entries.insert(
+                    filename.to_string(),
+                    SheetCacheEntry {
+                        mtime,
+                        pregap,
+                        sheet: sheet.clone(),
+                    },
+                );
+                cache.is_changed = true;
+            });
+        }
+        return Ok(sheet);
+    }
+
     fn new(filename: &str) -> Result<Self> {
         let s = fs::read_to_string(filename).with_context(|| format!("cannot read: {filename}"))?;
         let cue = Cuna::new(&s).with_context(|| format!("cannot parse CUE: {filename}"))?;
+        let comments = CueComments::parse(&s);
 
         let source_filename = Self::find_source(filename)
             .with_context(|| format!("no source file found for {filename}"))?;
 
+        let pregap = PREGAP.get().copied().unwrap_or_default();
         let mut tracks: Vec<CueTrack> = Vec::new();
         if let Some(file) = cue.first_file() {
             let tracks_count = file.tracks.len();
             for track in file.tracks.iter().rev() {
                 let index = track.id() as usize;
-                let start = Self::extract_track_start(track)
+                let index_start = Self::extract_track_start(track)
                     .with_context(|| format!("cannot extract track {index} start"))?;
+                let pregap_start = Self::extract_pregap_start(track).unwrap_or(index_start);
+                let (start, boundary) = match pregap {
+                    CuePregap::AppendToPrevious => (index_start, index_start),
+                    CuePregap::PrependToNext => (pregap_start, pregap_start),
+                    CuePregap::Skip => (index_start, pregap_start),
+                };
                 let duration = if tracks.is_empty() {
                     None
                 } else {
-                    let start_next = &tracks[tracks.len() - 1].start;
-                    let duration = start_next.saturating_sub(start);
+                    let boundary_next = &tracks[tracks.len() - 1].boundary;
+                    let duration = boundary_next.saturating_sub(start);
                     if duration.is_zero() {
                         bail!("track {} has zero length", index);
                     }
                     Some(duration)
                 };
-                let meta = Self::extract_track_meta(&cue, track, tracks_count);
+                let meta = Self::extract_track_meta(&cue, &comments, track, tracks_count);
 
                 tracks.push(CueTrack {
                     index,
                     start,
+                    boundary,
                     duration,
                     meta,
                 });
@@ -159,6 +326,33 @@ impl CueSheet {
         });
     }
 
+    // chapters of an audio file are exposed the same way as CUE tracks
+    fn from_chapters(filename: &str, chapters: Vec<Chapter>) -> Self {
+        let tracks_count = chapters.len();
+        let mut tracks: Vec<CueTrack> = Vec::with_capacity(tracks_count);
+        for (i, chapter) in chapters.into_iter().enumerate() {
+            if let Some(prev_track) = tracks.last_mut() {
+                prev_track.duration = Some(chapter.start.saturating_sub(prev_track.start));
+            }
+            tracks.push(CueTrack {
+                index: i + 1,
+                start: chapter.start,
+                boundary: chapter.start,
+                duration: None,
+                meta: TrackMeta {
+                    title: chapter.title,
+                    track: Some(i + 1),
+                    track_total: Some(tracks_count),
+                    ..TrackMeta::default()
+                },
+            });
+        }
+        return Self {
+            tracks,
+            source_filename: filename.to_string(),
+        };
+    }
+
     pub fn track_ids(&self) -> Vec<usize> {
         return self.tracks.iter().map(|t| t.index).collect();
     }
@@ -173,6 +367,15 @@ impl CueSheet {
         bail!("cannot detect the start of track {}", track.id());
     }
 
+    // INDEX 00, if the track has a pregap
+    fn extract_pregap_start(track: &Track) -> Option<Duration> {
+        return track
+            .index
+            .iter()
+            .find(|i| i.id() == 0)
+            .map(|i| i.begin_time.into());
+    }
+
     fn track(&self, index: usize) -> Result<&CueTrack> {
         for track in &self.tracks {
             if track.index == index {
@@ -199,10 +402,10 @@ impl CueSheet {
         return None;
     }
 
-    fn extract_comment(cue: &Cuna, tag: &str) -> Option<String> {
+    fn extract_comment(comments: &[String], tag: &str) -> Option<String> {
         let rx_str = String::from(r"(?i)^") + &regex::escape(tag) + r#"\s+(.+)"?$"#;
         let rx = Regex::new(&rx_str).unwrap();
-        for comment in &cue.comments.0 {
+        for comment in comments {
             if let Some(m) = rx.captures(comment) {
                 if let Some(m) = m.get(1) {
                     let s = m.as_str();
@@ -216,48 +419,72 @@ impl CueSheet {
         return None;
     }
 
-    fn extract_comment_num<T>(cue: &Cuna, tag: &str) -> Option<T>
+    // the REM lines of the track override the ones of the whole sheet
+    fn extract_track_comment(comments: &CueComments, index: usize, tag: &str) -> Option<String> {
+        return Self::extract_comment(comments.for_track(index), tag)
+            .or_else(|| Self::extract_comment(&comments.global, tag));
+    }
+
+    fn extract_comment_num<T>(comments: &CueComments, index: usize, tag: &str) -> Option<T>
     where
         T: FromStr + Clone,
     {
-        if let Some(comment) = Self::extract_comment(cue, tag) {
+        if let Some(comment) = Self::extract_track_comment(comments, index, tag) {
             return if let Ok(num) = comment.parse() {
                 Some(num)
             } else {
-                eprintln_with_date("cannot parse \"{tag}\" as number");
+                eprintln_with_date(format!("cannot parse \"{tag}\" as number"));
                 None
             };
         }
         return None;
     }
 
-    fn extract_track_meta(cue: &Cuna, track: &Track, tracks_count: usize) -> TrackMeta {
-        return TrackMeta {
+    fn extract_track_meta(
+        cue: &Cuna,
+        comments: &CueComments,
+        track: &Track,
+        tracks_count: usize,
+    ) -> TrackMeta {
+        let index = track.id() as usize;
+        let comment = |tag| Self::extract_track_comment(comments, index, tag);
+        let gain = |tag| comment(tag).as_deref().and_then(ReplayGain::parse_value);
+        let mut meta = TrackMeta {
             duration: Duration::ZERO,
             album: Self::opt_str(cue.title()),
             title: Self::opt_str(track.title()),
-            artist: Self::opt_str2(track.performer(), cue.performer()),
-            disc: Self::extract_comment_num(cue, "DISCNUMBER"),
-            disc_total: Self::extract_comment_num(cue, "TOTALDISCS"),
-            track: Some(track.id() as usize),
+            artist: None,
+            artists: Vec::new(),
+            disc: Self::extract_comment_num(comments, index, "DISCNUMBER"),
+            disc_total: Self::extract_comment_num(comments, index, "TOTALDISCS"),
+            track: Some(index),
             track_total: Some(tracks_count),
-            year: Self::extract_comment_num(cue, "DATE"),
+            year: Self::extract_comment_num(comments, index, "DATE"),
+            genre: comment("GENRE"),
+            rating: None,
+            mbids: MusicBrainzIds::default(),
+            comment: comment("COMMENT"),
+            replay_gain: ReplayGain {
+                track_gain: gain("REPLAYGAIN_TRACK_GAIN"),
+                track_peak: gain("REPLAYGAIN_TRACK_PEAK"),
+                album_gain: gain("REPLAYGAIN_ALBUM_GAIN"),
+                album_peak: gain("REPLAYGAIN_ALBUM_PEAK"),
+            },
         };
+        meta.set_artists(Self::opt_str2(track.performer(), cue.performer()).as_deref());
+        return meta;
     }
 
-    fn opt_def<T>(opt1: &Option<T>, opt2: &Option<T>) -> Option<T>
+    fn opt_def<T>(opt1: Option<&T>, opt2: Option<&T>) -> Option<T>
     where
         T: Clone,
     {
-        if opt1.is_some() {
-            return opt1.clone();
-        }
-        return opt2.clone();
+        return opt1.or(opt2).cloned();
     }
 
     pub fn track_index_by_position(&self, position: Duration) -> usize {
         for track in self.tracks.iter().rev() {
-            if position >= track.start {
+            if position >= track.boundary {
                 return track.index;
             }
         }
@@ -275,29 +502,70 @@ impl CueSheet {
         return Ok(track.start);
     }
 
+    // the last track lasts till the end of the file
+    pub fn track_duration(&self, index: usize, file_duration: Duration) -> Result<Duration> {
+        let track = self
+            .track(index)
+            .context("cannot get track for duration info")?;
+        return Ok(track
+            .duration
+            .unwrap_or_else(|| file_duration.saturating_sub(track.start)));
+    }
+
     pub fn track_meta(&self, index: usize, file_meta: &TrackMeta) -> Result<TrackMeta> {
         let track = self
             .track(index)
             .context("cannot get track for meta info")?;
         let meta = &track.meta;
-        let duration = track
-            .duration
-            .unwrap_or_else(|| file_meta.duration.saturating_sub(track.start));
+        let duration = self.track_duration(index, file_meta.duration)?;
 
         return Ok(TrackMeta {
             duration,
-            album: Self::opt_def(&meta.album, &file_meta.album),
-            title: Self::opt_def(&meta.title, &file_meta.title),
-            artist: Self::opt_def(&meta.artist, &file_meta.artist),
+            album: Self::opt_def(meta.album.as_ref(), file_meta.album.as_ref()),
+            title: Self::opt_def(meta.title.as_ref(), file_meta.title.as_ref()),
+            artist: Self::opt_def(meta.artist.as_ref(), file_meta.artist.as_ref()),
+            artists: if meta.artist.is_some() {
+                meta.artists.clone()
+            } else {
+                file_meta.artists.clone()
+            },
             disc: meta.disc.or(file_meta.disc),
             disc_total: meta.disc_total.or(file_meta.disc_total),
             track: meta.track,
             track_total: meta.track_total,
             year: meta.year.or(file_meta.year),
+            genre: Self::opt_def(meta.genre.as_ref(), file_meta.genre.as_ref()),
+            rating: None,
+            comment: Self::opt_def(meta.comment.as_ref(), file_meta.comment.as_ref()),
+            // the track gain of the file is measured over all of its tracks
+            replay_gain: meta.replay_gain.or(ReplayGain {
+                track_gain: None,
+                track_peak: None,
+                album_gain: file_meta
+                    .replay_gain
+                    .album_gain
+                    .or(file_meta.replay_gain.track_gain),
+                album_peak: file_meta
+                    .replay_gain
+                    .album_peak
+                    .or(file_meta.replay_gain.track_peak),
+            }),
+            // the recording ID of the file belongs to the whole file,
+            // and the artist ID is only known if the artist is the same
+            mbids: MusicBrainzIds {
+                recording: None,
+                release: file_meta.mbids.release.clone(),
+                artist: if meta.artist.is_none() {
+                    file_meta.mbids.artist.clone()
+                } else {
+                    None
+                },
+            },
         });
     }
 }
 
+#[derive(Clone)]
 pub struct CueFactory {
     sheets: HashMap<String, Option<Arc<CueSheet>>>,
 }
@@ -310,28 +578,169 @@ impl CueFactory {
     }
 
     pub fn get_or_new(&mut self, filename: &str) -> Result<Option<Arc<CueSheet>>> {
-        let filename = filename.to_string();
-        if let Some(cue) = self.sheets.get(&filename) {
+        if let Some(cue) = self.sheets.get(filename) {
             return Ok(cue.clone());
         }
+        let sheet = Self::load(filename)?;
+        self.insert(filename, sheet.clone());
+        return Ok(sheet);
+    }
 
-        if !CueSheet::is_supported_file(&filename) {
-            return Ok(None);
-        }
+    // get_or_new does not read the file if it's loaded
+    pub fn is_loaded(&self, filename: &str) -> bool {
+        return self.sheets.contains_key(filename);
+    }
 
-        let sheet = match CueSheet::new(&filename) {
-            Ok(sheet) => Some(Arc::new(sheet)),
-            Err(e) => bail!("reading CUE sheet {}: {}", filename, e),
-        };
-        self.sheets.insert(filename, sheet.clone());
-        return Ok(sheet);
+    pub fn insert(&mut self, filename: &str, sheet: Option<Arc<CueSheet>>) {
+        self.sheets.insert(filename.to_string(), sheet);
+    }
+
+    // Reads the sheet (or the chapters) of the file without remembering it,
+    // so it can be done without locking the factory.
+    pub fn load(filename: &str) -> Result<Option<Arc<CueSheet>>> {
+        if CueSheet::is_supported_file(filename) {
+            return match CueSheet::load_cached(filename) {
+                Ok(sheet) => Ok(Some(sheet)),
+                Err(e) => bail!("reading CUE sheet {}: {}", filename, e),
+            };
+        }
+        if chapters::is_supported_file(filename) {
+            return Ok(match chapters::read(filename) {
+                // a single chapter is the same as no chapters
+                Ok(chapters) if chapters.len() > 1 => {
+                    Some(Arc::new(CueSheet::from_chapters(filename, chapters)))
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    e.log_context(format!("cannot read chapters: {filename}"));
+                    None
+                }
+            });
+        }
+        return Ok(None);
     }
 
     pub fn clear(&mut self) {
         self.sheets.clear();
     }
 
+    pub fn merge(&mut self, other: &Self) {
+        for (filename, sheet) in &other.sheets {
+            self.sheets
+                .entry(filename.clone())
+                .or_insert_with(|| sheet.clone());
+        }
+    }
+
     pub fn sheets(&self) -> Vec<Arc<CueSheet>> {
         return self.sheets.values().filter_map(|v| v.clone()).collect();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = "\u{feff}REM GENRE Rock
+REM DATE 1999
+REM REPLAYGAIN_ALBUM_GAIN -7.50 dB
+REM REPLAYGAIN_ALBUM_PEAK 0.5
+PERFORMER \"Band\"
+TITLE \"Album\"
+FILE \"album.flac\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"One\"
+    REM REPLAYGAIN_TRACK_GAIN -6.25 dB
+    REM GENRE \"Jazz\"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE \"Two\"
+    PERFORMER \"Guest\"
+    rem replaygain_track_gain +1.5 dB
+    REM REPLAYGAIN_TRACK_PEAK 0.25
+    REM DISCNUMBER two
+    INDEX 00 01:00:00
+    INDEX 01 01:02:00
+";
+
+    fn track_metas() -> Vec<TrackMeta> {
+        let cue = Cuna::new(SHEET).unwrap();
+        let comments = CueComments::parse(SHEET);
+        let tracks = &cue.first_file().unwrap().tracks;
+        return tracks
+            .iter()
+            .map(|track| return CueSheet::extract_track_meta(&cue, &comments, track, tracks.len()))
+            .collect();
+    }
+
+    #[test]
+    fn comments_by_track() {
+        let comments = CueComments::parse(SHEET);
+        assert_eq!(
+            comments.global,
+            [
+                "GENRE Rock",
+                "DATE 1999",
+                "REPLAYGAIN_ALBUM_GAIN -7.50 dB",
+                "REPLAYGAIN_ALBUM_PEAK 0.5"
+            ]
+        );
+        assert_eq!(
+            comments.for_track(1),
+            ["REPLAYGAIN_TRACK_GAIN -6.25 dB", "GENRE \"Jazz\""]
+        );
+        assert_eq!(
+            comments.for_track(2),
+            [
+                "replaygain_track_gain +1.5 dB",
+                "REPLAYGAIN_TRACK_PEAK 0.25",
+                "DISCNUMBER two"
+            ]
+        );
+        assert!(comments.for_track(3).is_empty());
+    }
+
+    #[test]
+    fn comments_without_tracks() {
+        let comments = CueComments::parse("REM A 1\r\nREMARK x\r\n  rem B 2\r\n");
+        assert_eq!(comments.global, ["A 1", "B 2"]);
+        assert!(comments.tracks.is_empty());
+    }
+
+    #[test]
+    fn track_comments_override_global_ones() {
+        let metas = track_metas();
+        assert_eq!(metas[0].genre.as_deref(), Some("Jazz"));
+        assert_eq!(metas[1].genre.as_deref(), Some("Rock"));
+        assert_eq!(metas[0].year, Some(1999));
+        assert_eq!(metas[1].year, Some(1999));
+    }
+
+    #[test]
+    fn track_fields() {
+        let metas = track_metas();
+        assert_eq!(metas[0].title.as_deref(), Some("One"));
+        assert_eq!(metas[0].album.as_deref(), Some("Album"));
+        assert_eq!(metas[0].artist.as_deref(), Some("Band"));
+        assert_eq!(metas[1].artist.as_deref(), Some("Guest"));
+        assert_eq!(metas[1].track, Some(2));
+        assert_eq!(metas[1].track_total, Some(2));
+        // not a number
+        assert_eq!(metas[1].disc, None);
+    }
+
+    #[test]
+    fn replay_gain() {
+        let metas = track_metas();
+        let gain = metas[0].replay_gain;
+        assert_eq!(gain.track_gain, Some(-6.25));
+        assert_eq!(gain.track_peak, None);
+        assert_eq!(gain.album_gain, Some(-7.5));
+        assert_eq!(gain.album_peak, Some(0.5));
+
+        let gain = metas[1].replay_gain;
+        assert_eq!(gain.track_gain, Some(1.5));
+        assert_eq!(gain.track_peak, Some(0.25));
+        assert_eq!(gain.album_gain, Some(-7.5));
+    }
+}
@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+};
+
+use crate::{
+    decoder::{Decoder, DecoderReadResult, ReadStep},
+    thread_util,
+};
+
+// The decoding runs in its own thread, so the player commands are never stuck behind it.
+// The decoder is locked only to check what to read and to add the decoded samples,
+// the file I/O is done without the lock.
+// The buffer is filled up to the decoder's soft stop, then the thread sleeps
+// until the audio output drains the buffer below the low watermark
+// or until the player changes what is decoded (play, seek, etc).
+
+#[derive(Default)]
+struct WakeState {
+    wanted: bool,
+    // e.g. the buffer is released during a long pause
+    suspended: bool,
+    quit: bool,
+}
+
+#[derive(Default)]
+struct Signal {
+    state: Mutex<WakeState>,
+    cond: Condvar,
+}
+
+#[derive(Clone, Default)]
+pub struct DecodeWaker {
+    signal: Arc<Signal>,
+}
+
+impl DecodeWaker {
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut WakeState),
+    {
+        let mut state = self.signal.state.lock().unwrap();
+        f(&mut state);
+        self.signal.cond.notify_one();
+    }
+
+    pub fn wake(&self) {
+        let mut state = self.signal.state.lock().unwrap();
+        if state.wanted {
+            return;
+        }
+        state.wanted = true;
+        drop(state);
+        self.signal.cond.notify_one();
+    }
+
+    pub fn suspend(&self) {
+        self.update(|state| state.suspended = true);
+    }
+
+    pub fn resume(&self) {
+        self.update(|state| {
+            state.suspended = false;
+            state.wanted = true;
+        });
+    }
+
+    pub fn quit(&self) {
+        self.update(|state| state.quit = true);
+    }
+
+    fn is_suspended(&self) -> bool {
+        return self.signal.state.lock().unwrap().suspended;
+    }
+
+    // false when the thread should exit
+    fn wait(&self) -> bool {
+        let mut state = self.signal.state.lock().unwrap();
+        while !state.quit && (!state.wanted || state.suspended) {
+            state = self.signal.cond.wait(state).unwrap();
+        }
+        state.wanted = false;
+        return !state.quit;
+    }
+}
+
+// on_result receives the decoder epoch along with the result,
+// so the results that came before a play/seek/stop can be told apart;
+// it returns false when nobody listens anymore
+pub fn start<F>(decoder: Arc<Mutex<Decoder>>, waker: DecodeWaker, on_result: F)
+where
+    F: Fn(u64, DecoderReadResult) -> bool + Send + 'static,
+{
+    thread_util::thread("decoder", move || {
+        // the samples of a single packet before they are added to the decoder buffer
+        let mut samples = VecDeque::new();
+        while waker.wait() {
+            loop {
+                let mut locked = decoder.lock().unwrap();
+                if waker.is_suspended() {
+                    break;
+                }
+                let step = locked.begin_read();
+                let result = match step {
+                    ReadStep::Done(result) => result,
+                    ReadStep::Read(read) => {
+                        drop(locked);
+                        let read_result = read.read(&mut samples);
+                        locked = decoder.lock().unwrap();
+                        locked.finish_read(read_result, &mut samples)
+                    }
+                };
+                let epoch = locked.epoch();
+                drop(locked);
+                if matches!(result, DecoderReadResult::BufferNotFull) {
+                    continue;
+                }
+                if !on_result(epoch, result) {
+                    return;
+                }
+                break;
+            }
+        }
+    });
+}
@@ -2,17 +2,18 @@
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use cpal::{
     traits::{DeviceTrait, HostTrait},
     Sample, SizedSample,
 };
 use num_traits::ToPrimitive;
+use regex::Regex;
 use symphonia::core::{
     audio::RawSample,
     conv::{ConvertibleSample, IntoSample},
@@ -20,13 +21,22 @@ use symphonia::core::{
 
 use crate::{
     cue::{CueFactory, CueSheet},
-    err_util::{eprintln_with_date, IgnoreErr, LogErr},
+    decode_ahead::DecodeWaker,
+    dsp::{self, DspSettings},
+    err_util::{eprintln_with_date, IgnoreErr},
+    error_popup::{self, ErrorSeverity},
+    loudness::{LoudnessHistory, LoudnessMeter},
+    path_filter,
+    sample_buf::{BufferMarker, SampleBuf},
     stream_base::{Stream, StreamPacketMeta, Track, TrackMeta},
     stream_man,
 };
 
 const BUFFER_CAPACITY: usize = 65535;
 const BUFFER_SOFT_STOP: usize = 60000;
+const LOW_LATENCY_BUFFER_SOFT_STOP: usize = 8192;
+// about 5 seconds of stereo 48kHz, to ride out the network mount hiccups
+const NETWORK_BUFFER_SOFT_STOP: usize = 480_000;
 
 trait AudioOutputSample:
     Sample + SizedSample + ConvertibleSample + RawSample + ToPrimitive + Send + 'static
@@ -34,21 +44,74 @@ trait AudioOutputSample:
 }
 impl AudioOutputSample for f32 {}
 
+// The extra delay of the output devices that is not reported by the audio system
+// (e.g. Bluetooth headphones), selected by the device name pattern.
+#[derive(Default)]
+pub struct DeviceLatency {
+    patterns: Vec<(Regex, Duration)>,
+}
+
+impl DeviceLatency {
+    pub fn new(latency_ms: &HashMap<String, u64>) -> Self {
+        let mut patterns: Vec<(Regex, Duration)> = latency_ms
+            .iter()
+            .filter_map(|(pattern, ms)| {
+                let re = path_filter::pattern_to_regex(pattern)
+                    .context("invalid device latency pattern")
+                    .to_option()?;
+                return Some((re, Duration::from_millis(*ms)));
+            })
+            .collect();
+        // the order of the config keys is not preserved, so the longest pattern wins
+        patterns.sort_by_key(|(re, _)| std::cmp::Reverse(re.as_str().len()));
+        return Self { patterns };
+    }
+
+    fn for_device(&self, name: &str) -> Duration {
+        return self
+            .patterns
+            .iter()
+            .find(|(re, _)| re.is_match(name))
+            .map(|(_, latency)| *latency)
+            .unwrap_or_default();
+    }
+}
+
+// locked separately from the decoder, so the file I/O does not hold up the player commands
+type SharedStream = Arc<Mutex<Box<dyn Stream>>>;
+
 pub struct Decoder {
-    stream: Option<Box<dyn Stream>>,
+    stream: Option<SharedStream>,
     track: Option<Track>,
     packet_meta: Option<StreamPacketMeta>,
     previous_packet_meta: Option<StreamPacketMeta>,
     file_meta: Option<TrackMeta>,
     pub track_meta: Option<TrackMeta>,
-    pub new_track_meta: Option<TrackMeta>,
-    buf: Arc<Mutex<VecDeque<f32>>>,
+    buf: Arc<Mutex<SampleBuf<f32>>>,
     position: Duration,
     at_end: bool,
     wait_empty_buf: bool,
     cue_factory: CueFactory,
     cue_sheet: Option<Arc<CueSheet>>,
     volume: Arc<Mutex<f32>>,
+    dsp: Arc<Mutex<DspSettings>>,
+    // the audio that is already taken from the buffer but not heard yet
+    output_latency: Arc<Mutex<Duration>>,
+    device_latency: DeviceLatency,
+    // the markers that were already played but not heard yet
+    pending_markers: VecDeque<(Instant, BufferMarker)>,
+    buffer_soft_stop: usize,
+    // the files on the network mounts are buffered further
+    local_buffer_soft_stop: usize,
+    loudness: LoudnessHistory,
+    // the track is measured only if it's played from the start till the end
+    meter: Option<LoudnessMeter>,
+    measuring: bool,
+    // changes whenever the decoded audio stops being a continuation of the previous one
+    epoch: u64,
+    // changes whenever the stream is replaced or seeked or the buffer is dropped,
+    // so the packet that was being read at that moment is thrown away
+    stream_version: u64,
 }
 
 pub enum DecoderReadResult {
@@ -56,12 +119,65 @@ pub enum DecoderReadResult {
     BufferFull,
     NeedResetOutput,
     ReadEnd,
+    ReadError(anyhow::Error),
+}
+
+// what's left to do after the checks under the decoder lock
+pub enum ReadStep {
+    Done(DecoderReadResult),
+    Read(PacketRead),
+}
+
+// the reading and the decoding of a single packet, done without the decoder lock
+pub struct PacketRead {
+    stream: SharedStream,
+    stream_version: u64,
+}
+
+// what's opened for a track without the decoder lock, to be installed under it
+enum OpenedTrack {
+    // the next track of the CUE sheet that is being decoded, the decoding just continues
+    NextInSheet(Arc<CueSheet>),
+    // the stream is at the start of the track
+    InSheet {
+        stream: SharedStream,
+        sheet: Arc<CueSheet>,
+        position: Duration,
+        is_new_stream: bool,
+    },
+    File(SharedStream),
+}
+
+pub struct PacketReadResult {
+    stream_version: u64,
+    packet: Result<Option<StreamPacketMeta>>,
+    written: bool,
+}
+
+impl PacketRead {
+    // the samples are appended to the given buffer, to be moved to the decoder buffer later
+    pub fn read(self, samples: &mut VecDeque<f32>) -> PacketReadResult {
+        let mut stream = self.stream.lock().unwrap();
+        let packet = stream.read_packet();
+        let written = matches!(packet, Ok(Some(_))) && stream.write(samples).to_option().is_some();
+        drop(stream);
+        return PacketReadResult {
+            stream_version: self.stream_version,
+            packet,
+            written,
+        };
+    }
 }
 
 impl Decoder {
-    pub fn new() -> Self {
-        let mut buf = VecDeque::<f32>::new();
-        buf.reserve(BUFFER_CAPACITY);
+    pub fn new(low_latency: bool) -> Self {
+        let buffer_soft_stop = if low_latency {
+            LOW_LATENCY_BUFFER_SOFT_STOP
+        } else {
+            BUFFER_SOFT_STOP
+        };
+        let mut buf = SampleBuf::with_capacity(BUFFER_CAPACITY);
+        buf.set_low_watermark(buffer_soft_stop / 2);
         let buf = Arc::new(Mutex::new(buf));
 
         return Self {
@@ -71,7 +187,6 @@ impl Decoder {
             previous_packet_meta: None,
             file_meta: None,
             track_meta: None,
-            new_track_meta: None,
             buf,
             position: Duration::ZERO,
             at_end: false,
@@ -79,44 +194,98 @@ impl Decoder {
             cue_factory: CueFactory::new(),
             cue_sheet: None,
             volume: Arc::new(Mutex::new(1.0)),
+            dsp: Arc::new(Mutex::new(DspSettings::default())),
+            output_latency: Arc::new(Mutex::new(Duration::ZERO)),
+            device_latency: DeviceLatency::default(),
+            pending_markers: VecDeque::new(),
+            buffer_soft_stop,
+            local_buffer_soft_stop: buffer_soft_stop,
+            loudness: LoudnessHistory::load(),
+            meter: None,
+            measuring: false,
+            epoch: 0,
+            stream_version: 0,
         };
     }
 
+    pub const fn epoch(&self) -> u64 {
+        return self.epoch;
+    }
+
+    pub fn set_waker(&self, waker: DecodeWaker) {
+        self.buf.lock().unwrap().set_waker(waker);
+    }
+
     pub fn stop(&mut self) {
+        self.epoch += 1;
+        self.stream_version += 1;
         self.stream = None;
         self.track = None;
         self.packet_meta = None;
         self.previous_packet_meta = None;
         self.file_meta = None;
         self.track_meta = None;
-        self.new_track_meta = None;
         self.cue_sheet = None;
         self.position = Duration::default();
-        self.buf.lock().unwrap().clear();
+        self.buf.lock().unwrap().reset();
+        self.pending_markers.clear();
+    }
+
+    pub const fn track(&self) -> Option<&Track> {
+        return self.track.as_ref();
     }
 
     pub fn clear_cue_factory(&mut self) {
         self.cue_factory.clear();
     }
 
+    pub fn merge_cue_factory(&mut self, cue_factory: &CueFactory) {
+        self.cue_factory.merge(cue_factory);
+    }
+
     pub fn set_cue_factory(&mut self, cue_factory: CueFactory) {
         self.cue_factory = cue_factory;
     }
 
-    fn sheet_for_track(&mut self, track: &Track) -> Result<Option<Arc<CueSheet>>> {
-        if track.index.is_some() {
-            let sheet = self
-                .cue_factory
-                .get_or_new(&track.filename)?
-                .with_context(|| format!("file is not CUE: {}", &track.filename))?;
-            return Ok(Some(sheet));
+    // the sheet is loaded without the decoder lock, e.g. the chapters are read from the file
+    fn sheet_for_track(decoder: &Mutex<Self>, track: &Track) -> Result<Option<Arc<CueSheet>>> {
+        if track.index.is_none() {
+            return Ok(None);
         }
-        return Ok(None);
+        let mut locked = decoder.lock().unwrap();
+        let sheet = if locked.cue_factory.is_loaded(&track.filename) {
+            let sheet = locked.cue_factory.get_or_new(&track.filename)?;
+            drop(locked);
+            sheet
+        } else {
+            drop(locked);
+            let sheet = CueFactory::load(&track.filename)?;
+            decoder
+                .lock()
+                .unwrap()
+                .cue_factory
+                .insert(&track.filename, sheet.clone());
+            sheet
+        };
+        let sheet = sheet.with_context(|| format!("file is not CUE: {}", &track.filename))?;
+        return Ok(Some(sheet));
+    }
+
+    // the file that contains the audio data of the track
+    pub fn source_filename(decoder: &Mutex<Self>, track: &Track) -> Result<String> {
+        let sheet = Self::sheet_for_track(decoder, track)?;
+        return Ok(sheet.map_or_else(
+            || track.filename.to_string(),
+            |sheet| sheet.source_filename.clone(),
+        ));
     }
 
     #[allow(clippy::type_complexity)]
-    fn open(&mut self, track: &Track) -> Result<(Box<dyn Stream>, Option<Arc<CueSheet>>)> {
-        let sheet = self.sheet_for_track(track).with_context(|| {
+    fn open(
+        decoder: &Mutex<Self>,
+        track: &Track,
+    ) -> Result<(Box<dyn Stream>, Option<Arc<CueSheet>>)> {
+        let sheet = Self::sheet_for_track(decoder, track).with_context(|| {
             format!(
                 "cannot load CUE for track {}:{}",
                 &track.filename,
@@ -125,101 +294,209 @@ impl Decoder {
         })?;
         let filename = sheet
             .as_ref()
-            .map_or(&track.filename, |sheet| &sheet.source_filename);
+            .map_or(&*track.filename, |sheet| &sheet.source_filename);
         let stream =
             stream_man::open(filename).with_context(|| format!("error opening {filename}"))?;
         return Ok((stream, sheet));
     }
 
-    pub fn load_meta(&mut self, track: &Track) -> Result<()> {
-        let (mut stream, sheet) = self.open(track).context("cannot open track")?;
-        let packet = stream.read_packet().context("cannot read packet")?;
-        if let Some(meta) = &packet.track_meta {
-            let file_meta = meta.clone();
-            if let (Some(sheet), Some(index)) = (&sheet, track.index) {
-                self.track_meta = Some(
-                    sheet
-                        .track_meta(index, &file_meta)
-                        .context("cannot get track meta")?,
-                );
-            } else {
-                self.track_meta = Some(meta.clone());
-            }
-            self.file_meta = Some(meta.clone());
-            self.packet_meta = Some(packet);
-            self.cue_sheet = sheet;
-        } else {
+    // the file is opened and read without the decoder lock
+    pub fn load_meta(decoder: &Mutex<Self>, track: &Track) -> Result<()> {
+        let (mut stream, sheet) = Self::open(decoder, track).context("cannot open track")?;
+        let packet = stream
+            .read_packet()
+            .context("cannot read packet")?
+            .context("the stream is empty")?;
+        let Some(file_meta) = packet.track_meta.clone() else {
             bail!("no meta data found: {}", &track.filename);
+        };
+        let track_meta = if let (Some(sheet), Some(index)) = (&sheet, track.index) {
+            sheet
+                .track_meta(index, &file_meta)
+                .context("cannot get track meta")?
+        } else {
+            file_meta.clone()
+        };
+        let mut locked = decoder.lock().unwrap();
+        locked.track_meta = Some(track_meta);
+        locked.file_meta = Some(file_meta);
+        locked.packet_meta = Some(packet);
+        locked.cue_sheet = sheet;
+        drop(locked);
+        return Ok(());
+    }
+
+    // re-reads the tags if the track is from this file (e.g. after they were edited)
+    pub fn reload_meta(decoder: &Mutex<Self>, track: &Track, filename: &str) -> Option<TrackMeta> {
+        let locked = decoder.lock().unwrap();
+        let sheet = locked.cue_sheet.clone();
+        drop(locked);
+        let source = sheet
+            .as_ref()
+            .map_or(&*track.filename, |sheet| &sheet.source_filename);
+        if source != filename {
+            return None;
+        }
+        // the tags are read without the decoder lock
+        let mut cue_factory = CueFactory::new();
+        if track.index.is_some() {
+            cue_factory.insert(&track.filename, sheet.clone());
         }
+        let mut meta = stream_man::read_track_meta(track, &mut cue_factory)?;
+        let mut locked = decoder.lock().unwrap();
+        // the duration from the stream is more precise
+        if let Some(old_meta) = &locked.track_meta {
+            meta.duration = old_meta.duration;
+        }
+        locked.track_meta = Some(meta.clone());
+        drop(locked);
+        return Some(meta);
+    }
+
+    // The track becomes current for the listener once its start is reached in the buffer,
+    // e.g. the next track of a CUE sheet is decoded while the previous one is still heard.
+    // The file is opened and seeked without the decoder lock.
+    pub fn play(
+        decoder: &Mutex<Self>,
+        track: &Track,
+        playlist_index: usize,
+        on_network: bool,
+    ) -> Result<()> {
+        let opened = Self::open_for_play(decoder, track)?;
+        decoder
+            .lock()
+            .unwrap()
+            .start(track, opened, playlist_index, on_network);
         return Ok(());
     }
 
-    pub fn play(&mut self, track: &Track) -> Result<()> {
-        let new_sheet = self.sheet_for_track(track).with_context(|| {
+    fn start(
+        &mut self,
+        track: &Track,
+        opened: OpenedTrack,
+        playlist_index: usize,
+        on_network: bool,
+    ) {
+        self.epoch += 1;
+        self.install(track, opened);
+        self.buf
+            .lock()
+            .unwrap()
+            .add_first_marker(BufferMarker::TrackStart {
+                playlist_index,
+                track: track.clone(),
+            });
+        self.buffer_soft_stop = if on_network {
+            NETWORK_BUFFER_SOFT_STOP.max(self.local_buffer_soft_stop)
+        } else {
+            self.local_buffer_soft_stop
+        };
+        self.buf
+            .lock()
+            .unwrap()
+            .set_low_watermark(self.buffer_soft_stop / 2);
+        self.meter = None;
+        self.measuring = self.dsp.lock().unwrap().auto_gain;
+    }
+
+    fn open_for_play(decoder: &Mutex<Self>, track: &Track) -> Result<OpenedTrack> {
+        let new_sheet = Self::sheet_for_track(decoder, track).with_context(|| {
             format!(
                 "cannot load CUE for track {}:{}",
                 &track.filename,
                 track.index.unwrap_or_default()
             )
         })?;
-        if let (Some(new_sheet), Some(new_index)) = (new_sheet, track.index) {
-            if let (Some(_), Some(cur_sheet)) = (&mut self.stream, &self.cue_sheet) {
-                if new_sheet.source_filename == cur_sheet.source_filename {
-                    if let Some(cur_track) = &self.track {
-                        if let Some(cur_index) = cur_track.index {
-                            if new_index == cur_index + 1 {
-                                if let Some(file_meta) = &self.file_meta {
-                                    self.new_track_meta =
-                                        new_sheet.track_meta(new_index, file_meta).to_option();
-                                }
-                                self.track = Some(track.clone());
-                                self.at_end = false;
-                                return Ok(());
-                            }
-                        }
-                    }
-                    self.track_meta = None;
-                    self.track = Some(track.clone());
-                    self.seek_to(Duration::ZERO)
-                        .context("cannot seek to the start")?;
-                    if let Some(file_meta) = &self.file_meta {
-                        self.new_track_meta =
-                            new_sheet.track_meta(new_index, file_meta).to_option();
-                    }
-                    self.at_end = false;
-                    return Ok(());
-                }
+        let (Some(new_sheet), Some(new_index)) = (new_sheet, track.index) else {
+            let stream = stream_man::open(&track.filename)
+                .with_context(|| format!("error opening {}", &track.filename))?;
+            return Ok(OpenedTrack::File(Arc::new(Mutex::new(stream))));
+        };
+
+        let mut locked = decoder.lock().unwrap();
+        let cur_stream = locked
+            .cue_sheet
+            .as_ref()
+            .filter(|cur_sheet| return cur_sheet.source_filename == new_sheet.source_filename)
+            .and_then(|_| return locked.stream.clone());
+        if cur_stream.is_some() {
+            let cur_index = locked.track.as_ref().and_then(|track| track.index);
+            if cur_index.is_some_and(|cur_index| return new_index == cur_index + 1) {
+                return Ok(OpenedTrack::NextInSheet(new_sheet));
             }
-            let new_stream = stream_man::open(&new_sheet.source_filename)
-                .with_context(|| format!("error opening {}", &new_sheet.source_filename))?;
-            self.stream = Some(new_stream);
-            self.track_meta = None;
-            self.file_meta = None;
-            self.track = Some(track.clone());
-            self.cue_sheet = Some(new_sheet);
-            self.seek_to(Duration::ZERO)
-                .context("cannot seek to the start")?;
-            self.at_end = false;
-            return Ok(());
+            // nothing is read from the stream while it seeks
+            locked.stream = None;
+            locked.stream_version += 1;
         }
+        drop(locked);
 
-        if let Some(meta) = self.packet_meta.take() {
-            self.previous_packet_meta = Some(meta);
-        }
+        let is_new_stream = cur_stream.is_none();
+        let stream = if let Some(stream) = cur_stream {
+            stream
+        } else {
+            let stream = stream_man::open(&new_sheet.source_filename)
+                .with_context(|| format!("error opening {}", &new_sheet.source_filename))?;
+            Arc::new(Mutex::new(stream))
+        };
+        let start = new_sheet
+            .track_start(new_index)
+            .with_context(|| format!("can't get the start of track {new_index}"))?;
+        let position = stream
+            .lock()
+            .unwrap()
+            .seek(start)
+            .context("cannot seek to the start")?;
+        return Ok(OpenedTrack::InSheet {
+            stream,
+            sheet: new_sheet,
+            position,
+            is_new_stream,
+        });
+    }
 
-        self.track_meta = None;
-        self.file_meta = None;
-        match stream_man::open(&track.filename) {
-            Ok(stream) => {
+    fn install(&mut self, track: &Track, opened: OpenedTrack) {
+        let index = track.index.unwrap_or_default();
+        match opened {
+            OpenedTrack::NextInSheet(sheet) => {
+                self.add_sheet_meta_marker(&sheet, index);
+            }
+            OpenedTrack::InSheet {
+                stream,
+                sheet,
+                position,
+                is_new_stream,
+            } => {
                 self.stream = Some(stream);
+                self.stream_version += 1;
+                self.track_meta = None;
+                if is_new_stream {
+                    self.file_meta = None;
+                    self.cue_sheet = Some(sheet.clone());
+                }
+                self.position = position;
+                self.buf.lock().unwrap().clear();
+                self.add_sheet_meta_marker(&sheet, index);
             }
-            Err(e) => {
-                bail!("error opening {}: {}", &track.filename, e);
+            OpenedTrack::File(stream) => {
+                if let Some(meta) = self.packet_meta.take() {
+                    self.previous_packet_meta = Some(meta);
+                }
+                self.stream = Some(stream);
+                self.stream_version += 1;
+                self.track_meta = None;
+                self.file_meta = None;
             }
         }
-        self.at_end = false;
         self.track = Some(track.clone());
-        return Ok(());
+        self.at_end = false;
+    }
+
+    fn add_sheet_meta_marker(&self, sheet: &CueSheet, index: usize) {
+        if let Some(file_meta) = &self.file_meta {
+            if let Some(meta) = sheet.track_meta(index, file_meta).to_option() {
+                self.add_buffer_marker(BufferMarker::TrackMeta(Box::new(meta)));
+            }
+        }
     }
 
     fn buffer_len(&self) -> usize {
@@ -227,9 +504,33 @@ impl Decoder {
         return buf_size;
     }
 
+    fn add_buffer_marker(&self, marker: BufferMarker) {
+        self.buf.lock().unwrap().add_marker(marker);
+    }
+
+    // the markers are returned once they become audible
+    pub fn take_reached_buffer_markers(&mut self) -> Vec<BufferMarker> {
+        let reached = self.buf.lock().unwrap().take_reached_markers();
+        let now = Instant::now();
+        let audible_at = now + self.latency();
+        self.pending_markers
+            .extend(reached.into_iter().map(|marker| (audible_at, marker)));
+        let mut markers = Vec::new();
+        while self
+            .pending_markers
+            .front()
+            .is_some_and(|(audible_at, _)| *audible_at <= now)
+        {
+            if let Some((_, marker)) = self.pending_markers.pop_front() {
+                markers.push(marker);
+            }
+        }
+        return markers;
+    }
+
     fn can_read_more(&self) -> bool {
         let buf_len = self.buffer_len();
-        return buf_len < BUFFER_SOFT_STOP;
+        return buf_len < self.buffer_soft_stop;
     }
 
     fn buf_items_per_sec(&self) -> Result<usize> {
@@ -246,8 +547,13 @@ impl Decoder {
         return Ok(Duration::from_secs_f64(buf_secs));
     }
 
+    // how long it takes for the decoded audio to become audible
+    fn latency(&self) -> Duration {
+        return *self.output_latency.lock().unwrap() + self.dsp.lock().unwrap().latency();
+    }
+
     pub fn playback_position(&self) -> Duration {
-        let buf_dur = self.buffer_duration().ok_or_default();
+        let buf_dur = self.buffer_duration().ok_or_default() + self.latency();
         let mut pos = self.position.saturating_sub(buf_dur);
         if let Some((sheet, index)) = self.sheet_and_index() {
             let start = sheet.track_start(index).unwrap_or_default();
@@ -257,7 +563,7 @@ impl Decoder {
     }
 
     pub fn valid_playback_position(&self) -> Result<Duration> {
-        let buf_dur = self.buffer_duration()?;
+        let buf_dur = self.buffer_duration()? + self.latency();
         let mut pos = self.position.saturating_sub(buf_dur);
         if let Some((sheet, index)) = self.sheet_and_index() {
             let start = sheet.track_start(index)?;
@@ -266,6 +572,15 @@ impl Decoder {
         return Ok(pos);
     }
 
+    // for a CUE track it's always the duration of the track that is being decoded,
+    // even if the meta of the previous one is still the last that was read from the file
+    pub fn track_duration(&self) -> Option<Duration> {
+        if let (Some((sheet, index)), Some(file_meta)) = (self.sheet_and_index(), &self.file_meta) {
+            return sheet.track_duration(index, file_meta.duration).to_option();
+        }
+        return self.track_meta.as_ref().map(|meta| meta.duration);
+    }
+
     fn sheet_and_index(&self) -> Option<(&Arc<CueSheet>, usize)> {
         if let (Some(sheet), Some(index)) =
             (&self.cue_sheet, self.track.as_ref().and_then(|t| t.index))
@@ -275,8 +590,10 @@ impl Decoder {
         return None;
     }
 
-    pub fn seek_to(&mut self, pos: Duration) -> Result<Duration> {
-        let start = if let Some((sheet, index)) = self.sheet_and_index() {
+    // the stream is taken out of the decoder while it seeks, so the decoder is not locked meanwhile
+    pub fn seek_to(decoder: &Mutex<Self>, pos: Duration) -> Result<Duration> {
+        let mut locked = decoder.lock().unwrap();
+        let start = if let Some((sheet, index)) = locked.sheet_and_index() {
             sheet
                 .track_start(index)
                 .with_context(|| format!("can't get the start of track {index}"))?
@@ -284,98 +601,251 @@ impl Decoder {
             Duration::ZERO
         };
         let pos = pos.saturating_add(start);
+        locked.epoch += 1;
+        locked.stream_version += 1;
+        let stream = locked
+            .stream
+            .take()
+            .context("the stream is not ready for seeking")?;
+        drop(locked);
+
+        // waits for the packet that is being read
+        let seeked_to = stream.lock().unwrap().seek(pos);
+        let mut locked = decoder.lock().unwrap();
+        locked.stream = Some(stream);
+        locked.stream_version += 1;
+        let seeked_to = seeked_to.context("cannot seek")?;
+        locked.meter = None;
+        locked.measuring = false;
+        locked.position = seeked_to;
+        locked.buf.lock().unwrap().clear();
+        locked.at_end = false;
+        drop(locked);
+        return Ok(seeked_to.saturating_sub(start));
+    }
 
-        if let Some(stream) = &mut self.stream {
-            let seeked_to = stream.seek(pos).context("cannot seek")?;
-            self.buf.lock().unwrap().clear();
-            self.at_end = false;
-            return Ok(seeked_to.saturating_sub(start));
-        }
-        bail!("the stream is not ready for seeking");
+    // frees the decoded audio (e.g. during a long pause),
+    // the position stays at what was heard last
+    pub fn release(&mut self) {
+        let heard = self.buffer_duration().ok_or_default() + self.latency();
+        self.position = self.position.saturating_sub(heard);
+        self.stream_version += 1;
+        *self.output_latency.lock().unwrap() = Duration::ZERO;
+        self.buf.lock().unwrap().release();
+    }
+
+    // decodes the released audio again
+    pub fn restore(decoder: &Mutex<Self>) -> Result<Duration> {
+        let locked = decoder.lock().unwrap();
+        locked
+            .buf
+            .lock()
+            .unwrap()
+            .samples_mut()
+            .reserve(BUFFER_CAPACITY);
+        let position = locked.playback_position();
+        drop(locked);
+        return Self::seek_to(decoder, position);
     }
 
-    pub fn set_volume(&self, volume: f32) -> f32 {
-        let volume = volume.clamp(0.0, 1.0);
-        *self.volume.lock().unwrap() = volume;
-        return volume;
+    pub fn set_volume(&self, volume: f32) {
+        *self.volume.lock().unwrap() = volume.clamp(0.0, 1.0);
     }
 
-    fn is_format_change(cur_meta: &Option<StreamPacketMeta>, new_meta: &StreamPacketMeta) -> bool {
-        if let Some(cur_meta) = &cur_meta {
+    pub fn set_device_latency(&mut self, device_latency: DeviceLatency) {
+        self.device_latency = device_latency;
+    }
+
+    pub fn set_dsp(&self, settings: DspSettings) {
+        *self.dsp.lock().unwrap() = settings;
+    }
+
+    fn is_format_change(cur_meta: Option<&StreamPacketMeta>, new_meta: &StreamPacketMeta) -> bool {
+        if let Some(cur_meta) = cur_meta {
             return cur_meta.channels_count != new_meta.channels_count
                 || cur_meta.sample_rate != new_meta.sample_rate;
         }
         return false;
     }
 
-    fn set_track_meta(&mut self, track_meta: &Option<TrackMeta>) {
-        if let Some(track_meta) = &track_meta {
-            self.track_meta = if let Some((sheet, index)) = self.sheet_and_index() {
-                sheet.track_meta(index, track_meta).to_option()
-            } else {
-                Some(track_meta.clone())
-            };
-            self.file_meta = Some(track_meta.clone());
-            self.new_track_meta.clone_from(&self.track_meta);
+    // returns false if there's no new meta
+    fn update_track_meta(&mut self, track_meta: Option<&TrackMeta>) -> bool {
+        let Some(track_meta) = track_meta else {
+            return false;
+        };
+        self.track_meta = if let Some((sheet, index)) = self.sheet_and_index() {
+            sheet.track_meta(index, track_meta).to_option()
+        } else {
+            Some(track_meta.clone())
+        };
+        self.file_meta = Some(track_meta.clone());
+        return true;
+    }
+
+    fn add_track_meta_marker(&self) {
+        if let Some(meta) = &self.track_meta {
+            self.add_buffer_marker(BufferMarker::TrackMeta(Box::new(meta.clone())));
+        }
+    }
+
+    fn set_track_meta(&mut self, track_meta: Option<&TrackMeta>) {
+        if self.update_track_meta(track_meta) {
+            self.add_track_meta_marker();
+        }
+    }
+
+    // the loudness is measured before the gain is applied
+    fn measure(&mut self, samples: &VecDeque<f32>) {
+        if !self.measuring {
+            return;
+        }
+        let Some(packet_meta) = &self.packet_meta else {
+            return;
+        };
+        let meter = self.meter.get_or_insert_with(|| {
+            return LoudnessMeter::new(packet_meta.channels_count, packet_meta.sample_rate);
+        });
+        meter.add(samples);
+    }
+
+    fn finish_measuring(&mut self) {
+        if let (Some(meter), Some(track)) = (self.meter.take(), &self.track) {
+            if self.measuring {
+                self.loudness.add(track, &meter);
+            }
         }
+        self.measuring = false;
     }
 
-    pub fn read_stream(&mut self) -> DecoderReadResult {
+    // the gain is applied to the samples that were just decoded
+    fn apply_replay_gain(&self, samples: &mut VecDeque<f32>) {
+        let settings = self.dsp.lock().unwrap().clone();
+        let Some(meta) = &self.track_meta else {
+            return;
+        };
+        let tags_factor = settings
+            .replay_gain
+            .and_then(|mode| dsp::replay_gain_factor(mode, &meta.replay_gain));
+        let auto_factor = || {
+            if !settings.auto_gain {
+                return None;
+            }
+            return self
+                .track
+                .as_ref()
+                .and_then(|track| self.loudness.gain_factor(track));
+        };
+        let Some(factor) = tags_factor.or_else(auto_factor) else {
+            return;
+        };
+        #[allow(clippy::float_cmp)]
+        if factor == 1.0 {
+            return;
+        }
+        for sample in samples {
+            *sample *= factor;
+        }
+    }
+
+    // the next packet is read with PacketRead::read and then passed to finish_read
+    pub fn begin_read(&mut self) -> ReadStep {
         if self.at_end || !self.can_read_more() {
-            return DecoderReadResult::BufferFull;
+            return ReadStep::Done(DecoderReadResult::BufferFull);
         }
+        let Some(stream) = &self.stream else {
+            return ReadStep::Done(DecoderReadResult::BufferFull);
+        };
+        let stream = stream.clone();
 
-        if let Some(stream) = &mut self.stream {
-            if self.wait_empty_buf {
-                if self.buffer_len() != 0 {
-                    return DecoderReadResult::BufferFull;
-                }
-                self.wait_empty_buf = false;
-                return DecoderReadResult::NeedResetOutput;
+        if self.wait_empty_buf {
+            if self.buffer_len() != 0 {
+                return ReadStep::Done(DecoderReadResult::BufferFull);
             }
+            self.wait_empty_buf = false;
+            return ReadStep::Done(DecoderReadResult::NeedResetOutput);
+        }
 
-            let prev_meta = self.previous_packet_meta.take();
-            if let Ok(mut packet_meta) = stream.read_packet() {
-                let format_changed = Self::is_format_change(&prev_meta, &packet_meta);
+        return ReadStep::Read(PacketRead {
+            stream,
+            stream_version: self.stream_version,
+        });
+    }
 
-                let track_meta = packet_meta.track_meta.take();
-                if format_changed {
-                    self.wait_empty_buf = true;
-                    self.set_track_meta(&track_meta);
-                    return DecoderReadResult::BufferFull;
-                }
+    // the decoded samples are moved to the buffer
+    pub fn finish_read(
+        &mut self,
+        read: PacketReadResult,
+        samples: &mut VecDeque<f32>,
+    ) -> DecoderReadResult {
+        if read.stream_version != self.stream_version {
+            // read before a seek/stop/another file
+            samples.clear();
+            return DecoderReadResult::BufferNotFull;
+        }
+        let prev_meta = self.previous_packet_meta.take();
+        let packet_meta = match read.packet {
+            Ok(packet_meta) => packet_meta,
+            Err(e) => {
+                self.at_end = true;
+                return DecoderReadResult::ReadError(e);
+            }
+        };
+        let Some(mut packet_meta) = packet_meta else {
+            self.at_end = true;
+            self.finish_measuring();
+            return DecoderReadResult::ReadEnd;
+        };
 
-                let res = stream.write(&mut self.buf.lock().unwrap());
-                if res.to_bool() {
-                    self.packet_meta = Some(packet_meta);
-                    self.set_track_meta(&track_meta);
-                }
+        let track_meta = packet_meta.track_meta.take();
+        if Self::is_format_change(prev_meta.as_ref(), &packet_meta) {
+            // the new format is not played until the output is recreated
+            samples.clear();
+            self.wait_empty_buf = true;
+            self.measuring = false;
+            self.set_track_meta(track_meta.as_ref());
+            return DecoderReadResult::BufferFull;
+        }
 
-                if let Some(position) = self.packet_meta.as_ref().and_then(|m| m.position) {
-                    self.position = position;
-                    if let Some((sheet, index)) = self.sheet_and_index() {
-                        let pos_index = sheet.track_index_by_position(position);
-                        if pos_index > index {
-                            self.at_end = true;
-                            return DecoderReadResult::ReadEnd;
-                        }
-                    }
+        if read.written {
+            self.packet_meta = Some(packet_meta);
+            let has_meta = self.update_track_meta(track_meta.as_ref());
+            self.measure(samples);
+            // the gain is applied before the samples can be heard
+            self.apply_replay_gain(samples);
+            self.buf.lock().unwrap().samples_mut().append(samples);
+            if has_meta {
+                self.add_track_meta_marker();
+            }
+        }
+        samples.clear();
+
+        if let Some(position) = self.packet_meta.as_ref().and_then(|m| m.position) {
+            self.position = position;
+            if let Some((sheet, index)) = self.sheet_and_index() {
+                let pos_index = sheet.track_index_by_position(position);
+                if pos_index > index {
+                    self.at_end = true;
+                    self.finish_measuring();
+                    return DecoderReadResult::ReadEnd;
                 }
-            } else {
-                self.at_end = true;
-                return DecoderReadResult::ReadEnd;
             }
-            return DecoderReadResult::BufferNotFull;
         }
-        return DecoderReadResult::BufferFull;
+        return DecoderReadResult::BufferNotFull;
     }
 
     pub fn create_output_stream(&self) -> Option<cpal::Stream> {
         if self.stream.is_some() {
             if let Some(meta) = &self.packet_meta {
                 return Some(
-                    create_output_stream(meta, &self.buf, &self.volume)
-                        .expect("cannot create output stream"),
+                    create_output_stream(
+                        meta,
+                        &self.buf,
+                        &self.volume,
+                        &self.dsp,
+                        &self.output_latency,
+                        &self.device_latency,
+                    )
+                    .expect("cannot create output stream"),
                 );
             }
         }
@@ -410,13 +880,20 @@ fn copy_with_volume<T: AudioOutputSample>(src: &[T], dest: &mut [T], volume: f32
 
 fn create_output_stream<T: AudioOutputSample>(
     meta: &StreamPacketMeta,
-    buf: &Arc<Mutex<VecDeque<T>>>,
+    buf: &Arc<Mutex<SampleBuf<T>>>,
     volume: &Arc<Mutex<f32>>,
+    dsp: &Arc<Mutex<DspSettings>>,
+    output_latency: &Arc<Mutex<Duration>>,
+    device_latency: &DeviceLatency,
 ) -> Result<cpal::Stream> {
     let host = cpal::default_host();
     let device = host
         .default_output_device()
         .expect("no output device available");
+    let extra_latency = device
+        .name()
+        .map(|name| device_latency.for_device(&name))
+        .unwrap_or_default();
 
     let config = cpal::StreamConfig {
         channels: meta.channels_count as cpal::ChannelCount,
@@ -426,13 +903,26 @@ fn create_output_stream<T: AudioOutputSample>(
 
     let buf = buf.clone();
     let volume = volume.clone();
+    let dsp = dsp.clone();
+    let output_latency = output_latency.clone();
+    let channels = meta.channels_count;
+    let items_per_sec = (meta.channels_count * meta.sample_rate).max(1);
     let stream = device
         .build_output_stream(
             &config,
-            move |data: &mut [T], _| {
+            move |data: &mut [T], info| {
+                // the device buffer delay plus the chunk that is about to be written
+                let timestamp = info.timestamp();
+                let device_delay = timestamp
+                    .playback
+                    .duration_since(&timestamp.callback)
+                    .unwrap_or_default();
+                let chunk = Duration::from_secs_f64(data.len() as f64 / items_per_sec as f64);
+                *output_latency.lock().unwrap() = device_delay + chunk + extra_latency;
+
                 let buf = &mut buf.lock().unwrap();
 
-                let (s1, s2) = buf.as_slices();
+                let (s1, s2) = buf.samples().as_slices();
                 let mut len = s1.len().min(data.len());
                 //data[0..len].clone_from_slice(&s1[0..len]);
                 let volume = volume.lock().unwrap();
@@ -448,9 +938,15 @@ fn create_output_stream<T: AudioOutputSample>(
                         data[len..].iter_mut().for_each(|x| *x = T::MID);
                     }
                 }
-                buf.drain(0..len);
+                dsp::process(&dsp.lock().unwrap(), &mut data[0..len], channels);
+                buf.consume(len);
+            },
+            move |e| {
+                error_popup::report(
+                    ErrorSeverity::Critical,
+                    anyhow!(e).context("audio output error"),
+                );
             },
-            move |e| e.log(),
             None,
         )
         .context("cannot create output stream")?;
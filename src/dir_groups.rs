@@ -0,0 +1,67 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::path::Path;
+
+use crate::stream_base::Track;
+
+// Consecutive playlist entries from the same directory form a group.
+#[derive(Default)]
+pub struct DirGroups {
+    starts: Vec<usize>,
+    group_indices: Vec<usize>,
+}
+
+fn track_dir(track: &Track) -> &Path {
+    return Path::new(&*track.filename)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+}
+
+impl DirGroups {
+    pub fn new(tracks: &[Track]) -> Self {
+        let mut groups = Self::default();
+        groups.extend(tracks);
+        return groups;
+    }
+
+    // processes the tracks that were appended to the playlist since the last call
+    pub fn extend(&mut self, tracks: &[Track]) {
+        for index in self.group_indices.len()..tracks.len() {
+            if index == 0 || track_dir(&tracks[index]) != track_dir(&tracks[index - 1]) {
+                self.starts.push(index);
+            }
+            self.group_indices.push(self.starts.len() - 1);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.group_indices.len();
+    }
+
+    pub fn group_start(&self, index: usize) -> usize {
+        return self.starts[self.group_indices[index]];
+    }
+
+    pub fn next_group_start(&self, index: usize) -> Option<usize> {
+        return self.starts.get(self.group_indices[index] + 1).copied();
+    }
+
+    // the start of a random group other than the one of the given index (if there are others)
+    pub fn random_group_start(&self, index: usize) -> usize {
+        let count = self.starts.len();
+        let cur_group = self.group_indices[index];
+        if count < 2 {
+            return self.starts[cur_group];
+        }
+        let mut group = fastrand::usize(..count - 1);
+        if group >= cur_group {
+            group += 1;
+        }
+        return self.starts[group];
+    }
+
+    pub fn is_same_group(&self, index_a: usize, index_b: usize) -> bool {
+        return self.group_indices[index_a] == self.group_indices[index_b];
+    }
+}
@@ -0,0 +1,101 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    err_util::IgnoreErr,
+    project_file::{Migration, ProjectFileJson},
+    stream_base::Track,
+};
+
+// The last played track of every folder that was opened via the command line,
+// so opening an album or an audiobook folder again resumes it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DirPosition {
+    pub filename: String,
+    pub index: Option<usize>,
+    pub position_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct DirMemory {
+    // the folders of the current playlist
+    dirs: Vec<String>,
+    positions: HashMap<String, DirPosition>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    // 0: the positions from before the versioning have the same format
+    Ok,
+];
+
+fn file() -> ProjectFileJson {
+    return ProjectFileJson::for_state("dir_positions.json", "folder positions")
+        .with_migrations(MIGRATIONS);
+}
+
+impl DirPosition {
+    pub fn matches(&self, track: &Track) -> bool {
+        return *track.filename == self.filename && track.index == self.index;
+    }
+
+    pub const fn position(&self) -> Duration {
+        return Duration::from_millis(self.position_ms);
+    }
+}
+
+impl DirMemory {
+    pub fn load() -> Self {
+        let file = file();
+        if !file.exists() {
+            return Self::default();
+        }
+        return file.load().ok_or_default();
+    }
+
+    fn save(&self) {
+        file().save(self).ignore_err();
+    }
+
+    // only a single folder is resumed, a mix of paths is a new playlist
+    pub fn open(&mut self, paths: &[&Path]) -> Option<DirPosition> {
+        self.dirs = paths
+            .iter()
+            .filter(|path| path.is_dir())
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+        self.save();
+        if paths.len() != 1 {
+            return None;
+        }
+        return self
+            .dirs
+            .first()
+            .and_then(|dir| self.positions.get(dir))
+            .cloned();
+    }
+
+    pub fn set_position(&mut self, track: &Track, position: Duration) {
+        let mut is_changed = false;
+        for dir in &self.dirs {
+            if !Path::new(&*track.filename).starts_with(dir) {
+                continue;
+            }
+            self.positions.insert(
+                dir.clone(),
+                DirPosition {
+                    filename: track.filename.to_string(),
+                    index: track.index,
+                    position_ms: u64::try_from(position.as_millis()).unwrap_or(u64::MAX),
+                },
+            );
+            is_changed = true;
+        }
+        if is_changed {
+            self.save();
+        }
+    }
+}
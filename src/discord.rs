@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    env,
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    process,
+    sync::mpsc::{self, Receiver, Sender},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+
+use crate::{err_util::debugln_with_date, stream_base::TrackMeta, thread_util};
+
+const OP_HANDSHAKE: u32 = 0;
+const OP_FRAME: u32 = 1;
+const MAX_SOCKETS: usize = 10;
+// the strings that are longer are rejected by Discord
+const MAX_TEXT_LEN: usize = 128;
+
+enum Update {
+    Set(Value),
+    Clear,
+}
+
+// Publishes the current track to the Discord Rich Presence.
+// Discord is contacted from a separate thread, since it may not be running at all.
+pub struct DiscordPresence {
+    tx: Sender<Update>,
+}
+
+struct Connection {
+    stream: UnixStream,
+    nonce: u64,
+}
+
+fn socket_dirs() -> Vec<PathBuf> {
+    let base = ["XDG_RUNTIME_DIR", "TMPDIR"]
+        .into_iter()
+        .find_map(env::var_os)
+        .map_or_else(|| PathBuf::from("/tmp"), PathBuf::from);
+    // Flatpak and Snap versions of Discord put the socket into a subfolder
+    return vec![
+        base.join("app/com.discordapp.Discord"),
+        base.join("snap.discord"),
+        base,
+    ];
+}
+
+fn truncate(s: &str) -> String {
+    return s.chars().take(MAX_TEXT_LEN).collect();
+}
+
+impl Connection {
+    fn open(client_id: &str) -> Result<Self> {
+        let stream = socket_dirs()
+            .into_iter()
+            .flat_map(|dir| (0..MAX_SOCKETS).map(move |i| dir.join(format!("discord-ipc-{i}"))))
+            .find_map(|path| UnixStream::connect(path).ok())
+            .context("Discord is not running")?;
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .context("cannot set Discord socket timeout")?;
+        let mut conn = Self { stream, nonce: 0 };
+        conn.send(OP_HANDSHAKE, &json!({"v": 1, "client_id": client_id}))
+            .context("Discord handshake failed")?;
+        return Ok(conn);
+    }
+
+    // every message is answered, the answer is read so it does not pile up
+    fn send(&mut self, op: u32, payload: &Value) -> Result<Value> {
+        let payload = payload.to_string();
+        let len = u32::try_from(payload.len()).context("Discord message is too long")?;
+        let mut frame = Vec::with_capacity(8 + payload.len());
+        frame.extend_from_slice(&op.to_le_bytes());
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.extend_from_slice(payload.as_bytes());
+        self.stream
+            .write_all(&frame)
+            .context("cannot write to Discord")?;
+
+        let mut header = [0u8; 8];
+        self.stream
+            .read_exact(&mut header)
+            .context("cannot read from Discord")?;
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let mut payload = vec![0u8; len as usize];
+        self.stream
+            .read_exact(&mut payload)
+            .context("cannot read from Discord")?;
+        let response: Value =
+            serde_json::from_slice(&payload).context("cannot parse Discord response")?;
+        if response["evt"] == "ERROR" {
+            bail!("Discord error: {}", response["data"]["message"]);
+        }
+        return Ok(response);
+    }
+
+    fn set_activity(&mut self, activity: Option<&Value>) -> Result<()> {
+        self.nonce += 1;
+        self.send(
+            OP_FRAME,
+            &json!({
+                "cmd": "SET_ACTIVITY",
+                "args": {"pid": process::id(), "activity": activity},
+                "nonce": self.nonce.to_string(),
+            }),
+        )?;
+        return Ok(());
+    }
+}
+
+fn process_updates(client_id: &str, rx: &Receiver<Update>) {
+    let mut conn: Option<Connection> = None;
+    while let Ok(mut update) = rx.recv() {
+        // only the latest update matters
+        while let Ok(newer) = rx.try_recv() {
+            update = newer;
+        }
+        let activity = match &update {
+            Update::Set(activity) => Some(activity),
+            Update::Clear => None,
+        };
+        if conn.is_none() && activity.is_none() {
+            continue;
+        }
+        // Discord may have been restarted since the last update, so reconnect once
+        for _ in 0..2 {
+            let result = conn
+                .take()
+                .map_or_else(|| Connection::open(client_id), Ok)
+                .and_then(|mut c| {
+                    c.set_activity(activity)?;
+                    return Ok(c);
+                });
+            match result {
+                Ok(c) => {
+                    conn = Some(c);
+                    break;
+                }
+                Err(e) => debugln_with_date(format!("{e:#}")),
+            }
+        }
+    }
+}
+
+impl DiscordPresence {
+    pub fn new(client_id: &str) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let client_id = client_id.to_string();
+        thread_util::thread("Discord presence", move || {
+            process_updates(&client_id, &rx);
+        });
+        return Self { tx };
+    }
+
+    // the elapsed time is only shown while playing
+    pub fn set(&self, meta: &TrackMeta, position: Option<Duration>) {
+        let title = meta.title.as_deref().unwrap_or("Unknown track");
+        let mut state = meta.artist.clone().unwrap_or_default();
+        if let Some(album) = &meta.album {
+            if state.is_empty() {
+                state.clone_from(album);
+            } else {
+                state = format!("{state} — {album}");
+            }
+        }
+        let mut activity = json!({
+            "type": 2,
+            "details": truncate(title),
+        });
+        if !state.is_empty() {
+            activity["state"] = json!(truncate(&state));
+        }
+        if let Some(position) = position {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let start = now.saturating_sub(position);
+            activity["timestamps"] = json!({
+                "start": start.as_millis() as u64,
+                "end": (start + meta.duration).as_millis() as u64,
+            });
+        }
+        self.tx.send(Update::Set(activity)).ok();
+    }
+
+    pub fn clear(&self) {
+        self.tx.send(Update::Clear).ok();
+    }
+}
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::time::Duration;
+
+use cpal::Sample;
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use symphonia::core::conv::IntoSample;
+
+use crate::stream_base::ReplayGain;
+
+pub const DEFAULT_KARAOKE_STRENGTH: f32 = 1.0;
+// nothing is allocated while the audio is played
+const MAX_CHANNELS: usize = 32;
+// the karaoke mode works frame by frame
+const KARAOKE_LATENCY: Duration = Duration::ZERO;
+
+// which of the ReplayGain values is preferred
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayGainMode {
+    Track,
+    Album,
+}
+
+// The processing that is applied to the samples right before they are played.
+#[derive(Clone, Default)]
+pub struct DspSettings {
+    // 0 - no effect, 1 - the center channel is removed completely
+    pub karaoke_strength: Option<f32>,
+    // applied while decoding, since the gain belongs to the track that is decoded
+    pub replay_gain: Option<ReplayGainMode>,
+    // the measured loudness is used for the tracks without ReplayGain values
+    pub auto_gain: bool,
+}
+
+impl DspSettings {
+    pub const fn is_empty(&self) -> bool {
+        return self.karaoke_strength.is_none();
+    }
+
+    // the sum of the delays of all enabled stages
+    pub fn latency(&self) -> Duration {
+        let mut latency = Duration::ZERO;
+        if self.karaoke_strength.is_some() {
+            latency += KARAOKE_LATENCY;
+        }
+        return latency;
+    }
+}
+
+// The multiplier for the samples, None if the track has no ReplayGain values.
+// The other value is used if the preferred one is missing,
+// and the gain is lowered if the peak would clip otherwise.
+pub fn replay_gain_factor(mode: ReplayGainMode, gain: &ReplayGain) -> Option<f32> {
+    let track = (gain.track_gain, gain.track_peak);
+    let album = (gain.album_gain, gain.album_peak);
+    let (preferred, other) = match mode {
+        ReplayGainMode::Track => (track, album),
+        ReplayGainMode::Album => (album, track),
+    };
+    let chosen = if preferred.0.is_some() {
+        preferred
+    } else {
+        other
+    };
+    let (Some(gain_db), peak) = chosen else {
+        return None;
+    };
+    let factor = 10_f32.powf(gain_db / 20.0);
+    return Some(match peak {
+        Some(peak) if peak > 0.0 => factor.min(1.0 / peak),
+        _ => factor,
+    });
+}
+
+// the classic trick: the vocals are usually panned to the center,
+// so subtracting the mono mix from both channels removes them
+fn attenuate_center(frame: &mut [f32], strength: f32) {
+    if let [left, right] = frame {
+        let mid = (*left + *right) / 2.0 * strength;
+        *left -= mid;
+        *right -= mid;
+    }
+}
+
+pub fn process<T>(settings: &DspSettings, samples: &mut [T], channels: usize)
+where
+    T: Sample + ToPrimitive,
+    f32: IntoSample<T>,
+{
+    if settings.is_empty() || channels == 0 || channels > MAX_CHANNELS {
+        return;
+    }
+    let mut frame_buf = [0.0; MAX_CHANNELS];
+    let frame = &mut frame_buf[..channels];
+    for samples in samples.chunks_exact_mut(channels) {
+        for (value, sample) in frame.iter_mut().zip(samples.iter()) {
+            *value = sample.to_f32().unwrap_or_default();
+        }
+        if let Some(strength) = settings.karaoke_strength {
+            attenuate_center(frame, strength);
+        }
+        for (value, sample) in frame.iter().zip(samples.iter_mut()) {
+            *sample = (*value).into_sample();
+        }
+    }
+}
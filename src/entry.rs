@@ -1,24 +1,29 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::{env::current_dir, path::PathBuf};
+use std::{env::current_dir, path::PathBuf, sync::mpsc::RecvTimeoutError, time::Duration};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    app,
+    app, bad_files, bookmarks,
     cli::{self, Args},
-    err_util::println_with_date,
-    lastfm::LastFM,
+    err_util::{self, println_with_date},
+    history,
+    lastfm::{self, LastFM},
     listenbrainz::ListenBrainz,
-    project_file::ProjectFileString,
-    project_info, quit_signal, show_file,
+    logger, lyrics, playlist_man,
+    project_file::{ProjectDir, ProjectFileString},
+    project_info, report, scrobble_retry, share, show_file, signals,
     singleton::Singleton,
+    startup_report, subsonic, systemd, tag_edit,
 };
 
 const SINGLETON_ID: &str = "bfde662d-2ed2-4672-b3bb-ca27b6b97002";
+// a bit less than the sender waits for the reply
+const SCAN_REPLY_TIMEOUT: Duration = Duration::from_secs(20);
 
 #[derive(Serialize, Deserialize)]
 struct SingletonPayload {
@@ -26,28 +31,108 @@ struct SingletonPayload {
     current_dir: String,
 }
 
+// what the running instance has done with the payload
+#[derive(Serialize, Deserialize)]
+struct SingletonReply {
+    ok: bool,
+    message: String,
+}
+
+impl SingletonReply {
+    fn new(result: Result<Result<String>, RecvTimeoutError>) -> Self {
+        return match result {
+            Ok(Ok(message)) => Self { ok: true, message },
+            Ok(Err(e)) => Self {
+                ok: false,
+                message: format!("{e:#}"),
+            },
+            Err(RecvTimeoutError::Timeout) => Self {
+                ok: true,
+                message: "accepted, still scanning".to_string(),
+            },
+            Err(RecvTimeoutError::Disconnected) => Self {
+                ok: false,
+                message: "cancelled by a newer request".to_string(),
+            },
+        };
+    }
+}
+
+// the name of the control socket
+fn singleton_name() -> String {
+    return format!("{}-{SINGLETON_ID}", project_info::instance_name());
+}
+
+// the commands that do not need the running instance
+fn run_local_command(cmd: &cli::Command) -> Result<()> {
+    match cmd {
+        cli::Command::LastFMAuth => LastFM::cli_auth(lastfm::Service::LastFM)?,
+        cli::Command::LibreFMAuth => LastFM::cli_auth(lastfm::Service::LibreFM)?,
+        cli::Command::ListenBrainzAuth => ListenBrainz::cli_auth()?,
+        cli::Command::DataFolder => {
+            for kind in ProjectDir::ALL {
+                if let Some(dir) = ProjectFileString::dir_for(kind) {
+                    println!("{}: {}", kind.title(), dir.to_string_lossy());
+                }
+            }
+            let dir = ProjectFileString::dir_for_data().context("cannot get the data directory")?;
+            let dir_str = dir
+                .to_str()
+                .context("cannot convert data directory path to string")?;
+            show_file::open_folder(dir_str)?;
+        }
+        cli::Command::History { limit } => history::cli_print(*limit)?,
+        cli::Command::Errors => bad_files::cli_print(),
+        cli::Command::Bookmark {
+            command: cli::BookmarkCommand::List,
+        } => bookmarks::cli_print()?,
+        cli::Command::Lyrics => lyrics::cli_print()?,
+        // the running instance only re-reads the tags
+        cli::Command::Tag { command } => tag_edit::cli_run(command)?,
+        cli::Command::Subsonic { command } => subsonic::cli_run(command)?,
+        cli::Command::Readme => project_info::print_readme(),
+        cli::Command::Logs { lines, show } => {
+            if *show {
+                logger::cli_show()?;
+            } else {
+                logger::cli_print(*lines)?;
+            }
+        }
+        cli::Command::Report => report::cli_print(),
+        cli::Command::Share { output } => share::cli_share(output.as_deref())?,
+        cli::Command::Status => startup_report::cli_print(),
+        cli::Command::Version => project_info::print_version_info(),
+        cli::Command::InstallService => systemd::cli_install(&singleton_name())?,
+        // handled by the running instance
+        cli::Command::Rate { .. }
+        | cli::Command::Jump { .. }
+        | cli::Command::PlayFile { .. }
+        | cli::Command::RandomDir
+        | cli::Command::Love
+        | cli::Command::Bookmark { .. }
+        | cli::Command::Playlist { .. }
+        | cli::Command::Scrobbles { .. }
+        | cli::Command::Open { .. } => {}
+    }
+    return Ok(());
+}
+
 pub fn main() -> Result<()> {
     let cli_args = Args::parse();
+    err_util::set_log_level(cli_args.log_level);
+    if let Some(profile) = &cli_args.profile {
+        project_info::set_profile(profile);
+    }
+    ProjectFileString::migrate_legacy_files();
     if cli_args.version {
         println!("{}", project_info::version());
         return Ok(());
     }
     if let Some(cmd) = &cli_args.command {
-        match cmd {
-            cli::Command::LastFMAuth => LastFM::cli_auth()?,
-            cli::Command::ListenBrainzAuth => ListenBrainz::cli_auth()?,
-            cli::Command::DataFolder => {
-                let dir =
-                    ProjectFileString::dir_for_data().context("cannot get the config directory")?;
-                let dir_str = dir
-                    .to_str()
-                    .context("cannot convert data directory path to string")?;
-                show_file::open_folder(dir_str)?;
-            }
-            cli::Command::Readme => project_info::print_readme(),
-            cli::Command::Version => project_info::print_version_info(),
+        run_local_command(cmd)?;
+        if !cmd.is_remote() {
+            return Ok(());
         }
-        return Ok(());
     }
 
     let singleton_payload = SingletonPayload {
@@ -58,26 +143,78 @@ pub fn main() -> Result<()> {
             .unwrap_or_default()
             .to_string(),
     };
-    let singleton_name = format!("{}-{SINGLETON_ID}", project_info::name());
-    let single = Singleton::new(&singleton_name, move || Some(singleton_payload))?;
+    let activated_socket = cli_args
+        .daemon
+        .then(systemd::take_activated_socket)
+        .flatten();
+    let mut reply = None;
+    let single = match activated_socket {
+        Some(socket) => Some(Singleton::from_activated(&singleton_name(), socket)?),
+        None => Singleton::new(
+            &singleton_name(),
+            move || Some(singleton_payload),
+            |r: SingletonReply| reply = Some(r),
+        )?,
+    };
+    if let Some(reply) = reply {
+        if !reply.ok {
+            bail!(reply.message);
+        }
+        if !reply.message.is_empty() {
+            println!("{}", reply.message);
+        }
+    }
     if let Some(single) = single {
+        if let Some(cmd) = &cli_args.command {
+            // nothing uses the stored playlist, so it can be changed directly
+            if let cli::Command::Playlist { command } = cmd {
+                return match command {
+                    cli::PlaylistCommand::Prune => playlist_man::cli_prune(),
+                    cli::PlaylistCommand::Undo => playlist_man::cli_undo(),
+                };
+            }
+            if let cli::Command::Scrobbles { command } = cmd {
+                return match command {
+                    cli::ScrobblesCommand::Flush => scrobble_retry::cli_flush(),
+                };
+            }
+            // the tags are already saved and there's nothing to refresh
+            if let cli::Command::Tag { .. } = cmd {
+                return Ok(());
+            }
+            // the shared playlist is stored and then played as usual
+            if let cli::Command::Open { source } = cmd {
+                share::open_as_stored(source)?;
+            } else {
+                bail!("{} is not running", project_info::instance_title());
+            }
+        }
         println_with_date("starting up...");
         let cur_dir = current_dir().unwrap_or_default();
         let app_handle = app::start(&cli_args, &cur_dir)?;
 
         let app = app_handle.app.clone();
-        single.listen(move |payload| {
-            app.lock()
+        single.listen(move |payload: SingletonPayload| {
+            let reply_rx = app
+                .lock()
                 .unwrap()
                 .new_args(&payload.cli_args, &PathBuf::from(&payload.current_dir));
+            return SingletonReply::new(reply_rx.recv_timeout(SCAN_REPLY_TIMEOUT));
         })?;
 
         let app = app_handle.app.clone();
-        quit_signal::listen(move || {
-            app.lock().unwrap().quit();
-        });
+        let action_app = app_handle.app.clone();
+        signals::listen(
+            move || {
+                app.lock().unwrap().quit();
+            },
+            move |action| {
+                action_app.lock().unwrap().process_hotkey(action);
+            },
+        );
 
         println_with_date("started");
+        systemd::notify_ready();
         app_handle.wait();
         println_with_date("shutdown complete");
     }
@@ -1,30 +1,109 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::fmt::{Debug, Display};
+use std::{
+    fmt::{Debug, Display},
+    panic::Location,
+    path::Path,
+    sync::atomic::{AtomicU8, Ordering},
+};
 
 use anyhow::{anyhow, bail, Error, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 
-fn get_now_str() -> String {
+use crate::{logger, report};
+
+#[derive(
+    ValueEnum, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Error,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    pub const fn title(self) -> &'static str {
+        return match self {
+            Self::Error => "ERROR",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+        };
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn is_log_level_enabled(level: LogLevel) -> bool {
+    return LOG_LEVEL.load(Ordering::Relaxed) >= level as u8;
+}
+
+// the name of the calling module, e.g. "lastfm" for src/lastfm.rs
+#[track_caller]
+fn caller_module() -> &'static str {
+    return Path::new(Location::caller().file())
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default();
+}
+
+pub fn get_now_str() -> String {
     let now = chrono::Local::now();
     let now_str = now.format("%Y-%m-%d %H:%M:%S").to_string();
     return now_str;
 }
 
+// the errors go to stderr, the rest to stdout, and everything to the log file
+pub fn log_line(level: LogLevel, module: &str, message: &str) {
+    let now_str = get_now_str();
+    if level == LogLevel::Error {
+        eprintln!("[{now_str}] {message}");
+    } else {
+        println!("[{now_str}] {message}");
+    }
+    logger::write(level, module, &now_str, message);
+}
+
+#[track_caller]
+fn log_with_date<T>(level: LogLevel, s: T)
+where
+    T: Display,
+{
+    let module = caller_module();
+    if logger::is_enabled(level, module, false) {
+        log_line(level, module, &s.to_string());
+    }
+}
+
+#[track_caller]
 pub fn println_with_date<T>(s: T)
 where
     T: Display,
 {
-    let now_str = get_now_str();
-    println!("[{now_str}] {s}");
+    log_with_date(LogLevel::Info, s);
 }
 
+#[track_caller]
+pub fn debugln_with_date<T>(s: T)
+where
+    T: Display,
+{
+    log_with_date(LogLevel::Debug, s);
+}
+
+#[track_caller]
 pub fn eprintln_with_date<T>(s: T)
 where
     T: Display,
 {
-    let now_str = get_now_str();
-    eprintln!("[{now_str}] {s}");
+    log_with_date(LogLevel::Error, s);
 }
 
 pub trait LogErr
@@ -41,10 +120,14 @@ impl<T> LogErr for T
 where
     T: Into<Error>,
 {
+    #[track_caller]
     fn log(self) {
-        eprintln_with_date(format!("{:?}", anyhow!(self)));
+        let e = anyhow!(self);
+        eprintln_with_date(format!("{e:?}"));
+        report::append_error(&format!("[{}] {e:#}", get_now_str()));
     }
 
+    #[track_caller]
     fn log_context<'a, C>(self, context: C)
     where
         C: Display + Send + Sync + 'static,
@@ -60,10 +143,6 @@ pub trait IgnoreErr<T, E> {
     fn to_option(self) -> Option<T>
     where
         E: Into<Error>;
-    fn map_to_option<F, R>(self, f: F) -> Option<R>
-    where
-        E: Into<Error>,
-        F: FnOnce(T) -> Option<R> + 'static;
     fn to_bool(self) -> bool
     where
         E: Into<Error>;
@@ -81,6 +160,7 @@ pub trait IgnoreErr<T, E> {
 }
 
 impl<T, E> IgnoreErr<T, E> for core::result::Result<T, E> {
+    #[track_caller]
     fn ignore_err(self)
     where
         E: Into<Error>,
@@ -90,6 +170,7 @@ impl<T, E> IgnoreErr<T, E> for core::result::Result<T, E> {
         }
     }
 
+    #[track_caller]
     fn to_option(self) -> Option<T>
     where
         E: Into<Error>,
@@ -103,20 +184,7 @@ impl<T, E> IgnoreErr<T, E> for core::result::Result<T, E> {
         };
     }
 
-    fn map_to_option<F, R>(self, f: F) -> Option<R>
-    where
-        E: Into<Error>,
-        F: FnOnce(T) -> Option<R> + 'static,
-    {
-        return match self {
-            Ok(val) => f(val),
-            Err(e) => {
-                e.log();
-                None
-            }
-        };
-    }
-
+    #[track_caller]
     fn to_bool(self) -> bool
     where
         E: Into<Error>,
@@ -128,6 +196,7 @@ impl<T, E> IgnoreErr<T, E> for core::result::Result<T, E> {
         return true;
     }
 
+    #[track_caller]
     fn ok_or<F>(self, f: F) -> T
     where
         E: Into<Error>,
@@ -142,6 +211,7 @@ impl<T, E> IgnoreErr<T, E> for core::result::Result<T, E> {
         };
     }
 
+    #[track_caller]
     fn ok_or_default(self) -> T
     where
         E: Into<Error>,
@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::{err_util::LogErr, popup::Popup};
+
+// the errors that come in bursts (e.g. a broken network) show only one notification
+const MIN_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorSeverity {
+    // the player will try again by itself, e.g. a scrobble while offline
+    Minor,
+    // something was not done, e.g. a file cannot be played or a scrobble was rejected
+    Major,
+    // the playback cannot continue, e.g. the output device is lost
+    Critical,
+}
+
+// which errors are shown as notifications
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorNotifications {
+    All,
+    #[default]
+    Major,
+    Critical,
+    Off,
+}
+
+impl ErrorNotifications {
+    const fn min_severity(self) -> Option<ErrorSeverity> {
+        return match self {
+            Self::All => Some(ErrorSeverity::Minor),
+            Self::Major => Some(ErrorSeverity::Major),
+            Self::Critical => Some(ErrorSeverity::Critical),
+            Self::Off => None,
+        };
+    }
+}
+
+struct Notifier {
+    popup: Popup,
+    min_severity: ErrorSeverity,
+    last_shown: Mutex<Option<Instant>>,
+    suppressed: Mutex<usize>,
+}
+
+static NOTIFIER: OnceLock<Notifier> = OnceLock::new();
+
+// the errors are only logged until this is called (e.g. for the CLI commands)
+pub fn init(popup: Popup, notifications: ErrorNotifications) {
+    if let Some(min_severity) = notifications.min_severity() {
+        NOTIFIER
+            .set(Notifier {
+                popup,
+                min_severity,
+                last_shown: Mutex::new(None),
+                suppressed: Mutex::new(0),
+            })
+            .ok();
+    }
+}
+
+// the errors that come too soon after the previous one are only counted
+pub fn notify(severity: ErrorSeverity, message: &str) {
+    let Some(notifier) = NOTIFIER.get() else {
+        return;
+    };
+    if severity < notifier.min_severity {
+        return;
+    }
+    let mut last_shown = notifier.last_shown.lock().unwrap();
+    let mut suppressed = notifier.suppressed.lock().unwrap();
+    if last_shown.is_some_and(|last_shown| last_shown.elapsed() < MIN_INTERVAL) {
+        *suppressed += 1;
+        return;
+    }
+    *last_shown = Some(Instant::now());
+    let count = std::mem::take(&mut *suppressed);
+    drop(suppressed);
+    drop(last_shown);
+    if count == 0 {
+        notifier.popup.show(message);
+    } else {
+        notifier.popup.show(&format!(
+            "{message}\n(and {count} more errors, see the log)"
+        ));
+    }
+}
+
+// the crash is always shown (unless the notifications are off), the process ends right after
+pub fn notify_now(message: &str) {
+    if let Some(notifier) = NOTIFIER.get() {
+        notifier.popup.show_now(message);
+    }
+}
+
+#[track_caller]
+pub fn report(severity: ErrorSeverity, e: Error) {
+    let message = format!("{e:#}");
+    e.log();
+    notify(severity, &message);
+}
@@ -0,0 +1,314 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    collections::VecDeque,
+    ffi::{c_char, c_int, c_short, CStr, CString},
+    ptr,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    chapters::Chapter,
+    stream_base::{Stream, StreamHelper, StreamPacketMeta, TrackMeta},
+};
+
+// Game_Music_Emu, see gme.h
+#[repr(C)]
+struct MusicEmu {
+    _private: [u8; 0],
+}
+
+// only some of the fields are used, but the layout must match
+#[allow(dead_code)]
+#[repr(C)]
+struct GmeInfo {
+    length: c_int,
+    intro_length: c_int,
+    loop_length: c_int,
+    play_length: c_int,
+    fade_length: c_int,
+    i5: [c_int; 11],
+    system: *const c_char,
+    game: *const c_char,
+    song: *const c_char,
+    author: *const c_char,
+    copyright: *const c_char,
+    comment: *const c_char,
+    dumper: *const c_char,
+    s7: [*const c_char; 9],
+}
+
+type GmeErr = *const c_char;
+
+#[link(name = "gme")]
+extern "C" {
+    fn gme_open_file(path: *const c_char, out: *mut *mut MusicEmu, sample_rate: c_int) -> GmeErr;
+    fn gme_delete(emu: *mut MusicEmu);
+    fn gme_track_count(emu: *const MusicEmu) -> c_int;
+    fn gme_track_info(emu: *const MusicEmu, out: *mut *mut GmeInfo, track: c_int) -> GmeErr;
+    fn gme_free_info(info: *mut GmeInfo);
+    fn gme_start_track(emu: *mut MusicEmu, index: c_int) -> GmeErr;
+    fn gme_play(emu: *mut MusicEmu, count: c_int, out: *mut c_short) -> GmeErr;
+    fn gme_seek(emu: *mut MusicEmu, msec: c_int) -> GmeErr;
+    fn gme_set_fade(emu: *mut MusicEmu, start_msec: c_int);
+}
+
+const EXTS: [&str; 11] = [
+    "ay", "gbs", "gym", "hes", "kss", "nsf", "nsfe", "sap", "spc", "vgm", "vgz",
+];
+const SAMPLE_RATE: usize = 44100;
+const CHANNELS: usize = 2;
+// about 20ms
+const PACKET_SAMPLES: usize = 882 * CHANNELS;
+// the looping tunes fade out at the end of their play length
+const FADE_LENGTH: Duration = Duration::from_secs(8);
+
+fn check(err: GmeErr) -> Result<()> {
+    if err.is_null() {
+        return Ok(());
+    }
+    // SAFETY: the errors are static C strings
+    let message = unsafe { CStr::from_ptr(err) }.to_string_lossy();
+    bail!("{message}");
+}
+
+fn info_string(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        return None;
+    }
+    // SAFETY: the info strings live until the info is freed
+    let s = unsafe { CStr::from_ptr(s) }
+        .to_string_lossy()
+        .trim()
+        .to_string();
+    return (!s.is_empty()).then_some(s);
+}
+
+fn millis(d: Duration) -> c_int {
+    return c_int::try_from(d.as_millis()).unwrap_or(c_int::MAX);
+}
+
+// a song of the file, they are played one after another
+struct Subtune {
+    start: Duration,
+    length: Duration,
+    title: Option<String>,
+}
+
+struct FileInfo {
+    subtunes: Vec<Subtune>,
+    game: Option<String>,
+    author: Option<String>,
+    comment: Option<String>,
+}
+
+struct Emu {
+    ptr: *mut MusicEmu,
+}
+
+impl Emu {
+    fn open(path: &str) -> Result<Self> {
+        let c_path = CString::new(path).context("invalid path")?;
+        let mut emu = ptr::null_mut();
+        // SAFETY: the pointer is only used if the call succeeds
+        check(unsafe { gme_open_file(c_path.as_ptr(), &mut emu, SAMPLE_RATE as c_int) })
+            .with_context(|| format!("cannot open {path}"))?;
+        return Ok(Self { ptr: emu });
+    }
+
+    fn info(&self) -> FileInfo {
+        // SAFETY: the emulator is open
+        let count = unsafe { gme_track_count(self.ptr) };
+        let mut info = FileInfo {
+            subtunes: Vec::new(),
+            game: None,
+            author: None,
+            comment: None,
+        };
+        let mut start = Duration::ZERO;
+        for index in 0..count {
+            let mut track_info = ptr::null_mut();
+            // SAFETY: the info is freed below
+            if check(unsafe { gme_track_info(self.ptr, &mut track_info, index) }).is_err() {
+                continue;
+            }
+            // SAFETY: the call above has succeeded
+            let track = unsafe { &*track_info };
+            // play_length is always set, it's 2.5 minutes if the length is unknown
+            let length =
+                Duration::from_millis(u64::try_from(track.play_length).unwrap_or_default());
+            info.subtunes.push(Subtune {
+                start,
+                length,
+                title: info_string(track.song),
+            });
+            start += length;
+            if index == 0 {
+                info.game = info_string(track.game);
+                info.author = info_string(track.author);
+                info.comment = info_string(track.comment);
+            }
+            // SAFETY: not used after this
+            unsafe { gme_free_info(track_info) };
+        }
+        return info;
+    }
+
+    fn start(&self, index: usize, length: Duration) -> Result<()> {
+        let index = c_int::try_from(index).context("invalid subtune")?;
+        // SAFETY: the emulator is open
+        check(unsafe { gme_start_track(self.ptr, index) }).context("cannot start subtune")?;
+        if let Some(fade_start) = length.checked_sub(FADE_LENGTH) {
+            // SAFETY: the emulator is open
+            unsafe { gme_set_fade(self.ptr, millis(fade_start)) };
+        }
+        return Ok(());
+    }
+
+    fn seek(&self, offset: Duration) -> Result<()> {
+        // SAFETY: the emulator is open
+        return check(unsafe { gme_seek(self.ptr, millis(offset)) }).context("cannot seek");
+    }
+
+    fn play(&self, buf: &mut [c_short]) -> Result<()> {
+        let count = c_int::try_from(buf.len()).context("buffer is too big")?;
+        // SAFETY: the buffer has the requested size
+        return check(unsafe { gme_play(self.ptr, count, buf.as_mut_ptr()) })
+            .context("cannot play");
+    }
+}
+
+impl Drop for Emu {
+    fn drop(&mut self) {
+        // SAFETY: not used after this
+        unsafe { gme_delete(self.ptr) };
+    }
+}
+
+// SAFETY: the emulator is used by one thread at a time
+unsafe impl Send for Emu {}
+unsafe impl Sync for Emu {}
+
+// Game music (NSF, SPC, VGM, etc.) via Game_Music_Emu.
+// All subtunes of a file are played one after another as a single stream,
+// and they are exposed as chapters, so each of them is a separate track in the playlist.
+pub struct GmeStream {
+    emu: Emu,
+    info: FileInfo,
+    subtune: usize,
+    // the samples played since the start of the subtune
+    subtune_samples: usize,
+    buf: Vec<c_short>,
+    metadata_sent: bool,
+}
+
+impl GmeStream {
+    fn track_meta(info: &FileInfo) -> TrackMeta {
+        let mut meta = TrackMeta {
+            album: info.game.clone(),
+            comment: info.comment.clone(),
+            duration: info.subtunes.iter().map(|subtune| subtune.length).sum(),
+            ..TrackMeta::default()
+        };
+        meta.set_artists(info.author.as_deref());
+        return meta;
+    }
+
+    pub fn read_meta(path: &str) -> Option<TrackMeta> {
+        let emu = Emu::open(path).ok()?;
+        return Some(Self::track_meta(&emu.info()));
+    }
+
+    pub fn read_chapters(path: &str) -> Result<Vec<Chapter>> {
+        let emu = Emu::open(path)?;
+        return Ok(emu
+            .info()
+            .subtunes
+            .into_iter()
+            .map(|subtune| Chapter {
+                start: subtune.start,
+                title: subtune.title,
+            })
+            .collect());
+    }
+
+    fn subtune_position(&self) -> Duration {
+        let frames = (self.subtune_samples / CHANNELS) as u64;
+        return Duration::from_micros(frames * 1_000_000 / SAMPLE_RATE as u64);
+    }
+}
+
+impl Stream for GmeStream {
+    fn open(path: &str) -> Result<Self> {
+        let emu = Emu::open(path)?;
+        let info = emu.info();
+        let Some(first) = info.subtunes.first() else {
+            bail!("no subtunes in {path}");
+        };
+        emu.start(0, first.length)?;
+        return Ok(Self {
+            emu,
+            info,
+            subtune: 0,
+            subtune_samples: 0,
+            buf: vec![0; PACKET_SAMPLES],
+            metadata_sent: false,
+        });
+    }
+
+    fn is_path_supported(path: &str) -> bool {
+        return Self::is_extension_supported(path, &EXTS);
+    }
+
+    fn read_packet(&mut self) -> Result<Option<StreamPacketMeta>> {
+        let subtune = &self.info.subtunes[self.subtune];
+        if self.subtune_position() >= subtune.length {
+            let Some(next) = self.info.subtunes.get(self.subtune + 1) else {
+                return Ok(None);
+            };
+            self.emu.start(self.subtune + 1, next.length)?;
+            self.subtune += 1;
+            self.subtune_samples = 0;
+        }
+        let position = self.info.subtunes[self.subtune].start + self.subtune_position();
+        self.emu.play(&mut self.buf)?;
+        self.subtune_samples += self.buf.len();
+        let track_meta = if self.metadata_sent {
+            None
+        } else {
+            self.metadata_sent = true;
+            Some(Self::track_meta(&self.info))
+        };
+        return Ok(Some(StreamPacketMeta {
+            channels_count: CHANNELS,
+            sample_rate: SAMPLE_RATE,
+            track_meta,
+            position: Some(position),
+        }));
+    }
+
+    fn write(&mut self, data: &mut VecDeque<f32>) -> Result<usize> {
+        data.extend(self.buf.iter().map(|x| f32::from(*x) / 32768.0));
+        return Ok(self.buf.len());
+    }
+
+    fn seek(&mut self, pos: Duration) -> Result<Duration> {
+        let index = self
+            .info
+            .subtunes
+            .iter()
+            .rposition(|subtune| subtune.start <= pos)
+            .unwrap_or_default();
+        let subtune = &self.info.subtunes[index];
+        let offset = pos.saturating_sub(subtune.start).min(subtune.length);
+        self.emu.start(index, subtune.length)?;
+        self.emu.seek(offset)?;
+        self.subtune = index;
+        self.subtune_samples =
+            (offset.as_micros() * SAMPLE_RATE as u128 / 1_000_000) as usize * CHANNELS;
+        return Ok(subtune.start + offset);
+    }
+}
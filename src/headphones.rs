@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use alsa::{
+    card,
+    ctl::ElemIface,
+    hctl::{Elem, HCtl},
+    poll::{self, Descriptors},
+};
+use anyhow::{bail, Result};
+
+use crate::{
+    err_util::{debugln_with_date, LogErr},
+    thread_util,
+};
+
+// e.g. "Headphone Jack" or "Front Headphone Jack"
+fn is_headphone_jack(elem: &Elem) -> bool {
+    let Ok(id) = elem.get_id() else {
+        return false;
+    };
+    return id.get_interface() == ElemIface::Card
+        && id
+            .get_name()
+            .is_ok_and(|name| return name.contains("Headphone") && name.ends_with("Jack"));
+}
+
+// the sound cards that report the state of a headphone jack,
+// it's the same for PulseAudio and PipeWire
+fn jack_cards() -> Vec<HCtl> {
+    return card::Iter::new()
+        .filter_map(Result::ok)
+        .filter_map(|card| {
+            let hctl = HCtl::from_card(&card, true).ok()?;
+            // also subscribes to the events of the card
+            hctl.load().ok()?;
+            let has_jack = hctl.elem_iter().any(|elem| return is_headphone_jack(&elem));
+            return has_jack.then_some(hctl);
+        })
+        .collect();
+}
+
+// the elements are kept up to date by handling the events of the cards
+fn headphones_plugged(cards: &[HCtl]) -> bool {
+    return cards.iter().any(|hctl| {
+        return hctl.elem_iter().filter(is_headphone_jack).any(|elem| {
+            return elem
+                .read()
+                .ok()
+                .and_then(|value| value.get_boolean(0))
+                .unwrap_or_default();
+        });
+    });
+}
+
+// Calls back with true when the headphones are plugged in and with false when they are unplugged.
+// The thread sleeps until a sound card reports a change.
+pub fn watch<F>(f: F) -> Result<()>
+where
+    F: Fn(bool) + Send + 'static,
+{
+    let cards = jack_cards();
+    if cards.is_empty() {
+        bail!("no headphone jack");
+    }
+    let mut plugged = headphones_plugged(&cards);
+    debugln_with_date(format!("headphones plugged: {plugged}"));
+    thread_util::thread("headphones watcher", move || loop {
+        let descriptors: Vec<&dyn Descriptors> = cards
+            .iter()
+            .map(|hctl| return hctl as &dyn Descriptors)
+            .collect();
+        if let Err(e) = poll::poll_all(&descriptors, -1) {
+            if e.errno() == libc::EINTR {
+                continue;
+            }
+            e.log_context("cannot wait for the headphone jack events");
+            return;
+        }
+        for hctl in &cards {
+            hctl.handle_events().ok();
+        }
+        let now_plugged = headphones_plugged(&cards);
+        if now_plugged != plugged {
+            plugged = now_plugged;
+            f(plugged);
+        }
+    });
+    return Ok(());
+}
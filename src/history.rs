@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        mpsc::{self, Sender},
+        OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    err_util::IgnoreErr,
+    project_file::{Migration, ProjectFileJsonLines},
+    stream_base::{Track, TrackMeta},
+    thread_util,
+};
+
+const MIGRATIONS: &[Migration] = &[
+    // 0: the history from before the versioning has the same fields
+    Ok,
+];
+
+static WRITER: OnceLock<Sender<HistoryItem>> = OnceLock::new();
+
+#[derive(Serialize, Deserialize)]
+pub struct HistoryItem {
+    pub filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub title: Option<String>,
+    pub timestamp: u64,
+}
+
+impl HistoryItem {
+    fn display_name(&self) -> String {
+        let title = if let Some(title) = &self.title {
+            title.clone()
+        } else {
+            Path::new(&self.filename)
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        let name = if let Some(artist) = &self.artist {
+            format!("{artist} - {title}")
+        } else {
+            title
+        };
+        if let Some(album) = &self.album {
+            return format!("{name} ({album})");
+        }
+        return name;
+    }
+
+    fn date_str(&self) -> String {
+        return chrono::DateTime::from_timestamp(self.timestamp as i64, 0)
+            .map(|d| {
+                d.with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_default();
+    }
+}
+
+fn file() -> ProjectFileJsonLines {
+    return ProjectFileJsonLines::for_data("history.jsonl", "playback history")
+        .with_migrations(MIGRATIONS);
+}
+
+pub fn add(track: &Track, meta: &TrackMeta) -> Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("cannot get current timestamp")?
+        .as_secs();
+    let item = HistoryItem {
+        filename: track.filename.to_string(),
+        index: track.index,
+        artist: meta.artist.clone(),
+        album: meta.album.clone(),
+        title: meta.title.clone(),
+        timestamp,
+    };
+    // the file is appended to in the background, so the caller is not blocked by the disk
+    let writer = WRITER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<HistoryItem>();
+        thread_util::thread("history writer", move || {
+            for item in rx {
+                file()
+                    .append(&[item])
+                    .context("cannot add the track to the playback history")
+                    .ignore_err();
+            }
+        });
+        return tx;
+    });
+    writer
+        .send(item)
+        .context("the history writer has stopped")?;
+    return Ok(());
+}
+
+pub fn load() -> Result<Vec<HistoryItem>> {
+    let file = file();
+    if !file.exists() {
+        return Ok(Vec::new());
+    }
+    return file.load();
+}
+
+pub fn play_counts(items: &[HistoryItem]) -> Vec<(&HistoryItem, usize)> {
+    let mut counts: HashMap<(&str, Option<usize>), (&HistoryItem, usize)> = HashMap::new();
+    for item in items {
+        counts
+            .entry((&item.filename, item.index))
+            .and_modify(|(last_item, count)| {
+                *last_item = item;
+                *count += 1;
+            })
+            .or_insert((item, 1));
+    }
+    let mut counts: Vec<(&HistoryItem, usize)> = counts.into_values().collect();
+    counts.sort_by(|(a_item, a_count), (b_item, b_count)| {
+        b_count
+            .cmp(a_count)
+            .then_with(|| b_item.timestamp.cmp(&a_item.timestamp))
+    });
+    return counts;
+}
+
+pub fn cli_print(limit: usize) -> Result<()> {
+    let items = load().context("cannot load playback history")?;
+    if items.is_empty() {
+        println!("The playback history is empty.");
+        return Ok(());
+    }
+
+    println!("Recent plays:");
+    for item in items.iter().rev().take(limit) {
+        println!("  {}  {}", item.date_str(), item.display_name());
+    }
+
+    println!();
+    println!("Play counts:");
+    for (item, count) in play_counts(&items).iter().take(limit) {
+        println!("  {count:>5}  {}", item.display_name());
+    }
+
+    return Ok(());
+}
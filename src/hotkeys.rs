@@ -1,16 +1,11 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-    thread::JoinHandle,
-    time::Duration,
-};
+use std::{collections::HashMap, sync::mpsc};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use global_hotkey::{
-    hotkey::{Code, HotKey},
+    hotkey::{Code, HotKey, Modifiers},
     GlobalHotKeyEvent, GlobalHotKeyManager, HotKeyState,
 };
 
@@ -23,49 +18,128 @@ pub enum HotKeyAction {
     Prev,
     NextDir,
     PrevDir,
+    RandomDir,
     PauseToggle,
     VolUp,
     VolDown,
     SysVolUp,
     SysVolDown,
+    Rate(u8),
+    ToggleKaraoke,
+    Love,
+    SeekForward,
+    SeekBackward,
+    LargeSeekForward,
+    LargeSeekBackward,
 }
 
-const ACTIONS: [(Code, HotKeyAction); 10] = [
-    (Code::Numpad5, HotKeyAction::StopPlay),
-    (Code::Numpad6, HotKeyAction::Next),
-    (Code::Numpad4, HotKeyAction::Prev),
-    (Code::Numpad9, HotKeyAction::NextDir),
-    (Code::Numpad7, HotKeyAction::PrevDir),
-    (Code::Numpad0, HotKeyAction::PauseToggle),
-    (Code::Numpad2, HotKeyAction::VolDown),
-    (Code::Numpad8, HotKeyAction::VolUp),
-    (Code::Numpad1, HotKeyAction::SysVolDown),
-    (Code::Numpad3, HotKeyAction::SysVolUp),
+const ACTIONS: [(Option<Modifiers>, Code, HotKeyAction); 22] = [
+    (None, Code::Numpad5, HotKeyAction::StopPlay),
+    (None, Code::Numpad6, HotKeyAction::Next),
+    (None, Code::Numpad4, HotKeyAction::Prev),
+    (None, Code::Numpad9, HotKeyAction::NextDir),
+    (None, Code::Numpad7, HotKeyAction::PrevDir),
+    (None, Code::Numpad0, HotKeyAction::PauseToggle),
+    (None, Code::Numpad2, HotKeyAction::VolDown),
+    (None, Code::Numpad8, HotKeyAction::VolUp),
+    (None, Code::Numpad1, HotKeyAction::SysVolDown),
+    (None, Code::Numpad3, HotKeyAction::SysVolUp),
+    (
+        Some(Modifiers::CONTROL),
+        Code::Numpad1,
+        HotKeyAction::Rate(1),
+    ),
+    (
+        Some(Modifiers::CONTROL),
+        Code::Numpad2,
+        HotKeyAction::Rate(2),
+    ),
+    (
+        Some(Modifiers::CONTROL),
+        Code::Numpad3,
+        HotKeyAction::Rate(3),
+    ),
+    (
+        Some(Modifiers::CONTROL),
+        Code::Numpad4,
+        HotKeyAction::Rate(4),
+    ),
+    (
+        Some(Modifiers::CONTROL),
+        Code::Numpad5,
+        HotKeyAction::Rate(5),
+    ),
+    (
+        Some(Modifiers::CONTROL),
+        Code::Numpad9,
+        HotKeyAction::RandomDir,
+    ),
+    (
+        Some(Modifiers::CONTROL),
+        Code::Numpad0,
+        HotKeyAction::ToggleKaraoke,
+    ),
+    (Some(Modifiers::CONTROL), Code::Numpad8, HotKeyAction::Love),
+    (
+        Some(Modifiers::ALT),
+        Code::Numpad6,
+        HotKeyAction::SeekForward,
+    ),
+    (
+        Some(Modifiers::ALT),
+        Code::Numpad4,
+        HotKeyAction::SeekBackward,
+    ),
+    (
+        Some(Modifiers::ALT),
+        Code::Numpad9,
+        HotKeyAction::LargeSeekForward,
+    ),
+    (
+        Some(Modifiers::ALT),
+        Code::Numpad7,
+        HotKeyAction::LargeSeekBackward,
+    ),
 ];
 
-const THREAD_SLEEP: Duration = Duration::from_millis(100);
+// e.g. ["alt", "shift"]
+pub fn parse_modifiers(names: &[String]) -> Result<Modifiers> {
+    let mut modifiers = Modifiers::empty();
+    for name in names {
+        modifiers |= match name.to_lowercase().as_str() {
+            "alt" => Modifiers::ALT,
+            "ctrl" | "control" => Modifiers::CONTROL,
+            "shift" => Modifiers::SHIFT,
+            "super" | "meta" => Modifiers::SUPER,
+            _ => bail!("unknown hotkey modifier: {name}"),
+        };
+    }
+    return Ok(modifiers);
+}
 
 pub struct HotKeys {
-    thread: Option<JoinHandle<()>>,
-    stop_flag: Arc<Mutex<bool>>,
+    manager: Option<GlobalHotKeyManager>,
+    hotkeys: Vec<HotKey>,
 }
 
 impl HotKeys {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         return Self {
-            thread: None,
-            stop_flag: Arc::new(Mutex::new(false)),
+            manager: None,
+            hotkeys: Vec::new(),
         };
     }
 
-    pub fn start<F>(&mut self, action_func: F) -> Result<()>
+    // the extra modifiers are added to every hotkey
+    pub fn start<F>(&mut self, extra_modifiers: Modifiers, action_func: F) -> Result<()>
     where
-        F: Fn(HotKeyAction) + Clone + Sync + Send + 'static,
+        F: Fn(HotKeyAction) + Send + 'static,
     {
         let mut id_action_map = HashMap::new();
         let mut hotkeys = Vec::new();
-        for (code, action) in ACTIONS {
-            let hotkey = HotKey::new(None, code);
+        for (modifiers, code, action) in ACTIONS {
+            let modifiers = modifiers.unwrap_or_default() | extra_modifiers;
+            let hotkey = HotKey::new(Some(modifiers), code);
             let id = hotkey.id();
             hotkeys.push(hotkey);
             id_action_map.insert(id, action);
@@ -74,29 +148,31 @@ impl HotKeys {
         let manager = GlobalHotKeyManager::new()?;
         manager.register_all(&hotkeys)?;
 
-        let stop_flag = self.stop_flag.clone();
-        let thread = thread_util::thread("hotkeys manager", move || {
-            while !*stop_flag.lock().unwrap() {
-                if let Ok(event) = GlobalHotKeyEvent::receiver().recv_timeout(THREAD_SLEEP) {
-                    if event.state == HotKeyState::Pressed {
-                        if let Some(action) = id_action_map.get(&event.id) {
-                            action_func(*action);
-                        }
-                    }
+        // The handler runs on the thread of global-hotkey, so it only passes the action on.
+        // Otherwise a key press while the hotkeys are being unregistered would block that thread.
+        // The handler can only be set once, so the hotkeys can only be started once.
+        let (tx, rx) = mpsc::channel();
+        GlobalHotKeyEvent::set_event_handler(Some(move |event: GlobalHotKeyEvent| {
+            if event.state == HotKeyState::Pressed {
+                if let Some(action) = id_action_map.get(&event.id) {
+                    tx.send(*action).ok();
                 }
             }
-            manager.unregister_all(&hotkeys).ignore_err();
-            drop(manager); // this will move the manager into the closure and will keep it alive
+        }));
+        thread_util::thread("hotkeys", move || {
+            for action in rx {
+                action_func(action);
+            }
         });
-        self.thread = Some(thread);
+        self.manager = Some(manager);
+        self.hotkeys = hotkeys;
 
         return Ok(());
     }
 
     pub fn stop(&mut self) {
-        *self.stop_flag.lock().unwrap() = true;
-        if let Some(t) = self.thread.take() {
-            t.join().unwrap();
+        if let Some(manager) = self.manager.take() {
+            manager.unregister_all(&self.hotkeys).ignore_err();
         }
     }
 }
@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{sync::OnceLock, thread, time::Duration};
+
+use ureq::{Agent, AgentBuilder, Proxy, Request, Response};
+
+use crate::{config::Config, err_util::LogErr, project_info};
+
+// a stalled server must not block the API threads forever
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_RETRIES: u32 = 2;
+// multiplied by the number of the attempt
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+// a longer wait is not worth it, the request is retried later anyway (e.g. a scrobble)
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+const STATUS_TOO_MANY_REQUESTS: u16 = 429;
+
+struct Settings {
+    proxy: Option<Proxy>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    retries: u32,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+fn timeout(secs: Option<f64>) -> Duration {
+    return secs
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .unwrap_or(DEFAULT_TIMEOUT);
+}
+
+fn settings() -> &'static Settings {
+    return SETTINGS.get_or_init(|| {
+        let config = Config::load_or_default();
+        let proxy = config
+            .http_proxy
+            .and_then(|proxy| match Proxy::new(&proxy) {
+                Ok(proxy) => Some(proxy),
+                Err(e) => {
+                    e.log_context(format!("invalid proxy: {proxy}"));
+                    None
+                }
+            });
+        return Settings {
+            proxy,
+            connect_timeout: timeout(config.http_connect_timeout_secs),
+            read_timeout: timeout(config.http_read_timeout_secs),
+            retries: config.http_retries.unwrap_or(DEFAULT_RETRIES),
+        };
+    });
+}
+
+pub fn user_agent() -> String {
+    return format!("{}/{}", project_info::title(), project_info::version());
+}
+
+// the proxy from the config is preferred, then the one from the environment (e.g. HTTPS_PROXY)
+pub fn new_agent() -> Agent {
+    let settings = settings();
+    let builder = AgentBuilder::new()
+        .timeout_connect(settings.connect_timeout)
+        .timeout_read(settings.read_timeout)
+        .try_proxy_from_env(true)
+        .user_agent(&user_agent());
+    let builder = match &settings.proxy {
+        Some(proxy) => builder.proxy(proxy.clone()),
+        None => builder,
+    };
+    return builder.build();
+}
+
+// the delay that a rate-limited server asks for, e.g. "Retry-After: 5"
+fn retry_after(response: &Response) -> Option<Duration> {
+    let secs: u64 = response.header("Retry-After")?.trim().parse().ok()?;
+    return Some(Duration::from_secs(secs).min(MAX_RETRY_AFTER));
+}
+
+// Repeats the request if the server cannot be reached or if it's rate-limited.
+// Other responses of the server (including the errors) are returned as is.
+#[allow(clippy::result_large_err)]
+fn with_retries(send: impl Fn() -> Result<Response, ureq::Error>) -> Result<Response, ureq::Error> {
+    let mut attempt = 0;
+    loop {
+        let delay = match send() {
+            Err(ureq::Error::Transport(_)) if attempt < settings().retries => {
+                RETRY_DELAY * (attempt + 1)
+            }
+            Err(ureq::Error::Status(STATUS_TOO_MANY_REQUESTS, response))
+                if attempt < settings().retries =>
+            {
+                retry_after(&response).unwrap_or(RETRY_DELAY * (attempt + 1))
+            }
+            result => return result,
+        };
+        attempt += 1;
+        thread::sleep(delay);
+    }
+}
+
+#[allow(clippy::result_large_err)]
+pub fn call(request: &Request) -> Result<Response, ureq::Error> {
+    return with_retries(|| request.clone().call());
+}
+
+#[allow(clippy::result_large_err)]
+pub fn send_string(request: &Request, data: &str) -> Result<Response, ureq::Error> {
+    return with_retries(|| request.clone().send_string(data));
+}
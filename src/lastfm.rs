@@ -2,9 +2,8 @@
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
 use std::{
-    sync::{Arc, Mutex},
-    thread::JoinHandle,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    fmt, process, thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{bail, Context, Result};
@@ -12,23 +11,108 @@ use serde::{Deserialize, Serialize};
 use url::Url;
 
 use crate::{
-    cli,
     err_util::{eprintln_with_date, IgnoreErr, LogErr},
-    project_file::{ProjectFileJson, ProjectFileString},
-    project_info, thread_util,
+    error_popup::{self, ErrorSeverity},
+    http,
+    project_file::{Migration, ProjectFileJson},
+    project_info,
+    scrobbler::{self, ApiThread, PendingQueue, ScrobbleTrack, Scrobbler},
+    secret_store::SecretStore,
 };
 
 include!(concat!(env!("OUT_DIR"), "/lastfm_keys.rs"));
 
-const API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+const LASTFM_API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+const LIBREFM_API_URL: &str = "https://libre.fm/2.0/";
+// Libre.fm accepts any key, so it works even without the Last.fm keys
+const LIBREFM_FALLBACK_KEY: [u8; 16] = [0; 16];
 const MAX_SCROBBLES: usize = 50;
+const AUTH_POLL_INTERVAL: Duration = Duration::from_secs(3);
+// the tokens are valid for 60 minutes
+const AUTH_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+const ERR_TOKEN_NOT_AUTHORIZED: usize = 14;
+
+const QUEUE_MIGRATIONS: &[Migration] = &[
+    // 0: the queues from before the versioning have the same items
+    Ok,
+];
+
+// the services that implement the Last.fm API
+#[derive(Copy, Clone)]
+pub enum Service {
+    LastFM,
+    LibreFM,
+}
+
+impl Service {
+    pub const fn title(self) -> &'static str {
+        return match self {
+            Self::LastFM => "Last.fm",
+            Self::LibreFM => "Libre.fm",
+        };
+    }
+
+    const fn api_url(self) -> &'static str {
+        return match self {
+            Self::LastFM => LASTFM_API_URL,
+            Self::LibreFM => LIBREFM_API_URL,
+        };
+    }
+
+    // where the user grants the access to the token
+    const fn auth_url(self) -> &'static str {
+        return match self {
+            Self::LastFM => "https://www.last.fm/api/auth/",
+            Self::LibreFM => "https://libre.fm/api/auth/",
+        };
+    }
+
+    // the prefix of the data files
+    const fn name(self) -> &'static str {
+        return match self {
+            Self::LastFM => "lastfm",
+            Self::LibreFM => "librefm",
+        };
+    }
+
+    const fn keys(self) -> Option<([u8; 16], [u8; 16])> {
+        return match (API_KEY, SHARED_SECRET, self) {
+            (Some(key), Some(secret), _) => Some((key, secret)),
+            (_, _, Self::LibreFM) => Some((LIBREFM_FALLBACK_KEY, LIBREFM_FALLBACK_KEY)),
+            (_, _, Self::LastFM) => None,
+        };
+    }
+}
 
 pub struct LastFM {
+    service: Service,
     api_key: String,
     shared_secret: String,
     session_key: Option<String>,
-    not_scrobbled: Arc<Mutex<Vec<ScrobbleItem>>>,
-    api_thread: Option<JoinHandle<()>>,
+    not_scrobbled: PendingQueue<ScrobbleItem>,
+    not_loved: PendingQueue<LoveItem>,
+    api_thread: ApiThread,
+}
+
+#[derive(Deserialize)]
+struct SimilarArtistsResponse {
+    #[serde(rename = "similarartists")]
+    similar_artists: SimilarArtists,
+}
+
+#[derive(Deserialize)]
+struct SimilarArtists {
+    artist: Vec<SimilarArtist>,
+}
+
+#[derive(Deserialize)]
+struct SimilarArtist {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: String,
 }
 
 #[derive(Deserialize)]
@@ -42,12 +126,59 @@ struct AuthSession {
     key: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
 struct ErrorResponse {
     message: String,
     error: usize,
 }
 
+#[derive(Debug)]
+enum ApiError {
+    // reported by the API itself, see https://www.last.fm/api/errorcodes
+    Service {
+        code: usize,
+        message: String,
+        status: u16,
+    },
+    // an error status without the API error (e.g. from a proxy)
+    Status(u16),
+    // the server cannot be reached
+    Transport(String),
+}
+
+impl ApiError {
+    fn from_http(e: ureq::Error) -> Self {
+        return match e {
+            ureq::Error::Status(status, response) => response
+                .into_string()
+                .ok()
+                .and_then(|json| serde_json::from_str::<ErrorResponse>(&json).ok())
+                .map_or(Self::Status(status), |response| Self::Service {
+                    code: response.error,
+                    message: response.message,
+                    status,
+                }),
+            ureq::Error::Transport(e) => Self::Transport(e.to_string()),
+        };
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Self::Service {
+                code,
+                message,
+                status,
+            } => write!(f, "{message}, Error Code = {code}, HTTP status = {status}"),
+            Self::Status(status) => write!(f, "HTTP status = {status}"),
+            Self::Transport(message) => write!(f, "HTTP error: {message}"),
+        };
+    }
+}
+
+impl std::error::Error for ApiError {}
+
 #[derive(Deserialize)]
 struct NowPlayingResponse {
     #[serde(rename = "nowplaying")]
@@ -88,7 +219,7 @@ enum ScrobbleResponseRoot {
     Single { scrobble: TrackResult },
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct ScrobbleItem {
     artist: String,
     track: String,
@@ -96,91 +227,104 @@ struct ScrobbleItem {
     number: Option<usize>,
     duration: Option<u64>,
     timestamp: u64,
+    #[serde(default)]
+    mbid: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct LoveItem {
+    artist: String,
+    track: String,
 }
 
 impl LastFM {
-    fn new_or_none() -> Option<Self> {
-        if let (Some(key), Some(secret)) = (API_KEY, SHARED_SECRET) {
-            let session_key = Self::session_key_file().load().to_option();
-            let not_scrobbled = if session_key.is_some() {
-                Self::not_scrobbled_file().load().ok_or(Vec::new)
-            } else {
-                Vec::new()
-            };
+    fn new_or_none(service: Service) -> Option<Self> {
+        if let Some((key, secret)) = service.keys() {
             return Some(Self {
+                service,
                 api_key: Self::key_arr_to_string(&key),
                 shared_secret: Self::key_arr_to_string(&secret),
-                session_key,
-                not_scrobbled: Arc::new(Mutex::new(not_scrobbled)),
-                api_thread: None,
+                session_key: Self::session_key_store(service).load().to_option(),
+                not_scrobbled: PendingQueue::load(Self::not_scrobbled_file(service)),
+                not_loved: PendingQueue::load(Self::not_loved_file(service)),
+                api_thread: ApiThread::default(),
             });
         }
         return None;
     }
 
-    pub fn useable_or_none() -> Option<Self> {
-        let lfm = Self::new_or_none();
+    fn useable_or_none(service: Service) -> Option<Self> {
+        let lfm = Self::new_or_none(service);
         if let Some(lfm) = lfm {
             if lfm.is_useable() {
                 return Some(lfm);
             }
-            eprintln_with_date("no authorization for Last.fm");
+            eprintln_with_date(format!("no authorization for {}", service.title()));
             return None;
         }
-        eprintln_with_date("Last.fm is not supported in this build");
+        eprintln_with_date(format!(
+            "{} is not supported in this build",
+            service.title()
+        ));
         return None;
     }
 
-    fn is_useable(&self) -> bool {
-        return self.session_key.is_some();
+    // Last.fm and Libre.fm, the ones that are authorized
+    pub fn all_useable() -> Vec<Self> {
+        return [Service::LastFM, Service::LibreFM]
+            .into_iter()
+            .filter_map(Self::useable_or_none)
+            .collect();
     }
 
-    fn wait_for_api_thread(&mut self) {
-        if let Some(t) = self.api_thread.take() {
-            t.join().to_anyhow().ignore_err();
-        }
+    // the requests that are left from the previous runs
+    pub fn has_pending() -> bool {
+        return [Service::LastFM, Service::LibreFM]
+            .into_iter()
+            .any(|service| {
+                return scrobbler::has_stored_items(&Self::not_scrobbled_file(service))
+                    || scrobbler::has_stored_items(&Self::not_loved_file(service));
+            });
     }
 
-    pub fn playing_now(
-        &mut self,
-        artist: &str,
-        album: &Option<String>,
-        track: &str,
-        number: Option<usize>,
-        duration: Option<Duration>,
-    ) -> Result<()> {
+    fn is_useable(&self) -> bool {
+        return self.session_key.is_some();
+    }
+
+    fn send_playing_now(&mut self, track: &ScrobbleTrack) -> Result<()> {
         let mut params = vec![
-            ("artist".to_string(), artist.to_string()),
-            ("track".to_string(), track.to_string()),
+            ("artist".to_string(), track.artist.clone()),
+            ("track".to_string(), track.title.clone()),
         ];
         if let Some(session_key) = &self.session_key {
-            params.push(("sk".to_string(), session_key.to_string()));
+            params.push(("sk".to_string(), session_key.clone()));
         } else {
-            bail!("Last.fm session key is not set");
+            bail!("{} session key is not set", self.title());
         }
-        if let Some(album) = album {
-            params.push(("album".to_string(), album.to_string()));
+        if let Some(album) = &track.album {
+            params.push(("album".to_string(), album.clone()));
         }
-        if let Some(number) = number {
+        if let Some(number) = track.number {
             params.push(("trackNumber".to_string(), number.to_string()));
         }
-        if let Some(duration) = duration {
-            params.push(("duration".to_string(), duration.as_secs().to_string()));
+        params.push(("duration".to_string(), track.duration.as_secs().to_string()));
+        if let Some(mbid) = &track.mbids.recording {
+            params.push(("mbid".to_string(), mbid.clone()));
         }
 
         let url = self
             .get_method_url("track.updateNowPlaying", &params)
             .context("cannot get URL for playing_now")?;
 
-        self.wait_for_api_thread();
-        thread_util::thread(
-            "Last.fm now playing API call",
+        let title = self.title();
+        self.api_thread.run(
+            &format!("{title} now playing API call"),
             move || match Self::api_call::<NowPlayingResponse>(&url) {
                 Ok(response) => {
                     response.now_playing.warn_if_ignored();
                 }
                 Err(e) => e
-                    .context("cannot perform Last.fm API playing_now call")
+                    .context(format!("cannot perform {title} API playing_now call"))
                     .log(),
             },
         );
@@ -188,45 +332,15 @@ impl LastFM {
         return Ok(());
     }
 
-    pub fn scrobble(
-        &mut self,
-        artist: &str,
-        album: &Option<String>,
-        track: &str,
-        number: Option<usize>,
-        duration: Option<Duration>,
-    ) -> Result<()> {
+    fn send_not_scrobbled(&mut self) -> Result<()> {
         let mut params = vec![];
         if let Some(session_key) = &self.session_key {
             params.push(("sk".to_string(), session_key.clone()));
         } else {
-            bail!("Last.fm session key is not set");
+            bail!("{} session key is not set", self.title());
         }
 
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .context("cannot get current timestamp")?
-            .as_secs();
-        let item = ScrobbleItem {
-            artist: artist.to_string(),
-            album: album.clone(),
-            track: track.to_string(),
-            number,
-            duration: duration.map(|d| d.as_secs()),
-            timestamp,
-        };
-
-        let items_arc = self.not_scrobbled.clone();
-        let mut items = items_arc.lock().unwrap();
-        let was_empty = items.is_empty();
-        items.push(item);
-        let items_len = items.len();
-        let first_item_index = if items_len >= MAX_SCROBBLES {
-            items_len - MAX_SCROBBLES
-        } else {
-            0
-        };
-        let batch = &items[first_item_index..items_len];
+        let batch = self.not_scrobbled.last(MAX_SCROBBLES);
         let mut timestamps = Vec::new();
         for (i, item) in batch.iter().enumerate() {
             timestamps.push(item.timestamp);
@@ -234,59 +348,123 @@ impl LastFM {
             params.push((format!("track[{i}]"), item.track.clone()));
             params.push((format!("timestamp[{i}]"), item.timestamp.to_string()));
 
-            if let Some(album) = album {
+            if let Some(album) = &item.album {
                 params.push((format!("album[{i}]"), album.clone()));
             }
-            if let Some(number) = number {
+            if let Some(number) = item.number {
                 params.push((format!("trackNumber[{i}]"), number.to_string()));
             }
-            if let Some(duration) = duration {
-                params.push((format!("duration[{i}]"), duration.as_secs().to_string()));
+            if let Some(duration) = item.duration {
+                params.push((format!("duration[{i}]"), duration.to_string()));
+            }
+            if let Some(mbid) = &item.mbid {
+                params.push((format!("mbid[{i}]"), mbid.clone()));
             }
         }
-        drop(items);
 
         let url = self
             .get_method_url("track.scrobble", &params)
             .context("cannot get URL for scrobble")?;
 
-        let items_arc = self.not_scrobbled.clone();
-        self.wait_for_api_thread();
-        self.api_thread = Some(thread_util::thread(
-            "Last.fm scrobble API call",
-            move || {
-                match Self::api_call::<ScrobbleResponse>(&url) {
-                    Ok(response) => {
-                        let infos = match response.scrobbles {
-                            ScrobbleResponseRoot::Many { scrobble } => scrobble,
-                            ScrobbleResponseRoot::Single { scrobble } => vec![scrobble],
-                        };
-
-                        for info in &infos {
-                            info.warn_if_ignored();
+        let queue = self.not_scrobbled.clone();
+        let title = self.title();
+        self.api_thread.run(
+            &format!("{title} scrobble API call"),
+            move || match Self::api_call::<ScrobbleResponse>(&url) {
+                Ok(response) => {
+                    let infos = match response.scrobbles {
+                        ScrobbleResponseRoot::Many { scrobble } => scrobble,
+                        ScrobbleResponseRoot::Single { scrobble } => vec![scrobble],
+                    };
+
+                    for info in &infos {
+                        if info.warn_if_ignored() {
+                            error_popup::notify(
+                                ErrorSeverity::Major,
+                                &format!(
+                                    "{title} ignored the scrobble: {}",
+                                    info.ignored_message.text
+                                ),
+                            );
                         }
-
-                        let mut items = items_arc.lock().unwrap();
-                        items.retain(|i| !timestamps.contains(&i.timestamp));
-                    }
-                    Err(e) => {
-                        e.context("Last.fm API scrobble call failed").log();
                     }
+
+                    queue.remove(|i| timestamps.contains(&i.timestamp));
                 }
-                let items = items_arc.lock().unwrap();
-                if !items.is_empty() || !was_empty {
-                    Self::not_scrobbled_file()
-                        .save::<Vec<ScrobbleItem>>(&items)
-                        .ignore_err();
+                Err(e) => {
+                    // the scrobbles are sent again later anyway if the server is not reachable
+                    let severity = match e.downcast_ref::<ApiError>() {
+                        Some(ApiError::Transport(_)) => ErrorSeverity::Minor,
+                        _ => ErrorSeverity::Major,
+                    };
+                    error_popup::report(
+                        severity,
+                        e.context(format!("{title} API scrobble call failed")),
+                    );
+                    queue.save();
                 }
             },
-        ));
+        );
+
+        return Ok(());
+    }
+
+    // the tracks that were not loved before (e.g. while offline) are sent as well
+    fn send_love(&mut self, artist: &str, track: &str) -> Result<()> {
+        let Some(session_key) = self.session_key.clone() else {
+            bail!("{} session key is not set", self.title());
+        };
+
+        let item = LoveItem {
+            artist: artist.to_string(),
+            track: track.to_string(),
+        };
+        if !self.not_loved.contains(|i| *i == item) {
+            self.not_loved.push(item);
+        }
+        let mut calls = Vec::new();
+        for item in self.not_loved.all() {
+            let params = [
+                ("artist".to_string(), item.artist.clone()),
+                ("track".to_string(), item.track.clone()),
+                ("sk".to_string(), session_key.clone()),
+            ];
+            let url = self
+                .get_method_url("track.love", &params)
+                .context("cannot get URL for love")?;
+            calls.push((item, url));
+        }
+
+        let queue = self.not_loved.clone();
+        let title = self.title();
+        self.api_thread
+            .run(&format!("{title} love API call"), move || {
+                for (item, url) in calls {
+                    match Self::api_call::<serde_json::Value>(&url) {
+                        Ok(_) => queue.remove(|i| *i == item),
+                        Err(e) => e.context(format!("{title} API love call failed")).log(),
+                    }
+                }
+                queue.save();
+            });
 
         return Ok(());
     }
 
-    fn not_scrobbled_file() -> ProjectFileJson {
-        return ProjectFileJson::for_data("lastfm_not_scrobbled.json", "not-scrobbled tracks file");
+    fn not_loved_file(service: Service) -> ProjectFileJson {
+        return ProjectFileJson::for_state(
+            &format!("{}_not_loved.json", service.name()),
+            "not-loved tracks file",
+        )
+        .with_migrations(QUEUE_MIGRATIONS);
+    }
+
+    fn not_scrobbled_file(service: Service) -> ProjectFileJson {
+        return ProjectFileJson::for_state(
+            &format!("{}_not_scrobbled.json", service.name()),
+            "not-scrobbled tracks file",
+        )
+        .with_migrations(QUEUE_MIGRATIONS);
     }
 
     fn key_arr_to_string(key: &[u8]) -> String {
@@ -295,8 +473,12 @@ impl LastFM {
         return key_str;
     }
 
-    fn session_key_file() -> ProjectFileString {
-        return ProjectFileString::for_data("lastfm_session_key", "Last.fm session key file");
+    fn session_key_store(service: Service) -> SecretStore {
+        return SecretStore::for_data(
+            &format!("{}_session_key", service.name()),
+            &format!("{} session key", service.title()),
+            "session key file",
+        );
     }
 
     fn calc_sig(&self, params: &[(String, String)]) -> String {
@@ -322,7 +504,7 @@ impl LastFM {
         let signature = self.calc_sig(&params);
         params.push(("api_sig".to_string(), signature));
         params.push(("format".to_string(), "json".to_string()));
-        let url = Url::parse_with_params(API_URL, &params)
+        let url = Url::parse_with_params(self.service.api_url(), &params)
             .with_context(|| format!("cannot build URL for method {method}"))?;
         let full_url = url.as_str();
         return Ok(full_url.to_string());
@@ -332,85 +514,161 @@ impl LastFM {
     where
         for<'de> T: Deserialize<'de>,
     {
-        let user_agent = format!("{}/{}", project_info::title(), project_info::version());
-
-        let result = ureq::post(url)
-            .set("User-Agent", &user_agent)
+        let request = http::new_agent()
+            .post(url)
             .set("Content-Type", "application/json")
-            .set("Content-Length", "0")
-            .call();
-
-        let result = match result {
-            Ok(result) => result,
-            Err(e) => match e {
-                ureq::Error::Status(status, response) => {
-                    let json = response
-                        .into_string()
-                        .context("cannot read error status HTTP response as string")?;
-                    let err: ErrorResponse = serde_json::from_str(&json)
-                        .context("cannot parse error status HTTP response ")?;
-                    bail!(
-                        "{}, Error Code = {}, HTTP status = {}",
-                        &err.message,
-                        err.error,
-                        status
-                    );
-                }
-                ureq::Error::Transport(e) => {
-                    let msg = e.message().unwrap_or_default();
-                    let kind = e.kind();
-                    bail!("HTTP error [{kind}]: {msg}")
-                }
-            },
-        };
-        let json = result
+            .set("Content-Length", "0");
+        let json = http::call(&request)
+            .map_err(ApiError::from_http)?
             .into_string()
             .context("cannot get HTTP response as string")?;
         let result = serde_json::from_str(&json).context("cannot parse HTTP response")?;
         return Ok(result);
     }
 
-    pub fn cli_auth() -> Result<()> {
-        let lastfm = Self::new_or_none().context("Last.fm support was not enabled")?;
+    pub fn cli_auth(service: Service) -> Result<()> {
+        let title = service.title();
+        let lastfm = Self::new_or_none(service)
+            .with_context(|| format!("{title} support was not enabled"))?;
         if lastfm.session_key.is_some() {
-            let session_key = Self::session_key_file();
-            bail!("there is already a stored session key at {:?}. Remove this file to authenticate again.", session_key.filename()?);
+            bail!(
+                "there is already a stored session key {} to authenticate again.",
+                Self::session_key_store(service).location()
+            );
         }
 
-        let username = cli::read_line("Last.fm username: ").context("cannot read username")?;
-        if username.is_empty() {
-            bail!("the username can't be empty");
-        }
-        let password =
-            rpassword::prompt_password("Last.fm password: ").context("cannot read password")?;
-        if username.is_empty() {
-            bail!("the password can't be empty");
-        }
+        let url = lastfm
+            .get_method_url("auth.getToken", &[])
+            .context("cannot get token URL")?;
+        let token = Self::api_call::<TokenResponse>(&url)
+            .context("cannot perform token API call")?
+            .token;
+
+        let auth_url = Url::parse_with_params(
+            service.auth_url(),
+            &[("api_key", lastfm.api_key.as_str()), ("token", &token)],
+        )
+        .context("cannot build auth URL")?;
+        println!(
+            "Allow {} to access your {title} account:\n{auth_url}",
+            project_info::title()
+        );
+        // the URL is printed anyway, so it can be opened manually
+        process::Command::new("xdg-open")
+            .arg(auth_url.as_str())
+            .spawn()
+            .context("cannot open the browser")
+            .ignore_err();
 
         let url = lastfm
-            .get_method_url(
-                "auth.getMobileSession",
-                &[
-                    ("username".to_string(), username),
-                    ("password".to_string(), password),
-                ],
-            )
+            .get_method_url("auth.getSession", &[("token".to_string(), token)])
             .context("cannot get auth URL")?;
-        let result =
-            Self::api_call::<AuthResponse>(&url).context("cannot perform auth API call")?;
+        let result = Self::wait_for_session(&url)?;
 
-        Self::session_key_file()
+        Self::session_key_store(service)
             .save(&result.session.key)
             .context("cannot save session key")?;
         println!("Authenticated: {}", &result.session.name);
 
         return Ok(());
     }
+
+    // does not need the authorization
+    pub fn similar_artists(artist: &str) -> Result<Vec<String>> {
+        let lastfm =
+            Self::new_or_none(Service::LastFM).context("Last.fm support was not enabled")?;
+        let url = lastfm
+            .get_method_url(
+                "artist.getSimilar",
+                &[
+                    ("artist".to_string(), artist.to_string()),
+                    ("autocorrect".to_string(), "1".to_string()),
+                ],
+            )
+            .context("cannot get URL for similar artists")?;
+        let result = Self::api_call::<SimilarArtistsResponse>(&url)
+            .context("cannot perform similar artists API call")?;
+        return Ok(result
+            .similar_artists
+            .artist
+            .into_iter()
+            .map(|artist| artist.name)
+            .collect());
+    }
+
+    // the session is only given after the user allows the access in the browser
+    fn wait_for_session(url: &str) -> Result<AuthResponse> {
+        let started_at = Instant::now();
+        loop {
+            match Self::api_call::<AuthResponse>(url) {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    let is_pending = matches!(
+                        e.downcast_ref::<ApiError>(),
+                        Some(ApiError::Service {
+                            code: ERR_TOKEN_NOT_AUTHORIZED,
+                            ..
+                        })
+                    );
+                    if !is_pending {
+                        return Err(e.context("cannot perform auth API call"));
+                    }
+                }
+            }
+            if started_at.elapsed() >= AUTH_TIMEOUT {
+                bail!("the access was not allowed in time");
+            }
+            thread::sleep(AUTH_POLL_INTERVAL);
+        }
+    }
 }
 
-impl Drop for LastFM {
-    fn drop(&mut self) {
-        self.wait_for_api_thread();
+impl Scrobbler for LastFM {
+    fn title(&self) -> String {
+        return self.service.title().to_string();
+    }
+
+    fn playing_now(&mut self, track: &ScrobbleTrack) -> Result<()> {
+        return self.send_playing_now(track);
+    }
+
+    fn scrobble(&mut self, track: &ScrobbleTrack) -> Result<()> {
+        if self.session_key.is_none() {
+            bail!("{} session key is not set", self.title());
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("cannot get current timestamp")?
+            .as_secs();
+        self.not_scrobbled.push(ScrobbleItem {
+            artist: track.artist.clone(),
+            album: track.album.clone(),
+            track: track.title.clone(),
+            number: track.number,
+            duration: Some(track.duration.as_secs()),
+            timestamp,
+            mbid: track.mbids.recording.clone(),
+        });
+
+        return self.send_not_scrobbled();
+    }
+
+    fn love(&mut self, artist: &str, title: &str) -> Result<()> {
+        return self.send_love(artist, title);
+    }
+
+    fn flush(&mut self) -> Result<bool> {
+        if self.not_scrobbled.is_empty() {
+            return Ok(false);
+        }
+        self.send_not_scrobbled()?;
+        return Ok(true);
+    }
+
+    fn save_pending(&self) {
+        self.not_scrobbled.save();
+        self.not_loved.save();
     }
 }
 
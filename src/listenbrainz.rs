@@ -1,26 +1,41 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::{
-    sync::{Arc, Mutex, MutexGuard},
-    thread::JoinHandle,
-    time::{Duration, SystemTime, UNIX_EPOCH},
-};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
+use url::Url;
 
 use crate::{
     cli,
-    err_util::{eprintln_with_date, IgnoreErr, LogErr},
-    project_file::{ProjectFileJson, ProjectFileString},
-    project_info, thread_util,
+    err_util::{eprintln_with_date, LogErr},
+    error_popup::{self, ErrorSeverity},
+    http,
+    project_file::{Migration, ProjectFileJson},
+    project_info,
+    scrobbler::{self, ApiThread, PendingQueue, ScrobbleTrack, Scrobbler},
+    secret_store::SecretStore,
+    stream_base::MusicBrainzIds,
 };
 
-const SUBMIT_ENDPOINT: &str = "https://api.listenbrainz.org/1/submit-listens";
-const VALIDATE_ENDPOINT: &str = "https://api.listenbrainz.org/1/validate-token";
+const DEFAULT_API_ROOT: &str = "https://api.listenbrainz.org";
+const SUBMIT_ENDPOINT: &str = "/1/submit-listens";
+const VALIDATE_ENDPOINT: &str = "/1/validate-token";
+const LOOKUP_ENDPOINT: &str = "/1/metadata/lookup/";
+const FEEDBACK_ENDPOINT: &str = "/1/feedback/recording-feedback";
+const RECOMMENDATION_ENDPOINT: &str = "/1/cf/recommendation/user/";
+const MAX_RECOMMENDATIONS: usize = 100;
+const FEEDBACK_LOVE: i8 = 1;
 const MAX_IMPORT: usize = 25; // https://listenbrainz.readthedocs.io/en/production/dev/api/#listenbrainz.webserver.views.api_tools.MAX_LISTEN_SIZE
 
+const QUEUE_MIGRATIONS: &[Migration] = &[
+    // 0: the queues from before the versioning have the same items
+    Ok,
+];
+
+// serde passes the field by reference
+#[allow(clippy::ref_option)]
 fn skip_if_none_or_empty(x: &Option<String>) -> bool {
     if let Some(val) = x {
         if !val.is_empty() {
@@ -44,6 +59,12 @@ struct AdditionalInfo {
     #[serde(skip_serializing_if = "Option::is_none")]
     duration: Option<u64>,
     media_player: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recording_mbid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    release_mbid: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    artist_mbids: Vec<String>,
 }
 
 #[derive(Serialize)]
@@ -68,6 +89,38 @@ struct Request {
     payload: Vec<Payload>,
 }
 
+#[derive(Deserialize)]
+struct LookupResponse {
+    recording_mbid: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FeedbackRequest {
+    recording_mbid: String,
+    score: i8,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct LoveItem {
+    artist: String,
+    track: String,
+}
+
+#[derive(Deserialize)]
+struct RecommendationResponse {
+    payload: RecommendationPayload,
+}
+
+#[derive(Deserialize)]
+struct RecommendationPayload {
+    mbids: Vec<RecommendedRecording>,
+}
+
+#[derive(Deserialize)]
+struct RecommendedRecording {
+    recording_mbid: String,
+}
+
 #[derive(Deserialize)]
 struct TokenValidationResponse {
     code: u16,
@@ -76,7 +129,7 @@ struct TokenValidationResponse {
     user_name: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct ListenItem {
     artist: String,
     track: String,
@@ -84,18 +137,52 @@ struct ListenItem {
     number: Option<usize>,
     duration_secs: Option<u64>,
     timestamp: u64,
+    #[serde(default)]
+    mbids: MusicBrainzIds,
+}
+
+// A ListenBrainz-compatible server (e.g. Maloja or a self-hosted ListenBrainz).
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ListenBrainzServer {
+    // used for the names of the files with the listens that were not submitted yet
+    pub name: String,
+    // the part of the API URLs before "/1/", e.g. "https://maloja.example.com/apis/listenbrainz"
+    pub url: String,
+    pub token: String,
+}
+
+impl ListenBrainzServer {
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            bail!(
+                "invalid ListenBrainz server name (only latin letters, digits and underscores are allowed): {}",
+                self.name
+            );
+        }
+        return Ok(());
+    }
 }
 
 pub struct ListenBrainz {
+    // None for the official server
+    name: Option<String>,
+    api_root: String,
+    agent: ureq::Agent,
     token: Option<String>,
-    not_submitted: Arc<Mutex<Vec<ListenItem>>>,
-    api_thread: Option<JoinHandle<()>>,
+    not_submitted: PendingQueue<ListenItem>,
+    not_loved: PendingQueue<LoveItem>,
+    api_thread: ApiThread,
 }
 
 impl ListenBrainz {
-    pub fn useable_or_none() -> Option<Self> {
-        return match Self::token_file().load() {
-            Ok(token) => Some(Self::new(Some(token))),
+    fn useable_or_none() -> Option<Self> {
+        return match Self::token_store().load() {
+            Ok(token) => Some(Self::new(None, DEFAULT_API_ROOT, Some(token))),
             Err(e) => {
                 e.context("no authorization for ListenBrainz").log();
                 None
@@ -103,123 +190,89 @@ impl ListenBrainz {
         };
     }
 
-    fn new(token: Option<String>) -> Self {
-        let not_submitted = Self::not_submitted_file().load().ok_or(Vec::new);
+    // the official server (if authorized) and all the configured ones
+    pub fn all_useable(servers: &[ListenBrainzServer]) -> Vec<Self> {
+        let mut result: Vec<Self> = Self::useable_or_none().into_iter().collect();
+        for server in servers {
+            if let Err(e) = server.validate() {
+                e.log();
+                continue;
+            }
+            result.push(Self::new(
+                Some(server.name.clone()),
+                server.url.trim_end_matches('/'),
+                Some(server.token.clone()),
+            ));
+        }
+        return result;
+    }
+
+    // the listens that are left from the previous runs
+    pub fn has_pending(servers: &[ListenBrainzServer]) -> bool {
+        return std::iter::once(None)
+            .chain(
+                servers
+                    .iter()
+                    .map(|server| return Some(server.name.as_str())),
+            )
+            .any(|name| {
+                return scrobbler::has_stored_items(&Self::not_submitted_file(name))
+                    || scrobbler::has_stored_items(&Self::not_loved_file(name));
+            });
+    }
+
+    fn new(name: Option<String>, api_root: &str, token: Option<String>) -> Self {
         return Self {
+            not_submitted: PendingQueue::load(Self::not_submitted_file(name.as_deref())),
+            not_loved: PendingQueue::load(Self::not_loved_file(name.as_deref())),
+            name,
+            api_root: api_root.to_string(),
+            agent: http::new_agent(),
             token,
-            not_submitted: Arc::new(Mutex::new(not_submitted)),
-            api_thread: None,
+            api_thread: ApiThread::default(),
         };
     }
 
-    fn token_file() -> ProjectFileString {
-        return ProjectFileString::for_data("listenbrainz_token", "ListenBrainz token file");
+    fn token_store() -> SecretStore {
+        return SecretStore::for_data(
+            "listenbrainz_token",
+            "ListenBrainz token",
+            "ListenBrainz token file",
+        );
     }
 
-    fn not_submitted_file() -> ProjectFileJson {
-        return ProjectFileJson::for_data(
-            "listenbrainz_not_submitted.json",
-            "ListenBrainz not-submitted listens list",
+    fn file_prefix(name: Option<&str>) -> String {
+        return name.map_or_else(
+            || "listenbrainz".to_string(),
+            |name| format!("listenbrainz_{name}"),
         );
     }
 
-    pub fn playing_now(
-        &mut self,
-        artist: &str,
-        album: &Option<String>,
-        track: &str,
-        number: Option<usize>,
-        duration: Duration,
-    ) -> Result<()> {
-        let release_name = album.clone();
-
-        let payload = Payload {
-            listened_at: None,
-            track_metadata: TrackMetaData {
-                artist_name: artist.to_string(),
-                track_name: track.to_string(),
-                release_name,
-                additional_info: AdditionalInfo::new(number, Some(duration.as_secs())),
-            },
-        };
-
-        let request = Request {
-            listen_type: ListenType::PlayingNow,
-            payload: vec![payload],
-        };
-
-        self.send(
-            request,
-            |_| {},
-            |json| {
-                eprintln_with_date(json);
-            },
+    fn not_submitted_file(name: Option<&str>) -> ProjectFileJson {
+        return ProjectFileJson::for_state(
+            &format!("{}_not_submitted.json", Self::file_prefix(name)),
+            "ListenBrainz not-submitted listens list",
         )
-        .context("cannot perform ListenBrainz playing_now API call")?;
-
-        return Ok(());
+        .with_migrations(QUEUE_MIGRATIONS);
     }
 
-    pub fn submit(
-        &mut self,
-        artist: &str,
-        album: &Option<String>,
-        track: &str,
-        number: Option<usize>,
-        duration: Duration,
-    ) -> Result<()> {
-        let start = SystemTime::now();
-        let timestamp = start
-            .duration_since(UNIX_EPOCH)
-            .context("cannot get current timestamp")?
-            .as_secs();
-        let release_name = album.clone();
-
-        let listen = ListenItem {
-            artist: artist.to_string(),
-            album: release_name,
-            track: track.to_string(),
-            number,
-            duration_secs: Some(duration.as_secs()),
-            timestamp,
-        };
-
-        let items_arc = self.not_submitted.clone();
-        let mut items = items_arc.lock().unwrap();
-        let was_empty = items.is_empty();
-        items.push(listen);
-        let items_len = items.len();
-        let first_item_index = if items_len >= MAX_IMPORT {
-            items_len - MAX_IMPORT
-        } else {
-            0
-        };
-        let batch = &items[first_item_index..items_len];
+    fn send_not_submitted(&mut self) -> Result<()> {
+        let batch = self.not_submitted.last(MAX_IMPORT);
         let timestamps: Vec<u64> = batch.iter().map(|i| i.timestamp).collect();
 
         let request = Request {
             listen_type: ListenType::Import,
             payload: batch.iter().map(Payload::from_listen).collect(),
         };
-        drop(items);
 
+        let queue = self.not_submitted.clone();
+        let err_queue = self.not_submitted.clone();
         self.send(
             request,
-            {
-                let items_arc = self.not_submitted.clone();
-                move |_| {
-                    let mut items = items_arc.lock().unwrap();
-                    items.retain(|i| !timestamps.contains(&i.timestamp));
-                    if !was_empty || !items.is_empty() {
-                        Self::save_not_submitted_guarded(&items);
-                    }
-                    drop(items);
-                }
-            },
+            move |_| queue.remove(|i| timestamps.contains(&i.timestamp)),
             move |json| {
                 eprintln_with_date(json);
-                let items = items_arc.lock().unwrap();
-                Self::save_not_submitted_guarded(&items);
+                err_queue.save();
             },
         )
         .context("cannot perform ListenBrainz import API call")?;
@@ -227,16 +280,78 @@ impl ListenBrainz {
         return Ok(());
     }
 
-    fn save_not_submitted_guarded(items: &MutexGuard<Vec<ListenItem>>) {
-        Self::not_submitted_file()
-            .save::<Vec<ListenItem>>(items)
-            .ignore_err();
+    fn not_loved_file(name: Option<&str>) -> ProjectFileJson {
+        return ProjectFileJson::for_state(
+            &format!("{}_not_loved.json", Self::file_prefix(name)),
+            "ListenBrainz not-loved tracks list",
+        )
+        .with_migrations(QUEUE_MIGRATIONS);
+    }
+
+    // the feedback needs a MusicBrainz recording ID, so it's looked up first
+    fn send_love(agent: &ureq::Agent, api_root: &str, auth: &str, item: &LoveItem) -> Result<()> {
+        let json = agent
+            .get(&format!("{api_root}{LOOKUP_ENDPOINT}"))
+            .query("artist_name", &item.artist)
+            .query("recording_name", &item.track)
+            .call()
+            .context("cannot perform ListenBrainz lookup API call")?
+            .into_string()
+            .context("cannot read HTTP response as string")?;
+        let lookup: LookupResponse =
+            serde_json::from_str(&json).context("cannot parse ListenBrainz lookup response")?;
+        let Some(recording_mbid) = lookup.recording_mbid else {
+            eprintln_with_date(format!(
+                "the track is not found on ListenBrainz: {} - {}",
+                item.artist, item.track
+            ));
+            return Ok(());
+        };
+        let request = FeedbackRequest {
+            recording_mbid,
+            score: FEEDBACK_LOVE,
+        };
+        let json = serde_json::to_string(&request).context("cannot serialize feedback")?;
+        let request = agent
+            .post(&format!("{api_root}{FEEDBACK_ENDPOINT}"))
+            .set("Authorization", auth)
+            .set("Content-Type", "application/json");
+        http::send_string(&request, &json)
+            .context("cannot perform ListenBrainz feedback API call")?;
+        return Ok(());
     }
 
-    fn wait_for_api_thread(&mut self) {
-        if let Some(t) = self.api_thread.take() {
-            t.join().to_anyhow().ignore_err();
+    // the tracks that were not loved before (e.g. while offline) are sent as well
+    fn send_loves(&mut self, artist: &str, track: &str) -> Result<()> {
+        let Some(token) = &self.token else {
+            bail!("no token is set");
+        };
+        let auth = format!("Token {token}");
+
+        let item = LoveItem {
+            artist: artist.to_string(),
+            track: track.to_string(),
+        };
+        if !self.not_loved.contains(|i| *i == item) {
+            self.not_loved.push(item);
         }
+        let pending = self.not_loved.all();
+
+        let queue = self.not_loved.clone();
+        let agent = self.agent.clone();
+        let api_root = self.api_root.clone();
+        self.api_thread
+            .run("ListenBrainz feedback API call", move || {
+                for item in pending {
+                    match Self::send_love(&agent, &api_root, &auth, &item) {
+                        Ok(()) => queue.remove(|i| *i == item),
+                        Err(e) => e.log(),
+                    }
+                }
+                queue.save();
+            });
+
+        return Ok(());
     }
 
     fn send<S, E>(&mut self, request: Request, on_succ: S, on_err: E) -> Result<()>
@@ -247,42 +362,88 @@ impl ListenBrainz {
         if let Some(token) = &self.token {
             let auth = format!("Token {}", &token);
             let json = serde_json::to_string(&request).context("cannot serialize payload")?;
-
-            self.wait_for_api_thread();
-            let handle = thread_util::thread("ListenBrainz submit API call", move || {
-                let result = ureq::post(SUBMIT_ENDPOINT)
-                    .set("Authorization", &auth)
-                    .set("Content-Type", "application/json")
-                    .send_string(&json);
-
-                match result {
-                    Ok(resp) => {
-                        let json = resp.into_string().unwrap_or_default();
-                        on_succ(json.trim().to_string());
+            let agent = self.agent.clone();
+            let url = format!("{}{SUBMIT_ENDPOINT}", self.api_root);
+            let title = self.title();
+
+            self.api_thread
+                .run("ListenBrainz submit API call", move || {
+                    let http_request = agent
+                        .post(&url)
+                        .set("Authorization", &auth)
+                        .set("Content-Type", "application/json");
+                    let result = http::send_string(&http_request, &json);
+
+                    match result {
+                        Ok(resp) => {
+                            let json = resp.into_string().unwrap_or_default();
+                            on_succ(json.trim().to_string());
+                        }
+                        Err(e) => {
+                            // the listens are sent again later anyway if the server is not reachable
+                            let severity = match e {
+                                ureq::Error::Transport(_) => ErrorSeverity::Minor,
+                                ureq::Error::Status(..) => ErrorSeverity::Major,
+                            };
+                            let json = match e.into_response() {
+                                Some(resp) => resp.into_string().unwrap_or_default(),
+                                None => String::new(),
+                            };
+                            on_err(json.trim().to_string());
+                            eprintln_with_date(format!(
+                                "cannot perform {title} API call: {:?}",
+                                &request.listen_type
+                            ));
+                            error_popup::notify(severity, &format!("{title} rejected the listen"));
+                        }
                     }
-                    Err(e) => {
-                        let json = match e.into_response() {
-                            Some(resp) => resp.into_string().unwrap_or_default(),
-                            None => String::new(),
-                        };
-                        on_err(json.trim().to_string());
-                        eprintln_with_date(format!(
-                            "cannot perform ListenBrainz API call: {:?}",
-                            &request.listen_type
-                        ));
-                    }
-                }
-            });
-            self.api_thread = Some(handle);
+                });
 
             return Ok(());
         }
         bail!("no token is set");
     }
 
+    // the MBIDs of the recordings that are recommended to the user, the best ones first
+    pub fn recommended_recordings() -> Result<Vec<String>> {
+        let token = Self::token_store()
+            .load()
+            .context("no authorization for ListenBrainz")?;
+        let user_name = Self::validate_token(&token).context("cannot validate token")?;
+        let mut url = Url::parse(DEFAULT_API_ROOT).context("invalid API root")?;
+        url.set_path(RECOMMENDATION_ENDPOINT);
+        url.path_segments_mut()
+            .map_err(|()| anyhow!("invalid API root"))?
+            .pop_if_empty()
+            .extend([user_name.as_str(), "recording"]);
+        url.query_pairs_mut()
+            .append_pair("count", &MAX_RECOMMENDATIONS.to_string());
+        let resp = http::new_agent()
+            .get(url.as_str())
+            .set("Authorization", &format!("Token {token}"))
+            .call()
+            .context("cannot get recommendations")?;
+        // no recommendations were generated for the user yet
+        if resp.status() == 204 {
+            return Ok(Vec::new());
+        }
+        let json = resp
+            .into_string()
+            .context("cannot read HTTP response as string")?;
+        let msg: RecommendationResponse =
+            serde_json::from_str(&json).context("cannot parse recommendations")?;
+        return Ok(msg
+            .payload
+            .mbids
+            .into_iter()
+            .map(|rec| rec.recording_mbid)
+            .collect());
+    }
+
     fn validate_token(token: &str) -> Result<String> {
         let auth = format!("Token {}", &token);
-        let resp = ureq::get(VALIDATE_ENDPOINT)
+        let resp = http::new_agent()
+            .get(&format!("{DEFAULT_API_ROOT}{VALIDATE_ENDPOINT}"))
             .set("Authorization", &auth)
             .set("Content-Type", "application/json")
             .call();
@@ -312,10 +473,9 @@ impl ListenBrainz {
     pub fn cli_auth() -> Result<()> {
         let brainz = Self::useable_or_none();
         if brainz.is_some() {
-            let session_key = Self::token_file();
             bail!(
-                "there is already a stored token at {:?}. Remove this file to authenticate again.",
-                session_key.filename().context("no token filename")?
+                "there is already a stored token {} to authenticate again.",
+                Self::token_store().location()
             );
         }
         let token = cli::read_line("ListenBrainz token: ").context("cannot read token")?;
@@ -323,7 +483,7 @@ impl ListenBrainz {
             bail!("the token can't be empty");
         }
         let user_id = Self::validate_token(&token).context("cannot validate token")?;
-        Self::token_file()
+        Self::token_store()
             .save(&token)
             .context("cannot save token")?;
         println!("Authenticated: {}", &user_id);
@@ -340,24 +500,101 @@ impl Payload {
                 artist_name: listen.artist.clone(),
                 track_name: listen.track.clone(),
                 release_name: listen.album.clone(),
-                additional_info: AdditionalInfo::new(listen.number, listen.duration_secs),
+                additional_info: AdditionalInfo::new(
+                    listen.number,
+                    listen.duration_secs,
+                    &listen.mbids,
+                ),
             },
         };
     }
 }
 
 impl AdditionalInfo {
-    fn new(number: Option<usize>, duration_secs: Option<u64>) -> Self {
+    fn new(number: Option<usize>, duration_secs: Option<u64>, mbids: &MusicBrainzIds) -> Self {
         return Self {
             tracknumber: number,
             duration: duration_secs,
             media_player: project_info::title(),
+            recording_mbid: mbids.recording.clone(),
+            release_mbid: mbids.release.clone(),
+            artist_mbids: mbids.artist.iter().cloned().collect(),
         };
     }
 }
 
-impl Drop for ListenBrainz {
-    fn drop(&mut self) {
-        self.wait_for_api_thread();
+impl Scrobbler for ListenBrainz {
+    fn title(&self) -> String {
+        return self.name.as_ref().map_or_else(
+            || "ListenBrainz".to_string(),
+            |name| format!("ListenBrainz ({name})"),
+        );
+    }
+
+    fn playing_now(&mut self, track: &ScrobbleTrack) -> Result<()> {
+        let payload = Payload {
+            listened_at: None,
+            track_metadata: TrackMetaData {
+                artist_name: track.artist.clone(),
+                track_name: track.title.clone(),
+                release_name: track.album.clone(),
+                additional_info: AdditionalInfo::new(
+                    track.number,
+                    Some(track.duration.as_secs()),
+                    &track.mbids,
+                ),
+            },
+        };
+
+        let request = Request {
+            listen_type: ListenType::PlayingNow,
+            payload: vec![payload],
+        };
+
+        self.send(
+            request,
+            |_| {},
+            |json| {
+                eprintln_with_date(json);
+            },
+        )
+        .context("cannot perform ListenBrainz playing_now API call")?;
+
+        return Ok(());
+    }
+
+    fn scrobble(&mut self, track: &ScrobbleTrack) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("cannot get current timestamp")?
+            .as_secs();
+        self.not_submitted.push(ListenItem {
+            artist: track.artist.clone(),
+            album: track.album.clone(),
+            track: track.title.clone(),
+            number: track.number,
+            duration_secs: Some(track.duration.as_secs()),
+            timestamp,
+            mbids: track.mbids.clone(),
+        });
+
+        return self.send_not_submitted();
+    }
+
+    fn love(&mut self, artist: &str, title: &str) -> Result<()> {
+        return self.send_loves(artist, title);
+    }
+
+    fn flush(&mut self) -> Result<bool> {
+        if self.not_submitted.is_empty() {
+            return Ok(false);
+        }
+        self.send_not_submitted()?;
+        return Ok(true);
+    }
+
+    fn save_pending(&self) {
+        self.not_submitted.save();
+        self.not_loved.save();
     }
 }
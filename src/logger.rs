@@ -0,0 +1,174 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    err_util::{self, LogLevel},
+    project_file::ProjectFileString,
+    project_info, show_file,
+};
+
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+// konik.log.1, konik.log.2, ...
+const ROTATED_FILES: usize = 3;
+
+struct LogFile {
+    file: File,
+    path: PathBuf,
+    size: u64,
+}
+
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+static MODULE_LEVELS: OnceLock<HashMap<String, LogLevel>> = OnceLock::new();
+
+// the log of the running player, the other commands only print to the console
+fn log_path() -> Option<PathBuf> {
+    return ProjectFileString::dir_for_data()
+        .map(|dir| dir.join(format!("{}.log", project_info::name())));
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{index}"));
+    return path.into();
+}
+
+fn open(path: &Path) -> Option<LogFile> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).ok()?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .ok()?;
+    let size = file.metadata().map(|meta| meta.len()).unwrap_or_default();
+    return Some(LogFile {
+        file,
+        path: path.to_path_buf(),
+        size,
+    });
+}
+
+// the errors of the log file itself cannot be logged, so they are ignored
+fn rotate(path: &Path) -> Option<LogFile> {
+    for index in (1..ROTATED_FILES).rev() {
+        fs::rename(rotated_path(path, index), rotated_path(path, index + 1)).ok();
+    }
+    fs::rename(path, rotated_path(path, 1)).ok();
+    return open(path);
+}
+
+fn write_to_file(line: &str) {
+    let mut log_file = LOG_FILE.lock().unwrap();
+    let Some(file) = log_file.as_mut() else {
+        return;
+    };
+    if file.file.write_all(line.as_bytes()).is_ok() {
+        file.size += line.len() as u64;
+    }
+    if file.size >= MAX_FILE_SIZE {
+        *log_file = rotate(&file.path);
+    }
+}
+
+// the most specific configured level, e.g. "symphonia_core::probe" matches "symphonia_core"
+fn module_level(module: &str) -> Option<LogLevel> {
+    return MODULE_LEVELS
+        .get()?
+        .iter()
+        .filter(|(name, _)| {
+            return module == name.as_str()
+                || module
+                    .strip_prefix(name.as_str())
+                    .is_some_and(|rest| rest.starts_with("::"));
+        })
+        .max_by_key(|(name, _)| name.len())
+        .map(|(_, level)| *level);
+}
+
+// the messages of the libraries are hidden unless their level is configured
+pub fn is_enabled(level: LogLevel, module: &str, is_external: bool) -> bool {
+    return match module_level(module) {
+        Some(module_level) => module_level >= level,
+        None if is_external => level == LogLevel::Error,
+        None => err_util::is_log_level_enabled(level),
+    };
+}
+
+pub fn write(level: LogLevel, module: &str, date: &str, message: &str) {
+    let line = format!("[{date}] [{}] [{module}] {message}\n", level.title());
+    write_to_file(&line);
+}
+
+// the messages of the libraries that use the "log" crate (e.g. Symphonia)
+struct ExternalLogger;
+
+impl log::Log for ExternalLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        return is_enabled(level_of(metadata.level()), metadata.target(), true);
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.enabled(record.metadata()) {
+            err_util::log_line(
+                level_of(record.level()),
+                record.target(),
+                &record.args().to_string(),
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+const fn level_of(level: log::Level) -> LogLevel {
+    return match level {
+        log::Level::Error => LogLevel::Error,
+        log::Level::Warn | log::Level::Info => LogLevel::Info,
+        log::Level::Debug | log::Level::Trace => LogLevel::Debug,
+    };
+}
+
+static EXTERNAL_LOGGER: ExternalLogger = ExternalLogger;
+
+// Starts writing the log file and sets the levels of the modules,
+// e.g. {"lastfm": "debug", "symphonia_core": "info"}.
+pub fn init(module_levels: HashMap<String, LogLevel>) {
+    MODULE_LEVELS.set(module_levels).ok();
+    if let Some(path) = log_path() {
+        *LOG_FILE.lock().unwrap() = open(&path);
+    }
+    if log::set_logger(&EXTERNAL_LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Trace);
+    }
+}
+
+pub fn cli_print(lines: usize) -> Result<()> {
+    let path = log_path().context("cannot get the log location")?;
+    if !path.exists() {
+        bail!("no log yet: {}", path.to_string_lossy());
+    }
+    let text = fs::read_to_string(&path)
+        .with_context(|| format!("cannot read {}", path.to_string_lossy()))?;
+    let all_lines: Vec<&str> = text.lines().collect();
+    for line in &all_lines[all_lines.len().saturating_sub(lines)..] {
+        println!("{line}");
+    }
+    return Ok(());
+}
+
+pub fn cli_show() -> Result<()> {
+    let path = log_path().context("cannot get the log location")?;
+    let path = path.to_str().context("cannot convert log path to string")?;
+    return show_file::show_file(path);
+}
@@ -0,0 +1,224 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{collections::HashMap, f64::consts::PI};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    err_util::IgnoreErr,
+    project_file::{Migration, ProjectFileJson},
+    stream_base::Track,
+};
+
+// The loudness of the tracks without ReplayGain tags is measured while they are played (EBU R128),
+// so the next time they are played at the same perceived volume.
+
+// the same reference level as in ReplayGain 2.0
+const TARGET_LUFS: f64 = -18.0;
+const MAX_GAIN_DB: f64 = 12.0;
+const BLOCK_SECS: f64 = 0.4;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+// the tracks that are too short are not measured reliably
+const MIN_BLOCKS: usize = 5;
+
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    const fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        return Self { b, a, z: [0.0; 2] };
+    }
+
+    // transposed direct form II
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0].mul_add(x, self.z[0]);
+        self.z[0] = self.a[0].mul_add(-y, self.b[1].mul_add(x, self.z[1]));
+        self.z[1] = self.b[2].mul_add(x, -self.a[1] * y);
+        return y;
+    }
+}
+
+// the K-weighting filter: a high shelf followed by a high pass,
+// the coefficients are calculated for any sample rate (the same way as in libebur128)
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    let f0 = 1_681.974_450_955_533;
+    let gain_db = 3.999_843_853_973_347;
+    let q = 0.707_175_236_955_419_6;
+    let k = (PI * f0 / sample_rate).tan();
+    let vh = 10_f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let f0 = 38.135_470_876_024_44;
+    let q = 0.500_327_037_323_877_3;
+    let k = (PI * f0 / sample_rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    return [shelf, high_pass];
+}
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    return 10.0f64.mul_add(energy.log10(), -0.691);
+}
+
+// Measures the integrated loudness of the interleaved samples.
+// The gating blocks do not overlap, which is close enough for the volume adjustment.
+pub struct LoudnessMeter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    block_frames: usize,
+    frames_in_block: usize,
+    block_sum: f64,
+    blocks: Vec<f64>,
+    peak: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: usize, sample_rate: usize) -> Self {
+        let filters = k_weighting(sample_rate as f64);
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let block_frames = (sample_rate as f64 * BLOCK_SECS) as usize;
+        return Self {
+            channels,
+            filters: vec![filters; channels],
+            block_frames: block_frames.max(1),
+            frames_in_block: 0,
+            block_sum: 0.0,
+            blocks: Vec::new(),
+            peak: 0.0,
+        };
+    }
+
+    pub fn add<'a, I>(&mut self, samples: I)
+    where
+        I: IntoIterator<Item = &'a f32>,
+    {
+        if self.channels == 0 {
+            return;
+        }
+        for (i, sample) in samples.into_iter().enumerate() {
+            let channel = i % self.channels;
+            self.peak = self.peak.max(sample.abs());
+            let [high_shelf, high_pass] = &mut self.filters[channel];
+            let value = high_pass.process(high_shelf.process(f64::from(*sample)));
+            self.block_sum += value * value;
+            if channel == self.channels - 1 {
+                self.frames_in_block += 1;
+                if self.frames_in_block == self.block_frames {
+                    self.blocks.push(self.block_sum / self.block_frames as f64);
+                    self.block_sum = 0.0;
+                    self.frames_in_block = 0;
+                }
+            }
+        }
+    }
+
+    // None for silence and for the very short tracks
+    fn integrated_lufs(&self) -> Option<f64> {
+        let gated: Vec<f64> = self
+            .blocks
+            .iter()
+            .copied()
+            .filter(|energy| energy_to_lufs(*energy) > ABSOLUTE_GATE_LUFS)
+            .collect();
+        if gated.len() < MIN_BLOCKS {
+            return None;
+        }
+        let mean = gated.iter().sum::<f64>() / gated.len() as f64;
+        let relative_gate = energy_to_lufs(mean) + RELATIVE_GATE_LU;
+        let gated: Vec<f64> = gated
+            .into_iter()
+            .filter(|energy| energy_to_lufs(*energy) > relative_gate)
+            .collect();
+        if gated.is_empty() {
+            return None;
+        }
+        let mean = gated.iter().sum::<f64>() / gated.len() as f64;
+        return Some(energy_to_lufs(mean));
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Measurement {
+    lufs: f64,
+    peak: f32,
+}
+
+// The measured loudness of every track that was played till the end.
+pub struct LoudnessHistory {
+    measurements: HashMap<String, Measurement>,
+}
+
+// chapters and CUE tracks are measured separately
+fn track_key(track: &Track) -> String {
+    if let Some(index) = track.index {
+        return format!("{}#{index}", track.filename);
+    }
+    return track.filename.to_string();
+}
+
+const MIGRATIONS: &[Migration] = &[
+    // 0: the history from before the versioning has the same entries
+    Ok,
+];
+
+fn file() -> ProjectFileJson {
+    return ProjectFileJson::for_data("loudness.json", "loudness history")
+        .with_migrations(MIGRATIONS);
+}
+
+impl LoudnessHistory {
+    pub fn load() -> Self {
+        let file = file();
+        let measurements = if file.exists() {
+            file.load().ok_or_default()
+        } else {
+            HashMap::new()
+        };
+        return Self { measurements };
+    }
+
+    pub fn add(&mut self, track: &Track, meter: &LoudnessMeter) {
+        let Some(lufs) = meter.integrated_lufs() else {
+            return;
+        };
+        self.measurements.insert(
+            track_key(track),
+            Measurement {
+                lufs,
+                peak: meter.peak,
+            },
+        );
+        file().save(&self.measurements).ignore_err();
+    }
+
+    // the multiplier for the samples, None if the track was not measured yet
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn gain_factor(&self, track: &Track) -> Option<f32> {
+        let measurement = self.measurements.get(&track_key(track))?;
+        let gain_db = (TARGET_LUFS - measurement.lufs).clamp(-MAX_GAIN_DB, MAX_GAIN_DB);
+        let factor = 10_f64.powf(gain_db / 20.0) as f32;
+        if measurement.peak > 0.0 {
+            return Some(factor.min(1.0 / measurement.peak));
+        }
+        return Some(factor);
+    }
+}
@@ -0,0 +1,409 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    fs,
+    path::Path,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use lofty::{file::TaggedFileExt, probe::Probe, tag::ItemKey};
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    app_state::AppState,
+    config::Config,
+    cue::CueFactory,
+    err_util::{debugln_with_date, IgnoreErr},
+    http, playlist_man,
+    popup::Popup,
+    stream_base::{Track, TrackMeta},
+    stream_man, thread_util,
+};
+
+const LRCLIB_GET_ENDPOINT: &str = "/api/get";
+
+struct LyricsLine {
+    time: Option<Duration>,
+    text: String,
+}
+
+pub struct Lyrics {
+    lines: Vec<LyricsLine>,
+}
+
+// the LRCLIB API, see https://lrclib.net/docs
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProviderResponse {
+    plain_lyrics: Option<String>,
+    synced_lyrics: Option<String>,
+}
+
+// "mm:ss.xx" or "mm:ss"
+fn parse_time(s: &str) -> Option<Duration> {
+    let (mins, secs) = s.split_once(':')?;
+    let mins: u64 = mins.trim().parse().ok()?;
+    let secs = Duration::try_from_secs_f64(secs.trim().parse().ok()?).ok()?;
+    return Some(Duration::from_secs(mins * 60) + secs);
+}
+
+fn parse_offset(value: &str) -> Option<i64> {
+    return value.trim().parse().ok();
+}
+
+const fn apply_offset(time: Duration, offset_ms: i64) -> Duration {
+    let offset = Duration::from_millis(offset_ms.unsigned_abs());
+    // a positive offset shows the lyrics earlier
+    if offset_ms > 0 {
+        return time.saturating_sub(offset);
+    }
+    return time.saturating_add(offset);
+}
+
+impl Lyrics {
+    // LRC if there are time tags, plain text otherwise
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut lines = Vec::new();
+        let mut offset_ms = 0;
+        for line in text.lines() {
+            let mut rest = line.trim();
+            let mut times = Vec::new();
+            // e.g. [ar:Artist]
+            let mut has_id_tags = false;
+            while let Some(tag_end) = rest.strip_prefix('[').and_then(|r| r.find(']')) {
+                let tag = &rest[1..=tag_end];
+                if let Some(time) = parse_time(tag) {
+                    times.push(time);
+                } else if tag.contains(':') {
+                    has_id_tags = true;
+                    if let Some(offset) = tag.strip_prefix("offset:") {
+                        offset_ms = parse_offset(offset).unwrap_or_default();
+                    }
+                }
+                rest = rest[tag_end + 2..].trim_start();
+            }
+            if times.is_empty() {
+                if !has_id_tags {
+                    lines.push(LyricsLine {
+                        time: None,
+                        text: line.trim().to_string(),
+                    });
+                }
+            } else {
+                for time in times {
+                    lines.push(LyricsLine {
+                        time: Some(time),
+                        text: rest.to_string(),
+                    });
+                }
+            }
+        }
+        if lines.iter().any(|line| line.time.is_some()) {
+            lines.retain(|line| line.time.is_some());
+            for line in &mut lines {
+                line.time = line.time.map(|time| apply_offset(time, offset_ms));
+            }
+            lines.sort_by_key(|line| line.time);
+        }
+        while lines.last().is_some_and(|line| line.text.is_empty()) {
+            lines.pop();
+        }
+        if lines.iter().all(|line| line.text.is_empty()) {
+            return None;
+        }
+        return Some(Self { lines });
+    }
+
+    pub fn is_synced(&self) -> bool {
+        return self.lines.first().is_some_and(|line| line.time.is_some());
+    }
+
+    pub fn text(&self) -> String {
+        let lines: Vec<&str> = self.lines.iter().map(|line| line.text.as_str()).collect();
+        return lines.join("\n");
+    }
+
+    // the first line that starts after the position
+    fn next_line(&self, position: Duration) -> Option<&LyricsLine> {
+        return self
+            .lines
+            .iter()
+            .find(|line| line.time.is_some_and(|time| time > position));
+    }
+
+    fn line_at(&self, position: Duration) -> Option<&LyricsLine> {
+        return self
+            .lines
+            .iter()
+            .take_while(|line| line.time.is_some_and(|time| time <= position))
+            .last();
+    }
+}
+
+fn from_sidecar(filename: &str) -> Option<Lyrics> {
+    let path = Path::new(filename).with_extension("lrc");
+    let text = fs::read_to_string(path).ok()?;
+    return Lyrics::parse(&text);
+}
+
+fn from_tags(filename: &str) -> Option<Lyrics> {
+    let file = Probe::open(filename).ok()?.read().ok()?;
+    return file
+        .tags()
+        .iter()
+        .filter_map(|tag| tag.get_string(&ItemKey::Lyrics))
+        .find_map(Lyrics::parse);
+}
+
+fn from_provider(provider: &str, meta: &TrackMeta) -> Result<Option<Lyrics>> {
+    let (Some(artist), Some(title)) = (&meta.artist, &meta.title) else {
+        return Ok(None);
+    };
+    let mut params = vec![
+        ("artist_name", artist.clone()),
+        ("track_name", title.clone()),
+        ("duration", meta.duration.as_secs().to_string()),
+    ];
+    if let Some(album) = &meta.album {
+        params.push(("album_name", album.clone()));
+    }
+    let url = format!("{}{LRCLIB_GET_ENDPOINT}", provider.trim_end_matches('/'));
+    let url = Url::parse_with_params(&url, &params).context("cannot build lyrics URL")?;
+    let agent = http::new_agent();
+    let json = match http::call(&agent.get(url.as_str())) {
+        Ok(response) => response
+            .into_string()
+            .context("cannot read lyrics response")?,
+        Err(ureq::Error::Status(404, _)) => return Ok(None),
+        Err(e) => return Err(e).context("lyrics request failed"),
+    };
+    let response: ProviderResponse =
+        serde_json::from_str(&json).context("cannot parse lyrics response")?;
+    return Ok(response
+        .synced_lyrics
+        .as_deref()
+        .and_then(Lyrics::parse)
+        .or_else(|| response.plain_lyrics.as_deref().and_then(Lyrics::parse)));
+}
+
+// The sidecar .lrc file is preferred, then the tags, then the online provider.
+// The tracks inside CUE sheets only use the provider,
+// since the file and its tags belong to the whole album.
+pub fn load(track: &Track, meta: &TrackMeta, provider: Option<&str>) -> Result<Option<Lyrics>> {
+    if track.index.is_none() {
+        if let Some(lyrics) = from_sidecar(&track.filename).or_else(|| from_tags(&track.filename)) {
+            return Ok(Some(lyrics));
+        }
+    }
+    return match provider {
+        Some(provider) => from_provider(provider, meta),
+        None => Ok(None),
+    };
+}
+
+// prints the lyrics of the current track in the stored playlist
+pub fn cli_print() -> Result<()> {
+    let tracks = playlist_man::load_playlist().context("cannot load the playlist")?;
+    let Some(track) = AppState::load_or_default()
+        .playlist_index
+        .and_then(|index| tracks.get(index))
+    else {
+        bail!("no current track");
+    };
+    let meta = stream_man::read_track_meta(track, &mut CueFactory::new()).unwrap_or_default();
+    let provider = Config::load_or_default().lyrics_provider;
+    let Some(lyrics) = load(track, &meta, provider.as_deref())? else {
+        bail!("no lyrics found for {}", track.filename);
+    };
+    println!("{}", lyrics.text());
+    return Ok(());
+}
+
+enum NotifierCmd {
+    Track(Box<(Track, TrackMeta)>),
+    Position(Option<Duration>),
+}
+
+// Shows the lines of the synced lyrics as notifications while the track plays.
+// The lyrics are loaded in the background, since they may come from the network.
+pub struct LyricsNotifier {
+    tx: Sender<NotifierCmd>,
+}
+
+struct NotifierState {
+    lyrics: Option<Lyrics>,
+    // the playback position at the given moment, None when not playing
+    anchor: Option<(Instant, Duration)>,
+}
+
+impl NotifierState {
+    fn position(&self) -> Option<Duration> {
+        return self
+            .anchor
+            .map(|(instant, position)| position + instant.elapsed());
+    }
+
+    fn time_to_next_line(&self) -> Option<Duration> {
+        let position = self.position()?;
+        let line = self.lyrics.as_ref()?.next_line(position)?;
+        return line.time.map(|time| time.saturating_sub(position));
+    }
+
+    fn show_current_line(&self, popup: &Popup) {
+        let (Some(lyrics), Some(position)) = (&self.lyrics, self.position()) else {
+            return;
+        };
+        if let Some(line) = lyrics.line_at(position) {
+            if !line.text.is_empty() {
+                popup.show(&line.text);
+            }
+        }
+    }
+}
+
+fn process_notifier_cmds(rx: &Receiver<NotifierCmd>, popup: &Popup, provider: Option<&str>) {
+    let mut state = NotifierState {
+        lyrics: None,
+        anchor: None,
+    };
+    loop {
+        let cmd = match state.time_to_next_line() {
+            Some(timeout) => rx.recv_timeout(timeout),
+            None => rx.recv().map_err(RecvTimeoutError::from),
+        };
+        match cmd {
+            Ok(NotifierCmd::Track(track)) => {
+                let (track, meta) = *track;
+                state.lyrics = load(&track, &meta, provider)
+                    .context("cannot load lyrics")
+                    .to_option()
+                    .flatten()
+                    .filter(Lyrics::is_synced);
+                if state.lyrics.is_none() {
+                    debugln_with_date(format!("no synced lyrics: {}", track.filename));
+                }
+            }
+            Ok(NotifierCmd::Position(position)) => {
+                state.anchor = position.map(|position| (Instant::now(), position));
+            }
+            Err(RecvTimeoutError::Timeout) => state.show_current_line(popup),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+impl LyricsNotifier {
+    pub fn new(popup: Popup, provider: Option<String>) -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread_util::thread("lyrics", move || {
+            process_notifier_cmds(&rx, &popup, provider.as_deref());
+        });
+        return Self { tx };
+    }
+
+    pub fn set_track(&self, track: &Track, meta: &TrackMeta) {
+        self.tx
+            .send(NotifierCmd::Track(Box::new((track.clone(), meta.clone()))))
+            .ok();
+    }
+
+    // None when paused or stopped
+    pub fn set_position(&self, position: Option<Duration>) {
+        self.tx.send(NotifierCmd::Position(position)).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn times(lyrics: &Lyrics) -> Vec<Option<Duration>> {
+        return lyrics.lines.iter().map(|line| line.time).collect();
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse_time("00:12.34"), Some(Duration::from_millis(12340)));
+        assert_eq!(parse_time("01:02"), Some(Duration::from_secs(62)));
+        assert_eq!(
+            parse_time("90:00.5"),
+            Some(Duration::from_millis(5_400_500))
+        );
+        assert_eq!(parse_time("ar:Artist"), None);
+        assert_eq!(parse_time("00:-1"), None);
+        assert_eq!(parse_time("12.34"), None);
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(parse_offset("500"), Some(500));
+        assert_eq!(parse_offset(" +250 "), Some(250));
+        assert_eq!(parse_offset("-100"), Some(-100));
+        assert_eq!(parse_offset("abc"), None);
+
+        let time = Duration::from_secs(10);
+        assert_eq!(apply_offset(time, 500), Duration::from_millis(9500));
+        assert_eq!(apply_offset(time, -500), Duration::from_millis(10500));
+        assert_eq!(apply_offset(time, 20_000), Duration::ZERO);
+        assert_eq!(apply_offset(time, 0), time);
+    }
+
+    #[test]
+    fn synced() {
+        let lyrics = Lyrics::parse(
+            "[ar:Artist]\n\
+             [ti:Title]\n\
+             [00:05.00]Second\n\
+             [00:01.00][00:10.00]Chorus\n\
+             Not synced\n\
+             [00:20.00]\n",
+        )
+        .unwrap();
+        assert!(lyrics.is_synced());
+        assert_eq!(lyrics.text(), "Chorus\nSecond\nChorus");
+        assert_eq!(
+            times(&lyrics),
+            [
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(5)),
+                Some(Duration::from_secs(10))
+            ]
+        );
+    }
+
+    #[test]
+    fn synced_with_offset() {
+        let lyrics = Lyrics::parse("[offset:+1500]\n[00:01.00]One\n[00:03.00]Two\n").unwrap();
+        assert_eq!(
+            times(&lyrics),
+            [Some(Duration::ZERO), Some(Duration::from_millis(1500))]
+        );
+
+        let lyrics = Lyrics::parse("[offset:-500]\n[00:01.00]One\n").unwrap();
+        assert_eq!(times(&lyrics), [Some(Duration::from_millis(1500))]);
+    }
+
+    #[test]
+    fn plain() {
+        let lyrics = Lyrics::parse("  First line\n\nSecond line\n\n").unwrap();
+        assert!(!lyrics.is_synced());
+        assert_eq!(lyrics.text(), "First line\n\nSecond line");
+        assert!(Lyrics::parse("").is_none());
+        assert!(Lyrics::parse("\n[ar:Artist]\n").is_none());
+    }
+
+    #[test]
+    fn lines_by_position() {
+        let lyrics = Lyrics::parse("[00:01.00]One\n[00:03.00]Two\n").unwrap();
+        assert!(lyrics.line_at(Duration::ZERO).is_none());
+        let line = lyrics.line_at(Duration::from_secs(2)).unwrap();
+        assert_eq!(line.text, "One");
+        let line = lyrics.next_line(Duration::from_secs(2)).unwrap();
+        assert_eq!(line.text, "Two");
+        assert!(lyrics.next_line(Duration::from_secs(3)).is_none());
+    }
+}
@@ -24,29 +24,73 @@
 
 mod app;
 mod app_state;
+mod audiobook;
+mod auto_dj;
+mod bad_files;
+mod bookmarks;
+mod chapters;
 mod cli;
+mod config;
+mod cover_art;
+mod crash;
 mod cue;
+mod decode_ahead;
 mod decoder;
+mod dir_groups;
+mod dir_memory;
+mod discord;
+mod dsp;
 mod entry;
 mod err_util;
+mod error_popup;
+#[cfg(feature = "gme")]
+mod gme_stream;
+mod headphones;
+mod history;
 mod hotkeys;
+mod http;
 mod lastfm;
 mod listenbrainz;
+mod logger;
+mod loudness;
+mod lyrics;
 mod media_controls;
+mod meta_cache;
+mod navigation;
+mod other_players;
+mod path_filter;
 mod player;
 mod playlist_man;
 mod popup;
 mod project_file;
 mod project_info;
-mod quit_signal;
+mod quiet;
+mod rating;
+mod readahead;
+mod report;
+mod sample_buf;
+mod scrobble_filter;
+mod scrobble_retry;
+mod scrobbler;
+mod secret_store;
+mod share;
 mod show_file;
+mod signals;
+mod silence;
 mod singleton;
+mod smart_playlist;
+mod startup_report;
 mod stream_base;
 mod stream_man;
+mod subsonic;
 mod symphonia_stream;
 mod sys_vol;
+mod systemd;
+mod tag_edit;
 mod thread_util;
+mod timeout_file;
 mod tray_icon;
+mod zip_archive;
 
 fn main() -> anyhow::Result<()> {
     return entry::main();
@@ -1,10 +1,11 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::time::Duration;
+use std::{path::Path, time::Duration};
 
 use anyhow::{Context, Result};
 use souvlaki::{MediaControlEvent, MediaMetadata, MediaPlayback, MediaPosition, PlatformConfig};
+use url::Url;
 
 use crate::{err_util::IgnoreErr, player::PlaybackState, project_info, stream_base::TrackMeta};
 
@@ -13,15 +14,17 @@ pub struct MediaControls {
 }
 
 impl MediaControls {
-    pub fn new_if_available() -> Option<Self> {
+    pub fn new() -> Result<Self> {
+        let display_name = project_info::instance_title();
+        let dbus_name = project_info::instance_name();
         return souvlaki::MediaControls::new(PlatformConfig {
-            display_name: project_info::title(),
-            dbus_name: project_info::name(),
+            display_name: &display_name,
+            dbus_name: &dbus_name,
             hwnd: None,
         })
         .to_anyhow()
         .context("cannot create media controls")
-        .map_to_option(|controls| Some(Self { controls }));
+        .map(|controls| Self { controls });
     }
 
     pub fn attach<F>(&mut self, event_handler: F) -> Result<()>
@@ -60,10 +63,11 @@ impl MediaControls {
         return Ok(());
     }
 
-    pub fn set_metadata(&mut self, track_meta: &TrackMeta) -> Result<()> {
+    pub fn set_metadata(&mut self, track_meta: &TrackMeta, cover: Option<&Path>) -> Result<()> {
         let title = track_meta.title.as_deref();
         let artist = track_meta.artist.as_deref();
         let album = track_meta.album.as_deref();
+        let cover_url = cover.and_then(|cover| Url::from_file_path(cover).ok());
 
         self.controls
             .set_metadata(MediaMetadata {
@@ -71,7 +75,7 @@ impl MediaControls {
                 artist,
                 album,
                 duration: Some(track_meta.duration),
-                ..Default::default()
+                cover_url: cover_url.as_ref().map(Url::as_str),
             })
             .context("cannot set metadata")?;
         return Ok(());
@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cue::CueFactory,
+    err_util::IgnoreErr,
+    project_file::{Migration, ProjectFileJson},
+    stream_base::{Track, TrackMeta},
+    stream_man, thread_util, zip_archive,
+};
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    mtime: SystemTime,
+    meta: TrackMeta,
+}
+
+type Entries = HashMap<String, Entry>;
+
+// Remembers the tags of the tracks, so they can be shown without opening the files.
+// An entry is used only while the modification time of its file stays the same.
+#[derive(Clone)]
+pub struct MetaCache {
+    entries: Arc<Mutex<Entries>>,
+    tx: Sender<Vec<Track>>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    // 0: the cache from before the versioning has the same entries
+    Ok,
+];
+
+fn file() -> ProjectFileJson {
+    return ProjectFileJson::for_cache("meta_cache.json", "metadata cache")
+        .with_migrations(MIGRATIONS);
+}
+
+fn track_key(track: &Track) -> String {
+    if let Some(index) = track.index {
+        return format!("{}#{index}", track.filename);
+    }
+    return track.filename.to_string();
+}
+
+fn track_from_key(key: &str) -> Track {
+    if let Some((filename, index)) = key.rsplit_once('#') {
+        if let Ok(index) = index.parse() {
+            return Track {
+                filename: filename.into(),
+                index: Some(index),
+            };
+        }
+    }
+    return Track {
+        filename: key.into(),
+        index: None,
+    };
+}
+
+fn file_mtime(filename: &str) -> Option<SystemTime> {
+    // the tracks inside an archive change along with it
+    let filename = zip_archive::file_path(filename);
+    return fs::metadata(filename).and_then(|meta| meta.modified()).ok();
+}
+
+fn lookup(entries: &Entries, track: &Track, mtime: SystemTime) -> Option<TrackMeta> {
+    return entries
+        .get(&track_key(track))
+        .filter(|entry| entry.mtime == mtime)
+        .map(|entry| entry.meta.clone());
+}
+
+fn loader_thread(entries: &Mutex<Entries>, rx: &Receiver<Vec<Track>>) {
+    let mut cue_factory = CueFactory::new();
+    while let Ok(tracks) = rx.recv() {
+        let mut is_changed = false;
+        for track in tracks {
+            let Some(mtime) = file_mtime(&track.filename) else {
+                continue;
+            };
+            if lookup(&entries.lock().unwrap(), &track, mtime).is_some() {
+                continue;
+            }
+            // the lock is not held while the file is read
+            if let Some(meta) = stream_man::read_track_meta(&track, &mut cue_factory) {
+                entries
+                    .lock()
+                    .unwrap()
+                    .insert(track_key(&track), Entry { mtime, meta });
+                is_changed = true;
+            }
+        }
+        if is_changed {
+            file().save(&*entries.lock().unwrap()).ignore_err();
+        }
+    }
+}
+
+impl MetaCache {
+    pub fn new() -> Self {
+        let file = file();
+        let entries = if file.exists() {
+            file.load().ok_or_default()
+        } else {
+            HashMap::new()
+        };
+        let entries = Arc::new(Mutex::new(entries));
+        let (tx, rx) = channel();
+        let thread_entries = Arc::clone(&entries);
+        thread_util::thread("metadata cache", move || {
+            loader_thread(&thread_entries, &rx);
+        });
+        return Self { entries, tx };
+    }
+
+    pub fn get(&self, track: &Track) -> Option<TrackMeta> {
+        let mtime = file_mtime(&track.filename)?;
+        return lookup(&self.entries.lock().unwrap(), track, mtime);
+    }
+
+    // all the known tracks that still exist and match the filter
+    pub fn find<F>(&self, f: F) -> Vec<(Track, TrackMeta)>
+    where
+        F: Fn(&TrackMeta) -> bool,
+    {
+        let found: Vec<(Track, TrackMeta)> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| f(&entry.meta))
+            .map(|(key, entry)| (track_from_key(key), entry.meta.clone()))
+            .collect();
+        return found
+            .into_iter()
+            .filter(|(track, _)| file_mtime(&track.filename).is_some())
+            .collect();
+    }
+
+    // the tags of these tracks will be read in the background
+    pub fn prefetch(&self, tracks: Vec<Track>) {
+        if !tracks.is_empty() {
+            self.tx.send(tracks).ignore_err();
+        }
+    }
+}
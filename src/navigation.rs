@@ -0,0 +1,408 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use anyhow::{bail, Result};
+
+use crate::dir_groups::DirGroups;
+
+#[derive(Clone, Copy)]
+pub enum MoveTo {
+    Next,
+    Prev,
+    NextDir,
+    PrevDir,
+    RandomDir,
+}
+
+// Yields the playlist indices to try one by one
+// until a playable track is found or there's nothing left to try.
+pub struct Navigator {
+    step: MoveTo,
+    wrap: bool,
+    start_index: usize,
+    cur_index: usize,
+    files_left: usize,
+    index_after_dir_skip: Option<usize>,
+}
+
+impl Navigator {
+    pub fn new(step: MoveTo, start_index: usize, wrap: bool, dir_groups: &DirGroups) -> Self {
+        return Self {
+            step,
+            wrap,
+            start_index,
+            cur_index: start_index,
+            files_left: dir_groups.len(),
+            index_after_dir_skip: None,
+        };
+    }
+
+    fn fetch_next(&self, cur_index: usize, len: usize) -> Option<usize> {
+        if cur_index + 1 < len {
+            return Some(cur_index + 1);
+        }
+        if self.wrap {
+            return Some(0);
+        }
+        return None;
+    }
+
+    fn fetch_prev(&self, cur_index: usize, len: usize) -> Result<usize> {
+        if cur_index > 0 {
+            return Ok(cur_index - 1);
+        }
+        if self.wrap {
+            return Ok(len - 1);
+        }
+        bail!("playlist start reached");
+    }
+
+    // the first index of the directory that precedes the given index
+    fn fetch_first_in_dir(&self, cur_index: usize, dir_groups: &DirGroups) -> Result<usize> {
+        let index = self.fetch_prev(cur_index, dir_groups.len())?;
+        let group_start = dir_groups.group_start(index);
+        // do not go past the track where the navigation has started
+        if (group_start..=index).contains(&self.start_index) {
+            return Ok(self.start_index);
+        }
+        return Ok(group_start);
+    }
+
+    fn next_dir_index(&mut self, dir_groups: &DirGroups) -> Option<usize> {
+        let len = dir_groups.len();
+        if self.index_after_dir_skip.is_some() {
+            // the first track of the next directory is not playable
+            return self.fetch_next(self.cur_index, len);
+        }
+        let index = dir_groups
+            .next_group_start(self.cur_index)
+            .or_else(|| self.fetch_next(len - 1, len));
+        self.index_after_dir_skip = index;
+        return index;
+    }
+
+    fn random_dir_index(&mut self, dir_groups: &DirGroups) -> Option<usize> {
+        if self.index_after_dir_skip.is_some() {
+            // the first track of the chosen directory is not playable
+            return self.fetch_next(self.cur_index, dir_groups.len());
+        }
+        let index = dir_groups.random_group_start(self.cur_index);
+        self.index_after_dir_skip = Some(index);
+        return Some(index);
+    }
+
+    fn prev_dir_index(&mut self, dir_groups: &DirGroups) -> Result<usize> {
+        // if the first track of the directory is not playable then try the next ones
+        let next_index_in_dir = self
+            .index_after_dir_skip
+            .and_then(|_| self.fetch_next(self.cur_index, dir_groups.len()))
+            .filter(|next_index| {
+                *next_index != self.start_index
+                    && dir_groups.is_same_group(*next_index, self.cur_index)
+            });
+        if let Some(next_index) = next_index_in_dir {
+            return Ok(next_index);
+        }
+
+        let index = self.fetch_first_in_dir(
+            self.index_after_dir_skip.unwrap_or(self.cur_index),
+            dir_groups,
+        )?;
+        self.index_after_dir_skip = Some(index);
+        return Ok(index);
+    }
+
+    // returns None if the end of the playlist is reached
+    pub fn next_index(&mut self, dir_groups: &DirGroups) -> Result<Option<usize>> {
+        let len = dir_groups.len();
+        if len == 0 {
+            bail!("no files in the playlist");
+        }
+        if self.files_left == 0 {
+            bail!("no valid files in the playlist");
+        }
+        self.files_left -= 1;
+
+        let index = match self.step {
+            MoveTo::Next => self.fetch_next(self.cur_index, len),
+            MoveTo::Prev => Some(self.fetch_prev(self.cur_index, len)?),
+            MoveTo::NextDir => self.next_dir_index(dir_groups),
+            MoveTo::PrevDir => Some(self.prev_dir_index(dir_groups)?),
+            MoveTo::RandomDir => self.random_dir_index(dir_groups),
+        };
+        if let Some(index) = index {
+            self.cur_index = index;
+        }
+        return Ok(index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream_base::Track;
+
+    fn groups(paths: &[&str]) -> DirGroups {
+        let tracks: Vec<Track> = paths
+            .iter()
+            .map(|path| Track {
+                filename: (*path).into(),
+                index: None,
+            })
+            .collect();
+        return DirGroups::new(&tracks);
+    }
+
+    // indices 0-2 are in "a", 3-4 are in "b", 5-7 are in "c"
+    fn three_dirs() -> DirGroups {
+        return groups(&[
+            "/a/1", "/a/2", "/a/3", "/b/1", "/b/2", "/c/1", "/c/2", "/c/3",
+        ]);
+    }
+
+    // emulates the player: tries the indices until a playable one is found
+    fn navigate(
+        dir_groups: &DirGroups,
+        step: MoveTo,
+        start_index: usize,
+        wrap: bool,
+        unplayable: &[usize],
+    ) -> Result<Option<usize>> {
+        let mut navigator = Navigator::new(step, start_index, wrap, dir_groups);
+        loop {
+            match navigator.next_index(dir_groups)? {
+                Some(index) if unplayable.contains(&index) => {}
+                result => return Ok(result),
+            }
+        }
+    }
+
+    fn nav(step: MoveTo, start_index: usize, unplayable: &[usize]) -> Option<usize> {
+        return navigate(&three_dirs(), step, start_index, true, unplayable).unwrap();
+    }
+
+    #[test]
+    fn dir_groups() {
+        let dir_groups = three_dirs();
+        assert_eq!(dir_groups.len(), 8);
+        let starts: Vec<usize> = (0..8).map(|i| dir_groups.group_start(i)).collect();
+        assert_eq!(starts, [0, 0, 0, 3, 3, 5, 5, 5]);
+        let next_starts: Vec<Option<usize>> =
+            (0..8).map(|i| dir_groups.next_group_start(i)).collect();
+        assert_eq!(
+            next_starts,
+            [
+                Some(3),
+                Some(3),
+                Some(3),
+                Some(5),
+                Some(5),
+                None,
+                None,
+                None
+            ]
+        );
+        assert!(dir_groups.is_same_group(3, 4));
+        assert!(!dir_groups.is_same_group(2, 3));
+    }
+
+    #[test]
+    fn dir_groups_split_by_other_dir() {
+        let dir_groups = groups(&["/a/1", "/b/1", "/a/2"]);
+        assert!(!dir_groups.is_same_group(0, 2));
+        assert_eq!(dir_groups.group_start(2), 2);
+    }
+
+    #[test]
+    fn dir_groups_extend() {
+        let mut tracks: Vec<Track> = ["/a/1", "/a/2"]
+            .iter()
+            .map(|path| Track {
+                filename: (*path).into(),
+                index: None,
+            })
+            .collect();
+        let mut dir_groups = DirGroups::new(&tracks);
+        tracks.extend(["/a/3", "/b/1"].iter().map(|path| Track {
+            filename: (*path).into(),
+            index: None,
+        }));
+        dir_groups.extend(&tracks);
+        assert_eq!(dir_groups.len(), 4);
+        assert_eq!(dir_groups.group_start(2), 0);
+        assert_eq!(dir_groups.group_start(3), 3);
+    }
+
+    #[test]
+    fn next() {
+        assert_eq!(nav(MoveTo::Next, 0, &[]), Some(1));
+        assert_eq!(nav(MoveTo::Next, 2, &[]), Some(3));
+        assert_eq!(nav(MoveTo::Next, 7, &[]), Some(0));
+    }
+
+    #[test]
+    fn next_without_wrap() {
+        let dir_groups = three_dirs();
+        assert_eq!(
+            navigate(&dir_groups, MoveTo::Next, 6, false, &[]).unwrap(),
+            Some(7)
+        );
+        assert_eq!(
+            navigate(&dir_groups, MoveTo::Next, 7, false, &[]).unwrap(),
+            None
+        );
+        assert_eq!(
+            navigate(&dir_groups, MoveTo::Next, 5, false, &[6, 7]).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn next_skips_unplayable() {
+        assert_eq!(nav(MoveTo::Next, 0, &[1, 2]), Some(3));
+        assert_eq!(nav(MoveTo::Next, 6, &[7]), Some(0));
+    }
+
+    #[test]
+    fn prev() {
+        assert_eq!(nav(MoveTo::Prev, 1, &[]), Some(0));
+        assert_eq!(nav(MoveTo::Prev, 3, &[]), Some(2));
+        assert_eq!(nav(MoveTo::Prev, 0, &[]), Some(7));
+        assert_eq!(nav(MoveTo::Prev, 3, &[2, 1]), Some(0));
+    }
+
+    #[test]
+    fn prev_without_wrap() {
+        assert!(navigate(&three_dirs(), MoveTo::Prev, 0, false, &[]).is_err());
+    }
+
+    #[test]
+    fn next_dir() {
+        assert_eq!(nav(MoveTo::NextDir, 0, &[]), Some(3));
+        assert_eq!(nav(MoveTo::NextDir, 2, &[]), Some(3));
+        assert_eq!(nav(MoveTo::NextDir, 3, &[]), Some(5));
+        assert_eq!(nav(MoveTo::NextDir, 6, &[]), Some(0));
+    }
+
+    #[test]
+    fn next_dir_skips_unplayable() {
+        // the rest of the next directory is tried first
+        assert_eq!(nav(MoveTo::NextDir, 0, &[3]), Some(4));
+        assert_eq!(nav(MoveTo::NextDir, 0, &[3, 4]), Some(5));
+        assert_eq!(nav(MoveTo::NextDir, 5, &[0]), Some(1));
+    }
+
+    #[test]
+    fn next_dir_without_wrap() {
+        assert_eq!(
+            navigate(&three_dirs(), MoveTo::NextDir, 5, false, &[]).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn prev_dir() {
+        // inside a directory: go to its start
+        assert_eq!(nav(MoveTo::PrevDir, 1, &[]), Some(0));
+        assert_eq!(nav(MoveTo::PrevDir, 4, &[]), Some(3));
+        assert_eq!(nav(MoveTo::PrevDir, 7, &[]), Some(5));
+        // at the start of a directory: go to the start of the previous one
+        assert_eq!(nav(MoveTo::PrevDir, 3, &[]), Some(0));
+        assert_eq!(nav(MoveTo::PrevDir, 5, &[]), Some(3));
+        // at the start of the playlist: go to the start of the last directory
+        assert_eq!(nav(MoveTo::PrevDir, 0, &[]), Some(5));
+    }
+
+    #[test]
+    fn prev_dir_skips_unplayable() {
+        // the next tracks of the same directory are tried
+        assert_eq!(nav(MoveTo::PrevDir, 5, &[3]), Some(4));
+        assert_eq!(nav(MoveTo::PrevDir, 2, &[0]), Some(1));
+        // the whole directory is unplayable: go to the previous one
+        assert_eq!(nav(MoveTo::PrevDir, 5, &[3, 4]), Some(0));
+        // the starting track is not retried
+        assert_eq!(nav(MoveTo::PrevDir, 4, &[3]), Some(0));
+    }
+
+    #[test]
+    fn prev_dir_without_wrap() {
+        assert!(navigate(&three_dirs(), MoveTo::PrevDir, 0, false, &[]).is_err());
+    }
+
+    #[test]
+    fn single_dir() {
+        let dir_groups = groups(&["/a/1", "/a/2", "/a/3"]);
+        assert_eq!(
+            navigate(&dir_groups, MoveTo::NextDir, 1, true, &[]).unwrap(),
+            Some(0)
+        );
+        assert_eq!(
+            navigate(&dir_groups, MoveTo::PrevDir, 2, true, &[]).unwrap(),
+            Some(0)
+        );
+        assert_eq!(
+            navigate(&dir_groups, MoveTo::PrevDir, 0, true, &[]).unwrap(),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn all_unplayable() {
+        let dir_groups = three_dirs();
+        let all: Vec<usize> = (0..8).collect();
+        for step in [MoveTo::Next, MoveTo::Prev, MoveTo::NextDir, MoveTo::PrevDir] {
+            for start_index in 0..8 {
+                assert!(navigate(&dir_groups, step, start_index, true, &all).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn empty_playlist() {
+        let dir_groups = groups(&[]);
+        for step in [MoveTo::Next, MoveTo::Prev, MoveTo::NextDir, MoveTo::PrevDir] {
+            assert!(navigate(&dir_groups, step, 0, true, &[]).is_err());
+        }
+    }
+
+    #[test]
+    fn single_playable_is_always_found() {
+        let dir_groups = three_dirs();
+        for playable in 0..8 {
+            let unplayable: Vec<usize> = (0..8).filter(|i| *i != playable).collect();
+            for step in [MoveTo::Next, MoveTo::Prev, MoveTo::NextDir] {
+                for start_index in 0..8 {
+                    assert_eq!(
+                        navigate(&dir_groups, step, start_index, true, &unplayable).unwrap(),
+                        Some(playable)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn random_dir() {
+        for _ in 0..20 {
+            let index = nav(MoveTo::RandomDir, 0, &[]);
+            assert!(matches!(index, Some(3 | 5)));
+        }
+    }
+
+    #[test]
+    fn random_dir_unplayable() {
+        for _ in 0..20 {
+            let index = nav(MoveTo::RandomDir, 1, &[3, 5]);
+            assert!(matches!(index, Some(4 | 6)));
+        }
+    }
+
+    #[test]
+    fn random_dir_single_dir() {
+        let dir_groups = groups(&["/a/1", "/a/2"]);
+        assert_eq!(
+            navigate(&dir_groups, MoveTo::RandomDir, 1, true, &[]).unwrap(),
+            Some(0)
+        );
+    }
+}
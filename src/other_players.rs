@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use dbus::{
+    blocking::{stdintf::org_freedesktop_dbus::Properties, Connection},
+    message::MatchRule,
+};
+
+use crate::{
+    err_util::{IgnoreErr, LogErr},
+    project_info, thread_util,
+};
+
+// The other players (and the browsers, e.g. for the video calls) are found via MPRIS.
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const MPRIS_PLAYER: &str = "org.mpris.MediaPlayer2.Player";
+const DBUS_TIMEOUT: Duration = Duration::from_secs(1);
+// libdbus takes the timeout in milliseconds as i32, so it cannot wait forever
+const IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn is_other_playing(conn: &Connection) -> Result<bool> {
+    let proxy = conn.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        DBUS_TIMEOUT,
+    );
+    let (names,): (Vec<String>,) = proxy
+        .method_call("org.freedesktop.DBus", "ListNames", ())
+        .context("cannot list D-Bus names")?;
+    let own_name = format!("{MPRIS_PREFIX}{}", project_info::instance_name());
+    for name in names
+        .iter()
+        .filter(|name| name.starts_with(MPRIS_PREFIX) && **name != own_name)
+    {
+        let player = conn.with_proxy(name, MPRIS_PATH, DBUS_TIMEOUT);
+        // the players that do not answer are not playing anything useful anyway
+        let status: Option<String> = player.get(MPRIS_PLAYER, "PlaybackStatus").ok();
+        if status.as_deref() == Some("Playing") {
+            return Ok(true);
+        }
+    }
+    return Ok(false);
+}
+
+// the players are checked again when some player changes its state, appears or quits
+fn subscribe(conn: &Connection, is_changed: &Arc<AtomicBool>) -> Result<()> {
+    let props_changed = is_changed.clone();
+    conn.add_match(
+        MatchRule::new_signal("org.freedesktop.DBus.Properties", "PropertiesChanged")
+            .with_path(MPRIS_PATH),
+        move |(): (), _, _| {
+            props_changed.store(true, Ordering::Relaxed);
+            return true;
+        },
+    )
+    .context("cannot subscribe to the MPRIS changes")?;
+    let owner_changed = is_changed.clone();
+    conn.add_match(
+        MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged"),
+        move |(name, _, _): (String, String, String), _, _| {
+            if name.starts_with(MPRIS_PREFIX) {
+                owner_changed.store(true, Ordering::Relaxed);
+            }
+            return true;
+        },
+    )
+    .context("cannot subscribe to the D-Bus name changes")?;
+    return Ok(());
+}
+
+// Calls back with true when some other player starts playing and with false when all of them stop.
+// The thread sleeps until D-Bus tells about a change.
+pub fn watch<F>(f: F) -> Result<()>
+where
+    F: Fn(bool) + Send + 'static,
+{
+    let conn = Connection::new_session().context("no D-Bus session")?;
+    let is_changed = Arc::new(AtomicBool::new(false));
+    subscribe(&conn, &is_changed)?;
+    let mut is_playing = is_other_playing(&conn)?;
+    thread_util::thread("other players watcher", move || loop {
+        if let Err(e) = conn.process(IDLE_TIMEOUT) {
+            e.log_context("cannot wait for the MPRIS changes");
+            return;
+        }
+        if !is_changed.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+        let Some(now_playing) = is_other_playing(&conn).to_option() else {
+            continue;
+        };
+        if now_playing != is_playing {
+            is_playing = now_playing;
+            f(is_playing);
+        }
+    });
+    return Ok(());
+}
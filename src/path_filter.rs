@@ -0,0 +1,137 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use crate::err_util::IgnoreErr;
+
+const REGEX_PREFIX: &str = "re:";
+
+// Excludes paths by glob patterns (e.g. "*/unsorted/*")
+// or by regular expressions (e.g. "re:/\.[^/]*$").
+// Both are matched against the whole absolute path.
+#[derive(Default, Clone)]
+pub struct PathFilter {
+    patterns: Vec<Regex>,
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            _ => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    return re;
+}
+
+pub fn pattern_to_regex(pattern: &str) -> Result<Regex> {
+    let re = pattern
+        .strip_prefix(REGEX_PREFIX)
+        .map_or_else(|| glob_to_regex(pattern), ToString::to_string);
+    return Regex::new(&re).with_context(|| format!("invalid pattern: {pattern}"));
+}
+
+impl PathFilter {
+    pub fn new(patterns: &[String]) -> Self {
+        let patterns = patterns
+            .iter()
+            .filter_map(|pattern| pattern_to_regex(pattern).to_option())
+            .collect();
+        return Self { patterns };
+    }
+
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        if self.patterns.is_empty() {
+            return false;
+        }
+        let mut path = path.to_string_lossy().to_string();
+        if is_dir {
+            // so "*/dir/*" excludes the folder itself and nothing inside it is scanned
+            path.push('/');
+        }
+        return self.patterns.iter().any(|re| re.is_match(&path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(patterns: &[&str]) -> PathFilter {
+        let patterns: Vec<String> = patterns.iter().map(ToString::to_string).collect();
+        return PathFilter::new(&patterns);
+    }
+
+    fn is_file_excluded(filter: &PathFilter, path: &str) -> bool {
+        return filter.is_excluded(Path::new(path), false);
+    }
+
+    fn is_dir_excluded(filter: &PathFilter, path: &str) -> bool {
+        return filter.is_excluded(Path::new(path), true);
+    }
+
+    #[test]
+    fn globs() {
+        assert_eq!(glob_to_regex("*.mp3"), r"^.*\.mp3$");
+        assert_eq!(glob_to_regex("/a/?/[b]"), r"^/a/./\[b\]$");
+
+        let f = filter(&["*.log", "/music/track?.mp3"]);
+        assert!(is_file_excluded(&f, "/music/a.log"));
+        assert!(!is_file_excluded(&f, "/music/a.log.mp3"));
+        assert!(is_file_excluded(&f, "/music/track1.mp3"));
+        assert!(!is_file_excluded(&f, "/music/track10.mp3"));
+        assert!(!is_file_excluded(&f, "/other/music/track1.mp3"));
+    }
+
+    #[test]
+    fn special_chars_are_literal_in_globs() {
+        let f = filter(&["*/(live) [2001]/*"]);
+        assert!(is_file_excluded(&f, "/music/(live) [2001]/a.mp3"));
+        assert!(!is_file_excluded(&f, "/music/live 2001/a.mp3"));
+    }
+
+    #[test]
+    fn excluded_dirs() {
+        let f = filter(&["*/unsorted/*"]);
+        assert!(is_dir_excluded(&f, "/music/unsorted"));
+        assert!(is_file_excluded(&f, "/music/unsorted/a.mp3"));
+        // a file with the same name as the folder
+        assert!(!is_file_excluded(&f, "/music/unsorted"));
+        assert!(!is_dir_excluded(&f, "/music/unsorted2"));
+    }
+
+    #[test]
+    fn regexes() {
+        let f = filter(&[r"re:/\.[^/]*/?$"]);
+        assert!(is_file_excluded(&f, "/music/.hidden.mp3"));
+        assert!(is_dir_excluded(&f, "/music/.git"));
+        assert!(!is_file_excluded(&f, "/music/a.mp3"));
+        assert!(!is_file_excluded(&f, "/music/.git/a.mp3"));
+
+        // not anchored unless asked to
+        let f = filter(&["re:demo"]);
+        assert!(is_file_excluded(&f, "/music/demos/a.mp3"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_skipped() {
+        assert!(pattern_to_regex("re:(").is_err());
+        let f = filter(&["re:(", "*.log"]);
+        assert!(is_file_excluded(&f, "/a.log"));
+        assert!(!is_file_excluded(&f, "/a.mp3"));
+    }
+
+    #[test]
+    fn no_patterns() {
+        let f = filter(&[]);
+        assert!(!is_file_excluded(&f, "/music/a.mp3"));
+        assert!(!is_dir_excluded(&f, "/"));
+    }
+}
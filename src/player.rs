@@ -1,41 +1,68 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
 use std::sync::{
     mpsc::{channel, Receiver, Sender},
     Arc, Mutex,
 };
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
 use cpal::traits::StreamTrait;
 
 use crate::{
+    audiobook::Audiobooks,
     cue::CueFactory,
-    decoder::{Decoder, DecoderReadResult},
+    decode_ahead::{self, DecodeWaker},
+    decoder::{Decoder, DecoderReadResult, DeviceLatency},
+    dir_groups::DirGroups,
+    dsp::DspSettings,
     err_util::{eprintln_with_date, IgnoreErr, LogErr},
-    stream_base::{Track, TrackMeta},
-    thread_util,
+    meta_cache::MetaCache,
+    navigation::{MoveTo, Navigator},
+    playlist_man, readahead, report,
+    sample_buf::BufferMarker,
+    stream_base::{self, Track, TrackMeta},
+    thread_util, timeout_file,
 };
 
-const DECODER_THREAD_SLEEP: Duration = Duration::from_millis(100);
-const READ_PACKETS_PER_CYCLE: u8 = 5;
+// how often the position callbacks and the buffer markers are checked during the playback
+const POSITION_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+pub const DEFAULT_RELEASE_AFTER_PAUSE: Duration = Duration::from_secs(10 * 60);
+// the position jumps that are larger than this are not counted as listened
+const MAX_LISTENED_STEP: Duration = Duration::from_secs(1);
+const MAX_CONSECUTIVE_FAILURES: usize = 5;
 
 pub enum PlayerCmd {
     SetPlaylist {
         tracks: Vec<Track>,
         cue_factory: Option<CueFactory>,
     },
+    UpdatePlaylist {
+        tracks: Vec<Track>,
+        cue_factory: CueFactory,
+    },
+    AppendTracks {
+        tracks: Vec<Track>,
+        cue_factory: CueFactory,
+    },
+    PrunePlaylist,
 
     LoadMeta {
         index: usize,
     },
+    ReloadMeta {
+        filename: String,
+    },
 
     Play {
         index: Option<usize>,
     },
+    JumpTo {
+        target: JumpTarget,
+    },
     Pause,
     UnPause,
     Stop,
@@ -45,6 +72,7 @@ pub enum PlayerCmd {
     Prev,
     NextDir,
     PrevDir,
+    RandomDir,
 
     SeekBy {
         forward: bool,
@@ -57,10 +85,21 @@ pub enum PlayerCmd {
     SetVolume {
         volume: f32,
     },
+    SetDsp {
+        settings: DspSettings,
+    },
 
     Exit,
 }
 
+enum PlayerEvent {
+    Cmd(PlayerCmd),
+    Decoded {
+        epoch: u64,
+        result: DecoderReadResult,
+    },
+}
+
 pub enum PlayerResponse {
     NewPlaylistIndex {
         playlist_index: usize,
@@ -68,7 +107,7 @@ pub enum PlayerResponse {
         user_navigation: bool,
     },
     NewMeta {
-        meta: TrackMeta,
+        meta: Box<TrackMeta>,
         user_navigation: bool,
     },
     PlaybackStateChanged {
@@ -82,23 +121,27 @@ pub enum PlayerResponse {
         callback: PositionCallback,
     },
     PlaylistEnded,
+    JumpTargetNotFound,
+    TrackFailed {
+        track: Track,
+        reason: String,
+    },
+    TooManyFailures {
+        count: usize,
+    },
+    PlaylistUpdated {
+        playlist_index: Option<usize>,
+    },
+    PlaylistPruned {
+        tracks: Vec<Track>,
+        removed: usize,
+    },
     Seeked {
         position: Duration,
     },
-    VolumeSet {
-        volume: f32,
-    },
     Exited,
 }
 
-#[derive(Clone, Copy)]
-enum MoveTo {
-    Next,
-    Prev,
-    NextDir,
-    PrevDir,
-}
-
 #[derive(Debug, Default, Clone)]
 pub enum PlaybackState {
     #[default]
@@ -107,12 +150,38 @@ pub enum PlaybackState {
     Paused,
 }
 
+pub enum JumpTarget {
+    Index(usize),
+    File(String),
+}
+
 pub type PositionCallbackId = u32;
 
 #[derive(Clone)]
 pub enum PositionCallbackMarker {
     SecsFromStart(Duration),
     SecsFromEnd(Duration),
+    // the part of the duration, from 0 to 1
+    Fraction(f64),
+    // triggers when any of the markers is reached
+    Earliest(Vec<PositionCallbackMarker>),
+    // the marker is checked against the time the track was actually listened,
+    // so seeking forward does not bring it closer
+    Listened(Box<PositionCallbackMarker>),
+}
+
+impl PositionCallbackMarker {
+    fn is_reached(&self, position: Duration, listened: Duration, duration: Duration) -> bool {
+        return match self {
+            Self::SecsFromStart(marker) => position >= *marker,
+            Self::SecsFromEnd(marker) => position >= duration.saturating_sub(*marker),
+            Self::Fraction(fraction) => position >= duration.mul_f64(fraction.clamp(0.0, 1.0)),
+            Self::Earliest(markers) => markers
+                .iter()
+                .any(|marker| marker.is_reached(position, listened, duration)),
+            Self::Listened(marker) => marker.is_reached(listened, listened, duration),
+        };
+    }
 }
 
 #[derive(Clone)]
@@ -123,19 +192,36 @@ pub struct PositionCallback {
 
 pub type PositionCallbacks = Vec<PositionCallback>;
 
+#[allow(clippy::struct_excessive_bools)]
 struct PlayerThread {
-    decoder: Decoder,
+    decoder: Arc<Mutex<Decoder>>,
+    waker: DecodeWaker,
     playlist: Vec<Track>,
+    dir_groups: DirGroups,
     playlist_index: usize,
     sent_playlist_index: Option<usize>,
-    rx: Receiver<PlayerCmd>,
+    rx: Receiver<PlayerEvent>,
+    pending_cmd: Option<PlayerCmd>,
     tx: Sender<PlayerResponse>,
     position_callbacks: Option<PositionCallbacks>,
     triggered_callbacks: Vec<PositionCallbackId>,
+    listened: Duration,
+    last_listened_position: Option<Duration>,
     user_navigation_for_next_meta: bool,
-    need_fast_read: bool,
     output: Option<cpal::Stream>,
     output_is_paused: bool,
+    paused_at: Option<Instant>,
+    release_after_pause: Option<Duration>,
+    // the output and the buffer are freed until unpaused
+    is_released: bool,
+    low_latency: bool,
+    readahead: bool,
+    audiobooks: Audiobooks,
+    failed_tracks: HashMap<Track, String>,
+    // skipped until they are played again on request
+    missing_tracks: HashSet<Track>,
+    consecutive_failures: usize,
+    meta_cache: MetaCache,
 }
 
 impl PositionCallback {
@@ -157,28 +243,76 @@ impl PositionCallback {
 impl PlayerThread {
     fn new(
         tx: Sender<PlayerResponse>,
-        rx: Receiver<PlayerCmd>,
+        rx: Receiver<PlayerEvent>,
         position_callbacks: Option<PositionCallbacks>,
+        low_latency: bool,
+        readahead: bool,
+        audiobooks: Audiobooks,
+        meta_cache: MetaCache,
     ) -> Self {
+        let decoder = Decoder::new(low_latency);
+        let waker = DecodeWaker::default();
+        decoder.set_waker(waker.clone());
         return Self {
-            decoder: Decoder::new(),
+            decoder: Arc::new(Mutex::new(decoder)),
+            waker,
             playlist: Vec::new(),
+            dir_groups: DirGroups::default(),
             playlist_index: 0,
             sent_playlist_index: None,
             rx,
+            pending_cmd: None,
             tx,
             position_callbacks,
             triggered_callbacks: Vec::new(),
+            listened: Duration::ZERO,
+            last_listened_position: None,
             user_navigation_for_next_meta: false,
-            need_fast_read: true,
             output: None,
             output_is_paused: false,
+            paused_at: None,
+            release_after_pause: Some(DEFAULT_RELEASE_AFTER_PAUSE),
+            is_released: false,
+            low_latency,
+            readahead,
+            audiobooks,
+            failed_tracks: HashMap::new(),
+            missing_tracks: HashSet::new(),
+            consecutive_failures: 0,
+            meta_cache,
         };
     }
 
+    fn start_decode_ahead(&self, tx: Sender<PlayerEvent>) {
+        decode_ahead::start(
+            self.decoder.clone(),
+            self.waker.clone(),
+            move |epoch, result| {
+                return tx.send(PlayerEvent::Decoded { epoch, result }).is_ok();
+            },
+        );
+    }
+
+    fn remember_audiobook_position(&mut self) {
+        if self.output.is_none() {
+            return;
+        }
+        let decoder = self.decoder.lock().unwrap();
+        let position = decoder.playback_position();
+        let track = decoder.track().cloned();
+        drop(decoder);
+        if let Some(track) = track {
+            self.audiobooks.set_position(&track, position);
+        }
+    }
+
     fn stop(&mut self) {
-        self.decoder.stop();
+        self.remember_audiobook_position();
+        self.audiobooks.save();
+        self.decoder.lock().unwrap().stop();
         self.output = None;
+        self.paused_at = None;
+        self.is_released = false;
         self.sent_playlist_index = None;
         self.tx
             .send(PlayerResponse::PlaybackStateChanged {
@@ -191,17 +325,65 @@ impl PlayerThread {
     fn set_playlist(&mut self, files: Vec<Track>, cue_factory: Option<CueFactory>) {
         self.stop();
         if let Some(cue_factory) = cue_factory {
-            self.decoder.set_cue_factory(cue_factory);
+            self.decoder.lock().unwrap().set_cue_factory(cue_factory);
         } else {
-            self.decoder.clear_cue_factory();
+            self.decoder.lock().unwrap().clear_cue_factory();
         }
+        self.dir_groups = DirGroups::new(&files);
         self.playlist = files;
         self.playlist_index = 0;
     }
 
+    // replaces the playlist without interrupting the current track
+    fn update_playlist(&mut self, tracks: Vec<Track>, cue_factory: CueFactory) {
+        self.decoder.lock().unwrap().set_cue_factory(cue_factory);
+        self.replace_tracks(tracks);
+    }
+
+    fn replace_tracks(&mut self, tracks: Vec<Track>) {
+        let cur_track = self.playlist.get(self.playlist_index).cloned();
+        self.dir_groups = DirGroups::new(&tracks);
+        self.playlist = tracks;
+        let new_index = cur_track
+            .and_then(|cur_track| self.playlist.iter().position(|track| *track == cur_track));
+        if let Some(new_index) = new_index {
+            self.playlist_index = new_index;
+            if self.sent_playlist_index.is_some() {
+                self.sent_playlist_index = Some(new_index);
+            }
+        } else {
+            self.playlist_index = 0;
+        }
+        self.tx
+            .send(PlayerResponse::PlaylistUpdated {
+                playlist_index: new_index,
+            })
+            .unwrap();
+    }
+
+    fn prune_playlist(&mut self) {
+        let count = self.playlist.len();
+        let (tracks, _) = playlist_man::prune_tracks(self.playlist.clone(), None);
+        let removed = count - tracks.len();
+        if removed > 0 {
+            // the index will change, so it's remembered here
+            playlist_man::backup_playlist(Some(self.playlist_index)).ignore_err();
+            self.replace_tracks(tracks.clone());
+        }
+        self.tx
+            .send(PlayerResponse::PlaylistPruned { tracks, removed })
+            .unwrap();
+    }
+
+    fn append_tracks(&mut self, tracks: Vec<Track>, cue_factory: &CueFactory) {
+        self.decoder.lock().unwrap().merge_cue_factory(cue_factory);
+        self.playlist.extend(tracks);
+        self.dir_groups.extend(&self.playlist);
+    }
+
     fn load_meta(&mut self, index: usize) -> Result<()> {
         let track = &self.playlist[index];
-        self.decoder.load_meta(track).context("cannot load meta")?;
+        Decoder::load_meta(&self.decoder, track).context("cannot load meta")?;
         self.playlist_index = index;
 
         self.tx
@@ -212,10 +394,11 @@ impl PlayerThread {
             })
             .unwrap();
 
-        if let Some(meta) = self.decoder.track_meta.clone() {
+        let meta = self.decoder.lock().unwrap().track_meta.clone();
+        if let Some(meta) = meta {
             self.tx
                 .send(PlayerResponse::NewMeta {
-                    meta,
+                    meta: Box::new(meta),
                     user_navigation: false,
                 })
                 .unwrap();
@@ -231,10 +414,23 @@ impl PlayerThread {
         }
         let track = &self.playlist[index];
         self.playlist_index = index;
-        self.decoder.play(track).context("cannot play")?;
-        self.need_fast_read = true;
-        self.triggered_callbacks.clear();
-        self.send_playlist_index(user_navigation);
+        let filename = Decoder::source_filename(&self.decoder, track);
+        // checked without the decoder lock, since a stalled mount does not respond
+        let on_network =
+            filename.is_ok_and(|filename| return timeout_file::is_on_network_mount(&filename));
+        Decoder::play(&self.decoder, track, index, on_network).context("cannot play")?;
+        self.missing_tracks.remove(track);
+        self.paused_at = None;
+        self.is_released = false;
+        let resume_position = self.audiobooks.position(track);
+        if self.readahead {
+            self.hint_readahead(index);
+        }
+        self.prefetch_meta(index);
+        // otherwise it's sent once the previous track is heard till the end
+        if user_navigation {
+            self.send_playlist_index(index, user_navigation);
+        }
         self.user_navigation_for_next_meta = user_navigation;
         self.tx
             .send(PlayerResponse::PlaybackStateChanged {
@@ -242,154 +438,140 @@ impl PlayerThread {
                 position: Duration::ZERO,
             })
             .unwrap();
+        if let Some(position) = resume_position {
+            self.seek_to(position)
+                .context("cannot resume the audiobook")
+                .ignore_err();
+        }
         return Ok(());
     }
 
-    fn playlist_index_dir(&self, index: usize) -> PathBuf {
-        let track = &self.playlist[index];
-        let path = Path::new(&track.filename)
-            .parent()
-            .map(|p| p.to_path_buf())
-            .unwrap_or_default();
-        return path;
-    }
-
-    fn fetch_next_playlist_index(
-        &self,
-        cur_index: usize,
-        wrap: bool,
-        emit_ended: bool,
-    ) -> Result<usize> {
-        if cur_index < self.playlist.len() - 1 {
-            return Ok(cur_index + 1);
-        }
-        if wrap {
-            return Ok(0);
+    fn play_or_next(&mut self, index: Option<usize>) -> Result<()> {
+        self.stop();
+        if !self
+            .play(index, true)
+            .with_context(|| format!("cannot play track {index:?}"))
+            .to_bool()
+        {
+            self.next(false, true).context("cannot play next track")?;
         }
+        return Ok(());
+    }
 
-        if emit_ended {
-            self.tx.send(PlayerResponse::PlaylistEnded).unwrap();
+    fn jump_to(&mut self, target: &JumpTarget) -> Result<()> {
+        let index = match target {
+            JumpTarget::Index(index) => Some(*index).filter(|index| *index < self.playlist.len()),
+            JumpTarget::File(filename) => self
+                .playlist
+                .iter()
+                .position(|track| *track.filename == **filename),
+        };
+        let Some(index) = index else {
+            self.tx.send(PlayerResponse::JumpTargetNotFound).unwrap();
+            bail!("the track is not in the playlist");
+        };
+        self.stop();
+        self.consecutive_failures = 0;
+        if let Err(e) = self.play(Some(index), true) {
+            self.track_failed(self.playlist[index].clone(), &e);
+            return Err(e);
         }
-        bail!("playlist end reached");
+        return Ok(());
     }
 
-    fn fetch_prev_playlist_index(&self, cur_index: usize, wrap: bool) -> Result<usize> {
-        if cur_index > 0 {
-            return Ok(cur_index - 1);
+    // returns false if there were too many failures in a row
+    fn track_failed(&mut self, track: Track, e: &anyhow::Error) -> bool {
+        self.consecutive_failures += 1;
+        if stream_base::is_file_missing(e) {
+            self.missing_tracks.insert(track.clone());
         }
-
-        if wrap {
-            return Ok(self.playlist.len() - 1);
+        let reason = format!("{e:#}");
+        report::append_decoder_error(&format!(
+            "[{}] {}: {reason}",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+            track.filename
+        ));
+        if !self.failed_tracks.contains_key(&track) {
+            self.tx
+                .send(PlayerResponse::TrackFailed {
+                    track: track.clone(),
+                    reason: reason.clone(),
+                })
+                .unwrap();
         }
+        self.failed_tracks.insert(track, reason);
+        return self.consecutive_failures < MAX_CONSECUTIVE_FAILURES;
+    }
 
-        bail!("playlist start reached");
+    fn track_finished(&mut self, track: &Track) {
+        self.consecutive_failures = 0;
+        self.failed_tracks.remove(track);
+        self.missing_tracks.remove(track);
     }
 
-    fn fetch_first_playlist_index_in_dir(
-        &self,
-        cur_index: usize,
-        stop_index: usize,
-        wrap: bool,
-        files_left: &mut usize,
-    ) -> Result<usize> {
-        let mut cur_dir = self.playlist_index_dir(cur_index);
-        let mut index = self.fetch_prev_playlist_index(cur_index, wrap)?;
-        if index != 0 && index != stop_index && self.playlist_index_dir(index) != cur_dir {
-            cur_dir = self.playlist_index_dir(index);
-        }
-        while index != 0 && index != stop_index && self.playlist_index_dir(index - 1) == *cur_dir {
-            Self::dec_valid_files(files_left).context("no valid left")?;
-            index = self
-                .fetch_prev_playlist_index(index, wrap)
-                .context("cannot fetch previous playlist index")?;
+    fn stop_after_failures(&mut self) {
+        self.stop();
+        self.tx
+            .send(PlayerResponse::TooManyFailures {
+                count: self.consecutive_failures,
+            })
+            .unwrap();
+        self.consecutive_failures = 0;
+    }
+
+    // the current track and the one that will most likely be played next
+    fn hint_readahead(&self, index: usize) {
+        let mut filenames = Vec::new();
+        for track in self.playlist.iter().skip(index).take(2) {
+            let filename = Decoder::source_filename(&self.decoder, track);
+            if let Some(filename) = filename.to_option() {
+                if !filenames.contains(&filename) {
+                    filenames.push(filename);
+                }
+            }
         }
-        return Ok(index);
+        readahead::hint(filenames);
     }
 
-    fn dec_valid_files(x: &mut usize) -> Result<()> {
-        if *x == 0 {
-            bail!("no valid files in the playlist");
+    // the tracks that can be reached with a single navigation step
+    fn prefetch_meta(&self, index: usize) {
+        let mut indices = vec![index.saturating_sub(1), index + 1];
+        if let Some(next_dir_index) = self.dir_groups.next_group_start(index) {
+            indices.push(next_dir_index);
         }
-        *x -= 1;
-        return Ok(());
+        let tracks = indices
+            .into_iter()
+            .filter(|i| *i != index)
+            .filter_map(|i| self.playlist.get(i).cloned())
+            .collect();
+        self.meta_cache.prefetch(tracks);
     }
 
     fn move_and_play(&mut self, step: MoveTo, wrap: bool, user_navigation: bool) -> Result<()> {
-        let mut files_left = self.playlist.len();
-        if files_left == 0 {
-            bail!("no files in the playlist");
+        if user_navigation {
+            self.consecutive_failures = 0;
         }
-        let start_index = self.playlist_index;
-        let mut cur_index = self.playlist_index;
-        let mut index_after_dir_skip: Option<usize> = None;
+        let mut navigator = Navigator::new(step, self.playlist_index, wrap, &self.dir_groups);
         loop {
-            Self::dec_valid_files(&mut files_left)?;
-
-            let new_playlist_index = match step {
-                MoveTo::Next => self.fetch_next_playlist_index(cur_index, wrap, true)?,
-                MoveTo::Prev => self.fetch_prev_playlist_index(cur_index, wrap)?,
-                MoveTo::NextDir => {
-                    let mut index = self.fetch_next_playlist_index(cur_index, wrap, true)?;
-                    if index_after_dir_skip.is_none() {
-                        let cur_dir = self.playlist_index_dir(cur_index);
-                        while index != 0 && self.playlist_index_dir(index) == cur_dir {
-                            Self::dec_valid_files(&mut files_left)?;
-                            index = self.fetch_next_playlist_index(index, wrap, true)?;
-                        }
-                        index_after_dir_skip = Some(index);
-                    }
-                    index
-                }
-                MoveTo::PrevDir => {
-                    if let Some(found_index) = index_after_dir_skip {
-                        if let Ok(next_index) =
-                            self.fetch_next_playlist_index(cur_index, wrap, false)
-                        {
-                            if start_index != next_index
-                                && self.playlist_index_dir(next_index)
-                                    == self.playlist_index_dir(cur_index)
-                            {
-                                next_index
-                            } else {
-                                let index = self.fetch_first_playlist_index_in_dir(
-                                    found_index,
-                                    start_index,
-                                    wrap,
-                                    &mut files_left,
-                                )?;
-                                index_after_dir_skip = Some(index);
-                                index
-                            }
-                        } else {
-                            let index = self.fetch_first_playlist_index_in_dir(
-                                found_index,
-                                start_index,
-                                wrap,
-                                &mut files_left,
-                            )?;
-                            index_after_dir_skip = Some(index);
-                            index
-                        }
-                    } else {
-                        let index = self.fetch_first_playlist_index_in_dir(
-                            cur_index,
-                            start_index,
-                            wrap,
-                            &mut files_left,
-                        )?;
-                        index_after_dir_skip = Some(index);
-                        index
+            let Some(index) = navigator.next_index(&self.dir_groups)? else {
+                self.tx.send(PlayerResponse::PlaylistEnded).unwrap();
+                bail!("playlist end reached");
+            };
+            if !user_navigation && self.missing_tracks.contains(&self.playlist[index]) {
+                continue;
+            }
+            match self.play(Some(index), user_navigation) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    let track = self.playlist[index].clone();
+                    let may_continue = self.track_failed(track, &e);
+                    e.log();
+                    if !may_continue && !user_navigation {
+                        self.stop_after_failures();
+                        bail!("too many tracks failed in a row");
                     }
                 }
-            };
-
-            if self
-                .play(Some(new_playlist_index), user_navigation)
-                .to_bool()
-            {
-                return Ok(());
             }
-            cur_index = self.playlist_index;
         }
     }
 
@@ -397,48 +579,69 @@ impl PlayerThread {
         return self.move_and_play(MoveTo::Next, wrap, user_navigation);
     }
 
-    fn prev(&mut self) -> Result<()> {
-        return self.move_and_play(MoveTo::Prev, true, true);
-    }
-
-    fn next_dir(&mut self) -> Result<()> {
-        return self.move_and_play(MoveTo::NextDir, true, true);
-    }
-
-    fn prev_dir(&mut self) -> Result<()> {
-        return self.move_and_play(MoveTo::PrevDir, true, true);
-    }
-
-    fn send_playlist_index(&mut self, user_navigation: bool) {
-        if let Some(index) = self.sent_playlist_index {
-            if index == self.playlist_index {
-                return;
+    // the navigation commands that were queued while a track was opening
+    // are applied at once, so only the final track is opened
+    fn navigate(&mut self, step: MoveTo) -> Result<()> {
+        self.stop();
+        let mut step = step;
+        loop {
+            let queued_step = match self.rx.try_recv() {
+                Ok(PlayerEvent::Cmd(PlayerCmd::Next)) => MoveTo::Next,
+                Ok(PlayerEvent::Cmd(PlayerCmd::Prev)) => MoveTo::Prev,
+                Ok(PlayerEvent::Cmd(PlayerCmd::NextDir)) => MoveTo::NextDir,
+                Ok(PlayerEvent::Cmd(PlayerCmd::PrevDir)) => MoveTo::PrevDir,
+                Ok(PlayerEvent::Cmd(PlayerCmd::RandomDir)) => MoveTo::RandomDir,
+                // decoded before the stop, so it's stale anyway
+                Ok(PlayerEvent::Decoded { .. }) => continue,
+                Ok(PlayerEvent::Cmd(cmd)) => {
+                    self.pending_cmd = Some(cmd);
+                    break;
+                }
+                Err(_) => break,
+            };
+            let mut navigator = Navigator::new(step, self.playlist_index, true, &self.dir_groups);
+            if let Some(index) = navigator.next_index(&self.dir_groups)? {
+                self.playlist_index = index;
             }
+            step = queued_step;
         }
+        return self.move_and_play(step, true, true);
+    }
 
-        if self.playlist_index >= self.playlist.len() {
+    fn send_playlist_index(&mut self, playlist_index: usize, user_navigation: bool) {
+        if self.sent_playlist_index == Some(playlist_index) {
             return;
         }
 
+        let Some(track) = self.playlist.get(playlist_index) else {
+            return;
+        };
+
         self.tx
             .send(PlayerResponse::NewPlaylistIndex {
-                playlist_index: self.playlist_index,
-                track: self.playlist[self.playlist_index].clone(),
+                playlist_index,
+                track: track.clone(),
                 user_navigation,
             })
             .ignore_err();
 
-        self.sent_playlist_index = Some(self.playlist_index);
+        self.sent_playlist_index = Some(playlist_index);
     }
 
     fn pause(&mut self) -> Result<()> {
+        if self.is_released {
+            return Ok(());
+        }
+        self.remember_audiobook_position();
+        self.audiobooks.save();
         if let Some(output) = &self.output {
             output.pause()?;
             self.output_is_paused = true;
+            self.paused_at = Some(Instant::now());
             self.tx
                 .send(PlayerResponse::PlaybackStateChanged {
                     state: PlaybackState::Paused,
-                    position: self.decoder.playback_position(),
+                    position: self.decoder.lock().unwrap().playback_position(),
                 })
                 .unwrap();
             return Ok(());
@@ -447,13 +650,17 @@ impl PlayerThread {
     }
 
     fn unpause(&mut self) -> Result<()> {
+        if self.is_released {
+            return self.restore();
+        }
         if let Some(output) = &self.output {
             output.play()?;
             self.output_is_paused = false;
+            self.paused_at = None;
             self.tx
                 .send(PlayerResponse::PlaybackStateChanged {
                     state: PlaybackState::Playing,
-                    position: self.decoder.playback_position(),
+                    position: self.decoder.lock().unwrap().playback_position(),
                 })
                 .unwrap();
             return Ok(());
@@ -461,8 +668,56 @@ impl PlayerThread {
         bail!("no output created");
     }
 
+    // stops waking up while paused for a long time
+    fn release(&mut self) {
+        self.remember_audiobook_position();
+        self.waker.suspend();
+        self.output = None;
+        self.decoder.lock().unwrap().release();
+        self.paused_at = None;
+        self.is_released = true;
+    }
+
+    fn restore(&mut self) -> Result<()> {
+        self.is_released = false;
+        Decoder::restore(&self.decoder).context("cannot restore the playback position")?;
+        self.last_listened_position = None;
+        self.output_is_paused = false;
+        self.tx
+            .send(PlayerResponse::PlaybackStateChanged {
+                state: PlaybackState::Playing,
+                position: self.decoder.lock().unwrap().playback_position(),
+            })
+            .unwrap();
+        return Ok(());
+    }
+
+    fn time_until_release(&self) -> Option<Duration> {
+        if !self.output_is_paused || self.is_released {
+            return None;
+        }
+        let (Some(paused_at), Some(release_after)) = (self.paused_at, self.release_after_pause)
+        else {
+            return None;
+        };
+        return Some(release_after.saturating_sub(paused_at.elapsed()));
+    }
+
+    fn release_if_paused_for_long(&mut self) {
+        if let (Some(paused_at), Some(release_after)) = (self.paused_at, self.release_after_pause) {
+            if self.output_is_paused && paused_at.elapsed() >= release_after {
+                self.release();
+            }
+        }
+    }
+
     fn seek_to(&mut self, pos: Duration) -> Result<()> {
-        let seeked_to = self.decoder.seek_to(pos)?;
+        let seeked_to = Decoder::seek_to(&self.decoder, pos)?;
+        self.last_listened_position = None;
+        if self.low_latency && !self.output_is_paused {
+            // drop the audio that is already queued in the device
+            self.output = None;
+        }
         self.tx
             .send(PlayerResponse::Seeked {
                 position: seeked_to,
@@ -472,159 +727,290 @@ impl PlayerThread {
     }
 
     fn send_position(&self) {
-        let position = self.decoder.playback_position();
+        let position = self.decoder.lock().unwrap().playback_position();
         self.tx
             .send(PlayerResponse::PositionRequested { position })
             .unwrap();
     }
 
-    fn process_client_cmd(&mut self) -> Result<bool> {
-        let recv_timeout = if self.need_fast_read {
-            Duration::ZERO
+    fn reload_meta(&self, filename: &str) {
+        let Some(track) = self.playlist.get(self.playlist_index) else {
+            return;
+        };
+        let meta = Decoder::reload_meta(&self.decoder, track, filename);
+        if let Some(meta) = meta {
+            self.tx
+                .send(PlayerResponse::NewMeta {
+                    meta: Box::new(meta),
+                    user_navigation: false,
+                })
+                .unwrap();
+        }
+    }
+
+    fn load_first_valid_meta(&mut self, index: usize) {
+        let mut index = index;
+        let playlist_len = self.playlist.len();
+        while index < playlist_len {
+            if self.load_meta(index).to_bool() {
+                return;
+            }
+            index += 1;
+        }
+        eprintln_with_date("the current file is not valid");
+    }
+
+    fn seek_by(&mut self, forward: bool, length: Duration) -> Result<()> {
+        let result_pos = if forward {
+            self.decoder
+                .lock()
+                .unwrap()
+                .playback_position()
+                .saturating_add(length)
         } else {
-            DECODER_THREAD_SLEEP
+            self.decoder
+                .lock()
+                .unwrap()
+                .playback_position()
+                .saturating_sub(length)
         };
-        if let Ok(cmd) = self.rx.recv_timeout(recv_timeout) {
-            match cmd {
-                PlayerCmd::SetPlaylist {
-                    tracks,
-                    cue_factory,
+        return self.seek_to(result_pos);
+    }
+
+    fn next_event(&mut self) -> Option<PlayerEvent> {
+        if let Some(cmd) = self.pending_cmd.take() {
+            return Some(PlayerEvent::Cmd(cmd));
+        }
+        if self.output.is_some() && !self.output_is_paused {
+            return self.rx.recv_timeout(POSITION_CHECK_INTERVAL).ok();
+        }
+        if let Some(release_in) = self.time_until_release() {
+            return self.rx.recv_timeout(release_in).ok();
+        }
+        // stopped, released or loading, nothing to do until the next event
+        return self.rx.recv().ok();
+    }
+
+    // false when the player should exit
+    fn run_cmd(&mut self, cmd: PlayerCmd) -> Result<bool> {
+        match cmd {
+            PlayerCmd::SetPlaylist {
+                tracks,
+                cue_factory,
+            } => {
+                self.set_playlist(tracks, cue_factory);
+            }
+            PlayerCmd::UpdatePlaylist {
+                tracks,
+                cue_factory,
+            } => {
+                self.update_playlist(tracks, cue_factory);
+            }
+            PlayerCmd::AppendTracks {
+                tracks,
+                cue_factory,
+            } => {
+                self.append_tracks(tracks, &cue_factory);
+            }
+            PlayerCmd::PrunePlaylist => {
+                self.prune_playlist();
+            }
+            PlayerCmd::LoadMeta { index } => {
+                self.stop();
+                self.load_first_valid_meta(index);
+            }
+            PlayerCmd::ReloadMeta { filename } => {
+                self.reload_meta(&filename);
+            }
+            PlayerCmd::Play { index } => {
+                self.play_or_next(index)?;
+            }
+            PlayerCmd::JumpTo { target } => {
+                self.jump_to(&target).context("cannot jump to the track")?;
+            }
+            PlayerCmd::Stop => {
+                self.stop();
+            }
+            PlayerCmd::RequestPosition => {
+                self.send_position();
+            }
+            PlayerCmd::Next => {
+                self.navigate(MoveTo::Next)
+                    .context("cannot play next track")?;
+            }
+            PlayerCmd::Prev => {
+                self.navigate(MoveTo::Prev)
+                    .context("cannot play previous track")?;
+            }
+            PlayerCmd::NextDir => {
+                self.navigate(MoveTo::NextDir)
+                    .context("cannot jump to next directory")?;
+            }
+            PlayerCmd::PrevDir => {
+                self.navigate(MoveTo::PrevDir)
+                    .context("cannot jump to previous directory")?;
+            }
+            PlayerCmd::RandomDir => {
+                self.navigate(MoveTo::RandomDir)
+                    .context("cannot jump to random directory")?;
+            }
+            PlayerCmd::Pause => {
+                self.pause().context("cannot pause")?;
+            }
+            PlayerCmd::UnPause => {
+                self.unpause().context("cannot unpause")?;
+            }
+            PlayerCmd::SeekBy { forward, length } => {
+                self.seek_by(forward, length).context("cannot seek")?;
+            }
+            PlayerCmd::SeekTo { position } => {
+                self.seek_to(position).context("cannot seek")?;
+            }
+            PlayerCmd::SetVolume { volume } => {
+                self.decoder.lock().unwrap().set_volume(volume);
+            }
+            PlayerCmd::SetDsp { settings } => {
+                self.decoder.lock().unwrap().set_dsp(settings);
+            }
+            PlayerCmd::Exit => {
+                self.waker.quit();
+                self.remember_audiobook_position();
+                self.audiobooks.save();
+                self.tx.send(PlayerResponse::Exited)?;
+                return Ok(false);
+            }
+        }
+        return Ok(true);
+    }
+
+    fn process_buffer_markers(&mut self) {
+        let markers = self.decoder.lock().unwrap().take_reached_buffer_markers();
+        for marker in markers {
+            match marker {
+                BufferMarker::TrackStart {
+                    playlist_index,
+                    track,
                 } => {
-                    self.set_playlist(tracks, cue_factory);
-                }
-                PlayerCmd::LoadMeta { index } => {
-                    self.stop();
-                    let mut index = index;
-                    let playlist_len = self.playlist.len();
-                    let mut is_loaded = false;
-                    while index < playlist_len {
-                        if self.load_meta(index).to_bool() {
-                            is_loaded = true;
-                            break;
-                        }
-                        index += 1;
-                    }
-                    if !is_loaded {
-                        eprintln_with_date("the current file is not valid");
-                    }
-                }
-                PlayerCmd::Play { index } => {
-                    self.stop();
-                    if !self
-                        .play(index, true)
-                        .with_context(|| format!("cannot play track {index:?}"))
-                        .to_bool()
-                    {
-                        self.next(false, true).context("cannot play next track")?;
-                    }
-                }
-                PlayerCmd::Stop => {
-                    self.stop();
-                }
-                PlayerCmd::RequestPosition => {
-                    self.send_position();
-                }
-                PlayerCmd::Next => {
-                    self.stop();
-                    self.next(true, true).context("cannot play next track")?;
-                }
-                PlayerCmd::Prev => {
-                    self.stop();
-                    self.prev().context("cannot play previous track")?;
-                }
-                PlayerCmd::NextDir => {
-                    self.stop();
-                    self.next_dir().context("cannot jump to next directory")?;
-                }
-                PlayerCmd::PrevDir => {
-                    self.stop();
-                    self.prev_dir()
-                        .context("cannot jump to previous directory")?;
-                }
-                PlayerCmd::Pause => {
-                    self.pause().context("cannot pause")?;
-                }
-                PlayerCmd::UnPause => {
-                    self.unpause().context("cannot unpause")?;
-                }
-                PlayerCmd::SeekBy { forward, length } => {
-                    let result_pos = if forward {
-                        self.decoder.playback_position().saturating_add(length)
+                    // the callbacks and the announcement switch to the new track together
+                    self.reset_position_callbacks();
+                    // the playlist may have been replaced since the track was started
+                    let playlist_index = if self.playlist.get(playlist_index) == Some(&track) {
+                        Some(playlist_index)
                     } else {
-                        self.decoder.playback_position().saturating_sub(length)
+                        self.playlist.iter().position(|t| *t == track)
                     };
-                    self.seek_to(result_pos).context("cannot seek")?;
-                }
-                PlayerCmd::SeekTo { position } => {
-                    self.seek_to(position).context("cannot seek")?;
-                }
-                PlayerCmd::SetVolume { volume } => {
-                    let volume = self.decoder.set_volume(volume);
-                    self.tx.send(PlayerResponse::VolumeSet { volume })?;
+                    if let Some(playlist_index) = playlist_index {
+                        self.send_playlist_index(
+                            playlist_index,
+                            self.user_navigation_for_next_meta,
+                        );
+                    }
                 }
-                PlayerCmd::Exit => {
-                    self.tx.send(PlayerResponse::Exited)?;
-                    return Ok(false);
+                BufferMarker::TrackMeta(track_meta) => {
+                    self.tx
+                        .send(PlayerResponse::NewMeta {
+                            meta: track_meta,
+                            user_navigation: self.user_navigation_for_next_meta,
+                        })
+                        .unwrap();
+                    self.user_navigation_for_next_meta = false;
                 }
             }
-        };
-        return Ok(true);
+        }
     }
 
-    fn send_new_meta(&mut self) {
-        if let Some(track_meta) = self.decoder.new_track_meta.take() {
-            self.tx
-                .send(PlayerResponse::NewMeta {
-                    meta: track_meta,
-                    user_navigation: self.user_navigation_for_next_meta,
-                })
-                .unwrap();
-            self.user_navigation_for_next_meta = false;
+    fn update_listened(&mut self, position: Duration) {
+        if let Some(last_position) = self.last_listened_position {
+            let step = position.saturating_sub(last_position);
+            // a larger step means that the position has not caught up with a seek yet
+            if step <= MAX_LISTENED_STEP {
+                self.listened += step;
+            }
         }
+        self.last_listened_position = Some(position);
+    }
+
+    // every track change starts the callbacks over
+    fn reset_position_callbacks(&mut self) {
+        self.triggered_callbacks.clear();
+        self.listened = Duration::ZERO;
+        self.last_listened_position = None;
     }
 
     fn process_position_callbacks(&mut self) {
-        if let (Some(callbacks), Some(duration)) = (
-            &self.position_callbacks,
-            &self.decoder.track_meta.as_ref().map(|m| m.duration),
-        ) {
-            match self.decoder.valid_playback_position() {
-                Ok(position) => {
-                    for callback in callbacks {
-                        if !self.triggered_callbacks.contains(&callback.id) {
-                            let must_trigger = match callback.marker {
-                                PositionCallbackMarker::SecsFromStart(marker) => position >= marker,
-                                PositionCallbackMarker::SecsFromEnd(marker) => {
-                                    let pos_from_start = duration.saturating_sub(marker);
-                                    position >= pos_from_start
-                                }
-                            };
-                            if must_trigger {
-                                self.tx
-                                    .send(PlayerResponse::PositionCallback {
-                                        callback: callback.clone(),
-                                    })
-                                    .unwrap();
-                                self.triggered_callbacks.push(callback.id);
-                            }
-                        }
-                    }
+        if self.position_callbacks.is_none() {
+            return;
+        }
+        let Some(duration) = self.decoder.lock().unwrap().track_duration() else {
+            return;
+        };
+        let position = self.decoder.lock().unwrap().valid_playback_position();
+        let position = match position {
+            Ok(position) => position,
+            Err(e) => {
+                e.log();
+                return;
+            }
+        };
+        self.update_listened(position);
+        self.trigger_position_callbacks(position, duration);
+    }
+
+    // The decoding of the next track starts before the end of the current one is heard,
+    // so the markers near the end are triggered while the track is still the current one.
+    fn finish_position_callbacks(&mut self) {
+        let decoder = self.decoder.lock().unwrap();
+        let duration = decoder.track_duration();
+        let position = decoder.valid_playback_position().ok();
+        drop(decoder);
+        let Some(duration) = duration else {
+            return;
+        };
+        // the rest is already in the buffer
+        if let Some(position) = self.last_listened_position.or(position) {
+            self.listened += duration.saturating_sub(position);
+        }
+        self.last_listened_position = Some(duration);
+        self.trigger_position_callbacks(duration, duration);
+    }
+
+    fn trigger_position_callbacks(&mut self, position: Duration, duration: Duration) {
+        if let Some(callbacks) = &self.position_callbacks {
+            for callback in callbacks {
+                if !self.triggered_callbacks.contains(&callback.id)
+                    && callback
+                        .marker
+                        .is_reached(position, self.listened, duration)
+                {
+                    self.tx
+                        .send(PlayerResponse::PositionCallback {
+                            callback: callback.clone(),
+                        })
+                        .unwrap();
+                    self.triggered_callbacks.push(callback.id);
                 }
-                Err(e) => e.log(),
             }
         }
     }
 
-    fn read_stream(&mut self) -> bool {
-        let mut may_create_output = false;
+    // the decoding is resumed unless the buffer is full or the playback has stopped
+    fn process_decoded(&mut self, epoch: u64, result: DecoderReadResult) {
+        if epoch != self.decoder.lock().unwrap().epoch() {
+            // decoded before a play/seek/stop
+            return;
+        }
         let mut need_next_track = false;
-        let mut need_read_fast = false;
-        match self.decoder.read_stream() {
-            DecoderReadResult::BufferNotFull => {
-                need_read_fast = true;
-            }
+        let mut read_error = None;
+        match result {
+            DecoderReadResult::BufferNotFull => {}
             DecoderReadResult::BufferFull => {
-                may_create_output = true;
+                if self.output.is_none() {
+                    self.output = self.decoder.lock().unwrap().create_output_stream();
+                    if self.output.is_some() {
+                        self.output_is_paused = false;
+                    }
+                }
+                return;
             }
             DecoderReadResult::NeedResetOutput => {
                 self.output = None;
@@ -632,70 +1018,92 @@ impl PlayerThread {
             DecoderReadResult::ReadEnd => {
                 need_next_track = true;
             }
-        }
-
-        self.send_new_meta();
-        if self.output.is_some() && !self.output_is_paused {
-            self.process_position_callbacks();
+            DecoderReadResult::ReadError(e) => {
+                need_next_track = true;
+                read_error = Some(e);
+            }
         }
 
         if need_next_track {
+            let track = self.decoder.lock().unwrap().track().cloned();
+            if let Some(track) = track {
+                if let Some(e) = read_error {
+                    let may_continue = self.track_failed(track, &e);
+                    e.log_context("cannot read the stream");
+                    if !may_continue {
+                        self.stop_after_failures();
+                        return;
+                    }
+                } else {
+                    self.finish_position_callbacks();
+                    self.audiobooks.forget_position(&track);
+                    self.track_finished(&track);
+                }
+            }
             if !self.next(false, false).to_bool() {
                 self.stop();
-                return false;
-            }
-            return true;
-        }
-
-        if may_create_output && self.output.is_none() {
-            self.output = self.decoder.create_output_stream();
-            if self.output.is_some() {
-                self.output_is_paused = false;
-            }
-        }
-        return need_read_fast;
-    }
-
-    fn read_stream_packets_batch(&mut self) -> bool {
-        let mut packets_left = READ_PACKETS_PER_CYCLE;
-        while packets_left > 0 {
-            if !self.read_stream() {
-                return false;
+                return;
             }
-            packets_left -= 1;
         }
-        return true;
+        self.waker.wake();
     }
 
     fn process(&mut self) -> bool {
-        match self.process_client_cmd() {
-            Ok(res) => {
-                if !res {
-                    return false;
+        match self.next_event() {
+            Some(PlayerEvent::Cmd(cmd)) => {
+                match self.run_cmd(cmd) {
+                    Ok(false) => return false,
+                    Ok(true) => {}
+                    Err(e) => e.log(),
+                }
+                if !self.is_released {
+                    self.waker.resume();
                 }
             }
-            Err(e) => e.log(),
+            Some(PlayerEvent::Decoded { epoch, result }) => {
+                self.process_decoded(epoch, result);
+            }
+            None => {}
+        }
+        if self.is_released {
+            return true;
+        }
+        self.process_buffer_markers();
+        if self.output.is_some() && !self.output_is_paused {
+            self.process_position_callbacks();
+        }
+        self.release_if_paused_for_long();
+        if !self.output_is_paused {
+            self.remember_audiobook_position();
+            self.audiobooks.save_periodically();
         }
-        self.need_fast_read = self.read_stream_packets_batch();
         return true;
     }
 }
 
 pub struct PlayerTx {
-    tx: Arc<Mutex<Sender<PlayerCmd>>>,
+    tx: Arc<Mutex<Sender<PlayerEvent>>>,
     server_thread: Option<JoinHandle<()>>,
 }
 
 impl PlayerTx {
-    pub fn new(tx: Sender<PlayerCmd>, server_thread: JoinHandle<()>) -> Self {
+    fn new(tx: Sender<PlayerEvent>, server_thread: JoinHandle<()>) -> Self {
         return Self {
             tx: Arc::new(Mutex::new(tx)),
             server_thread: Some(server_thread),
         };
     }
 
+    // a handle for sending commands from other threads
+    pub fn clone_sender(&self) -> Self {
+        return Self {
+            tx: self.tx.clone(),
+            server_thread: None,
+        };
+    }
+
     pub fn send(&self, cmd: PlayerCmd) {
-        self.tx.lock().unwrap().send(cmd).unwrap();
+        self.tx.lock().unwrap().send(PlayerEvent::Cmd(cmd)).unwrap();
     }
 
     pub fn set_playlist(&self, tracks: Vec<Track>, cue_factory: Option<CueFactory>) {
@@ -705,14 +1113,43 @@ impl PlayerTx {
         });
     }
 
+    pub fn update_playlist(&self, tracks: Vec<Track>, cue_factory: CueFactory) {
+        self.send(PlayerCmd::UpdatePlaylist {
+            tracks,
+            cue_factory,
+        });
+    }
+
+    pub fn append_tracks(&self, tracks: Vec<Track>, cue_factory: CueFactory) {
+        self.send(PlayerCmd::AppendTracks {
+            tracks,
+            cue_factory,
+        });
+    }
+
+    pub fn prune_playlist(&self) {
+        self.send(PlayerCmd::PrunePlaylist);
+    }
+
     pub fn play(&self, index: Option<usize>) {
         self.send(PlayerCmd::Play { index });
     }
 
+    pub fn jump_to(&self, target: JumpTarget) {
+        self.send(PlayerCmd::JumpTo { target });
+    }
+
     pub fn load_meta(&self, index: usize) {
         self.send(PlayerCmd::LoadMeta { index });
     }
 
+    // re-reads the tags if the file is the current track
+    pub fn reload_meta(&self, filename: &str) {
+        self.send(PlayerCmd::ReloadMeta {
+            filename: filename.to_string(),
+        });
+    }
+
     pub fn pause(&self) {
         self.send(PlayerCmd::Pause);
     }
@@ -745,6 +1182,10 @@ impl PlayerTx {
         self.send(PlayerCmd::PrevDir);
     }
 
+    pub fn random_dir(&self) {
+        self.send(PlayerCmd::RandomDir);
+    }
+
     pub fn seek_to(&self, position: Duration) {
         self.send(PlayerCmd::SeekTo { position });
     }
@@ -757,25 +1198,51 @@ impl PlayerTx {
         self.send(PlayerCmd::SetVolume { volume });
     }
 
+    pub fn set_dsp(&self, settings: DspSettings) {
+        self.send(PlayerCmd::SetDsp { settings });
+    }
+
     pub fn exit(&self) {
         self.send(PlayerCmd::Exit);
     }
 
-    pub fn wait(&mut self) {
-        if let Some(t) = self.server_thread.take() {
-            t.join().to_anyhow().ignore_err();
-        }
+    // so the thread can be joined without holding the owner of this sender
+    pub fn take_server_thread(&mut self) -> Option<JoinHandle<()>> {
+        return self.server_thread.take();
     }
 }
 
 pub fn start_thread(
     position_callbacks: Option<PositionCallbacks>,
+    low_latency: bool,
+    readahead: bool,
+    audiobook_dirs: Vec<String>,
+    meta_cache: MetaCache,
+    device_latency: DeviceLatency,
+    release_after_pause: Option<Duration>,
 ) -> (PlayerTx, Receiver<PlayerResponse>) {
     let (tx, rx) = channel();
     let (dtx, drx) = channel();
+    let decoded_tx = tx.clone();
 
     let server_thread = thread_util::thread("player server", move || {
-        let mut decoder = PlayerThread::new(dtx, rx, position_callbacks);
+        let audiobooks = Audiobooks::new(&audiobook_dirs);
+        let mut decoder = PlayerThread::new(
+            dtx,
+            rx,
+            position_callbacks,
+            low_latency,
+            readahead,
+            audiobooks,
+            meta_cache,
+        );
+        decoder
+            .decoder
+            .lock()
+            .unwrap()
+            .set_device_latency(device_latency);
+        decoder.release_after_pause = release_after_pause;
+        decoder.start_decode_ahead(decoded_tx);
         while decoder.process() {}
     });
 
@@ -1,108 +1,776 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::path::{Path, PathBuf};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use directories::BaseDirs;
 use path_absolutize::Absolutize;
+use serde::{Deserialize, Serialize};
 use url::Url;
 use walkdir::WalkDir;
 
 use crate::{
-    cue::CueFactory,
-    err_util::{IgnoreErr, LogErr},
-    project_file::ProjectFileJson,
+    app_state::AppState,
+    config::Config,
+    cue::{self, CueFactory},
+    err_util::{eprintln_with_date, println_with_date, IgnoreErr, LogErr},
+    path_filter::PathFilter,
+    project_file::{Migration, ProjectFileJson, ProjectFileJsonLines},
+    smart_playlist,
     stream_base::Track,
-    stream_man,
+    stream_man, subsonic, thread_util, zip_archive,
 };
 
-fn file() -> ProjectFileJson {
-    return ProjectFileJson::for_data("playlist.json", "playlist");
+const SCAN_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+const SCAN_CHUNK_INTERVAL: Duration = Duration::from_millis(100);
+const SCAN_CHUNK_SIZE: usize = 1000;
+const SCAN_BATCH_SIZE: usize = 256;
+const FILE_URI_SCHEME: &str = "file:";
+
+const MIGRATIONS: &[Migration] = &[
+    // 0: the playlists from before the versioning have the same tracks
+    Ok,
+];
+
+const BACKUP_MIGRATIONS: &[Migration] = &[
+    // 0: the backups from before the versioning have the same fields
+    Ok,
+];
+
+// one track per line, so the tracks can be appended without rewriting the whole file
+fn file() -> ProjectFileJsonLines {
+    return ProjectFileJsonLines::for_state("playlist.jsonl", "playlist")
+        .with_migrations(MIGRATIONS);
+}
+
+// the format used before
+fn legacy_file() -> ProjectFileJson {
+    return ProjectFileJson::for_state("playlist.json", "legacy playlist");
+}
+
+#[derive(Serialize, Deserialize)]
+struct PlaylistBackup {
+    playlist_index: Option<usize>,
+    tracks: Vec<Track>,
+}
+
+fn backup_file() -> ProjectFileJson {
+    return ProjectFileJson::for_state("playlist_backup.json", "playlist backup")
+        .with_migrations(BACKUP_MIGRATIONS);
 }
 
 pub fn save_playlist(tracks: &[Track]) -> Result<()> {
     return file().save(tracks);
 }
 
+pub fn append_to_playlist(tracks: &[Track]) -> Result<()> {
+    return file().append(tracks);
+}
+
+fn migrate_legacy_playlist() -> Result<Vec<Track>> {
+    let legacy_file = legacy_file();
+    let tracks: Vec<Track> = legacy_file.load()?;
+    save_playlist(&tracks).context("cannot convert the legacy playlist")?;
+    legacy_file.remove().ignore_err();
+    println_with_date("the playlist is converted to the new format");
+    return Ok(tracks);
+}
+
 pub fn load_playlist() -> Result<Vec<Track>> {
-    return file().load();
+    let file = file();
+    if !file.exists() && legacy_file().exists() {
+        return migrate_legacy_playlist();
+    }
+    return file.load();
 }
 
-fn uri_to_str(uri_str: &String) -> PathBuf {
-    if uri_str.starts_with("file://") {
-        match Url::parse(uri_str) {
-            Ok(url) => match url.to_file_path() {
-                Ok(path) => {
-                    return path;
-                }
-                Err(()) => {
-                    anyhow!("cannot get filesystem path from URL: {uri_str}").log();
-                }
-            },
-            Err(e) => e.log_context(format!("invalid URL: {uri_str}")),
+// keeps the stored playlist, so replacing or pruning it can be undone
+pub fn backup_playlist(playlist_index: Option<usize>) -> Result<()> {
+    if !file().exists() {
+        return Ok(());
+    }
+    let tracks = load_playlist()?;
+    if tracks.is_empty() {
+        return Ok(());
+    }
+    return backup_file().save(&PlaylistBackup {
+        playlist_index,
+        tracks,
+    });
+}
+
+// swaps the stored playlist with the backup, so the undo can be undone as well;
+// returns the restored tracks and the index of the track that was played
+pub fn restore_backup(playlist_index: Option<usize>) -> Result<(Vec<Track>, Option<usize>)> {
+    let backup_file = backup_file();
+    if !backup_file.exists() {
+        bail!("there is no previous playlist");
+    }
+    let backup: PlaylistBackup = backup_file.load()?;
+    backup_playlist(playlist_index).context("cannot backup the current playlist")?;
+    save_playlist(&backup.tracks).context("cannot save the playlist")?;
+    return Ok((backup.tracks, backup.playlist_index));
+}
+
+pub fn cli_undo() -> Result<()> {
+    let mut state = AppState::load_or_default();
+    let (tracks, playlist_index) = restore_backup(state.playlist_index)?;
+    state.playlist_index = playlist_index;
+    state.save().context("cannot save the state")?;
+    println!(
+        "Restored the previous playlist ({} tracks).",
+        format_count(tracks.len())
+    );
+    return Ok(());
+}
+
+// removes the tracks whose files no longer exist;
+// also returns the new index of the track at playlist_index (or of the track that took its place)
+pub fn prune_tracks(
+    tracks: Vec<Track>,
+    playlist_index: Option<usize>,
+) -> (Vec<Track>, Option<usize>) {
+    let mut new_index = None;
+    let mut existing_tracks = Vec::with_capacity(tracks.len());
+    for (index, track) in tracks.into_iter().enumerate() {
+        if playlist_index == Some(index) {
+            new_index = Some(existing_tracks.len());
+        }
+        // the songs on a Subsonic server are not checked
+        if subsonic::is_path(&track.filename)
+            || Path::new(zip_archive::file_path(&track.filename)).exists()
+        {
+            existing_tracks.push(track);
+        } else {
+            eprintln_with_date(format!(
+                "removed from the playlist (file not found): {}",
+                track.filename
+            ));
         }
     }
-    return uri_str.into();
+    let new_index =
+        new_index.and_then(|index| return Some(index.min(existing_tracks.len().checked_sub(1)?)));
+    return (existing_tracks, new_index);
 }
 
-pub fn collect_tracks(paths: &[String], cur_dir: &Path) -> (Vec<Track>, CueFactory) {
-    let mut cue_factory = CueFactory::new();
+pub fn cli_prune() -> Result<()> {
+    let tracks = load_playlist().context("cannot load the playlist")?;
+    let count = tracks.len();
+    let mut state = AppState::load_or_default();
+    let (tracks, playlist_index) = prune_tracks(tracks, state.playlist_index);
+    let removed = count - tracks.len();
+    if removed > 0 {
+        backup_playlist(state.playlist_index).context("cannot backup the playlist")?;
+        save_playlist(&tracks).context("cannot save the playlist")?;
+        state.playlist_index = playlist_index;
+        state.save().context("cannot save the state")?;
+    }
+    println!(
+        "Removed {} of {} tracks.",
+        format_count(removed),
+        format_count(count)
+    );
+    return Ok(());
+}
 
-    #[allow(clippy::needless_collect)] // not actually "needless"
-    let tracks: Vec<Track> = paths
-        .iter()
-        .map(uri_to_str)
-        .map(|path| cur_dir.join(path))
-        .flat_map(WalkDir::new)
-        .filter_map(|entry| entry.to_option())
-        .filter_map(|entry| {
-            if entry.file_type().is_file() {
-                return entry
-                    .path()
-                    .absolutize()
-                    .to_option()
-                    .and_then(|s| s.to_str().map(|s| s.to_string()));
+// the percent-escapes are decoded, and the query and the fragment are ignored
+fn uri_to_path(uri_str: &str) -> Option<PathBuf> {
+    match Url::parse(uri_str) {
+        Ok(url) => match url.to_file_path() {
+            Ok(path) => {
+                return Some(path);
             }
-            return None;
-        })
-        .filter_map(|path| {
-            if stream_man::is_path_supported(&path) {
-                return Some(vec![Track {
-                    filename: path,
-                    index: None,
-                }]);
+            Err(()) => {
+                anyhow!("cannot get filesystem path from URL: {uri_str}").log();
             }
+        },
+        Err(e) => e.log_context(format!("invalid URL: {uri_str}")),
+    }
+    return None;
+}
 
-            return cue_factory.get_or_new(&path).map_to_option(move |sheet| {
-                sheet.map(|sheet| {
-                    sheet
-                        .track_ids()
-                        .iter()
-                        .map(|id| Track {
-                            filename: path.clone(),
-                            index: Some(*id),
-                        })
-                        .collect()
-                })
-            });
+fn is_file_uri(path: &str) -> bool {
+    return path
+        .get(..FILE_URI_SCHEME.len())
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case(FILE_URI_SCHEME));
+}
+
+// "~" and "~/..." are expanded as in the shell
+fn expand_home(path: &str) -> Option<PathBuf> {
+    let rest = match path.strip_prefix('~') {
+        Some("") => "",
+        Some(rest) => rest.strip_prefix('/')?,
+        None => return None,
+    };
+    return BaseDirs::new().map(|dirs| dirs.home_dir().join(rest));
+}
+
+// The paths may come from another process, so they are resolved against its working directory.
+// The paths may also come from files with Windows line endings, so the trailing CR is dropped.
+pub fn resolve_path(path: &str, cur_dir: &Path) -> PathBuf {
+    let path = path.strip_suffix('\r').unwrap_or(path);
+    let path = if is_file_uri(path) {
+        uri_to_path(path).unwrap_or_else(|| path.into())
+    } else {
+        expand_home(path).unwrap_or_else(|| path.into())
+    };
+    return match path.absolutize_from(cur_dir) {
+        Ok(path) => path.into_owned(),
+        Err(_) => cur_dir.join(path),
+    };
+}
+
+pub trait ScanObserver {
+    fn is_cancelled(&self) -> bool;
+    fn progress(&mut self, files_scanned: usize);
+    fn tracks_found(&mut self, tracks: Vec<Track>, cue_factory: &CueFactory);
+}
+
+struct TrackCollector<'a> {
+    cue_factory: CueFactory,
+    files_scanned: usize,
+    last_progress: Instant,
+    pending_tracks: Vec<Track>,
+    last_chunk: Instant,
+    sort_order: SortOrder,
+    path_filter: PathFilter,
+    max_depth: Option<usize>,
+    tag_sort_keys: TagSortKeys,
+    // the tracks of the current folder, when sorting by tags
+    dir_tracks: Vec<Track>,
+    observer: &'a mut dyn ScanObserver,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    // the paths are not sorted, only the folder contents are
+    AsGiven,
+    #[default]
+    Alphanumeric,
+    // by folder, then by album, disc and track number
+    Tags,
+}
+
+// what happens when the last track of the playlist has been played
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaylistEndAction {
+    Stop,
+    Repeat,
+    // the stored playlist is shuffled and played again
+    Shuffle,
+    AutoDj,
+    Quit,
+}
+
+fn compare_paths(a: &str, b: &str) -> Ordering {
+    return alphanumeric_sort::compare_str(a.to_uppercase(), b.to_uppercase());
+}
+
+fn compare_tracks_by_path(a: &Track, b: &Track) -> Ordering {
+    return compare_paths(&a.filename, &b.filename).then_with(|| a.index.cmp(&b.index));
+}
+
+fn track_dir(track: &Track) -> &Path {
+    return Path::new(&*track.filename)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+}
+
+fn compare_tracks_by_dir(a: &Track, b: &Track) -> Ordering {
+    return compare_paths(
+        &track_dir(a).to_string_lossy(),
+        &track_dir(b).to_string_lossy(),
+    );
+}
+
+struct TagSortKey {
+    album: Option<String>,
+    disc: Option<usize>,
+    track: Option<usize>,
+}
+
+impl TagSortKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        return compare_paths(
+            self.album.as_deref().unwrap_or_default(),
+            other.album.as_deref().unwrap_or_default(),
+        )
+        .then_with(|| self.disc.cmp(&other.disc))
+        .then_with(|| self.track.cmp(&other.track));
+    }
+}
+
+// The tags are read only when the tracks of their folder need to be sorted,
+// so the first folders can be played before the whole scan is finished.
+#[derive(Default)]
+struct TagSortKeys {
+    keys: HashMap<Track, TagSortKey>,
+}
+
+impl TagSortKeys {
+    fn load(&mut self, track: &Track, cue_factory: &mut CueFactory) {
+        if self.keys.contains_key(track) {
+            return;
+        }
+        let meta = stream_man::read_track_meta(track, cue_factory).unwrap_or_default();
+        self.keys.insert(
+            track.clone(),
+            TagSortKey {
+                album: meta.album,
+                disc: meta.disc,
+                track: meta.track,
+            },
+        );
+    }
+
+    // sorts the tracks of a single folder
+    fn sort_dir_tracks(&mut self, tracks: &mut [Track], cue_factory: &mut CueFactory) {
+        for track in tracks.iter() {
+            self.load(track, cue_factory);
+        }
+        tracks.sort_by(|a, b| {
+            return self.keys[a]
+                .cmp(&self.keys[b])
+                .then_with(|| compare_tracks_by_path(a, b));
+        });
+    }
+
+    fn sort(&mut self, tracks: &mut [Track], cue_factory: &mut CueFactory) {
+        tracks
+            .sort_by(|a, b| compare_tracks_by_dir(a, b).then_with(|| compare_tracks_by_path(a, b)));
+        for dir_tracks in tracks.chunk_by_mut(|a, b| track_dir(a) == track_dir(b)) {
+            self.sort_dir_tracks(dir_tracks, cue_factory);
+        }
+    }
+}
+
+// the supported files inside a ZIP archive, CUE sheets and chapters are not looked for there
+fn archive_tracks(path: &str) -> Vec<Track> {
+    let mut filenames: Vec<String> = zip_archive::list(path)
+        .ok_or_default()
+        .into_iter()
+        .filter(|filename| stream_man::is_path_supported(filename))
+        .collect();
+    filenames.sort_by(|a, b| compare_paths(a, b));
+    return filenames
+        .into_iter()
+        .map(|filename| Track {
+            filename: filename.into(),
+            index: None,
         })
-        .flatten()
         .collect();
+}
+
+fn entry_tracks(path: &str, cue_factory: &mut CueFactory) -> Vec<Track> {
+    if subsonic::is_path(path) {
+        return subsonic::tracks(path).ok_or_default();
+    }
+    if zip_archive::is_archive(path) {
+        return archive_tracks(path);
+    }
+
+    // CUE sheets, and also audio files with chapters
+    if let Some(Some(sheet)) = cue_factory.get_or_new(path).to_option() {
+        // all tracks of the sheet share the same filename
+        let filename: Arc<str> = path.into();
+        return sheet
+            .track_ids()
+            .iter()
+            .map(|id| Track {
+                filename: Arc::clone(&filename),
+                index: Some(*id),
+            })
+            .collect();
+    }
+
+    if stream_man::is_path_supported(path) {
+        return vec![Track {
+            filename: path.into(),
+            index: None,
+        }];
+    }
+
+    return Vec::new();
+}
+
+fn chunk_tracks(paths: &[String]) -> (Vec<Vec<Track>>, CueFactory) {
+    let mut cue_factory = CueFactory::new();
+    let tracks = paths
+        .iter()
+        .map(|path| entry_tracks(path, &mut cue_factory))
+        .collect();
+    return (tracks, cue_factory);
+}
+
+impl TrackCollector<'_> {
+    // reading CUE sheets and chapters is slow (especially on network drives),
+    // so the files are processed in parallel, but the order is preserved
+    fn batch_tracks(&mut self, paths: &[String]) -> Vec<Vec<Track>> {
+        let threads_count = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let chunk_size = paths.len().div_ceil(threads_count).max(1);
+        let results: Vec<(Vec<Vec<Track>>, CueFactory)> = if paths.len() <= chunk_size {
+            vec![chunk_tracks(paths)]
+        } else {
+            thread::scope(|scope| {
+                let mut handles = Vec::new();
+                for chunk in paths.chunks(chunk_size) {
+                    handles.push(thread_util::scoped_thread(
+                        scope,
+                        "playlist scanner worker",
+                        || chunk_tracks(chunk),
+                    ));
+                }
+                return handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("playlist scanner worker failed"))
+                    .collect();
+            })
+        };
+
+        let mut batch_tracks = Vec::with_capacity(paths.len());
+        for (tracks, cue_factory) in results {
+            self.cue_factory.merge(&cue_factory);
+            batch_tracks.extend(tracks);
+        }
+        return batch_tracks;
+    }
+
+    fn process_batch(&mut self, paths: &[String], tracks: &mut Vec<Track>, report_tracks: bool) {
+        let sort_by_tags = self.sort_order == SortOrder::Tags;
+        for (path, entry_tracks) in paths.iter().zip(self.batch_tracks(paths)) {
+            if report_tracks {
+                if sort_by_tags {
+                    if self
+                        .dir_tracks
+                        .first()
+                        .is_some_and(|track| Some(track_dir(track)) != Path::new(path).parent())
+                    {
+                        self.flush_dir_tracks();
+                    }
+                    self.dir_tracks.extend(entry_tracks.iter().cloned());
+                } else {
+                    self.pending_tracks.extend(entry_tracks.iter().cloned());
+                }
+            }
+            tracks.extend(entry_tracks);
+        }
+        if self.pending_tracks.len() >= SCAN_CHUNK_SIZE
+            || self.last_chunk.elapsed() >= SCAN_CHUNK_INTERVAL
+        {
+            self.flush_pending_tracks();
+        }
+    }
+
+    fn flush_dir_tracks(&mut self) {
+        let mut dir_tracks = std::mem::take(&mut self.dir_tracks);
+        self.tag_sort_keys
+            .sort_dir_tracks(&mut dir_tracks, &mut self.cue_factory);
+        self.pending_tracks.extend(dir_tracks);
+    }
+
+    fn flush_pending_tracks(&mut self) {
+        if self.pending_tracks.is_empty() {
+            return;
+        }
+        self.last_chunk = Instant::now();
+        let tracks = std::mem::take(&mut self.pending_tracks);
+        // the playlist may be replaced already (e.g. by another scan or by the undo)
+        if self.observer.is_cancelled() {
+            return;
+        }
+        let tracks = remove_cue_sources(tracks, &self.cue_factory);
+        if !tracks.is_empty() {
+            self.observer.tracks_found(tracks, &self.cue_factory);
+        }
+    }
+
+    // returns false if the scan was cancelled
+    fn walk(
+        &mut self,
+        paths: &[String],
+        cur_dir: &Path,
+        tracks: &mut Vec<Track>,
+        report_tracks: bool,
+    ) -> bool {
+        for path in paths {
+            if subsonic::is_path(path) {
+                self.process_batch(std::slice::from_ref(path), tracks, report_tracks);
+                continue;
+            }
+            let path = resolve_path(path, cur_dir);
+            // the sorted walk yields (almost) the final order, so the found tracks can be played right away
+            let sort_by_tags = self.sort_order == SortOrder::Tags;
+            let mut walker = WalkDir::new(path);
+            if let Some(max_depth) = self.max_depth {
+                walker = walker.max_depth(max_depth);
+            }
+            let path_filter = self.path_filter.clone();
+            let walker = walker.sort_by(move |a, b| {
+                // when sorting by tags, every folder's files must go in one batch
+                let dirs_order = if sort_by_tags {
+                    a.file_type().is_dir().cmp(&b.file_type().is_dir())
+                } else {
+                    Ordering::Equal
+                };
+                return dirs_order.then_with(|| {
+                    compare_paths(
+                        &a.file_name().to_string_lossy(),
+                        &b.file_name().to_string_lossy(),
+                    )
+                });
+            });
+            // the explicitly given paths are never excluded
+            let walker = walker.into_iter().filter_entry(move |entry| {
+                return entry.depth() == 0
+                    || !path_filter.is_excluded(entry.path(), entry.file_type().is_dir());
+            });
+            let mut batch = Vec::new();
+            let mut batch_started = Instant::now();
+            for entry in walker {
+                if self.observer.is_cancelled() {
+                    return false;
+                }
+                if let Some(entry) = entry.to_option() {
+                    if !entry.file_type().is_file() {
+                        continue;
+                    }
+                    self.files_scanned += 1;
+                    if let Some(path) = entry
+                        .path()
+                        .absolutize()
+                        .to_option()
+                        .and_then(|s| s.to_str().map(|s| s.to_string()))
+                    {
+                        batch.push(path);
+                    }
+                    if batch.len() >= SCAN_BATCH_SIZE
+                        || batch_started.elapsed() >= SCAN_CHUNK_INTERVAL
+                    {
+                        self.process_batch(&batch, tracks, report_tracks);
+                        batch.clear();
+                        batch_started = Instant::now();
+                    }
+                    if self.last_progress.elapsed() >= SCAN_PROGRESS_INTERVAL {
+                        self.last_progress = Instant::now();
+                        self.observer.progress(self.files_scanned);
+                    }
+                }
+            }
+            self.process_batch(&batch, tracks, report_tracks);
+        }
+        return true;
+    }
+
+    fn finalize_tracks(&mut self, tracks: Vec<Track>) -> Vec<Track> {
+        let mut tracks = remove_cue_sources(tracks, &self.cue_factory);
+
+        match self.sort_order {
+            SortOrder::AsGiven => {}
+            SortOrder::Alphanumeric => tracks.sort_by(compare_tracks_by_path),
+            SortOrder::Tags => self.tag_sort_keys.sort(&mut tracks, &mut self.cue_factory),
+        }
+
+        return tracks;
+    }
 
+    fn walk_smart(&mut self, names: &[&str], cur_dir: &Path, tracks: &mut Vec<Track>) -> bool {
+        let config = Config::load_or_default();
+        for name in names {
+            if let Some(playlist) = config.smart_playlists.get(*name) {
+                let mut playlist_tracks = Vec::new();
+                if !self.walk(&playlist.paths, cur_dir, &mut playlist_tracks, false) {
+                    return false;
+                }
+                let playlist_tracks = remove_cue_sources(playlist_tracks, &self.cue_factory);
+                tracks.extend(smart_playlist::filter_tracks(
+                    name,
+                    &playlist.query,
+                    playlist_tracks,
+                    &mut self.cue_factory,
+                ));
+            } else {
+                eprintln_with_date(format!("smart playlist not found: {name}"));
+            }
+        }
+        return true;
+    }
+}
+
+fn remove_cue_sources(tracks: Vec<Track>, cue_factory: &CueFactory) -> Vec<Track> {
     let cue_source_filenames = cue_factory
         .sheets()
         .iter()
         .map(|sheet| sheet.source_filename.clone())
         .collect::<Vec<String>>();
-    let mut tracks = tracks
+    return tracks
         .into_iter()
-        .filter(|track| !cue_source_filenames.contains(&track.filename))
-        .collect::<Vec<Track>>();
+        .filter(|track| {
+            track.index.is_some() || !cue_source_filenames.iter().any(|x| **x == *track.filename)
+        })
+        .collect();
+}
 
-    tracks.sort_by(|a, b| {
-        alphanumeric_sort::compare_str(a.filename.to_uppercase(), b.filename.to_uppercase())
-            .then_with(|| a.index.cmp(&b.index))
-    });
+pub fn collect_tracks(
+    paths: &[String],
+    cur_dir: &Path,
+    observer: &mut dyn ScanObserver,
+) -> Option<(Vec<Track>, CueFactory)> {
+    let config = Config::load_or_default();
+    let sort_order = config.sort_order;
+    let mut collector = TrackCollector {
+        cue_factory: CueFactory::new(),
+        files_scanned: 0,
+        last_progress: Instant::now(),
+        pending_tracks: Vec::new(),
+        last_chunk: Instant::now(),
+        sort_order,
+        path_filter: PathFilter::new(&config.scan_exclude),
+        max_depth: config.scan_max_depth,
+        tag_sort_keys: TagSortKeys::default(),
+        dir_tracks: Vec::new(),
+        observer,
+    };
 
-    return (tracks, cue_factory);
+    let (smart_paths, mut paths): (Vec<String>, Vec<String>) = paths
+        .iter()
+        .cloned()
+        .partition(|path| path.starts_with(smart_playlist::PATH_PREFIX));
+    if sort_order != SortOrder::AsGiven {
+        paths.sort_by(|a, b| compare_paths(a, b));
+    }
+    let smart_names: Vec<&str> = smart_paths
+        .iter()
+        .map(|path| &path[smart_playlist::PATH_PREFIX.len()..])
+        .collect();
+
+    let mut tracks = Vec::new();
+    if !collector.walk(&paths, cur_dir, &mut tracks, true) {
+        return None;
+    }
+    collector.flush_dir_tracks();
+    collector.flush_pending_tracks();
+    if !collector.walk_smart(&smart_names, cur_dir, &mut tracks) {
+        return None;
+    }
+
+    let tracks = collector.finalize_tracks(tracks);
+    cue::save_sheet_cache();
+    return Some((tracks, collector.cue_factory));
+}
+
+pub fn format_count(n: usize) -> String {
+    let digits = n.to_string();
+    let mut result = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            result.push(',');
+        }
+        result.push(c);
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(path: &str) -> PathBuf {
+        return resolve_path(path, Path::new("/cur/dir"));
+    }
+
+    fn home() -> PathBuf {
+        return BaseDirs::new().unwrap().home_dir().to_path_buf();
+    }
+
+    #[test]
+    fn file_uris() {
+        assert!(is_file_uri("file:///music/a.mp3"));
+        assert!(is_file_uri("FILE:///music/a.mp3"));
+        assert!(is_file_uri("file:"));
+        assert!(!is_file_uri("fil"));
+        assert!(!is_file_uri("/music/file:a.mp3"));
+        assert!(!is_file_uri("http://example.com/a.mp3"));
+        assert!(!is_file_uri("файл"));
+    }
+
+    #[test]
+    fn home_expansion() {
+        assert_eq!(expand_home("~"), Some(home()));
+        assert_eq!(
+            expand_home("~/music/a.mp3"),
+            Some(home().join("music/a.mp3"))
+        );
+        assert_eq!(expand_home("~user/a.mp3"), None);
+        assert_eq!(expand_home("/music/~/a.mp3"), None);
+        assert_eq!(expand_home("a.mp3"), None);
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(resolve("a.mp3"), PathBuf::from("/cur/dir/a.mp3"));
+        assert_eq!(resolve("./sub/a.mp3"), PathBuf::from("/cur/dir/sub/a.mp3"));
+        assert_eq!(resolve("../a.mp3"), PathBuf::from("/cur/a.mp3"));
+        assert_eq!(resolve("."), PathBuf::from("/cur/dir"));
+    }
+
+    #[test]
+    fn absolute_paths() {
+        assert_eq!(resolve("/music/a.mp3"), PathBuf::from("/music/a.mp3"));
+        assert_eq!(
+            resolve("/music/./x/../a.mp3"),
+            PathBuf::from("/music/a.mp3")
+        );
+    }
+
+    #[test]
+    fn home_paths() {
+        assert_eq!(resolve("~"), home());
+        assert_eq!(resolve("~/a.mp3"), home().join("a.mp3"));
+        assert_eq!(
+            resolve("~user/a.mp3"),
+            PathBuf::from("/cur/dir/~user/a.mp3")
+        );
+    }
+
+    #[test]
+    fn uri_paths() {
+        assert_eq!(
+            resolve("file:///music/a%20b%23c.mp3"),
+            PathBuf::from("/music/a b#c.mp3")
+        );
+        assert_eq!(
+            resolve("file:///music/a.mp3?query=1#fragment"),
+            PathBuf::from("/music/a.mp3")
+        );
+        assert_eq!(
+            resolve("file:///music/%D0%BC%D1%83%D0%B7.mp3"),
+            PathBuf::from("/music/муз.mp3")
+        );
+        assert_eq!(
+            resolve("FILE:///music/a.mp3"),
+            PathBuf::from("/music/a.mp3")
+        );
+    }
+
+    #[test]
+    fn windows_lines() {
+        assert_eq!(resolve("a.mp3\r"), PathBuf::from("/cur/dir/a.mp3"));
+        assert_eq!(resolve("/music/a.mp3\r"), PathBuf::from("/music/a.mp3"));
+        assert_eq!(resolve("~/a.mp3\r"), home().join("a.mp3"));
+        assert_eq!(
+            resolve("file:///music/a%20b.mp3\r"),
+            PathBuf::from("/music/a b.mp3")
+        );
+    }
 }
@@ -1,37 +1,97 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::sync::{Arc, Mutex};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::{Context, Result};
 use notify_rust::Notification;
 
-use crate::{err_util::IgnoreErr, project_info, thread_util};
+use crate::{
+    err_util::IgnoreErr,
+    project_info,
+    quiet::{self, QuietHours},
+    thread_util,
+};
 
+#[derive(Clone)]
 pub struct Popup {
     handle_id: Arc<Mutex<Option<u32>>>,
+    // nothing is shown in the headless mode
+    enabled: bool,
+    quiet_hours: Option<QuietHours>,
 }
 
 impl Popup {
-    pub fn new() -> Self {
+    pub fn new(quiet_hours: Option<QuietHours>) -> Self {
         return Self {
             handle_id: Arc::new(Mutex::new(None)),
+            enabled: true,
+            quiet_hours,
+        };
+    }
+
+    pub fn headless() -> Self {
+        return Self {
+            handle_id: Arc::new(Mutex::new(None)),
+            enabled: false,
+            quiet_hours: None,
         };
     }
 
     pub fn show(&self, body: &str) {
+        self.show_with_image(body, None);
+    }
+
+    // waits until the popup is shown, e.g. when the process is about to end
+    pub fn show_now(&self, body: &str) {
+        if !self.enabled {
+            return;
+        }
+        Self::show_raw(body, None, &self.handle_id).ignore_err();
+    }
+
+    pub fn show_with_image(&self, body: &str, image: Option<&Path>) {
+        self.spawn(body, image, false);
+    }
+
+    // the track changes, unless the user does not want to be disturbed
+    pub fn announce(&self, body: &str, image: Option<&Path>) {
+        self.spawn(body, image, true);
+    }
+
+    fn spawn(&self, body: &str, image: Option<&Path>, check_quiet: bool) {
+        if !self.enabled {
+            return;
+        }
         let handle_id = self.handle_id.clone();
+        let quiet_hours = self.quiet_hours.clone();
 
         let body = body.to_string();
+        let image = image.map(Path::to_path_buf);
         thread_util::thread("popup", move || {
-            Self::show_raw(&body, &handle_id).ignore_err();
+            if check_quiet && quiet::is_quiet(quiet_hours.as_ref()) {
+                return;
+            }
+            Self::show_raw(&body, image.as_deref(), &handle_id).ignore_err();
         });
     }
 
-    fn show_raw(body: &str, handle_id_arc: &Arc<Mutex<Option<u32>>>) -> Result<()> {
+    fn show_raw(
+        body: &str,
+        image: Option<&Path>,
+        handle_id_arc: &Arc<Mutex<Option<u32>>>,
+    ) -> Result<()> {
         let mut popup = Notification::new();
         let html_body = html_escape::encode_text(body);
-        let popup = popup.body(&html_body).appname(project_info::title());
+        let popup = popup
+            .body(&html_body)
+            .appname(&project_info::instance_title());
+        if let Some(image) = image {
+            popup.image_path(&image.to_string_lossy());
+        }
         let mut handle_id_guarded = handle_id_arc.lock().unwrap();
         let handle;
         let cur_handle_id;
@@ -1,13 +1,71 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::{fs, path::PathBuf};
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use anyhow::{bail, Context, Result};
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::project_info;
+use crate::{err_util::IgnoreErr, project_info};
+
+// makes the temporary file names unique within the process
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// where the files go, as in the XDG Base Directory spec
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ProjectDir {
+    Config,
+    Data,
+    State,
+    Cache,
+}
+
+impl ProjectDir {
+    pub const ALL: [Self; 4] = [Self::Config, Self::Data, Self::State, Self::Cache];
+
+    pub const fn title(self) -> &'static str {
+        return match self {
+            Self::Config => "config",
+            Self::Data => "data",
+            Self::State => "state",
+            Self::Cache => "cache",
+        };
+    }
+
+    // where the file was moved from the data folder, if it belongs elsewhere
+    fn of_legacy_file(name: &str) -> Option<Self> {
+        return match name {
+            "config.json" => Some(Self::Config),
+            "meta_cache.json" | "cue_cache.json" | "silence_cache.json" | "covers" => {
+                Some(Self::Cache)
+            }
+            "state.json"
+            | "playlist.jsonl"
+            | "playlist.json"
+            | "playlist_backup.json"
+            | "audiobook_positions.json"
+            | "dir_positions.json"
+            | "bad_files.json"
+            | "status.json" => Some(Self::State),
+            // the scrobble queues, named after the services
+            _ if name.ends_with("_not_scrobbled.json")
+                || name.ends_with("_not_submitted.json")
+                || name.ends_with("_not_loved.json") =>
+            {
+                Some(Self::State)
+            }
+            _ => None,
+        };
+    }
+}
 
 pub struct ProjectFileString {
     description: &'static str,
@@ -19,8 +77,33 @@ struct ProjectFilePaths {
     full_filename: PathBuf,
 }
 
+// converts the data of one version to the next one
+pub type Migration = fn(Value) -> Result<Value>;
+
+const VERSION_KEY: &str = "version";
+const DATA_KEY: &str = "data";
+
+// The JSON objects are saved with the version of their format,
+// and the other values (e.g. arrays) are wrapped into {"version": N, "data": ...}.
+// The files without a version are version 0.
 pub struct ProjectFileJson {
     file: ProjectFileString,
+    // the migration from version N is at index N
+    migrations: &'static [Migration],
+}
+
+// One JSON value per line, so the values can be appended without rewriting the whole file.
+// The first line is {"version": N}, the files without it are version 0.
+// The migrations are applied to every line.
+pub struct ProjectFileJsonLines {
+    file: ProjectFileJson,
+}
+
+fn parse_version(value: &Value, description: &str) -> Result<usize> {
+    return value
+        .as_u64()
+        .and_then(|version| usize::try_from(version).ok())
+        .with_context(|| format!("invalid version of {description}"));
 }
 
 impl ProjectFileString {
@@ -36,22 +119,78 @@ impl ProjectFileString {
         );
     }
 
+    pub fn dir_for(kind: ProjectDir) -> Option<PathBuf> {
+        let dirs = Self::dirs()?;
+        let dir = match kind {
+            ProjectDir::Config => dirs.config_dir(),
+            ProjectDir::Data => dirs.data_dir(),
+            // only Linux has a separate state folder
+            ProjectDir::State => dirs.state_dir().unwrap_or_else(|| dirs.data_dir()),
+            ProjectDir::Cache => dirs.cache_dir(),
+        };
+        if let Some(profile) = project_info::profile() {
+            return Some(dir.join("profiles").join(profile));
+        }
+        return Some(dir.to_path_buf());
+    }
+
     pub fn dir_for_data() -> Option<PathBuf> {
-        return Self::dirs().map(|dirs| dirs.data_dir().to_path_buf());
+        return Self::dir_for(ProjectDir::Data);
     }
 
-    pub fn for_data(filename: &str, description: &'static str) -> Self {
-        if let Some(dir) = Self::dir_for_data() {
-            let full_filename = dir.join(filename);
-            return Self {
-                description,
-                paths: Some(ProjectFilePaths { dir, full_filename }),
+    pub fn path_for(kind: ProjectDir, name: &str) -> Option<PathBuf> {
+        return Some(Self::dir_for(kind)?.join(name));
+    }
+
+    // Everything used to be in the data folder,
+    // so the files (and folders) are moved from there on startup.
+    // The files that are already in their new places are not overwritten.
+    pub fn migrate_legacy_files() {
+        let Some(data_dir) = Self::dir_for_data() else {
+            return;
+        };
+        let Ok(entries) = fs::read_dir(&data_dir) else {
+            return;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Some(kind) = ProjectDir::of_legacy_file(&name) else {
+                continue;
+            };
+            let Some(dir) = Self::dir_for(kind) else {
+                continue;
             };
+            let path = dir.join(&name);
+            // e.g. there's no separate state folder on this system
+            if dir == data_dir || path.exists() {
+                continue;
+            }
+            move_path(&entry.path(), &dir, &path).ignore_err();
         }
-        return Self {
-            description,
-            paths: None,
-        };
+    }
+
+    fn new(kind: ProjectDir, filename: &str, description: &'static str) -> Self {
+        let paths = Self::path_for(kind, filename).and_then(|full_filename| {
+            let dir = full_filename.parent()?.to_path_buf();
+            return Some(ProjectFilePaths { dir, full_filename });
+        });
+        return Self { description, paths };
+    }
+
+    pub fn for_data(filename: &str, description: &'static str) -> Self {
+        return Self::new(ProjectDir::Data, filename, description);
+    }
+
+    pub fn for_config(filename: &str, description: &'static str) -> Self {
+        return Self::new(ProjectDir::Config, filename, description);
+    }
+
+    pub fn for_state(filename: &str, description: &'static str) -> Self {
+        return Self::new(ProjectDir::State, filename, description);
+    }
+
+    pub fn for_cache(filename: &str, description: &'static str) -> Self {
+        return Self::new(ProjectDir::Cache, filename, description);
     }
 
     fn paths(&self) -> Result<&ProjectFilePaths> {
@@ -84,9 +223,85 @@ impl ProjectFileString {
                 paths.full_filename.to_string_lossy()
             )
         })?;
-        fs::write(&paths.full_filename, contents).with_context(|| {
+        // A crash in the middle of writing leaves the previous version intact.
+        // Every writer (the app, the CLI commands) uses its own temporary file.
+        let mut tmp_filename = paths.full_filename.clone().into_os_string();
+        tmp_filename.push(format!(
+            ".{}.{}.tmp",
+            process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let tmp_filename = PathBuf::from(tmp_filename);
+        if let Err(e) = Self::write_synced(&tmp_filename, contents) {
+            fs::remove_file(&tmp_filename).ok();
+            return Err(e).with_context(|| {
+                format!(
+                    "cannot write to {}: {}",
+                    self.description,
+                    tmp_filename.to_string_lossy()
+                )
+            });
+        }
+        if let Err(e) = fs::rename(&tmp_filename, &paths.full_filename) {
+            fs::remove_file(&tmp_filename).ok();
+            return Err(e).with_context(|| {
+                format!(
+                    "cannot replace {}: {}",
+                    self.description,
+                    paths.full_filename.to_string_lossy()
+                )
+            });
+        }
+        // the rename itself is only durable after the folder is synced
+        File::open(&paths.dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("cannot sync {}", paths.dir.to_string_lossy()))
+            .ignore_err();
+        return Ok(());
+    }
+
+    // the contents are on the disk before the file replaces the previous version
+    fn write_synced(filename: &Path, contents: &str) -> std::io::Result<()> {
+        let mut file = File::create(filename)?;
+        file.write_all(contents.as_bytes())?;
+        return file.sync_all();
+    }
+
+    pub fn append(&self, contents: &str) -> Result<()> {
+        let paths = self.paths()?;
+        fs::create_dir_all(&paths.dir).with_context(|| {
+            format!(
+                "cannot create directory for {}: {}",
+                self.description,
+                paths.full_filename.to_string_lossy()
+            )
+        })?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&paths.full_filename)
+            .with_context(|| {
+                format!(
+                    "cannot open {} for appending: {}",
+                    self.description,
+                    paths.full_filename.to_string_lossy()
+                )
+            })?;
+        file.write_all(contents.as_bytes()).with_context(|| {
+            format!(
+                "cannot append to {}: {}",
+                self.description,
+                paths.full_filename.to_string_lossy()
+            )
+        })?;
+        return Ok(());
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        let paths = self.paths()?;
+        fs::remove_file(&paths.full_filename).with_context(|| {
             format!(
-                "cannot write to {}: {}",
+                "cannot remove {}: {}",
                 self.description,
                 paths.full_filename.to_string_lossy()
             )
@@ -98,13 +313,125 @@ impl ProjectFileString {
         let paths = self.paths()?;
         return Ok(&paths.full_filename);
     }
+
+    pub fn exists(&self) -> bool {
+        return self
+            .paths()
+            .is_ok_and(|paths| paths.full_filename.is_file());
+    }
+}
+
+fn move_path(from: &Path, dir: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("cannot create directory {}", dir.to_string_lossy()))?;
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    // e.g. the folders are on different file systems
+    if from.is_dir() {
+        bail!(
+            "cannot move {} to {}",
+            from.to_string_lossy(),
+            dir.to_string_lossy()
+        );
+    }
+    fs::copy(from, to).with_context(|| {
+        format!(
+            "cannot copy {} to {}",
+            from.to_string_lossy(),
+            dir.to_string_lossy()
+        )
+    })?;
+    fs::remove_file(from).with_context(|| format!("cannot remove {}", from.to_string_lossy()))?;
+    return Ok(());
 }
 
 impl ProjectFileJson {
-    pub fn for_data(filename: &str, description: &'static str) -> Self {
+    const fn new(file: ProjectFileString) -> Self {
         return Self {
-            file: ProjectFileString::for_data(filename, description),
+            file,
+            migrations: &[],
+        };
+    }
+
+    pub fn for_data(filename: &str, description: &'static str) -> Self {
+        return Self::new(ProjectFileString::for_data(filename, description));
+    }
+
+    pub fn for_config(filename: &str, description: &'static str) -> Self {
+        return Self::new(ProjectFileString::for_config(filename, description));
+    }
+
+    pub fn for_state(filename: &str, description: &'static str) -> Self {
+        return Self::new(ProjectFileString::for_state(filename, description));
+    }
+
+    pub fn for_cache(filename: &str, description: &'static str) -> Self {
+        return Self::new(ProjectFileString::for_cache(filename, description));
+    }
+
+    pub const fn with_migrations(mut self, migrations: &'static [Migration]) -> Self {
+        self.migrations = migrations;
+        return self;
+    }
+
+    const fn version(&self) -> usize {
+        return self.migrations.len();
+    }
+
+    // the value without its version
+    fn unwrap_versioned(&self, value: Value) -> Result<(Value, usize)> {
+        let Value::Object(mut obj) = value else {
+            return Ok((value, 0));
+        };
+        let Some(version) = obj.remove(VERSION_KEY) else {
+            return Ok((Value::Object(obj), 0));
         };
+        let version = parse_version(&version, self.file.description)?;
+        if obj.len() == 1 {
+            if let Some(data) = obj.remove(DATA_KEY) {
+                return Ok((data, version));
+            }
+        }
+        return Ok((Value::Object(obj), version));
+    }
+
+    fn wrap_versioned(&self, value: Value) -> Value {
+        if self.version() == 0 {
+            return value;
+        }
+        return match value {
+            Value::Object(mut obj) => {
+                obj.insert(VERSION_KEY.to_string(), self.version().into());
+                Value::Object(obj)
+            }
+            value => serde_json::json!({ VERSION_KEY: self.version(), DATA_KEY: value }),
+        };
+    }
+
+    fn check_version(&self, version: usize) -> Result<()> {
+        if version > self.version() {
+            bail!(
+                "{} was saved by a newer version of {} (format version {version})",
+                self.file.description,
+                project_info::title()
+            );
+        }
+        return Ok(());
+    }
+
+    fn migrate(&self, value: Value, version: usize) -> Result<Value> {
+        let mut value = value;
+        self.check_version(version)?;
+        for (from_version, migration) in self.migrations.iter().enumerate().skip(version) {
+            value = migration(value).with_context(|| {
+                format!(
+                    "cannot migrate {} from version {from_version}",
+                    self.file.description
+                )
+            })?;
+        }
+        return Ok(value);
     }
 
     pub fn load<T>(&self) -> Result<T>
@@ -112,7 +439,11 @@ impl ProjectFileJson {
         T: for<'de> Deserialize<'de>,
     {
         let json = self.file.load()?;
-        let result = serde_json::from_str(&json)
+        let value = serde_json::from_str(&json)
+            .with_context(|| format!("cannot parse {}", self.file.description))?;
+        let (value, version) = self.unwrap_versioned(value)?;
+        let value = self.migrate(value, version)?;
+        let result = serde_json::from_value(value)
             .with_context(|| format!("cannot parse {}", self.file.description))?;
         return Ok(result);
     }
@@ -121,9 +452,215 @@ impl ProjectFileJson {
     where
         T: ?Sized + Serialize,
     {
-        let json = serde_json::to_string(obj)
+        let value = serde_json::to_value(obj)
+            .with_context(|| format!("cannot serialize {}", self.file.description))?;
+        let value = self.wrap_versioned(value);
+        let json = serde_json::to_string(&value)
             .with_context(|| format!("cannot serialize {}", self.file.description))?;
         self.file.save(&json)?;
         return Ok(());
     }
+
+    pub fn exists(&self) -> bool {
+        return self.file.exists();
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        return self.file.remove();
+    }
+}
+
+impl ProjectFileJsonLines {
+    pub fn for_data(filename: &str, description: &'static str) -> Self {
+        return Self {
+            file: ProjectFileJson::for_data(filename, description),
+        };
+    }
+
+    pub fn for_state(filename: &str, description: &'static str) -> Self {
+        return Self {
+            file: ProjectFileJson::for_state(filename, description),
+        };
+    }
+
+    pub const fn with_migrations(mut self, migrations: &'static [Migration]) -> Self {
+        self.file.migrations = migrations;
+        return self;
+    }
+
+    fn to_lines<T>(&self, items: &[T], with_version: bool) -> Result<String>
+    where
+        T: Serialize,
+    {
+        let mut lines = String::new();
+        if with_version && self.file.version() > 0 {
+            lines += &serde_json::json!({ VERSION_KEY: self.file.version() }).to_string();
+            lines.push('\n');
+        }
+        for item in items {
+            lines += &serde_json::to_string(item)
+                .with_context(|| format!("cannot serialize {}", self.file.file.description))?;
+            lines.push('\n');
+        }
+        return Ok(lines);
+    }
+
+    // the version line, if it's there
+    fn line_version(&self, value: &Value) -> Result<Option<usize>> {
+        let Value::Object(obj) = value else {
+            return Ok(None);
+        };
+        if obj.len() != 1 {
+            return Ok(None);
+        }
+        return obj
+            .get(VERSION_KEY)
+            .map(|version| return parse_version(version, self.file.file.description))
+            .transpose();
+    }
+
+    // the invalid lines are logged and skipped
+    pub fn load<T>(&self) -> Result<Vec<T>>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let description = self.file.file.description;
+        let text = self.file.file.load()?;
+        let mut lines = text.lines().filter(|line| return !line.trim().is_empty());
+        let mut version = 0;
+        let mut items = Vec::new();
+        let mut first_line = lines.next();
+        if let Some(line) = first_line {
+            if let Ok(value) = serde_json::from_str::<Value>(line) {
+                if let Some(line_version) = self.line_version(&value)? {
+                    version = line_version;
+                    first_line = None;
+                }
+            }
+        }
+        self.file.check_version(version)?;
+        for line in first_line.into_iter().chain(lines) {
+            let item = serde_json::from_str(line)
+                .context("cannot parse")
+                .and_then(|value| return self.file.migrate(value, version))
+                .and_then(|value| return serde_json::from_value(value).context("cannot parse"))
+                .with_context(|| format!("invalid line in {description}: {line}"));
+            if let Some(item) = item.to_option() {
+                items.push(item);
+            }
+        }
+        return Ok(items);
+    }
+
+    pub fn save<T>(&self, items: &[T]) -> Result<()>
+    where
+        T: Serialize,
+    {
+        return self.file.file.save(&self.to_lines(items, true)?);
+    }
+
+    // a new file starts with the version
+    pub fn append<T>(&self, items: &[T]) -> Result<()>
+    where
+        T: Serialize,
+    {
+        let lines = self.to_lines(items, !self.exists())?;
+        return self.file.file.append(&lines);
+    }
+
+    pub fn exists(&self) -> bool {
+        return self.file.exists();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const MIGRATIONS: &[Migration] = &[
+        // 0: the same format
+        Ok,
+        // 1: the numbers are doubled
+        |value| {
+            let Value::Array(items) = value else {
+                bail!("not an array");
+            };
+            return Ok(items
+                .into_iter()
+                .map(|item| return json!(item.as_u64().unwrap_or_default() * 2))
+                .collect());
+        },
+    ];
+
+    fn file() -> ProjectFileJson {
+        return ProjectFileJson::for_cache("test.json", "test file").with_migrations(MIGRATIONS);
+    }
+
+    fn load(value: Value) -> Result<Value> {
+        let file = file();
+        let (value, version) = file.unwrap_versioned(value)?;
+        return file.migrate(value, version);
+    }
+
+    #[test]
+    fn versioned_arrays() {
+        let file = file();
+        let value = file.wrap_versioned(json!([1, 2]));
+        assert_eq!(value, json!({"version": 2, "data": [1, 2]}));
+        assert_eq!(load(value).unwrap(), json!([1, 2]));
+    }
+
+    #[test]
+    fn versioned_objects() {
+        let file = file();
+        let value = file.wrap_versioned(json!({"a": 1}));
+        assert_eq!(value, json!({"a": 1, "version": 2}));
+        assert_eq!(file.unwrap_versioned(value).unwrap(), (json!({"a": 1}), 2));
+
+        // an object that has the data field among others is not unwrapped
+        let value = json!({"version": 2, "data": 1, "b": 2});
+        assert_eq!(
+            file.unwrap_versioned(value).unwrap(),
+            (json!({"data": 1, "b": 2}), 2)
+        );
+    }
+
+    #[test]
+    fn migrations() {
+        assert_eq!(load(json!([1, 2])).unwrap(), json!([2, 4]));
+        assert_eq!(
+            load(json!({"version": 1, "data": [3]})).unwrap(),
+            json!([6])
+        );
+        assert!(load(json!({"version": 3, "data": [1]})).is_err());
+        assert!(load(json!({"version": "x", "data": [1]})).is_err());
+        assert!(load(json!({"a": 1})).is_err());
+    }
+
+    #[test]
+    fn unversioned_files() {
+        let file = ProjectFileJson::for_cache("test.json", "test file");
+        assert_eq!(file.wrap_versioned(json!([1])), json!([1]));
+        assert_eq!(file.wrap_versioned(json!({"a": 1})), json!({"a": 1}));
+    }
+
+    #[test]
+    fn version_lines() {
+        let file =
+            ProjectFileJsonLines::for_data("test.jsonl", "test file").with_migrations(MIGRATIONS);
+        assert_eq!(
+            file.to_lines(&[1, 2], true).unwrap(),
+            "{\"version\":2}\n1\n2\n"
+        );
+        assert_eq!(file.to_lines(&[3], false).unwrap(), "3\n");
+        assert_eq!(file.line_version(&json!({"version": 1})).unwrap(), Some(1));
+        assert_eq!(
+            file.line_version(&json!({"version": 1, "a": 2})).unwrap(),
+            None
+        );
+        assert_eq!(file.line_version(&json!([1])).unwrap(), None);
+        assert!(file.line_version(&json!({"version": -1})).is_err());
+    }
 }
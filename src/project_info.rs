@@ -1,7 +1,7 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::{io::Write, process::Stdio};
+use std::{io::Write, process::Stdio, sync::OnceLock};
 
 use anyhow::{Context, Result};
 
@@ -33,17 +33,50 @@ pub const fn qualifier() -> &'static str {
     return env!("PROJECT_QUALIFIER");
 }
 
-pub fn print_version_info() {
-    println!("version: {}", version());
-    println!("git commit: {}", built::GIT_COMMIT_HASH.unwrap_or_default());
-    println!("build time: {}", built::BUILT_TIME_UTC);
-    println!("rustc version: {}", built::RUSTC_VERSION);
-    println!(
-        "target system: {}-{}",
-        built::CFG_OS,
-        built::CFG_TARGET_ARCH
+static PROFILE: OnceLock<String> = OnceLock::new();
+
+// must be called before anything else, since the data folder depends on it
+pub fn set_profile(profile: &str) {
+    PROFILE.set(profile.to_string()).ok();
+}
+
+pub fn profile() -> Option<&'static str> {
+    return PROFILE.get().map(String::as_str);
+}
+
+// unique for each profile, so the instances of different profiles don't see each other
+pub fn instance_name() -> String {
+    return profile().map_or_else(
+        || name().to_string(),
+        |profile| format!("{}_{profile}", name()),
+    );
+}
+
+pub fn instance_title() -> String {
+    return profile().map_or_else(
+        || title().to_string(),
+        |profile| format!("{} ({profile})", title()),
     );
-    println!("debug: {}", built::DEBUG);
+}
+
+pub fn version_info() -> String {
+    return [
+        format!("version: {}", version()),
+        format!("git commit: {}", built::GIT_COMMIT_HASH.unwrap_or_default()),
+        format!("build time: {}", built::BUILT_TIME_UTC),
+        format!("rustc version: {}", built::RUSTC_VERSION),
+        format!(
+            "target system: {}-{}",
+            built::CFG_OS,
+            built::CFG_TARGET_ARCH
+        ),
+        format!("debug: {}", built::DEBUG),
+    ]
+    .join("\n");
+}
+
+pub fn print_version_info() {
+    println!("{}", version_info());
 }
 
 fn print_readme_via_less() -> Result<()> {
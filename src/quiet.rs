@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::{Local, NaiveTime};
+use dbus::blocking::{stdintf::org_freedesktop_dbus::Properties, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::err_util::IgnoreErr;
+
+// The track announcements are not shown while the user does not want to be disturbed.
+// The tray tooltip is updated anyway.
+const DBUS_TIMEOUT: Duration = Duration::from_millis(500);
+const NOTIFICATIONS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+const TIME_FORMAT: &str = "%H:%M";
+
+// e.g. from 22:00 to 07:00, local time
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QuietHours {
+    pub from: String,
+    pub to: String,
+}
+
+impl QuietHours {
+    fn parse_time(s: &str) -> Result<NaiveTime> {
+        return NaiveTime::parse_from_str(s, TIME_FORMAT)
+            .with_context(|| format!("invalid time in quiet_hours (expected HH:MM): {s}"));
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        Self::parse_time(&self.from)?;
+        Self::parse_time(&self.to)?;
+        return Ok(());
+    }
+
+    fn is_active(&self) -> Result<bool> {
+        return self.is_active_at(Local::now().time());
+    }
+
+    fn is_active_at(&self, now: NaiveTime) -> Result<bool> {
+        let from = Self::parse_time(&self.from)?;
+        let to = Self::parse_time(&self.to)?;
+        if from <= to {
+            return Ok(from <= now && now < to);
+        }
+        // over midnight
+        return Ok(now >= from || now < to);
+    }
+}
+
+// KDE Plasma and dunst tell about it via D-Bus
+fn dbus_dnd() -> Option<bool> {
+    let conn = Connection::new_session().ok()?;
+    let proxy = conn.with_proxy(NOTIFICATIONS_NAME, NOTIFICATIONS_PATH, DBUS_TIMEOUT);
+    if let Ok(inhibited) = proxy.get::<bool>(NOTIFICATIONS_NAME, "Inhibited") {
+        return Some(inhibited);
+    }
+    return proxy.get::<bool>("org.dunstproject.cmd0", "paused").ok();
+}
+
+// GNOME keeps it in the settings
+fn gnome_dnd() -> Option<bool> {
+    let output = Command::new("gsettings")
+        .args(["get", "org.gnome.desktop.notifications", "show-banners"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    return match String::from_utf8_lossy(&output.stdout).trim() {
+        "false" => Some(true),
+        "true" => Some(false),
+        _ => None,
+    };
+}
+
+fn dnd() -> bool {
+    return dbus_dnd().or_else(gnome_dnd).unwrap_or_default();
+}
+
+pub fn is_quiet(quiet_hours: Option<&QuietHours>) -> bool {
+    if quiet_hours.is_some_and(|hours| hours.is_active().ok_or_default()) {
+        return true;
+    }
+    return dnd();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hours(from: &str, to: &str) -> QuietHours {
+        return QuietHours {
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+    }
+
+    fn is_active_at(quiet_hours: &QuietHours, now: &str) -> bool {
+        return quiet_hours
+            .is_active_at(NaiveTime::parse_from_str(now, TIME_FORMAT).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn times() {
+        assert!(hours("00:00", "23:59").validate().is_ok());
+        assert!(hours("7:05", "22:00").validate().is_ok());
+        assert!(hours("24:00", "07:00").validate().is_err());
+        assert!(hours("22:00", "07:60").validate().is_err());
+        assert!(hours("22", "07:00").validate().is_err());
+        assert!(hours("22:00", "").validate().is_err());
+        assert!(hours("10pm", "07:00").validate().is_err());
+    }
+
+    #[test]
+    fn same_day() {
+        let quiet_hours = hours("13:00", "15:30");
+        assert!(!is_active_at(&quiet_hours, "12:59"));
+        assert!(is_active_at(&quiet_hours, "13:00"));
+        assert!(is_active_at(&quiet_hours, "15:29"));
+        assert!(!is_active_at(&quiet_hours, "15:30"));
+        assert!(!is_active_at(&quiet_hours, "00:00"));
+    }
+
+    #[test]
+    fn over_midnight() {
+        let quiet_hours = hours("22:00", "07:00");
+        assert!(!is_active_at(&quiet_hours, "21:59"));
+        assert!(is_active_at(&quiet_hours, "22:00"));
+        assert!(is_active_at(&quiet_hours, "23:59"));
+        assert!(is_active_at(&quiet_hours, "00:00"));
+        assert!(is_active_at(&quiet_hours, "06:59"));
+        assert!(!is_active_at(&quiet_hours, "07:00"));
+        assert!(!is_active_at(&quiet_hours, "12:00"));
+    }
+
+    #[test]
+    fn empty_range() {
+        let quiet_hours = hours("08:00", "08:00");
+        assert!(!is_active_at(&quiet_hours, "08:00"));
+        assert!(!is_active_at(&quiet_hours, "20:00"));
+    }
+
+    #[test]
+    fn invalid_range() {
+        let now = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        assert!(hours("xx", "07:00").is_active_at(now).is_err());
+    }
+}
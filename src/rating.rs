@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use anyhow::{bail, Context, Result};
+use lofty::{
+    config::WriteOptions,
+    file::TaggedFileExt,
+    id3::v2::{FrameFlags, PopularimeterFrame},
+    probe::Probe,
+    tag::{ItemKey, ItemValue, Tag, TagExt, TagItem, TagType},
+};
+
+use crate::stream_base::Track;
+
+pub const MAX_RATING: u8 = 5;
+
+// the same email and values that Windows Media Player uses,
+// since most other players understand them
+const POPM_EMAIL: &str = "Windows Media Player 9 Series";
+const POPM_VALUES: [u8; MAX_RATING as usize] = [1, 64, 128, 196, 255];
+
+const fn rating_from_popm(value: u8) -> Option<u8> {
+    return match value {
+        0 => None,
+        1..=31 => Some(1),
+        32..=95 => Some(2),
+        96..=159 => Some(3),
+        160..=223 => Some(4),
+        _ => Some(5),
+    };
+}
+
+fn rating_from_str(s: &str) -> Option<u8> {
+    let value = s.trim().parse::<u8>().ok()?;
+    if value == 0 {
+        return None;
+    }
+    if value <= MAX_RATING {
+        return Some(value);
+    }
+    // percents
+    return Some(value.min(100).div_ceil(20));
+}
+
+pub fn from_tag(tag: &Tag) -> Option<u8> {
+    let item = tag.get(&ItemKey::Popularimeter)?;
+    return match item.value() {
+        ItemValue::Binary(bytes) => {
+            let popm = PopularimeterFrame::parse(&mut &bytes[..], FrameFlags::default()).ok()?;
+            rating_from_popm(popm.rating)
+        }
+        ItemValue::Text(s) => rating_from_str(s),
+        ItemValue::Locator(_) => None,
+    };
+}
+
+fn rating_item(tag_type: TagType, rating: u8) -> Result<TagItem> {
+    let value = if tag_type == TagType::Id3v2 {
+        let popm =
+            PopularimeterFrame::new(POPM_EMAIL.to_string(), POPM_VALUES[rating as usize - 1], 0);
+        ItemValue::Binary(popm.as_bytes().context("cannot encode POPM frame")?)
+    } else {
+        ItemValue::Text((u16::from(rating) * 20).to_string())
+    };
+    return Ok(TagItem::new(ItemKey::Popularimeter, value));
+}
+
+pub fn write(track: &Track, rating: u8) -> Result<()> {
+    if !(1..=MAX_RATING).contains(&rating) {
+        bail!("invalid rating: {rating}");
+    }
+    if track.index.is_some() {
+        bail!("cannot rate a track inside a CUE sheet");
+    }
+
+    let path = &*track.filename;
+    let file = Probe::open(path)
+        .with_context(|| format!("cannot open {path}"))?
+        .read()
+        .with_context(|| format!("cannot read tags: {path}"))?;
+    let mut tag = file
+        .primary_tag()
+        .cloned()
+        .unwrap_or_else(|| Tag::new(file.primary_tag_type()));
+    let item = rating_item(tag.tag_type(), rating)?;
+    if !tag.insert(item) {
+        bail!("the tag type does not support ratings: {path}");
+    }
+    tag.save_to_path(path, WriteOptions::default())
+        .with_context(|| format!("cannot write tags: {path}"))?;
+    return Ok(());
+}
+
+pub fn stars(rating: u8) -> String {
+    let rating = rating.min(MAX_RATING) as usize;
+    return "★".repeat(rating) + &"☆".repeat(MAX_RATING as usize - rating);
+}
@@ -0,0 +1,42 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{fs::File, io, os::fd::AsRawFd};
+
+use anyhow::{Context, Result};
+
+use crate::{err_util::LogErr, subsonic, thread_util, zip_archive};
+
+fn advise_will_need(filename: &str) -> Result<()> {
+    // the tracks inside an archive are read from it
+    let filename = zip_archive::file_path(filename);
+    let file = File::open(filename).with_context(|| format!("cannot open {filename}"))?;
+    // SAFETY: the descriptor stays valid while the file is open
+    let res = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_WILLNEED) };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res))
+            .with_context(|| format!("posix_fadvise failed for {filename}"));
+    }
+    return Ok(());
+}
+
+// Asks the OS to start reading the files in the background,
+// so a sleeping HDD or a network mount has the data ready when it's needed.
+// A separate thread is used because even the hint may block on a stalled mount.
+pub fn hint(filenames: Vec<String>) {
+    // the remote songs are not files
+    let filenames: Vec<String> = filenames
+        .into_iter()
+        .filter(|filename| !subsonic::is_path(filename))
+        .collect();
+    if filenames.is_empty() {
+        return;
+    }
+    thread_util::thread("readahead", move || {
+        for filename in filenames {
+            if let Err(e) = advise_will_need(&filename) {
+                e.log();
+            }
+        }
+    });
+}
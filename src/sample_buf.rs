@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::collections::VecDeque;
+
+use crate::{
+    decode_ahead::DecodeWaker,
+    stream_base::{Track, TrackMeta},
+};
+
+pub enum BufferMarker {
+    // the first sample of a track, it goes before the meta of that track
+    TrackStart { playlist_index: usize, track: Track },
+    TrackMeta(Box<TrackMeta>),
+}
+
+// Markers are bound to absolute sample indices
+// and are considered reached when the sample at that index is consumed by the output.
+pub struct SampleBuf<T> {
+    samples: VecDeque<T>,
+    consumed: u64,
+    markers: VecDeque<(u64, BufferMarker)>,
+    reached: Vec<BufferMarker>,
+    // the decoding is resumed once the output drains the buffer below this
+    low_watermark: usize,
+    waker: Option<DecodeWaker>,
+}
+
+impl<T> SampleBuf<T> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        return Self {
+            samples: VecDeque::with_capacity(capacity),
+            consumed: 0,
+            markers: VecDeque::new(),
+            reached: Vec::new(),
+            low_watermark: 0,
+            waker: None,
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.samples.len();
+    }
+
+    pub fn set_waker(&mut self, waker: DecodeWaker) {
+        self.waker = Some(waker);
+    }
+
+    pub fn set_low_watermark(&mut self, low_watermark: usize) {
+        self.low_watermark = low_watermark;
+    }
+
+    pub const fn samples(&self) -> &VecDeque<T> {
+        return &self.samples;
+    }
+
+    pub fn samples_mut(&mut self) -> &mut VecDeque<T> {
+        return &mut self.samples;
+    }
+
+    fn write_index(&self) -> u64 {
+        return self.consumed + self.samples.len() as u64;
+    }
+
+    pub fn add_marker(&mut self, marker: BufferMarker) {
+        let index = self.write_index();
+        self.markers.push_back((index, marker));
+    }
+
+    // goes before the other markers of the same sample
+    pub fn add_first_marker(&mut self, marker: BufferMarker) {
+        let index = self.write_index();
+        let pos = self
+            .markers
+            .iter()
+            .position(|(marker_index, _)| *marker_index >= index)
+            .unwrap_or(self.markers.len());
+        self.markers.insert(pos, (index, marker));
+    }
+
+    fn move_reached_markers(&mut self) {
+        while let Some((index, _)) = self.markers.front() {
+            if *index >= self.consumed {
+                break;
+            }
+            if let Some((_, marker)) = self.markers.pop_front() {
+                self.reached.push(marker);
+            }
+        }
+    }
+
+    pub fn consume(&mut self, n: usize) {
+        let n = n.min(self.samples.len());
+        self.samples.drain(0..n);
+        self.consumed += n as u64;
+        self.move_reached_markers();
+        if self.samples.len() < self.low_watermark {
+            if let Some(waker) = &self.waker {
+                waker.wake();
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        // nothing that was written before the pending markers will ever be played
+        self.consumed = self.write_index();
+        self.samples.clear();
+        self.reached
+            .extend(self.markers.drain(..).map(|(_, marker)| marker));
+    }
+
+    pub fn reset(&mut self) {
+        self.consumed = self.write_index();
+        self.samples.clear();
+        self.markers.clear();
+        self.reached.clear();
+    }
+
+    // frees the memory as well, it's allocated again on the next write
+    pub fn release(&mut self) {
+        self.clear();
+        self.samples.shrink_to_fit();
+    }
+
+    pub fn take_reached_markers(&mut self) -> Vec<BufferMarker> {
+        return std::mem::take(&mut self.reached);
+    }
+}
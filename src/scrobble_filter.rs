@@ -0,0 +1,263 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{err_util::IgnoreErr, path_filter::PathFilter, scrobbler::ScrobbleTrack};
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TagField {
+    Artist,
+    Album,
+    Title,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TagRewrite {
+    pub field: TagField,
+    // a regular expression
+    pub pattern: String,
+    // may refer to the groups of the pattern, e.g. "$1"
+    #[serde(default)]
+    pub replacement: String,
+}
+
+// What is not scrobbled and how the tags are changed before scrobbling.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct ScrobbleRules {
+    // regular expressions
+    pub skip_artists: Vec<String>,
+    pub skip_albums: Vec<String>,
+    // same syntax as for scan_exclude
+    pub skip_paths: Vec<String>,
+    // applied in order
+    pub rewrites: Vec<TagRewrite>,
+}
+
+#[derive(Default)]
+pub struct ScrobbleFilter {
+    skip_artists: Vec<Regex>,
+    skip_albums: Vec<Regex>,
+    skip_paths: PathFilter,
+    rewrites: Vec<(TagField, Regex, String)>,
+}
+
+fn compile(pattern: &str) -> Result<Regex> {
+    return Regex::new(pattern).with_context(|| format!("invalid scrobble rule: {pattern}"));
+}
+
+fn compile_all(patterns: &[String]) -> Vec<Regex> {
+    return patterns
+        .iter()
+        .filter_map(|pattern| compile(pattern).to_option())
+        .collect();
+}
+
+impl ScrobbleFilter {
+    // the invalid rules are logged and skipped
+    pub fn new(rules: &ScrobbleRules) -> Self {
+        return Self {
+            skip_artists: compile_all(&rules.skip_artists),
+            skip_albums: compile_all(&rules.skip_albums),
+            skip_paths: PathFilter::new(&rules.skip_paths),
+            rewrites: rules
+                .rewrites
+                .iter()
+                .filter_map(|rewrite| {
+                    let re = compile(&rewrite.pattern).to_option()?;
+                    return Some((rewrite.field, re, rewrite.replacement.clone()));
+                })
+                .collect(),
+        };
+    }
+
+    fn is_skipped(&self, filename: &str, track: &ScrobbleTrack) -> bool {
+        if self.skip_paths.is_excluded(Path::new(filename), false) {
+            return true;
+        }
+        if self
+            .skip_artists
+            .iter()
+            .any(|re| re.is_match(&track.artist))
+        {
+            return true;
+        }
+        if let Some(album) = &track.album {
+            return self.skip_albums.iter().any(|re| re.is_match(album));
+        }
+        return false;
+    }
+
+    // None if the track must not be scrobbled
+    pub fn apply(&self, filename: &str, mut track: ScrobbleTrack) -> Option<ScrobbleTrack> {
+        if self.is_skipped(filename, &track) {
+            return None;
+        }
+        for (field, re, replacement) in &self.rewrites {
+            let value = match field {
+                TagField::Artist => &mut track.artist,
+                TagField::Title => &mut track.title,
+                TagField::Album => match &mut track.album {
+                    Some(album) => album,
+                    None => continue,
+                },
+            };
+            let new_value = re
+                .replace_all(value, replacement.as_str())
+                .trim()
+                .to_string();
+            *value = new_value;
+        }
+        if track.artist.is_empty() || track.title.is_empty() {
+            return None;
+        }
+        if track.album.as_ref().is_some_and(String::is_empty) {
+            track.album = None;
+        }
+        return Some(track);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::stream_base::MusicBrainzIds;
+
+    fn filter(json: &str) -> ScrobbleFilter {
+        let rules: ScrobbleRules = serde_json::from_str(json).unwrap();
+        return ScrobbleFilter::new(&rules);
+    }
+
+    fn track(artist: &str, album: Option<&str>, title: &str) -> ScrobbleTrack {
+        return ScrobbleTrack {
+            artist: artist.to_string(),
+            album: album.map(ToString::to_string),
+            title: title.to_string(),
+            number: None,
+            duration: Duration::from_secs(200),
+            mbids: MusicBrainzIds::default(),
+        };
+    }
+
+    fn fields(track: &ScrobbleTrack) -> (&str, Option<&str>, &str) {
+        return (&track.artist, track.album.as_deref(), &track.title);
+    }
+
+    #[test]
+    fn rules() {
+        let rules: ScrobbleRules = serde_json::from_str(
+            r#"{
+                "skip_artists": ["^Various"],
+                "rewrites": [{"field": "title", "pattern": " \\(.*\\)$"}]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(rules.skip_artists, ["^Various"]);
+        assert!(rules.skip_albums.is_empty());
+        assert!(rules.skip_paths.is_empty());
+        assert!(matches!(rules.rewrites[0].field, TagField::Title));
+        assert_eq!(rules.rewrites[0].pattern, r" \(.*\)$");
+        assert_eq!(rules.rewrites[0].replacement, "");
+
+        let rules: ScrobbleRules = serde_json::from_str("{}").unwrap();
+        assert!(rules.rewrites.is_empty());
+        assert!(serde_json::from_str::<ScrobbleRules>(
+            r#"{"rewrites": [{"field": "year", "pattern": "x"}]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn skipped() {
+        let f = filter(
+            r#"{
+                "skip_artists": ["(?i)^various artists$"],
+                "skip_albums": ["Demos"],
+                "skip_paths": ["*/podcasts/*"]
+            }"#,
+        );
+        assert!(f
+            .apply("/m/a.mp3", track("VARIOUS ARTISTS", None, "x"))
+            .is_none());
+        assert!(f
+            .apply("/m/a.mp3", track("Band", Some("Early Demos"), "x"))
+            .is_none());
+        assert!(f
+            .apply("/m/podcasts/a.mp3", track("Band", None, "x"))
+            .is_none());
+        assert!(f
+            .apply("/m/a.mp3", track("Various Artists Band", Some("LP"), "x"))
+            .is_some());
+        assert!(f.apply("/m/a.mp3", track("Band", None, "x")).is_some());
+    }
+
+    #[test]
+    fn rewrites() {
+        let f = filter(
+            r#"{
+                "rewrites": [
+                    {"field": "title", "pattern": "\\s*\\((Remastered|Live)[^)]*\\)"},
+                    {"field": "artist", "pattern": "^(.+), The$", "replacement": "The $1"},
+                    {"field": "album", "pattern": "\\s*\\[.*\\]"}
+                ]
+            }"#,
+        );
+        let result = f
+            .apply(
+                "/m/a.mp3",
+                track(
+                    "Beatles, The",
+                    Some("Abbey Road [2019 Mix]"),
+                    "Something (Remastered 2009)",
+                ),
+            )
+            .unwrap();
+        assert_eq!(
+            fields(&result),
+            ("The Beatles", Some("Abbey Road"), "Something")
+        );
+
+        let result = f.apply("/m/a.mp3", track("Band", None, "Song")).unwrap();
+        assert_eq!(fields(&result), ("Band", None, "Song"));
+    }
+
+    #[test]
+    fn rewrites_to_empty() {
+        let f = filter(
+            r#"{
+                "rewrites": [
+                    {"field": "album", "pattern": "^Singles$"},
+                    {"field": "title", "pattern": "^Untitled$"}
+                ]
+            }"#,
+        );
+        let result = f
+            .apply("/m/a.mp3", track("Band", Some("Singles"), "Song"))
+            .unwrap();
+        assert_eq!(fields(&result), ("Band", None, "Song"));
+        assert!(f
+            .apply("/m/a.mp3", track("Band", None, "Untitled"))
+            .is_none());
+    }
+
+    #[test]
+    fn invalid_rules_are_skipped() {
+        let f = filter(
+            r#"{
+                "skip_artists": ["(", "^Noise$"],
+                "rewrites": [{"field": "title", "pattern": "["}]
+            }"#,
+        );
+        assert!(f.apply("/m/a.mp3", track("Noise", None, "x")).is_none());
+        let result = f.apply("/m/a.mp3", track("Band", None, "[x]")).unwrap();
+        assert_eq!(fields(&result), ("Band", None, "[x]"));
+    }
+}
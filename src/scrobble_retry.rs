@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+
+use crate::{config::Config, err_util::LogErr, scrobbler};
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(10);
+const MIN_DELAY: Duration = Duration::from_secs(60);
+const MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+const NET_DIR: &str = "/sys/class/net";
+
+// any network interface except the loopback one is up
+fn is_online() -> bool {
+    let Ok(entries) = fs::read_dir(NET_DIR) else {
+        // cannot tell, so the network is never reported as coming up
+        return true;
+    };
+    return entries.filter_map(Result::ok).any(|entry| {
+        if entry.file_name() == "lo" {
+            return false;
+        }
+        let state = fs::read_to_string(entry.path().join("operstate")).unwrap_or_default();
+        return state.trim() == "up";
+    });
+}
+
+// Decides when to retry sending the scrobbles that were not sent before.
+// The delay doubles after each retry while something stays unsent
+// and the retry happens right away when the network comes up.
+pub struct RetrySchedule {
+    delay: Duration,
+    next_retry: Instant,
+    was_online: bool,
+}
+
+impl RetrySchedule {
+    pub fn new() -> Self {
+        return Self {
+            delay: MIN_DELAY,
+            next_retry: Instant::now() + MIN_DELAY,
+            was_online: is_online(),
+        };
+    }
+
+    pub fn is_due(&mut self) -> bool {
+        let is_online = is_online();
+        let came_online = is_online && !self.was_online;
+        self.was_online = is_online;
+        if came_online {
+            self.delay = MIN_DELAY;
+            return true;
+        }
+        return is_online && Instant::now() >= self.next_retry;
+    }
+
+    pub fn retried(&mut self, had_pending: bool) {
+        self.delay = if had_pending {
+            (self.delay * 2).min(MAX_DELAY)
+        } else {
+            MIN_DELAY
+        };
+        self.next_retry = Instant::now() + self.delay;
+    }
+}
+
+fn print_flush_result(name: &str, result: Result<bool>) {
+    match result {
+        Ok(true) => println!("{name}: sending the scrobbles"),
+        Ok(false) => println!("{name}: nothing to send"),
+        Err(e) => e.context(format!("cannot send {name} scrobbles")).log(),
+    }
+}
+
+// used when the app is not running, the scrobblers wait for the requests on drop
+pub fn cli_flush() -> Result<()> {
+    let mut scrobblers = scrobbler::all_useable(&Config::load_or_default().listenbrainz_servers);
+    if scrobblers.is_empty() {
+        bail!("no scrobbling service is authorized");
+    }
+    for scrobbler in &mut scrobblers {
+        print_flush_result(&scrobbler.title(), scrobbler.flush());
+    }
+    return Ok(());
+}
@@ -0,0 +1,254 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crash,
+    err_util::IgnoreErr,
+    lastfm::LastFM,
+    listenbrainz::{ListenBrainz, ListenBrainzServer},
+    project_file::ProjectFileJson,
+    stream_base::{MusicBrainzIds, TrackMeta},
+    thread_util,
+};
+
+// which artist is sent when the track has several of them
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrobbleArtist {
+    // the first one
+    #[default]
+    Primary,
+    // all of them, as displayed (e.g. "A & B")
+    Joined,
+}
+
+impl ScrobbleArtist {
+    pub fn of(self, meta: &TrackMeta) -> Option<&str> {
+        return match self {
+            Self::Primary => meta.primary_artist(),
+            Self::Joined => meta.artist.as_deref(),
+        };
+    }
+}
+
+// the track as it's sent to the scrobbling services
+pub struct ScrobbleTrack {
+    pub artist: String,
+    pub album: Option<String>,
+    pub title: String,
+    pub number: Option<usize>,
+    pub duration: Duration,
+    pub mbids: MusicBrainzIds,
+}
+
+// A scrobbling service.
+// The calls are made from the service's ScrobbleWorker, one after another.
+// They must not wait for the network, the requests are sent in the background.
+pub trait Scrobbler: Send {
+    // for the messages
+    fn title(&self) -> String;
+    fn playing_now(&mut self, track: &ScrobbleTrack) -> Result<()>;
+    fn scrobble(&mut self, track: &ScrobbleTrack) -> Result<()>;
+    fn love(&mut self, artist: &str, title: &str) -> Result<()>;
+    // sends the scrobbles that were not sent before (e.g. while offline);
+    // returns false if there was nothing to send
+    fn flush(&mut self) -> Result<bool>;
+    // stores the requests that are not confirmed yet, so they are not lost on exit
+    fn save_pending(&self);
+}
+
+// all the services that are authorized
+pub fn all_useable(listenbrainz_servers: &[ListenBrainzServer]) -> Vec<Box<dyn Scrobbler>> {
+    let mut scrobblers: Vec<Box<dyn Scrobbler>> = Vec::new();
+    for listenbrainz in ListenBrainz::all_useable(listenbrainz_servers) {
+        scrobblers.push(Box::new(listenbrainz));
+    }
+    for lastfm in LastFM::all_useable() {
+        scrobblers.push(Box::new(lastfm));
+    }
+    return scrobblers;
+}
+
+// whether some requests are left from the previous runs, without creating the scrobblers
+pub fn has_pending(listenbrainz_servers: &[ListenBrainzServer]) -> bool {
+    return ListenBrainz::has_pending(listenbrainz_servers) || LastFM::has_pending();
+}
+
+// whether the file of a PendingQueue has some items, without loading the queue
+pub fn has_stored_items(file: &ProjectFileJson) -> bool {
+    return file.exists()
+        && file
+            .load::<Vec<serde_json::Value>>()
+            .is_ok_and(|items| return !items.is_empty());
+}
+
+// The background thread for the API calls of a single service.
+// The calls are performed one after another, so they are never reordered.
+#[derive(Default)]
+pub struct ApiThread {
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ApiThread {
+    pub fn run<F>(&mut self, name: &str, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.wait();
+        self.handle = Some(thread_util::thread(name, f));
+    }
+
+    pub fn wait(&mut self) {
+        if let Some(t) = self.handle.take() {
+            t.join().to_anyhow().ignore_err();
+        }
+    }
+}
+
+impl Drop for ApiThread {
+    fn drop(&mut self) {
+        self.wait();
+    }
+}
+
+type ScrobbleJob = Box<dyn FnOnce(&mut dyn Scrobbler) + Send>;
+
+// A long-lived thread that owns a single service.
+// The calls are queued, so the caller never waits for the previous ones to finish.
+pub struct ScrobbleWorker {
+    tx: Sender<ScrobbleJob>,
+    handle: JoinHandle<()>,
+}
+
+impl ScrobbleWorker {
+    pub fn start(mut scrobbler: Box<dyn Scrobbler>) -> Self {
+        let (tx, rx) = mpsc::channel::<ScrobbleJob>();
+        let name = format!("{} calls", scrobbler.title());
+        let handle = thread_util::thread(&name, move || {
+            while let Ok(job) = rx.recv() {
+                job(scrobbler.as_mut());
+            }
+            // the last request is waited for when the scrobbler is dropped
+            scrobbler.save_pending();
+        });
+        return Self { tx, handle };
+    }
+
+    pub fn send<F>(&self, job: F)
+    where
+        F: FnOnce(&mut dyn Scrobbler) + Send + 'static,
+    {
+        self.tx.send(Box::new(job)).ok();
+    }
+
+    // the queued calls are still performed, the thread exits after them
+    pub fn close(self) -> JoinHandle<()> {
+        drop(self.tx);
+        return self.handle;
+    }
+}
+
+// The requests that were not sent yet.
+// They are kept in a file, so they are not lost between the runs.
+pub struct PendingQueue<T> {
+    items: Arc<Mutex<Vec<T>>>,
+    file: Arc<ProjectFileJson>,
+}
+
+impl<T> Clone for PendingQueue<T> {
+    fn clone(&self) -> Self {
+        return Self {
+            items: self.items.clone(),
+            file: self.file.clone(),
+        };
+    }
+}
+
+impl<T> PendingQueue<T>
+where
+    T: Serialize + for<'de> Deserialize<'de> + Clone + Send + 'static,
+{
+    pub fn load(file: ProjectFileJson) -> Self {
+        let items = if file.exists() {
+            file.load().ok_or(Vec::new)
+        } else {
+            Vec::new()
+        };
+        let queue = Self {
+            items: Arc::new(Mutex::new(items)),
+            file: Arc::new(file),
+        };
+        // the items are pushed before they are sent, so a crash must not lose them
+        let items = Arc::downgrade(&queue.items);
+        let file = queue.file.clone();
+        crash::on_crash(move || {
+            if let Some(items) = items.upgrade() {
+                if let Ok(items) = items.try_lock() {
+                    if !items.is_empty() {
+                        file.save(&*items).ignore_err();
+                    }
+                }
+            }
+        });
+        return queue;
+    }
+
+    // saved right away, so the item is not lost if the request hangs until the exit
+    pub fn push(&self, item: T) {
+        let mut items = self.items.lock().unwrap();
+        items.push(item);
+        self.save_locked(&items);
+        drop(items);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.items.lock().unwrap().is_empty();
+    }
+
+    // the most recent items
+    pub fn last(&self, n: usize) -> Vec<T> {
+        let items = self.items.lock().unwrap();
+        return items[items.len().saturating_sub(n)..].to_vec();
+    }
+
+    pub fn all(&self) -> Vec<T> {
+        return self.items.lock().unwrap().clone();
+    }
+
+    pub fn contains(&self, f: impl Fn(&T) -> bool) -> bool {
+        return self.items.lock().unwrap().iter().any(f);
+    }
+
+    // forgets the items that were sent
+    pub fn remove(&self, f: impl Fn(&T) -> bool) {
+        let mut items = self.items.lock().unwrap();
+        items.retain(|item| !f(item));
+        // saved under the lock, so the file always has the latest state
+        self.save_locked(&items);
+        drop(items);
+    }
+
+    pub fn save(&self) {
+        let items = self.items.lock().unwrap();
+        self.save_locked(&items);
+    }
+
+    // the file is not created just to store nothing
+    fn save_locked(&self, items: &[T]) {
+        if !items.is_empty() || self.file.exists() {
+            self.file.save(items).ignore_err();
+        }
+    }
+}
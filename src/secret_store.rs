@@ -0,0 +1,143 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{collections::HashMap, sync::OnceLock};
+
+use anyhow::{bail, Context, Result};
+use dbus_secret_service::{EncryptionType, SecretService};
+
+use crate::{
+    config::Config,
+    err_util::{println_with_date, IgnoreErr},
+    project_file::ProjectFileString,
+    project_info,
+};
+
+const CONTENT_TYPE: &str = "text/plain";
+
+static USE_KEYRING: OnceLock<bool> = OnceLock::new();
+
+fn use_keyring() -> bool {
+    return *USE_KEYRING.get_or_init(|| Config::load_or_default().use_keyring);
+}
+
+// A secret (e.g. a session key) that is kept either in a file in the data folder
+// or in the system keyring (Secret Service), depending on the configuration.
+// The secrets from the files are moved to the keyring once it's enabled.
+pub struct SecretStore {
+    name: String,
+    label: String,
+    file: ProjectFileString,
+}
+
+impl SecretStore {
+    // the file name is the name of the secret in the keyring
+    pub fn for_data(filename: &str, label: &str, file_description: &'static str) -> Self {
+        return Self {
+            name: filename.to_string(),
+            label: label.to_string(),
+            file: ProjectFileString::for_data(filename, file_description),
+        };
+    }
+
+    fn attributes(&self) -> HashMap<&str, &str> {
+        return HashMap::from([
+            ("application", project_info::name()),
+            ("profile", project_info::profile().unwrap_or_default()),
+            ("secret", self.name.as_str()),
+        ]);
+    }
+
+    fn connect() -> Result<SecretService> {
+        return SecretService::connect(EncryptionType::Plain)
+            .context("cannot connect to the system keyring");
+    }
+
+    fn load_from_keyring(&self) -> Result<Option<String>> {
+        let service = Self::connect()?;
+        let items = service
+            .search_items(self.attributes())
+            .context("cannot search the system keyring")?;
+        let Some(item) = items.unlocked.first().or_else(|| items.locked.first()) else {
+            return Ok(None);
+        };
+        item.ensure_unlocked()
+            .context("cannot unlock the system keyring")?;
+        let secret = item
+            .get_secret()
+            .context("cannot read the secret from the system keyring")?;
+        let secret = String::from_utf8(secret).context("invalid secret in the system keyring")?;
+        return Ok(Some(secret));
+    }
+
+    fn save_to_keyring(&self, secret: &str) -> Result<()> {
+        let service = Self::connect()?;
+        let collection = service
+            .get_default_collection()
+            .context("no default collection in the system keyring")?;
+        collection
+            .ensure_unlocked()
+            .context("cannot unlock the system keyring")?;
+        let label = format!("{} {}", project_info::instance_title(), self.label);
+        collection
+            .create_item(
+                &label,
+                self.attributes(),
+                secret.as_bytes(),
+                true,
+                CONTENT_TYPE,
+            )
+            .context("cannot save the secret to the system keyring")?;
+        return Ok(());
+    }
+
+    fn migrate(&self) -> Result<Option<String>> {
+        if !self.file.exists() {
+            return Ok(None);
+        }
+        let secret = self.file.load()?;
+        self.save_to_keyring(&secret)?;
+        self.file.remove()?;
+        println_with_date(format!("{} moved to the system keyring", self.label));
+        return Ok(Some(secret));
+    }
+
+    pub fn load(&self) -> Result<String> {
+        if !use_keyring() {
+            return self.file.load();
+        }
+        if let Some(secret) = self.load_from_keyring()? {
+            return Ok(secret);
+        }
+        if let Some(secret) = self.migrate()? {
+            return Ok(secret);
+        }
+        bail!("no {} in the system keyring", self.label);
+    }
+
+    pub fn save(&self, secret: &str) -> Result<()> {
+        if !use_keyring() {
+            return self.file.save(secret);
+        }
+        self.save_to_keyring(secret)?;
+        // the old copy must not outlive the new one
+        if self.file.exists() {
+            self.file.remove().ignore_err();
+        }
+        return Ok(());
+    }
+
+    // for the messages about how to revoke the access
+    pub fn location(&self) -> String {
+        if use_keyring() {
+            return format!(
+                "in the system keyring ({}). Remove it from there",
+                self.label
+            );
+        }
+        return match self.file.filename() {
+            Ok(filename) => format!("at {}. Remove this file", filename.display()),
+            Err(e) => e.to_string(),
+        };
+    }
+}
@@ -0,0 +1,273 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use url::form_urlencoded;
+
+use crate::{app_state::AppState, config::Config, playlist_man, stream_base::Track};
+
+const URI_PREFIX: &str = "share://";
+const VERSION: u32 = 1;
+
+// either "<root name>:<relative path>" or an absolute path
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SharedTrack {
+    File(String),
+    CueTrack(String, usize),
+}
+
+// The queue in a form that can be reproduced on another machine
+// that has the same library roots (possibly at other locations).
+#[derive(Serialize, Deserialize)]
+struct Share {
+    v: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    i: Option<usize>,
+    t: Vec<SharedTrack>,
+}
+
+fn relativize(filename: &str, roots: &HashMap<String, String>) -> String {
+    let path = Path::new(filename);
+    // the longest root wins, so nested roots work as expected
+    let mut roots: Vec<(&String, &String)> = roots.iter().collect();
+    roots.sort_by_key(|(_, dir)| std::cmp::Reverse(dir.len()));
+    for (name, dir) in roots {
+        if let Ok(relative) = path.strip_prefix(dir) {
+            return format!("{name}:{}", relative.to_string_lossy());
+        }
+    }
+    return filename.to_string();
+}
+
+fn resolve(shared: &str, roots: &HashMap<String, String>) -> Result<String> {
+    if shared.starts_with('/') {
+        return Ok(shared.to_string());
+    }
+    let (name, relative) = shared
+        .split_once(':')
+        .with_context(|| format!("invalid shared path: {shared}"))?;
+    let dir = roots
+        .get(name)
+        .with_context(|| format!("the library root is not configured: {name}"))?;
+    return Ok(PathBuf::from(dir)
+        .join(relative)
+        .to_string_lossy()
+        .to_string());
+}
+
+fn to_uri(share: &Share) -> Result<String> {
+    let json = serde_json::to_string(share).context("cannot serialize the queue")?;
+    let encoded: String = form_urlencoded::byte_serialize(json.as_bytes()).collect();
+    return Ok(format!("{URI_PREFIX}{encoded}"));
+}
+
+fn from_uri_or_file(source: &str) -> Result<Share> {
+    let json = if let Some(encoded) = source.strip_prefix(URI_PREFIX) {
+        // the rest of the URI is a form-encoded value
+        form_urlencoded::parse(format!("={encoded}").as_bytes())
+            .map(|(_, value)| value.to_string())
+            .next()
+            .unwrap_or_default()
+    } else {
+        let text = fs::read_to_string(source).with_context(|| format!("cannot read {source}"))?;
+        let text = text.trim();
+        if text.starts_with(URI_PREFIX) {
+            return from_uri_or_file(text);
+        }
+        text.to_string()
+    };
+    let share: Share = serde_json::from_str(&json).context("cannot parse the shared queue")?;
+    if share.v > VERSION {
+        bail!("the shared queue was made by a newer version");
+    }
+    return Ok(share);
+}
+
+fn to_share(
+    tracks: &[Track],
+    playlist_index: Option<usize>,
+    roots: &HashMap<String, String>,
+) -> Share {
+    return Share {
+        v: VERSION,
+        i: playlist_index,
+        t: tracks
+            .iter()
+            .map(|track| {
+                let path = relativize(&track.filename, roots);
+                return match track.index {
+                    Some(index) => SharedTrack::CueTrack(path, index),
+                    None => SharedTrack::File(path),
+                };
+            })
+            .collect(),
+    };
+}
+
+fn from_share(
+    share: &Share,
+    roots: &HashMap<String, String>,
+) -> Result<(Vec<Track>, Option<usize>)> {
+    let tracks = share
+        .t
+        .iter()
+        .map(|shared| {
+            let (path, index) = match shared {
+                SharedTrack::File(path) => (path, None),
+                SharedTrack::CueTrack(path, index) => (path, Some(*index)),
+            };
+            return Ok(Track {
+                filename: resolve(path, roots)?.into(),
+                index,
+            });
+        })
+        .collect::<Result<Vec<Track>>>()?;
+    if tracks.is_empty() {
+        bail!("the shared queue is empty");
+    }
+    let playlist_index = share.i.filter(|i| *i < tracks.len());
+    return Ok((tracks, playlist_index));
+}
+
+// prints the URI of the stored queue and optionally saves it to a file
+pub fn cli_share(output: Option<&str>) -> Result<()> {
+    let roots = Config::load_or_default().library_roots;
+    let tracks = playlist_man::load_playlist().context("cannot load the playlist")?;
+    if tracks.is_empty() {
+        bail!("the playlist is empty");
+    }
+    let share = to_share(&tracks, AppState::load_or_default().playlist_index, &roots);
+    let uri = to_uri(&share)?;
+    if let Some(output) = output {
+        fs::write(output, format!("{uri}\n")).with_context(|| format!("cannot write {output}"))?;
+    }
+    println!("{uri}");
+    return Ok(());
+}
+
+// returns the tracks and the index of the current track
+pub fn open(source: &str) -> Result<(Vec<Track>, Option<usize>)> {
+    let roots = Config::load_or_default().library_roots;
+    let share = from_uri_or_file(source)?;
+    return from_share(&share, &roots);
+}
+
+// used when the app is not running, the queue is picked up on startup
+pub fn open_as_stored(source: &str) -> Result<()> {
+    let (tracks, playlist_index) = open(source)?;
+    let mut state = AppState::load_or_default();
+    playlist_man::backup_playlist(state.playlist_index).context("cannot backup the playlist")?;
+    playlist_man::save_playlist(&tracks).context("cannot save the playlist")?;
+    state.playlist_index = playlist_index;
+    state.save().context("cannot save the state")?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roots(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        return pairs
+            .iter()
+            .map(|&(name, dir)| return (name.to_string(), dir.to_string()))
+            .collect();
+    }
+
+    fn track(filename: &str, index: Option<usize>) -> Track {
+        return Track {
+            filename: filename.into(),
+            index,
+        };
+    }
+
+    fn pairs(tracks: &[Track]) -> Vec<(&str, Option<usize>)> {
+        return tracks
+            .iter()
+            .map(|track| return (&*track.filename, track.index))
+            .collect();
+    }
+
+    fn round_trip(
+        tracks: &[Track],
+        playlist_index: Option<usize>,
+        from_roots: &HashMap<String, String>,
+        to_roots: &HashMap<String, String>,
+    ) -> (Vec<Track>, Option<usize>) {
+        let uri = to_uri(&to_share(tracks, playlist_index, from_roots)).unwrap();
+        assert!(uri.starts_with(URI_PREFIX));
+        let share = from_uri_or_file(&uri).unwrap();
+        return from_share(&share, to_roots).unwrap();
+    }
+
+    #[test]
+    fn same_roots() {
+        let roots = roots(&[("music", "/home/a/Music")]);
+        let tracks = [
+            track("/home/a/Music/Band/01 - Intro & Outro.flac", None),
+            track("/home/a/Music/Band/album.cue", Some(3)),
+            track("/mnt/other/100% Ünïcødé?#+.mp3", None),
+        ];
+        let (shared_tracks, index) = round_trip(&tracks, Some(1), &roots, &roots);
+        assert_eq!(pairs(&shared_tracks), pairs(&tracks));
+        assert_eq!(index, Some(1));
+    }
+
+    #[test]
+    fn other_roots() {
+        let from_roots = roots(&[("music", "/home/a/Music"), ("rock", "/home/a/Music/Rock")]);
+        let to_roots = roots(&[("music", "/srv/music"), ("rock", "/mnt/rock")]);
+        let tracks = [
+            track("/home/a/Music/Jazz/a.mp3", None),
+            track("/home/a/Music/Rock/b.mp3", None),
+        ];
+        let (shared_tracks, index) = round_trip(&tracks, None, &from_roots, &to_roots);
+        assert_eq!(
+            pairs(&shared_tracks),
+            [("/srv/music/Jazz/a.mp3", None), ("/mnt/rock/b.mp3", None)]
+        );
+        assert_eq!(index, None);
+    }
+
+    #[test]
+    fn relative_paths() {
+        let roots = roots(&[("music", "/home/a/Music"), ("rock", "/home/a/Music/Rock")]);
+        assert_eq!(relativize("/home/a/Music/Rock/b.mp3", &roots), "rock:b.mp3");
+        assert_eq!(
+            relativize("/home/a/Musical/b.mp3", &roots),
+            "/home/a/Musical/b.mp3"
+        );
+        assert_eq!(
+            resolve("rock:b.mp3", &roots).unwrap(),
+            "/home/a/Music/Rock/b.mp3"
+        );
+        assert!(resolve("jazz:b.mp3", &roots).is_err());
+        assert!(resolve("b.mp3", &roots).is_err());
+    }
+
+    #[test]
+    fn invalid_uris() {
+        let roots = roots(&[]);
+        assert!(from_uri_or_file("share://").is_err());
+        assert!(from_uri_or_file("share://%7B%7D").is_err());
+
+        let share = from_uri_or_file("share://%7B%22v%22%3A1%2C%22t%22%3A%5B%5D%7D").unwrap();
+        assert!(from_share(&share, &roots).is_err());
+
+        let share = from_uri_or_file(
+            "share://%7B%22v%22%3A1%2C%22i%22%3A5%2C%22t%22%3A%5B%22%2Fa.mp3%22%5D%7D",
+        )
+        .unwrap();
+        assert_eq!(from_share(&share, &roots).unwrap().1, None);
+
+        assert!(from_uri_or_file("share://%7B%22v%22%3A99%2C%22t%22%3A%5B%5D%7D").is_err());
+    }
+}
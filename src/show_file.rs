@@ -1,18 +1,55 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::time::Duration;
+use std::{
+    fs::File,
+    os::fd::IntoRawFd,
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
 
 use anyhow::{bail, Context, Result};
-use dbus::blocking::Connection;
+use dbus::{arg::PropMap, blocking::Connection};
 use url::Url;
 
+use crate::err_util::debugln_with_date;
+
+const DBUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The file manager is asked to select the file.
+// Without a file manager service (e.g. in a minimal WM setup)
+// the desktop portal or xdg-open just open the folder.
 pub fn show_file(path: &str) -> Result<()> {
-    return run_method(path, "ShowItems");
+    return run_method(path, "ShowItems")
+        .or_else(|e| {
+            debugln_with_date(format!("{e:#}, trying the desktop portal"));
+            return open_directory_via_portal(path);
+        })
+        .or_else(|e| {
+            debugln_with_date(format!("{e:#}, trying xdg-open"));
+            let dir = Path::new(path).parent().unwrap_or_else(|| Path::new("/"));
+            return xdg_open(dir);
+        });
 }
 
 pub fn open_folder(path: &str) -> Result<()> {
-    return run_method(path, "ShowFolders");
+    return run_method(path, "ShowFolders")
+        .or_else(|e| {
+            debugln_with_date(format!("{e:#}, trying the desktop portal"));
+            return open_uri_via_portal(path);
+        })
+        .or_else(|e| {
+            debugln_with_date(format!("{e:#}, trying xdg-open"));
+            return xdg_open(Path::new(path));
+        });
+}
+
+fn file_url(path: &str) -> Result<Url> {
+    return match Url::from_file_path(path) {
+        Ok(url) => Ok(url),
+        Err(()) => bail!("can't transform a path into URL: {}", path),
+    };
 }
 
 fn run_method(path: &str, method: &str) -> Result<()> {
@@ -20,16 +57,52 @@ fn run_method(path: &str, method: &str) -> Result<()> {
     let proxy = conn.with_proxy(
         "org.freedesktop.FileManager1",
         "/org/freedesktop/FileManager1",
-        Duration::from_millis(5000),
+        DBUS_TIMEOUT,
     );
-    match Url::from_file_path(path) {
-        Ok(url) => {
-            let url_str = url.as_str();
-            let _: () = proxy
-                .method_call("org.freedesktop.FileManager1", method, (vec![url_str], ""))
-                .with_context(|| format!("failed to call D-Bus method {method} on {url_str}"))?;
-        }
-        Err(()) => bail!("can't transform a path into URL: {}", path),
+    let url = file_url(path)?;
+    let url_str = url.as_str();
+    let _: () = proxy
+        .method_call("org.freedesktop.FileManager1", method, (vec![url_str], ""))
+        .with_context(|| format!("failed to call D-Bus method {method} on {url_str}"))?;
+    return Ok(());
+}
+
+fn portal_call<A: dbus::arg::AppendAll>(method: &str, args: A) -> Result<()> {
+    let conn = Connection::new_session().context("cannot create D-Bus session")?;
+    let proxy = conn.with_proxy(
+        "org.freedesktop.portal.Desktop",
+        "/org/freedesktop/portal/desktop",
+        DBUS_TIMEOUT,
+    );
+    let _: (dbus::Path,) = proxy
+        .method_call("org.freedesktop.portal.OpenURI", method, args)
+        .with_context(|| format!("failed to call the portal method {method}"))?;
+    return Ok(());
+}
+
+// the folder that contains the file
+fn open_directory_via_portal(path: &str) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("cannot open {path}"))?;
+    let fd = unsafe { dbus::arg::OwnedFd::new(file.into_raw_fd()) };
+    return portal_call("OpenDirectory", ("", fd, PropMap::new()));
+}
+
+fn open_uri_via_portal(path: &str) -> Result<()> {
+    let url = file_url(path)?;
+    return portal_call("OpenURI", ("", url.as_str(), PropMap::new()));
+}
+
+// xdg-open exits as soon as the application is started
+fn xdg_open(path: &Path) -> Result<()> {
+    let status = Command::new("xdg-open")
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .context("cannot run xdg-open")?;
+    if !status.success() {
+        bail!("xdg-open failed ({status}): {}", path.display());
     }
     return Ok(());
 }
@@ -0,0 +1,103 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::process;
+
+use serde::{Deserialize, Serialize};
+use signal_hook::{
+    consts::{SIGINT, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2},
+    iterator::Signals,
+};
+
+use crate::{
+    config::Config,
+    err_util::{eprintln_with_date, LogErr},
+    hotkeys::HotKeyAction,
+    thread_util,
+};
+
+// What SIGUSR1/SIGUSR2 do, e.g. for "pkill -USR1 konik" in the window manager keybindings.
+// Also what running konik without arguments does when it's already running.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SignalAction {
+    PauseToggle,
+    StopPlay,
+    Next,
+    Prev,
+    NextDir,
+    PrevDir,
+    RandomDir,
+    Love,
+    Off,
+}
+
+impl SignalAction {
+    pub const fn hotkey_action(self) -> Option<HotKeyAction> {
+        return match self {
+            Self::PauseToggle => Some(HotKeyAction::PauseToggle),
+            Self::StopPlay => Some(HotKeyAction::StopPlay),
+            Self::Next => Some(HotKeyAction::Next),
+            Self::Prev => Some(HotKeyAction::Prev),
+            Self::NextDir => Some(HotKeyAction::NextDir),
+            Self::PrevDir => Some(HotKeyAction::PrevDir),
+            Self::RandomDir => Some(HotKeyAction::RandomDir),
+            Self::Love => Some(HotKeyAction::Love),
+            Self::Off => None,
+        };
+    }
+}
+
+pub fn listen<Q, A>(on_quit: Q, on_action: A)
+where
+    Q: FnOnce() + Send + 'static,
+    A: Fn(HotKeyAction) + Send + 'static,
+{
+    let config = Config::load_or_default();
+    let usr1 = config
+        .signal_usr1
+        .unwrap_or(SignalAction::PauseToggle)
+        .hotkey_action();
+    let usr2 = config
+        .signal_usr2
+        .unwrap_or(SignalAction::Next)
+        .hotkey_action();
+
+    match Signals::new([SIGINT, SIGTERM, SIGQUIT, SIGUSR1, SIGUSR2]) {
+        Ok(mut signals) => {
+            thread_util::thread("signal listener", move || {
+                let mut on_quit = Some(on_quit);
+                let mut sigint_sent = false;
+                for sig in signals.forever() {
+                    match sig {
+                        SIGUSR1 => {
+                            if let Some(action) = usr1 {
+                                on_action(action);
+                            }
+                        }
+                        SIGUSR2 => {
+                            if let Some(action) = usr2 {
+                                on_action(action);
+                            }
+                        }
+                        _ => {
+                            if sig == SIGINT {
+                                // not a panic, so it's not reported as a crash
+                                if sigint_sent {
+                                    eprintln_with_date("force quit");
+                                    process::exit(1);
+                                }
+                                sigint_sent = true;
+                                print!("\r  \r\n"); // hide ^C
+                            }
+                            if let Some(on_quit) = on_quit.take() {
+                                on_quit();
+                            }
+                        }
+                    }
+                }
+            });
+        }
+        Err(e) => e.log(),
+    }
+}
@@ -0,0 +1,192 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Sender},
+        Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chapters::Chapter,
+    err_util::{println_with_date, IgnoreErr},
+    project_file::{Migration, ProjectFileJson},
+    stream_man, thread_util,
+};
+
+// about -50 dBFS
+const SILENCE_THRESHOLD: f32 = 0.003;
+const MIN_SILENCE: Duration = Duration::from_secs(2);
+const MIN_TRACK: Duration = Duration::from_secs(30);
+
+static DIRS: OnceLock<Vec<PathBuf>> = OnceLock::new();
+static CACHE: OnceLock<Mutex<Cache>> = OnceLock::new();
+static ANALYZER: OnceLock<Sender<(String, SystemTime)>> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CacheEntry {
+    mtime: SystemTime,
+    starts_ms: Vec<u64>,
+}
+
+struct Cache {
+    entries: HashMap<String, CacheEntry>,
+    // the files that wait for the analysis
+    queued: HashSet<String>,
+}
+
+const CACHE_MIGRATIONS: &[Migration] = &[
+    // 0: the cache from before the versioning has the same entries
+    Ok,
+];
+
+fn cache_file() -> ProjectFileJson {
+    return ProjectFileJson::for_cache("silence_cache.json", "silence analysis cache")
+        .with_migrations(CACHE_MIGRATIONS);
+}
+
+// the file is loaded once, and the lock is only held while the entries are accessed
+fn with_cache<T>(f: impl FnOnce(&mut Cache) -> T) -> T {
+    let cache = CACHE.get_or_init(|| {
+        let file = cache_file();
+        let entries = if file.exists() {
+            file.load().ok_or_default()
+        } else {
+            HashMap::new()
+        };
+        return Mutex::new(Cache {
+            entries,
+            queued: HashSet::new(),
+        });
+    });
+    let mut guard = cache.lock().unwrap();
+    let result = f(&mut guard);
+    drop(guard);
+    return result;
+}
+
+// the files inside these folders are split into tracks by the silence gaps
+pub fn init(dirs: &[String]) {
+    DIRS.set(dirs.iter().map(PathBuf::from).collect()).ok();
+}
+
+pub fn is_enabled_for(filename: &str) -> bool {
+    let Some(dirs) = DIRS.get() else {
+        return false;
+    };
+    let path = Path::new(filename);
+    return dirs.iter().any(|dir| path.starts_with(dir)) && stream_man::is_path_supported(filename);
+}
+
+fn rms(samples: &VecDeque<f32>) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let len = samples.len() as f32;
+    return (samples.iter().map(|x| x * x).sum::<f32>() / len).sqrt();
+}
+
+// the start of every track, each track starts in the middle of a silence gap
+fn analyze(filename: &str) -> Result<Vec<Duration>> {
+    let mut stream = stream_man::open(filename)?;
+    let mut starts = vec![Duration::ZERO];
+    let mut silence_start = None;
+    let mut samples = VecDeque::new();
+    while let Some(packet_meta) = stream.read_packet()? {
+        let Some(position) = packet_meta.position else {
+            continue;
+        };
+        samples.clear();
+        stream.write(&mut samples)?;
+        if rms(&samples) < SILENCE_THRESHOLD {
+            silence_start.get_or_insert(position);
+            continue;
+        }
+        if let Some(silence_start) = silence_start.take() {
+            let silence_len = position.saturating_sub(silence_start);
+            let boundary = silence_start + silence_len / 2;
+            let last_start = starts.last().copied().unwrap_or_default();
+            if silence_len >= MIN_SILENCE && boundary.saturating_sub(last_start) >= MIN_TRACK {
+                starts.push(boundary);
+            }
+        }
+    }
+    return Ok(starts);
+}
+
+// the only thread that saves the cache file
+fn analyze_and_cache(filename: &str, mtime: SystemTime) {
+    let starts_ms = analyze(filename)
+        .with_context(|| format!("cannot analyze silence: {filename}"))
+        .to_option()
+        .map(|starts| {
+            return starts
+                .iter()
+                .map(|start| u64::try_from(start.as_millis()).unwrap_or(u64::MAX))
+                .collect();
+        });
+    let entries = with_cache(|cache| {
+        cache.queued.remove(filename);
+        let starts_ms = starts_ms?;
+        cache
+            .entries
+            .insert(filename.to_string(), CacheEntry { mtime, starts_ms });
+        return Some(cache.entries.clone());
+    });
+    if let Some(entries) = entries {
+        cache_file().save(&entries).ignore_err();
+        println_with_date(format!("silence analyzed: {filename}"));
+    }
+}
+
+fn queue_analysis(filename: &str, mtime: SystemTime) {
+    let is_new = with_cache(|cache| return cache.queued.insert(filename.to_string()));
+    if !is_new {
+        return;
+    }
+    let analyzer = ANALYZER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<(String, SystemTime)>();
+        thread_util::thread("silence analysis", move || {
+            for (filename, mtime) in rx {
+                analyze_and_cache(&filename, mtime);
+            }
+        });
+        return tx;
+    });
+    analyzer.send((filename.to_string(), mtime)).ok();
+}
+
+// The analysis needs to decode the whole file, so it's done in the background
+// and the results are cached. Until then the file is a single track.
+pub fn read(filename: &str) -> Result<Vec<Chapter>> {
+    let mtime = fs::metadata(filename)
+        .and_then(|meta| meta.modified())
+        .with_context(|| format!("cannot get modification time: {filename}"))?;
+    let cached = with_cache(|cache| {
+        return cache
+            .entries
+            .get(filename)
+            .filter(|entry| entry.mtime == mtime)
+            .map(|entry| entry.starts_ms.clone());
+    });
+    let Some(starts_ms) = cached else {
+        queue_analysis(filename, mtime);
+        return Ok(Vec::new());
+    };
+    return Ok(starts_ms
+        .into_iter()
+        .map(|start_ms| Chapter {
+            start: Duration::from_millis(start_ms),
+            title: None,
+        })
+        .collect());
+}
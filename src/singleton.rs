@@ -3,10 +3,13 @@
 
 use anyhow::{Context, Result};
 use fd_lock::RwLock;
-use interprocess::local_socket::{
-    traits::{ListenerExt, Stream as StreamTrait},
-    GenericFilePath, GenericNamespaced, ListenerOptions, Name, NameType, Stream, ToFsName,
-    ToNsName,
+use interprocess::{
+    local_socket::{
+        traits::{ListenerExt, Stream as StreamTrait},
+        GenericFilePath, GenericNamespaced, Listener, ListenerOptions, Name, NameType, Stream,
+        ToFsName, ToNsName,
+    },
+    os::unix::uds_local_socket,
 };
 use serde::{Deserialize, Serialize};
 use std::{
@@ -16,30 +19,42 @@ use std::{
     io::Write,
     io::{self, BufRead, BufReader},
     marker::PhantomData,
+    os::fd::OwnedFd,
     path::PathBuf,
+    sync::mpsc,
     thread::JoinHandle,
+    time::Duration,
 };
 
 use crate::err_util::{IgnoreErr, LogErr};
 use crate::thread_util;
 
-pub struct Singleton<T>
+// the running instance may need a while, e.g. to scan the given folders
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// T is sent by the new process to the running one, R is sent back
+pub struct Singleton<T, R>
 where
     T: for<'de> Deserialize<'de> + Serialize + Sync + Send,
+    R: for<'de> Deserialize<'de> + Serialize + Send + 'static,
 {
     flock: Option<RwLock<File>>,
     flock_filename: PathBuf,
     name: String,
-    phantom_data: PhantomData<T>,
+    // the socket that was opened by systemd
+    activated_socket: Option<OwnedFd>,
+    phantom_data: PhantomData<(T, R)>,
 }
 
-impl<T> Singleton<T>
+impl<T, R> Singleton<T, R>
 where
     T: for<'de> Deserialize<'de> + Serialize + Sync + Send,
+    R: for<'de> Deserialize<'de> + Serialize + Send + 'static,
 {
-    pub fn new<F>(name: &str, pass_func: F) -> Result<Option<Self>>
+    pub fn new<F, H>(name: &str, pass_func: F, on_reply: H) -> Result<Option<Self>>
     where
         F: FnOnce() -> Option<T>,
+        H: FnOnce(R),
     {
         let sock_name = Self::sock_name(name).context("cannot get socket name")?;
 
@@ -50,6 +65,9 @@ where
                 let json =
                     serde_json::to_string(&send_data).context("cannot serialize singleton data")?;
                 writeln!(buf.get_mut(), "{json}").context("socket send failed")?;
+                if let Some(reply) = Self::wait_for_reply(buf) {
+                    on_reply(reply);
+                }
             }
             return Ok(None);
         }
@@ -61,11 +79,25 @@ where
             flock: Some(flock),
             flock_filename,
             name: name.to_string(),
+            activated_socket: None,
             phantom_data: PhantomData {},
         }));
     }
 
-    fn sock_name(name: &str) -> Result<Name> {
+    // the socket already exists, so connecting to it would only reach this process itself
+    pub fn from_activated(name: &str, socket: OwnedFd) -> Result<Self> {
+        let (flock, flock_filename) =
+            Self::create_lock_file(name).context("cannot create lock file")?;
+        return Ok(Self {
+            flock: Some(flock),
+            flock_filename,
+            name: name.to_string(),
+            activated_socket: Some(socket),
+            phantom_data: PhantomData {},
+        });
+    }
+
+    fn sock_name(name: &str) -> Result<Name<'_>> {
         let sock_name = if GenericNamespaced::is_supported() {
             name.to_ns_name::<GenericNamespaced>()?
         } else {
@@ -87,17 +119,33 @@ where
             .create(true)
             .truncate(true)
             .open(&filename)
-            .with_context(|| format!("cannot open {filename:?}"))?;
+            .with_context(|| format!("cannot open {}", filename.display()))?;
         let mut file = RwLock::new(file);
-        let mut write_file = file
-            .write()
-            .with_context(|| format!("cannot open lock file for writing: {filename:?}"))?;
+        let mut write_file = file.write().with_context(|| {
+            format!("cannot open lock file for writing: {}", filename.display())
+        })?;
         write!(write_file, "{}", &name)?;
         drop(write_file);
         return Ok((file, filename));
     }
 
-    fn process_connection(stream_result: io::Result<Stream>) -> Result<T> {
+    // the older versions of the running instance close the connection without a reply,
+    // and a stuck instance must not hang this process
+    fn wait_for_reply(mut buf: BufReader<Stream>) -> Option<R> {
+        let (tx, rx) = mpsc::channel();
+        thread_util::thread("singleton reply", move || {
+            let mut json = String::default();
+            if buf.read_line(&mut json).is_ok() {
+                tx.send(serde_json::from_str::<R>(&json).ok()).ok();
+            }
+        });
+        return rx.recv_timeout(REPLY_TIMEOUT).ok().flatten();
+    }
+
+    fn process_connection<F>(stream_result: io::Result<Stream>, on_data: &F) -> Result<()>
+    where
+        F: Fn(T) -> R,
+    {
         let stream = stream_result.context("failed to get incoming connection")?;
         let mut buf = BufReader::new(stream);
         let mut json = String::default();
@@ -105,21 +153,27 @@ where
             .context("cannot read socket buffer")?;
         let data =
             serde_json::from_str::<T>(&json).context("cannot parse incoming socket buffer")?;
-        return Ok(data);
+        let reply = serde_json::to_string(&on_data(data)).context("cannot serialize reply")?;
+        // the older versions of the sender do not wait for the reply
+        writeln!(buf.get_mut(), "{reply}").ok();
+        return Ok(());
     }
 
-    pub fn listen<F>(self, on_data: F) -> Result<JoinHandle<()>>
+    pub fn listen<F>(mut self, on_data: F) -> Result<JoinHandle<()>>
     where
-        F: Fn(T) + Clone + Sync + Send + 'static,
+        F: Fn(T) -> R + Clone + Sync + Send + 'static,
     {
-        let sock_name = Self::sock_name(&self.name)?;
-        let opts = ListenerOptions::new().name(sock_name);
-        let listener = opts.create_sync().context("cannot bind to local socket")?;
+        let listener = if let Some(socket) = self.activated_socket.take() {
+            Listener::from(uds_local_socket::Listener::from(socket))
+        } else {
+            let sock_name = Self::sock_name(&self.name)?;
+            let opts = ListenerOptions::new().name(sock_name);
+            opts.create_sync().context("cannot bind to local socket")?
+        };
         let t = thread_util::thread("singleton server", move || {
             for stream_result in listener.incoming() {
-                match Self::process_connection(stream_result) {
-                    Ok(data) => on_data(data),
-                    Err(e) => e.context("cannot process incoming connection").log(),
+                if let Err(e) = Self::process_connection(stream_result, &on_data) {
+                    e.context("cannot process incoming connection").log();
                 }
             }
         });
@@ -127,15 +181,16 @@ where
     }
 }
 
-impl<T> Drop for Singleton<T>
+impl<T, R> Drop for Singleton<T, R>
 where
     T: for<'de> Deserialize<'de> + Serialize + Sync + Send,
+    R: for<'de> Deserialize<'de> + Serialize + Send + 'static,
 {
     fn drop(&mut self) {
         if let Some(flock) = self.flock.take() {
             drop(flock);
             fs::remove_file(&self.flock_filename)
-                .with_context(|| format!("cannot remove file: {:?}", self.flock_filename))
+                .with_context(|| format!("cannot remove file: {}", self.flock_filename.display()))
                 .ignore_err();
         }
     }
@@ -0,0 +1,432 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cue::CueFactory,
+    err_util::{IgnoreErr, LogErr},
+    history,
+    stream_base::{Track, TrackMeta},
+    stream_man,
+};
+
+pub const PATH_PREFIX: &str = "smart:";
+
+const SECS_PER_DAY: u64 = 60 * 60 * 24;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SmartPlaylist {
+    pub paths: Vec<String>,
+    pub query: String,
+}
+
+#[derive(Clone, Copy)]
+enum Field {
+    Artist,
+    Album,
+    Title,
+    Genre,
+    Path,
+    Year,
+    Track,
+    Rating,
+    Played,
+    Added,
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Contains,
+}
+
+struct Condition {
+    field: Field,
+    op: Op,
+    value: String,
+}
+
+struct Query {
+    conditions: Vec<Condition>,
+}
+
+struct TrackInfo<'a> {
+    track: &'a Track,
+    meta: Option<TrackMeta>,
+    play_count: usize,
+    age_days: Option<u64>,
+}
+
+impl FromStr for Field {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let field = match s.to_lowercase().as_str() {
+            "artist" => Self::Artist,
+            "album" => Self::Album,
+            "title" => Self::Title,
+            "genre" => Self::Genre,
+            "path" => Self::Path,
+            "year" => Self::Year,
+            "track" => Self::Track,
+            "rating" => Self::Rating,
+            "played" => Self::Played,
+            "added" => Self::Added,
+            _ => bail!("unknown field: {s}"),
+        };
+        return Ok(field);
+    }
+}
+
+impl Field {
+    const fn is_numeric(self) -> bool {
+        return matches!(
+            self,
+            Self::Year | Self::Track | Self::Rating | Self::Played | Self::Added
+        );
+    }
+
+    const fn needs_meta(self) -> bool {
+        return matches!(
+            self,
+            Self::Artist
+                | Self::Album
+                | Self::Title
+                | Self::Genre
+                | Self::Year
+                | Self::Track
+                | Self::Rating
+        );
+    }
+}
+
+impl FromStr for Op {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let op = match s {
+            "=" => Self::Eq,
+            "!=" => Self::Ne,
+            ">" => Self::Gt,
+            ">=" => Self::Ge,
+            "<" => Self::Lt,
+            "<=" => Self::Le,
+            "~" => Self::Contains,
+            _ => bail!("unknown operator: {s}"),
+        };
+        return Ok(op);
+    }
+}
+
+impl Op {
+    fn matches_ordering(self, ordering: Ordering) -> bool {
+        return match self {
+            Self::Eq => ordering.is_eq(),
+            Self::Ne => ordering.is_ne(),
+            Self::Gt => ordering.is_gt(),
+            Self::Ge => ordering.is_ge(),
+            Self::Lt => ordering.is_lt(),
+            Self::Le => ordering.is_le(),
+            Self::Contains => false,
+        };
+    }
+}
+
+impl FromStr for Condition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.trim().eq_ignore_ascii_case("never played") {
+            return Ok(Self {
+                field: Field::Played,
+                op: Op::Eq,
+                value: "0".to_string(),
+            });
+        }
+
+        let rx = Regex::new(r"^\s*([A-Za-z_]+)\s*(>=|<=|!=|=|>|<|~)\s*(.*?)\s*$").unwrap();
+        let caps = rx
+            .captures(s)
+            .with_context(|| format!("invalid condition: {s}"))?;
+        let field: Field = caps[1].parse()?;
+        let op: Op = caps[2].parse()?;
+        let mut value = caps[3].to_string();
+        if value.len() > 1 && value.starts_with('"') && value.ends_with('"') {
+            value = value[1..value.len() - 1].to_string();
+        } else if value.is_empty() {
+            // an empty string must be quoted, so a forgotten value is not matched silently
+            bail!("no value: {s}");
+        }
+        if field.is_numeric() {
+            if matches!(op, Op::Contains) {
+                bail!("\"~\" cannot be used with numeric fields: {s}");
+            }
+            value
+                .parse::<u64>()
+                .with_context(|| format!("not a number: {value}"))?;
+        }
+        return Ok(Self { field, op, value });
+    }
+}
+
+impl Condition {
+    fn matches_str(&self, s: Option<&str>) -> bool {
+        if let Some(s) = s {
+            let s = s.to_lowercase();
+            let value = self.value.to_lowercase();
+            if matches!(self.op, Op::Contains) {
+                return s.contains(&value);
+            }
+            return self.op.matches_ordering(s.cmp(&value));
+        }
+        return matches!(self.op, Op::Ne);
+    }
+
+    fn matches_num(&self, n: Option<u64>) -> bool {
+        if let (Some(n), Ok(value)) = (n, self.value.parse::<u64>()) {
+            return self.op.matches_ordering(n.cmp(&value));
+        }
+        return false;
+    }
+
+    fn matches(&self, info: &TrackInfo) -> bool {
+        let meta = info.meta.as_ref();
+        return match self.field {
+            Field::Artist => self.matches_str(meta.and_then(|m| m.artist.as_deref())),
+            Field::Album => self.matches_str(meta.and_then(|m| m.album.as_deref())),
+            Field::Title => self.matches_str(meta.and_then(|m| m.title.as_deref())),
+            Field::Genre => self.matches_str(meta.and_then(|m| m.genre.as_deref())),
+            Field::Path => self.matches_str(Some(&info.track.filename)),
+            Field::Year => self.matches_num(meta.and_then(|m| m.year).map(|x| x as u64)),
+            Field::Track => self.matches_num(meta.and_then(|m| m.track).map(|x| x as u64)),
+            // unrated tracks are treated as rated with zero stars
+            Field::Rating => self.matches_num(Some(u64::from(
+                meta.and_then(|m| m.rating).unwrap_or_default(),
+            ))),
+            Field::Played => self.matches_num(Some(info.play_count as u64)),
+            Field::Added => self.matches_num(info.age_days),
+        };
+    }
+}
+
+impl FromStr for Query {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let rx = Regex::new(r"(?i)\s+AND\s+").unwrap();
+        let s = s.trim();
+        let mut conditions = Vec::new();
+        let mut start = 0;
+        for m in rx.find_iter(s) {
+            // "AND" inside a quoted value is a part of the value
+            if s[start..m.start()].matches('"').count() % 2 == 1 {
+                continue;
+            }
+            conditions.push(&s[start..m.start()]);
+            start = m.end();
+        }
+        conditions.push(&s[start..]);
+        let conditions = conditions
+            .into_iter()
+            .filter(|s| !s.trim().is_empty())
+            .map(Condition::from_str)
+            .collect::<Result<Vec<Condition>>>()?;
+        return Ok(Self { conditions });
+    }
+}
+
+impl Query {
+    fn needs_meta(&self) -> bool {
+        return self.conditions.iter().any(|c| c.field.needs_meta());
+    }
+
+    fn matches(&self, info: &TrackInfo) -> bool {
+        return self.conditions.iter().all(|c| c.matches(info));
+    }
+}
+
+fn file_age_days(filename: &str) -> Option<u64> {
+    let modified = fs::metadata(filename)
+        .and_then(|m| m.modified())
+        .to_option()?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or(Duration::ZERO);
+    return Some(age.as_secs() / SECS_PER_DAY);
+}
+
+pub fn filter_tracks(
+    name: &str,
+    query_str: &str,
+    tracks: Vec<Track>,
+    cue_factory: &mut CueFactory,
+) -> Vec<Track> {
+    let query = match Query::from_str(query_str) {
+        Ok(query) => query,
+        Err(e) => {
+            e.log_context(format!("invalid query for smart playlist \"{name}\""));
+            return Vec::new();
+        }
+    };
+
+    let mut play_counts: HashMap<(String, Option<usize>), usize> = HashMap::new();
+    for item in history::load().ok_or_default() {
+        *play_counts.entry((item.filename, item.index)).or_default() += 1;
+    }
+
+    let needs_meta = query.needs_meta();
+    return tracks
+        .into_iter()
+        .filter(|track| {
+            let info = TrackInfo {
+                track,
+                meta: if needs_meta {
+                    stream_man::read_track_meta(track, cue_factory)
+                } else {
+                    None
+                },
+                play_count: play_counts
+                    .get(&(track.filename.to_string(), track.index))
+                    .copied()
+                    .unwrap_or_default(),
+                age_days: file_age_days(&track.filename),
+            };
+            query.matches(&info)
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(artist: &str, title: &str, year: usize) -> TrackMeta {
+        return TrackMeta {
+            artist: Some(artist.to_string()),
+            title: Some(title.to_string()),
+            year: Some(year),
+            ..TrackMeta::default()
+        };
+    }
+
+    fn matches(query: &str, meta: TrackMeta, play_count: usize) -> bool {
+        let track = Track {
+            filename: "/music/live/01.flac".into(),
+            index: None,
+        };
+        let info = TrackInfo {
+            track: &track,
+            meta: Some(meta),
+            play_count,
+            age_days: Some(10),
+        };
+        return Query::from_str(query).unwrap().matches(&info);
+    }
+
+    fn is_valid(query: &str) -> bool {
+        return Query::from_str(query).is_ok();
+    }
+
+    #[test]
+    fn operators() {
+        let m = || meta("Artist", "Title", 2010);
+        assert!(matches("year=2010", m(), 0));
+        assert!(matches("year!=2011", m(), 0));
+        assert!(matches("year>2009", m(), 0));
+        assert!(!matches("year>2010", m(), 0));
+        assert!(matches("year>=2010", m(), 0));
+        assert!(matches("year<2011", m(), 0));
+        assert!(!matches("year<2010", m(), 0));
+        assert!(matches("year<=2010", m(), 0));
+        assert!(matches("title~itl", m(), 0));
+        assert!(matches("path~LIVE", m(), 0));
+        assert!(matches("added<=30", m(), 0));
+    }
+
+    #[test]
+    fn longer_operators_first() {
+        // not "year >" with the value "=2010"
+        let condition = Condition::from_str("year >= 2010").unwrap();
+        assert!(matches!(condition.op, Op::Ge));
+        assert_eq!(condition.value, "2010");
+        let condition = Condition::from_str("artist!=x").unwrap();
+        assert!(matches!(condition.op, Op::Ne));
+        assert_eq!(condition.value, "x");
+    }
+
+    #[test]
+    fn and() {
+        let m = || meta("Artist", "Title", 2010);
+        assert!(matches("artist=artist AND year>=2010", m(), 0));
+        assert!(matches("artist=artist and year>=2010", m(), 0));
+        assert!(!matches("artist=artist AND year>2010", m(), 0));
+        assert!(!matches("artist=other AND year>=2010", m(), 0));
+    }
+
+    #[test]
+    fn never_played() {
+        let m = || meta("Artist", "Title", 2010);
+        assert!(matches("never played", m(), 0));
+        assert!(matches("NEVER PLAYED AND year=2010", m(), 0));
+        assert!(!matches("never played", m(), 1));
+    }
+
+    #[test]
+    fn quoting() {
+        let m = || meta("Artist", "Rock AND Roll", 2010);
+        assert!(matches("title=\"Rock AND Roll\"", m(), 0));
+        assert!(matches("title=\"rock and roll\" AND year=2010", m(), 0));
+        assert!(matches("title~\" and \"", m(), 0));
+        assert!(!matches("title=\"Rock\"", m(), 0));
+        let condition = Condition::from_str("artist = \" spaced \"").unwrap();
+        assert_eq!(condition.value, " spaced ");
+        // unbalanced quotes are kept as is
+        let condition = Condition::from_str("artist=\"").unwrap();
+        assert_eq!(condition.value, "\"");
+    }
+
+    #[test]
+    fn invalid_fields() {
+        assert!(!is_valid("composer=Bach"));
+        assert!(!is_valid("year=2010 AND composer=Bach"));
+        assert!(!is_valid("=Bach"));
+        assert!(!is_valid("artist Bach"));
+        assert!(!is_valid("year~201"));
+        assert!(!is_valid("year=recent"));
+        assert!(!is_valid("played=-1"));
+        assert!(is_valid("ARTIST=Bach"));
+    }
+
+    #[test]
+    fn empty_values() {
+        assert!(!is_valid("artist="));
+        assert!(!is_valid("artist =  "));
+        assert!(!is_valid("year="));
+        assert!(!is_valid("year=\"\""));
+        assert!(is_valid(""));
+        assert!(matches("", meta("Artist", "Title", 2010), 0));
+
+        let mut no_album = meta("Artist", "Title", 2010);
+        no_album.album = Some(String::new());
+        assert!(matches("album=\"\"", no_album, 0));
+        // a missing tag is not equal to anything
+        assert!(!matches("album=\"\"", meta("Artist", "Title", 2010), 0));
+        assert!(matches("album!=x", meta("Artist", "Title", 2010), 0));
+    }
+}
@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    env, fs, process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use dbus::blocking::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    err_util::{eprintln_with_date, IgnoreErr},
+    popup::Popup,
+    project_file::ProjectFileJson,
+    project_info,
+};
+
+const SNI_WATCHER: &str = "org.kde.StatusNotifierWatcher";
+const NOTIFICATIONS: &str = "org.freedesktop.Notifications";
+
+#[derive(Serialize, Deserialize)]
+struct MissingSubsystem {
+    name: String,
+    reason: String,
+}
+
+// The optional parts that could not be started.
+// They are reported all at once instead of being scattered across the log.
+#[derive(Serialize, Deserialize)]
+pub struct StartupReport {
+    pid: u32,
+    started_at: u64,
+    missing: Vec<MissingSubsystem>,
+    // e.g. that the previous run has crashed
+    #[serde(default)]
+    notes: Vec<String>,
+}
+
+fn file() -> ProjectFileJson {
+    return ProjectFileJson::for_state("status.json", "startup report");
+}
+
+fn dbus_name_has_owner(name: &str) -> Result<bool> {
+    let conn = Connection::new_session().context("no D-Bus session")?;
+    let proxy = conn.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        Duration::from_secs(5),
+    );
+    let (has_owner,): (bool,) = proxy
+        .method_call("org.freedesktop.DBus", "NameHasOwner", (name,))
+        .context("cannot query D-Bus")?;
+    return Ok(has_owner);
+}
+
+// the tray icon is only shown if some panel hosts the StatusNotifierItems
+pub fn check_tray() -> Result<()> {
+    if !dbus_name_has_owner(SNI_WATCHER)? {
+        bail!("no SNI host");
+    }
+    return Ok(());
+}
+
+pub fn check_notifications() -> Result<()> {
+    if !dbus_name_has_owner(NOTIFICATIONS)? {
+        bail!("no notification server");
+    }
+    return Ok(());
+}
+
+impl StartupReport {
+    pub fn new() -> Self {
+        return Self {
+            pid: process::id(),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            missing: Vec::new(),
+            notes: Vec::new(),
+        };
+    }
+
+    // the innermost cause is the most telling one
+    pub fn check<T>(&mut self, name: &str, result: Result<T>) -> Option<T> {
+        return match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.missing.push(MissingSubsystem {
+                    name: name.to_string(),
+                    reason: e.root_cause().to_string(),
+                });
+                None
+            }
+        };
+    }
+
+    pub fn note(&mut self, note: String) {
+        self.notes.push(note);
+    }
+
+    fn summary(&self) -> Option<String> {
+        let mut lines = self.notes.clone();
+        if !self.missing.is_empty() {
+            let items: Vec<String> = self
+                .missing
+                .iter()
+                .map(|item| format!("{} ({})", item.name, item.reason))
+                .collect();
+            lines.push(format!("running without: {}", items.join(", ")));
+        }
+        if lines.is_empty() {
+            return None;
+        }
+        return Some(lines.join("\n"));
+    }
+
+    // saved for "konik status"
+    pub fn publish(&self, popup: &Popup) {
+        file().save(self).ignore_err();
+        if let Some(summary) = self.summary() {
+            eprintln_with_date(&summary);
+            popup.show(&summary);
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        // the PID may have been reused by another program
+        let exe = fs::read_link(format!("/proc/{}/exe", self.pid)).ok();
+        return exe.is_some() && exe == env::current_exe().ok();
+    }
+
+    fn started_at_str(&self) -> String {
+        return chrono::DateTime::from_timestamp(self.started_at as i64, 0)
+            .map(|d| {
+                d.with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_default();
+    }
+}
+
+pub fn cli_print() {
+    let title = project_info::instance_title();
+    let report = file();
+    let report: Option<StartupReport> = if report.exists() {
+        report.load().to_option()
+    } else {
+        None
+    };
+    let Some(report) = report.filter(StartupReport::is_running) else {
+        println!("{title} is not running");
+        return;
+    };
+    println!(
+        "{title} is running (PID {}, started at {})",
+        report.pid,
+        report.started_at_str()
+    );
+    println!(
+        "{}",
+        report
+            .summary()
+            .unwrap_or_else(|| "all subsystems are running".to_string())
+    );
+}
@@ -3,18 +3,32 @@
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::{collections::VecDeque, path::Path, time::Duration};
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display},
+    io,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Track {
-    pub filename: String,
+    // shared, so cloning a track does not copy the path
+    pub filename: Arc<str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub index: Option<usize>,
 }
 
-#[derive(Default, Clone)]
+// the tag values like "A; B" hold several artists
+const ARTIST_SEPARATORS: [char; 2] = [';', '\0'];
+
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct TrackMeta {
+    // all the artists joined for display
     pub artist: Option<String>,
+    #[serde(default)]
+    pub artists: Vec<String>,
     pub album: Option<String>,
     pub title: Option<String>,
     pub track: Option<usize>,
@@ -22,7 +36,112 @@ pub struct TrackMeta {
     pub disc: Option<usize>,
     pub disc_total: Option<usize>,
     pub year: Option<usize>,
+    pub genre: Option<String>,
+    pub rating: Option<u8>,
+    #[serde(default)]
+    pub mbids: MusicBrainzIds,
     pub duration: Duration,
+    #[serde(default)]
+    pub comment: Option<String>,
+    #[serde(default)]
+    pub replay_gain: ReplayGain,
+}
+
+// "A", "A & B", "A, B & C"
+fn join_artists(artists: &[String]) -> Option<String> {
+    return match artists {
+        [] => None,
+        [artist] => Some(artist.clone()),
+        [rest @ .., last] => Some(format!("{} & {last}", rest.join(", "))),
+    };
+}
+
+impl TrackMeta {
+    pub fn set_artists<'a>(&mut self, values: impl IntoIterator<Item = &'a str>) {
+        let mut artists: Vec<String> = Vec::new();
+        for artist in values
+            .into_iter()
+            .flat_map(|value| value.split(ARTIST_SEPARATORS))
+        {
+            let artist = artist.trim();
+            if !artist.is_empty() && !artists.iter().any(|a| a == artist) {
+                artists.push(artist.to_string());
+            }
+        }
+        self.artist = join_artists(&artists);
+        self.artists = artists;
+    }
+
+    // the first of the listed artists
+    pub fn primary_artist(&self) -> Option<&str> {
+        return self
+            .artists
+            .first()
+            .map(String::as_str)
+            .or(self.artist.as_deref());
+    }
+}
+
+// the IDs that link the track to the MusicBrainz database
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct MusicBrainzIds {
+    pub recording: Option<String>,
+    pub release: Option<String>,
+    pub artist: Option<String>,
+}
+
+// the values of the REPLAYGAIN_* tags: the gains are in dB, the peaks are the sample amplitudes
+#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    // "-6.48 dB" or "0.988547"
+    pub fn parse_value(s: &str) -> Option<f32> {
+        let value: f32 = s
+            .trim()
+            .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+            .trim()
+            .parse()
+            .ok()?;
+        return value.is_finite().then_some(value);
+    }
+
+    // the values that are missing here are taken from the other one
+    pub fn or(self, other: Self) -> Self {
+        return Self {
+            track_gain: self.track_gain.or(other.track_gain),
+            track_peak: self.track_peak.or(other.track_peak),
+            album_gain: self.album_gain.or(other.album_gain),
+            album_peak: self.album_peak.or(other.album_peak),
+        };
+    }
+}
+
+// the file was deleted or moved while it was played
+#[derive(Debug)]
+pub struct FileMissing;
+
+impl Display for FileMissing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "the file is missing");
+    }
+}
+
+impl std::error::Error for FileMissing {}
+
+// either the file has disappeared during the playback or it was not there at all
+pub fn is_file_missing(e: &anyhow::Error) -> bool {
+    return e.downcast_ref::<FileMissing>().is_some()
+        || e.chain().any(|cause| {
+            return cause
+                .downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::NotFound);
+        });
 }
 
 pub struct StreamPacketMeta {
@@ -39,7 +158,8 @@ pub trait Stream: Sync + Send {
     fn is_path_supported(path: &str) -> bool
     where
         Self: Sized;
-    fn read_packet(&mut self) -> Result<StreamPacketMeta>;
+    // returns None at the end of the stream
+    fn read_packet(&mut self) -> Result<Option<StreamPacketMeta>>;
     fn write(&mut self, data: &mut VecDeque<f32>) -> Result<usize>;
     fn seek(&mut self, pos: Duration) -> Result<Duration>;
 }
@@ -1,7 +1,16 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use crate::{err_util::LogErr, stream_base::Stream, symphonia_stream::SymphoniaStream};
+#[cfg(feature = "gme")]
+use crate::gme_stream::GmeStream;
+use crate::{
+    chapters::Chapter,
+    cue::CueFactory,
+    err_util::{IgnoreErr, LogErr},
+    stream_base::{Stream, Track, TrackMeta},
+    subsonic,
+    symphonia_stream::SymphoniaStream,
+};
 use anyhow::{bail, Result};
 
 fn open_stream<T: Stream + 'static>(path: &str) -> Option<Box<dyn Stream>> {
@@ -22,6 +31,10 @@ pub fn is_path_supported(path: &str) -> bool {
     if SymphoniaStream::is_path_supported(path) {
         return true;
     }
+    #[cfg(feature = "gme")]
+    if GmeStream::is_path_supported(path) {
+        return true;
+    }
     return false;
 }
 
@@ -29,6 +42,53 @@ pub fn open(path: &str) -> Result<Box<dyn Stream>> {
     if let Some(stream) = open_stream::<SymphoniaStream>(path) {
         return Ok(stream);
     }
+    #[cfg(feature = "gme")]
+    if let Some(stream) = open_stream::<GmeStream>(path) {
+        return Ok(stream);
+    }
 
     bail!("file not supported: {}", path);
 }
+
+pub fn read_meta(path: &str) -> Option<TrackMeta> {
+    // the tags are taken from the server, so the song is not downloaded
+    if subsonic::is_song_path(path) {
+        return subsonic::read_meta(path);
+    }
+    if SymphoniaStream::is_path_supported(path) {
+        return SymphoniaStream::get_lofty_meta(path);
+    }
+    #[cfg(feature = "gme")]
+    if GmeStream::is_path_supported(path) {
+        return GmeStream::read_meta(path);
+    }
+    return None;
+}
+
+// the files with several songs inside (e.g. game music), they are exposed as chapters
+#[cfg_attr(not(feature = "gme"), allow(unused_variables))]
+pub fn has_subtunes(path: &str) -> bool {
+    #[cfg(feature = "gme")]
+    if GmeStream::is_path_supported(path) {
+        return true;
+    }
+    return false;
+}
+
+pub fn read_subtunes(path: &str) -> Result<Vec<Chapter>> {
+    #[cfg(feature = "gme")]
+    if GmeStream::is_path_supported(path) {
+        return GmeStream::read_chapters(path);
+    }
+    bail!("no subtunes in {path}");
+}
+
+// also works for the tracks inside CUE sheets
+pub fn read_track_meta(track: &Track, cue_factory: &mut CueFactory) -> Option<TrackMeta> {
+    if let Some(index) = track.index {
+        let sheet = cue_factory.get_or_new(&track.filename).to_option()??;
+        let file_meta = read_meta(&sheet.source_filename).unwrap_or_default();
+        return sheet.track_meta(index, &file_meta).to_option();
+    }
+    return read_meta(&track.filename);
+}
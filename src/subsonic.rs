@@ -0,0 +1,368 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    io::Read,
+    iter,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    cli::SubsonicCommand,
+    config::Config,
+    err_util::{IgnoreErr, LogErr},
+    http, project_info,
+    stream_base::{Track, TrackMeta},
+    thread_util,
+};
+
+// The tracks on a Subsonic-compatible server (e.g. Navidrome) are addressed by virtual paths:
+// subsonic:<song ID> for a song and subsonic:album:<album ID> for all songs of an album.
+const SCHEME: &str = "subsonic:";
+const ALBUM_PREFIX: &str = "album:";
+const API_VERSION: &str = "1.16.1";
+const SALT_LEN: usize = 12;
+const SEARCH_LIMIT: usize = 50;
+const MAX_SONG_SIZE: u64 = 1024 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SubsonicServer {
+    // the part of the API URLs before "/rest/"
+    pub url: String,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    code: i32,
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Status {
+    status: String,
+    error: Option<ApiError>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Song {
+    id: String,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    track: Option<usize>,
+    disc_number: Option<usize>,
+    year: Option<usize>,
+    genre: Option<String>,
+    duration: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct SongResponse {
+    song: Song,
+}
+
+#[derive(Deserialize)]
+struct Album {
+    id: String,
+    name: String,
+    artist: Option<String>,
+    year: Option<usize>,
+    #[serde(default)]
+    song: Vec<Song>,
+}
+
+#[derive(Deserialize)]
+struct AlbumResponse {
+    album: Album,
+}
+
+#[derive(Deserialize)]
+struct Artist {
+    id: String,
+    name: String,
+    #[serde(default)]
+    album: Vec<Album>,
+}
+
+#[derive(Deserialize)]
+struct ArtistResponse {
+    artist: Artist,
+}
+
+#[derive(Deserialize)]
+struct ArtistIndex {
+    #[serde(default)]
+    artist: Vec<Artist>,
+}
+
+#[derive(Deserialize)]
+struct ArtistIndexes {
+    #[serde(default)]
+    index: Vec<ArtistIndex>,
+}
+
+#[derive(Deserialize)]
+struct ArtistsResponse {
+    artists: ArtistIndexes,
+}
+
+#[derive(Deserialize)]
+struct SearchResult {
+    #[serde(default)]
+    song: Vec<Song>,
+}
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    #[serde(rename = "searchResult3")]
+    result: SearchResult,
+}
+
+static SERVER: OnceLock<Option<SubsonicServer>> = OnceLock::new();
+
+fn server() -> Result<&'static SubsonicServer> {
+    return SERVER
+        .get_or_init(|| Config::load_or_default().subsonic)
+        .as_ref()
+        .context("no Subsonic server is configured");
+}
+
+pub fn is_path(path: &str) -> bool {
+    return path.starts_with(SCHEME);
+}
+
+fn song_id(path: &str) -> Option<&str> {
+    let id = path.strip_prefix(SCHEME)?;
+    return (!id.starts_with(ALBUM_PREFIX) && !id.is_empty()).then_some(id);
+}
+
+fn album_id(path: &str) -> Option<&str> {
+    return path.strip_prefix(SCHEME)?.strip_prefix(ALBUM_PREFIX);
+}
+
+pub fn is_song_path(path: &str) -> bool {
+    return song_id(path).is_some();
+}
+
+fn song_path(id: &str) -> String {
+    return format!("{SCHEME}{id}");
+}
+
+fn album_path(id: &str) -> String {
+    return format!("{SCHEME}{ALBUM_PREFIX}{id}");
+}
+
+// the token authentication: the password itself is never sent
+fn url(method: &str, params: &[(&str, &str)]) -> Result<Url> {
+    let server = server()?;
+    let salt: String = iter::repeat_with(fastrand::alphanumeric)
+        .take(SALT_LEN)
+        .collect();
+    let token = format!("{:x}", md5::compute(format!("{}{salt}", server.password)));
+    let base = format!("{}/rest/{method}.view", server.url.trim_end_matches('/'));
+    let auth = [
+        ("u", server.username.as_str()),
+        ("t", token.as_str()),
+        ("s", salt.as_str()),
+        ("v", API_VERSION),
+        ("c", project_info::name()),
+        ("f", "json"),
+    ];
+    return Url::parse_with_params(&base, auth.iter().chain(params))
+        .context("cannot build Subsonic URL");
+}
+
+fn call<T: DeserializeOwned>(method: &str, params: &[(&str, &str)]) -> Result<T> {
+    let url = url(method, params)?;
+    let agent = http::new_agent();
+    let json = http::call(&agent.get(url.as_str()))
+        .with_context(|| format!("Subsonic {method} request failed"))?
+        .into_string()
+        .context("cannot read Subsonic response")?;
+    let mut envelope: serde_json::Value =
+        serde_json::from_str(&json).context("cannot parse Subsonic response")?;
+    let response = envelope
+        .get_mut("subsonic-response")
+        .map(serde_json::Value::take)
+        .context("not a Subsonic response")?;
+    let status: Status =
+        serde_json::from_value(response.clone()).context("cannot parse Subsonic response")?;
+    if status.status != "ok" {
+        let (code, message) = status
+            .error
+            .map(|e| (e.code, e.message.unwrap_or_default()))
+            .unwrap_or_default();
+        bail!("Subsonic {method} error {code}: {message}");
+    }
+    return serde_json::from_value(response)
+        .with_context(|| format!("unexpected Subsonic {method} response"));
+}
+
+impl Song {
+    fn meta(self) -> TrackMeta {
+        let mut meta = TrackMeta {
+            album: self.album,
+            title: self.title,
+            track: self.track,
+            disc: self.disc_number,
+            year: self.year,
+            genre: self.genre,
+            duration: Duration::from_secs(self.duration.unwrap_or_default()),
+            ..TrackMeta::default()
+        };
+        meta.set_artists(self.artist.as_deref());
+        return meta;
+    }
+
+    fn track(&self) -> Track {
+        return Track {
+            filename: song_path(&self.id).into(),
+            index: None,
+        };
+    }
+
+    fn description(&self) -> String {
+        let parts: Vec<&str> = [&self.artist, &self.album, &self.title]
+            .into_iter()
+            .filter_map(|part| part.as_deref())
+            .collect();
+        return parts.join(" - ");
+    }
+}
+
+pub fn read_meta(path: &str) -> Option<TrackMeta> {
+    let id = song_id(path)?;
+    let response: SongResponse = call("getSong", &[("id", id)]).to_option()?;
+    return Some(response.song.meta());
+}
+
+// the whole song is downloaded, so it can be seeked freely
+pub fn download(path: &str) -> Result<Vec<u8>> {
+    let Some(id) = song_id(path) else {
+        bail!("not a Subsonic song: {path}");
+    };
+    let url = url("stream", &[("id", id)])?;
+    let agent = http::new_agent();
+    let response =
+        http::call(&agent.get(url.as_str())).with_context(|| format!("cannot stream {path}"))?;
+    let mut data = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_SONG_SIZE)
+        .read_to_end(&mut data)
+        .with_context(|| format!("cannot download {path}"))?;
+    return Ok(data);
+}
+
+fn album_songs(id: &str) -> Result<Vec<Song>> {
+    let response: AlbumResponse = call("getAlbum", &[("id", id)])?;
+    return Ok(response.album.song);
+}
+
+// the songs for a virtual path
+pub fn tracks(path: &str) -> Result<Vec<Track>> {
+    if let Some(id) = album_id(path) {
+        return Ok(album_songs(id)?.iter().map(Song::track).collect());
+    }
+    if let Some(id) = song_id(path) {
+        return Ok(vec![Track {
+            filename: song_path(id).into(),
+            index: None,
+        }]);
+    }
+    bail!("invalid Subsonic path: {path}");
+}
+
+// Reports the playback to the server in the background.
+// The scrobbles are also sent to the configured scrobblers as for any other track.
+pub fn report_playback(path: &str, submission: bool) {
+    let Some(id) = song_id(path) else {
+        return;
+    };
+    let id = id.to_string();
+    thread_util::thread("subsonic scrobble", move || {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis().to_string())
+            .unwrap_or_default();
+        let submission = submission.to_string();
+        let params = [
+            ("id", id.as_str()),
+            ("submission", submission.as_str()),
+            ("time", time.as_str()),
+        ];
+        if let Err(e) = call::<serde_json::Value>("scrobble", &params) {
+            e.log();
+        }
+    });
+}
+
+fn print_songs(songs: &[Song]) {
+    for song in songs {
+        println!("{}\t{}", song_path(&song.id), song.description());
+    }
+}
+
+fn cli_search(query: &str) -> Result<()> {
+    let count = SEARCH_LIMIT.to_string();
+    let response: SearchResponse = call(
+        "search3",
+        &[
+            ("query", query),
+            ("songCount", count.as_str()),
+            ("artistCount", "0"),
+            ("albumCount", "0"),
+        ],
+    )?;
+    print_songs(&response.result.song);
+    return Ok(());
+}
+
+fn cli_artists() -> Result<()> {
+    let response: ArtistsResponse = call("getArtists", &[])?;
+    for artist in response
+        .artists
+        .index
+        .iter()
+        .flat_map(|index| &index.artist)
+    {
+        println!("{}\t{}", artist.id, artist.name);
+    }
+    return Ok(());
+}
+
+fn cli_artist(id: &str) -> Result<()> {
+    let response: ArtistResponse = call("getArtist", &[("id", id)])?;
+    let artist = response.artist;
+    for album in artist.album {
+        let name = match album.year {
+            Some(year) => format!("{} ({year})", album.name),
+            None => album.name,
+        };
+        let artist_name = album.artist.as_deref().unwrap_or(&artist.name);
+        println!("{}\t{artist_name} - {name}", album_path(&album.id));
+    }
+    return Ok(());
+}
+
+fn cli_album(id: &str) -> Result<()> {
+    print_songs(&album_songs(id)?);
+    return Ok(());
+}
+
+pub fn cli_run(command: &SubsonicCommand) -> Result<()> {
+    return match command {
+        SubsonicCommand::Search { query } => cli_search(query),
+        SubsonicCommand::Artists => cli_artists(),
+        SubsonicCommand::Artist { id } => cli_artist(id),
+        SubsonicCommand::Album { id } => cli_album(id),
+    };
+}
@@ -1,11 +1,18 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::{collections::VecDeque, fs::File, path::Path, time::Duration};
+use std::{
+    cmp::Reverse,
+    collections::VecDeque,
+    io::{self, BufReader, Cursor},
+    path::Path,
+    sync::OnceLock,
+    time::Duration,
+};
 
 use anyhow::{bail, Context, Result};
 use lofty::{
-    file::{AudioFile, TaggedFileExt},
+    file::{AudioFile, FileType, TaggedFileExt},
     probe::Probe,
     tag::{Accessor, ItemKey, ItemValue, Tag},
 };
@@ -13,7 +20,7 @@ use symphonia::core::{
     audio::{AudioBufferRef, SampleBuffer},
     codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL},
     formats::{FormatOptions, SeekMode, SeekTo, Track},
-    io::{MediaSourceStream, MediaSourceStreamOptions},
+    io::{MediaSource, MediaSourceStream, MediaSourceStreamOptions},
     meta::MetadataOptions,
     probe::{Hint, ProbeResult},
     units::{Time, TimeStamp},
@@ -21,7 +28,11 @@ use symphonia::core::{
 
 use crate::{
     err_util::{eprintln_with_date, LogErr},
-    stream_base::{Stream, StreamHelper, StreamPacketMeta, TrackMeta},
+    rating,
+    stream_base::{FileMissing, ReplayGain, Stream, StreamHelper, StreamPacketMeta, TrackMeta},
+    subsonic,
+    timeout_file::TimeoutFile,
+    zip_archive,
 };
 
 pub struct SymphoniaStream {
@@ -33,14 +44,39 @@ pub struct SymphoniaStream {
     metadata_sent: bool,
 }
 
-const EXTS: [&str; 3] = ["flac", "ogg", "mp3"];
+const EXTS: [&str; 5] = ["flac", "ogg", "mp3", "m4a", "m4b"];
+// only the audio is played
+const VIDEO_EXTS: [&str; 3] = ["mp4", "m4v", "mov"];
+
+static AUDIO_SELECTION: OnceLock<AudioSelection> = OnceLock::new();
+
+// which files and audio streams are played
+#[derive(Default)]
+struct AudioSelection {
+    video_files: bool,
+    // e.g. "eng", the other streams are used if there's no such language
+    language: Option<String>,
+}
+
+pub fn init(video_files: bool, language: Option<&str>) {
+    AUDIO_SELECTION
+        .set(AudioSelection {
+            video_files,
+            language: language.map(str::to_string),
+        })
+        .ok();
+}
+
+fn audio_selection() -> &'static AudioSelection {
+    return AUDIO_SELECTION.get_or_init(AudioSelection::default);
+}
 
 impl Stream for SymphoniaStream {
     fn open(path: &str) -> Result<Self> {
-        let file = File::open(path).with_context(|| format!("cannot open file: {path}"))?;
+        let source = Self::open_source(path)?;
 
         let stream_opts = MediaSourceStreamOptions::default();
-        let stream = MediaSourceStream::new(Box::new(file), stream_opts);
+        let stream = MediaSourceStream::new(source, stream_opts);
 
         let mut hint = Hint::new();
         if let Some(ext) = Path::new(path).extension().and_then(|s| s.to_str()) {
@@ -71,18 +107,29 @@ impl Stream for SymphoniaStream {
     }
 
     fn is_path_supported(path: &str) -> bool {
-        return Self::is_extension_supported(path, &EXTS);
+        return Self::is_extension_supported(path, &EXTS)
+            || subsonic::is_song_path(path)
+            || (audio_selection().video_files && Self::is_extension_supported(path, &VIDEO_EXTS));
     }
 
-    fn read_packet(&mut self) -> Result<StreamPacketMeta> {
+    fn read_packet(&mut self) -> Result<Option<StreamPacketMeta>> {
         let decoder = &mut self.decoder;
 
         loop {
-            let packet = self
-                .probe
-                .format
-                .next_packet()
-                .context("cannot read packet")?;
+            let packet = match self.probe.format.next_packet() {
+                Ok(packet) => packet,
+                Err(symphonia::core::errors::Error::IoError(e))
+                    if e.kind() == io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(None);
+                }
+                Err(symphonia::core::errors::Error::IoError(e))
+                    if !Path::new(&self.path).exists() =>
+                {
+                    return Err(anyhow::Error::new(e).context(FileMissing));
+                }
+                Err(e) => return Err(e).context("cannot read packet"),
+            };
             if packet.track_id() != self.track_id {
                 continue;
             }
@@ -121,18 +168,18 @@ impl Stream for SymphoniaStream {
 
                     let position = self.timestamp_to_duration(packet.ts());
 
-                    return Ok(StreamPacketMeta {
+                    return Ok(Some(StreamPacketMeta {
                         channels_count: spec.channels.bits().count_ones() as usize,
                         sample_rate: spec.rate as usize,
                         track_meta: self.pull_track_info(),
                         position,
-                    });
+                    }));
                 }
                 Err(symphonia::core::errors::Error::DecodeError(e)) => {
                     eprintln_with_date(format!("decode error: {e}"));
                 }
                 Err(e) => bail!(e),
-            };
+            }
         }
     }
 
@@ -185,12 +232,32 @@ impl SymphoniaStream {
         return None;
     }
 
+    // the stream in the configured language is preferred, then the one with more channels
+    fn track_rank(track: &Track) -> (bool, u32, u32) {
+        let is_language = match (&audio_selection().language, &track.language) {
+            (Some(wanted), Some(language)) => wanted.eq_ignore_ascii_case(language),
+            _ => false,
+        };
+        let channels = track
+            .codec_params
+            .channels
+            .map(|channels| channels.count() as u32)
+            .unwrap_or_default();
+        let sample_rate = track.codec_params.sample_rate.unwrap_or_default();
+        return (is_language, channels, sample_rate);
+    }
+
+    // the files may have several audio streams (e.g. videos with several languages)
     fn track_and_decoder_by_probe(probe: &ProbeResult) -> Result<(&Track, Box<dyn Decoder>)> {
-        let track = probe
+        let mut tracks: Vec<&Track> = probe
             .format
             .tracks()
             .iter()
             .filter(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .collect();
+        tracks.sort_by_key(|t| Reverse(Self::track_rank(t)));
+        let track = tracks
+            .into_iter()
             .find_map(|t| {
                 let decoder_opts = DecoderOptions::default();
                 match symphonia::default::get_codecs().make(&t.codec_params, &decoder_opts) {
@@ -233,7 +300,10 @@ impl SymphoniaStream {
 
     fn fill_lofty_tag(tag: &Tag, info: &mut TrackMeta) {
         if info.artist.is_none() {
-            info.artist = Self::valid_lofty_tag_string(tag, &ItemKey::TrackArtist);
+            let artists = tag
+                .get_strings(&ItemKey::TrackArtist)
+                .filter(|s| !s.chars().any(|c| c.is_ascii_control() && c != '\0'));
+            info.set_artists(artists);
         }
         if info.album.is_none() {
             info.album = Self::valid_lofty_tag_string(tag, &ItemKey::AlbumTitle);
@@ -256,10 +326,60 @@ impl SymphoniaStream {
         if info.year.is_none() {
             info.year = tag.year().map(|x| x as usize);
         }
+        if info.genre.is_none() {
+            info.genre = Self::valid_lofty_tag_string(tag, &ItemKey::Genre);
+        }
+        if info.rating.is_none() {
+            info.rating = rating::from_tag(tag);
+        }
+        if info.mbids.recording.is_none() {
+            info.mbids.recording =
+                Self::valid_lofty_tag_string(tag, &ItemKey::MusicBrainzRecordingId);
+        }
+        if info.mbids.release.is_none() {
+            info.mbids.release = Self::valid_lofty_tag_string(tag, &ItemKey::MusicBrainzReleaseId);
+        }
+        if info.mbids.artist.is_none() {
+            info.mbids.artist = Self::valid_lofty_tag_string(tag, &ItemKey::MusicBrainzArtistId);
+        }
+        if info.comment.is_none() {
+            info.comment = Self::valid_lofty_tag_string(tag, &ItemKey::Comment);
+        }
+        info.replay_gain = info.replay_gain.or(Self::lofty_replay_gain(tag));
+    }
+
+    fn lofty_replay_gain(tag: &Tag) -> ReplayGain {
+        let value = |key| tag.get_string(&key).and_then(ReplayGain::parse_value);
+        return ReplayGain {
+            track_gain: value(ItemKey::ReplayGainTrackGain),
+            track_peak: value(ItemKey::ReplayGainTrackPeak),
+            album_gain: value(ItemKey::ReplayGainAlbumGain),
+            album_peak: value(ItemKey::ReplayGainAlbumPeak),
+        };
     }
 
-    fn get_lofty_meta(path: &str) -> Option<TrackMeta> {
-        match Probe::open(path) {
+    // the files inside ZIP archives and the songs on a Subsonic server are loaded into memory
+    fn open_source(path: &str) -> Result<Box<dyn MediaSource>> {
+        if subsonic::is_song_path(path) {
+            return Ok(Box::new(Cursor::new(subsonic::download(path)?)));
+        }
+        if let Some((archive, name)) = zip_archive::split_path(path) {
+            return Ok(Box::new(Cursor::new(zip_archive::read(archive, name)?)));
+        }
+        let file = TimeoutFile::open(path).with_context(|| format!("cannot open file: {path}"))?;
+        return Ok(Box::new(file));
+    }
+
+    fn open_lofty_probe(path: &str) -> Result<Probe<BufReader<Box<dyn MediaSource>>>> {
+        let probe = Probe::new(BufReader::new(Self::open_source(path)?));
+        if let Some(file_type) = FileType::from_path(path) {
+            return Ok(probe.set_file_type(file_type));
+        }
+        return Ok(probe);
+    }
+
+    pub fn get_lofty_meta(path: &str) -> Option<TrackMeta> {
+        match Self::open_lofty_probe(path) {
             Ok(probe) => match probe.read() {
                 Ok(file) => {
                     let mut info = TrackMeta::default();
@@ -285,7 +405,7 @@ impl SymphoniaStream {
                                 Self::fill_lofty_tag(tag, &mut info);
                             }
                         }
-                    };
+                    }
                     return Some(info);
                 }
                 Err(e) => {
@@ -0,0 +1,127 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    env, fs,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    path::PathBuf,
+    process,
+};
+
+use anyhow::{Context, Result};
+use directories::BaseDirs;
+
+use crate::{err_util::IgnoreErr, project_info};
+
+// The player as a systemd user service.
+// systemd holds the control socket, so the first command (e.g. "konik file.mp3") starts the service.
+
+// the first of the sockets that are passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+// the control socket that systemd has opened for the player (see the .socket unit)
+pub fn take_activated_socket() -> Option<OwnedFd> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    // the child processes must not take the socket
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if pid?.parse::<u32>().ok()? != process::id() || fds?.parse::<u32>().ok()? < 1 {
+        return None;
+    }
+    unsafe {
+        libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC);
+        return Some(OwnedFd::from_raw_fd(LISTEN_FDS_START));
+    }
+}
+
+// e.g. READY=1, the errors are ignored since the player works without systemd as well
+fn notify(state: &str) -> Result<()> {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&path),
+    }
+    .context("invalid NOTIFY_SOCKET")?;
+    let socket = UnixDatagram::unbound().context("cannot create notify socket")?;
+    socket
+        .send_to_addr(state.as_bytes(), &addr)
+        .context("cannot notify systemd")?;
+    return Ok(());
+}
+
+pub fn notify_ready() {
+    notify("READY=1").ignore_err();
+}
+
+pub fn notify_stopping() {
+    notify("STOPPING=1").ignore_err();
+}
+
+fn unit_dir() -> Result<PathBuf> {
+    let dirs = BaseDirs::new().context("cannot get the home folder")?;
+    return Ok(dirs.config_dir().join("systemd").join("user"));
+}
+
+fn service_unit() -> Result<String> {
+    let exe = env::current_exe().context("cannot get the executable path")?;
+    let profile_arg = project_info::profile()
+        .map(|profile| format!(" --profile {profile}"))
+        .unwrap_or_default();
+    let exec = format!("{} --daemon{profile_arg}", exe.to_string_lossy());
+    return Ok(format!(
+        "[Unit]\n\
+        Description={} music player\n\
+        Requires={}.socket\n\
+        After=graphical-session.target\n\
+        \n\
+        [Service]\n\
+        Type=notify\n\
+        ExecStart={exec}\n\
+        Restart=on-failure\n\
+        \n\
+        [Install]\n\
+        WantedBy=default.target\n",
+        project_info::instance_title(),
+        project_info::instance_name()
+    ));
+}
+
+// the singleton socket is in the abstract namespace
+fn socket_unit(socket_name: &str) -> String {
+    return format!(
+        "[Unit]\n\
+        Description={} control socket\n\
+        \n\
+        [Socket]\n\
+        ListenStream=@{socket_name}\n\
+        \n\
+        [Install]\n\
+        WantedBy=sockets.target\n",
+        project_info::instance_title()
+    );
+}
+
+pub fn cli_install(socket_name: &str) -> Result<()> {
+    let dir = unit_dir()?;
+    fs::create_dir_all(&dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    let name = project_info::instance_name();
+    let units = [
+        (format!("{name}.service"), service_unit()?),
+        (format!("{name}.socket"), socket_unit(socket_name)),
+    ];
+    for (filename, contents) in units {
+        let path = dir.join(filename);
+        fs::write(&path, contents).with_context(|| format!("cannot write {}", path.display()))?;
+        println!("{}", path.display());
+    }
+    println!("run: systemctl --user daemon-reload && systemctl --user enable --now {name}.socket");
+    return Ok(());
+}
@@ -0,0 +1,171 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use anyhow::{bail, Context, Result};
+use lofty::{
+    config::WriteOptions,
+    file::TaggedFileExt,
+    probe::Probe,
+    tag::{Accessor, Tag, TagExt},
+};
+
+use crate::cli::TagCommand;
+
+#[derive(Clone, Copy)]
+enum Field {
+    Artist,
+    Album,
+    Title,
+    Genre,
+    Track,
+    TrackTotal,
+    Disc,
+    DiscTotal,
+    Year,
+}
+
+const FIELDS: [(&str, Field); 9] = [
+    ("artist", Field::Artist),
+    ("album", Field::Album),
+    ("title", Field::Title),
+    ("genre", Field::Genre),
+    ("track", Field::Track),
+    ("track_total", Field::TrackTotal),
+    ("disc", Field::Disc),
+    ("disc_total", Field::DiscTotal),
+    ("year", Field::Year),
+];
+
+// an empty value removes the field
+struct Change {
+    field: Field,
+    value: String,
+}
+
+// "name=value"
+fn parse_change(s: &str) -> Result<Change> {
+    let Some((name, value)) = s.split_once('=') else {
+        bail!("expected name=value: {s}");
+    };
+    let name = name.trim().to_lowercase();
+    let Some(field) = FIELDS
+        .iter()
+        .find(|(field_name, _)| *field_name == name)
+        .map(|(_, field)| *field)
+    else {
+        let names: Vec<&str> = FIELDS.iter().map(|(field_name, _)| *field_name).collect();
+        bail!("unknown field: {name} (known fields: {})", names.join(", "));
+    };
+    return Ok(Change {
+        field,
+        value: value.trim().to_string(),
+    });
+}
+
+fn parse_changes(changes: &[String]) -> Result<Vec<Change>> {
+    return changes.iter().map(|s| parse_change(s)).collect();
+}
+
+fn parse_number(change: &Change) -> Result<Option<u32>> {
+    if change.value.is_empty() {
+        return Ok(None);
+    }
+    let number = change
+        .value
+        .parse()
+        .with_context(|| format!("not a number: {}", change.value))?;
+    return Ok(Some(number));
+}
+
+fn set_text(tag: &mut Tag, value: &str, set: fn(&mut Tag, String), remove: fn(&mut Tag)) {
+    if value.is_empty() {
+        remove(tag);
+    } else {
+        set(tag, value.to_string());
+    }
+}
+
+fn set_number(
+    tag: &mut Tag,
+    change: &Change,
+    set: fn(&mut Tag, u32),
+    remove: fn(&mut Tag),
+) -> Result<()> {
+    match parse_number(change)? {
+        Some(number) => set(tag, number),
+        None => remove(tag),
+    }
+    return Ok(());
+}
+
+fn apply(tag: &mut Tag, change: &Change) -> Result<()> {
+    let value = change.value.as_str();
+    match change.field {
+        Field::Artist => set_text(tag, value, Tag::set_artist, Tag::remove_artist),
+        Field::Album => set_text(tag, value, Tag::set_album, Tag::remove_album),
+        Field::Title => set_text(tag, value, Tag::set_title, Tag::remove_title),
+        Field::Genre => set_text(tag, value, Tag::set_genre, Tag::remove_genre),
+        Field::Track => set_number(tag, change, Tag::set_track, Tag::remove_track)?,
+        Field::TrackTotal => {
+            set_number(tag, change, Tag::set_track_total, Tag::remove_track_total)?;
+        }
+        Field::Disc => set_number(tag, change, Tag::set_disk, Tag::remove_disk)?,
+        Field::DiscTotal => {
+            set_number(tag, change, Tag::set_disk_total, Tag::remove_disk_total)?;
+        }
+        Field::Year => set_number(tag, change, Tag::set_year, Tag::remove_year)?,
+    }
+    return Ok(());
+}
+
+fn write(path: &str, changes: &[Change]) -> Result<()> {
+    let file = Probe::open(path)
+        .with_context(|| format!("cannot open {path}"))?
+        .read()
+        .with_context(|| format!("cannot read tags: {path}"))?;
+    let mut tag = file
+        .primary_tag()
+        .cloned()
+        .unwrap_or_else(|| Tag::new(file.primary_tag_type()));
+    for change in changes {
+        apply(&mut tag, change)?;
+    }
+    tag.save_to_path(path, WriteOptions::default())
+        .with_context(|| format!("cannot write tags: {path}"))?;
+    return Ok(());
+}
+
+// changes the tags of a single file, e.g. artist="X" title="Y"
+fn cli_set(path: &str, changes: &[String]) -> Result<()> {
+    let changes = parse_changes(changes)?;
+    write(path, &changes)?;
+    println!("Saved: {path}");
+    return Ok(());
+}
+
+// Sets the same tags in all files.
+// The files that cannot be changed are reported, the rest are still saved.
+fn cli_batch(paths: &[String], changes: &[String]) -> Result<()> {
+    let changes = parse_changes(changes)?;
+    let mut failed = 0;
+    for path in paths {
+        match write(path, &changes) {
+            Ok(()) => println!("Saved: {path}"),
+            Err(e) => {
+                eprintln!("{e:#}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} of {} files were not saved", paths.len());
+    }
+    return Ok(());
+}
+
+pub fn cli_run(command: &TagCommand) -> Result<()> {
+    return match command {
+        TagCommand::Set { path, changes } => cli_set(path, changes),
+        TagCommand::Batch { changes, paths } => cli_batch(paths, changes),
+    };
+}
@@ -1,7 +1,7 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::thread::{self, JoinHandle};
+use std::thread::{self, JoinHandle, Scope, ScopedJoinHandle};
 
 use anyhow::Context;
 
@@ -16,3 +16,19 @@ where
         .with_context(|| format!("starting thread: {name}"))
         .expect("failed to start the thread")
 }
+
+pub fn scoped_thread<'scope, F, T>(
+    scope: &'scope Scope<'scope, '_>,
+    name: &str,
+    f: F,
+) -> ScopedJoinHandle<'scope, T>
+where
+    F: FnOnce() -> T + Send + 'scope,
+    T: Send + 'scope,
+{
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn_scoped(scope, f)
+        .with_context(|| format!("starting thread: {name}"))
+        .expect("failed to start the thread")
+}
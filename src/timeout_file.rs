@@ -0,0 +1,384 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use symphonia::core::io::MediaSource;
+
+use crate::{err_util::debugln_with_date, thread_util};
+
+// the time for a single read/seek, including all its retries
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+// the network mounts may fail for a few seconds, so the file is reopened after a pause;
+// a stalled operation is not retried though, it has already used up all the time
+const MAX_RETRIES: u32 = 3;
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// NFS, SMB/CIFS, FUSE (sshfs, rclone), AFS, Ceph, 9P, Coda
+const NETWORK_FS_TYPES: [i64; 10] = [
+    0x6969,
+    0x517B,
+    0xFF53_4D42,
+    0xFE53_4D42,
+    0x6573_5546,
+    0x5346_414F,
+    0x00C3_6400,
+    0x0102_1997,
+    0x7375_7245,
+    0x0BD0_0BD0,
+];
+
+#[derive(Clone, Copy)]
+enum IoCmd {
+    Read(usize),
+    Seek(SeekFrom),
+}
+
+enum IoResult {
+    // the number of bytes in the shared buffer
+    Read(usize),
+    Seek(u64),
+}
+
+// the connection to the thread that owns the file descriptor
+struct IoThread {
+    tx: Sender<IoCmd>,
+    // MediaSource must be Sync
+    rx: Mutex<Receiver<io::Result<IoResult>>>,
+    // the data is read here, so there's no new buffer for each read
+    buf: Arc<Mutex<Vec<u8>>>,
+}
+
+// A file that is accessed from a separate thread.
+// If the file system does not respond in time (e.g. a stalled network mount)
+// then the operation fails instead of blocking the caller forever.
+// The stalled thread is abandoned and exits as soon as the file system wakes up.
+pub struct TimeoutFile {
+    io: IoThread,
+    path: String,
+    len: Option<u64>,
+    is_stalled: bool,
+    // where the file will be reopened at
+    position: u64,
+}
+
+fn timed_out_error() -> io::Error {
+    return io::Error::new(io::ErrorKind::TimedOut, "file I/O timed out");
+}
+
+fn thread_exited_error() -> io::Error {
+    return io::Error::new(io::ErrorKind::BrokenPipe, "file I/O thread exited");
+}
+
+// the errors after which the file may be readable again
+fn is_transient(e: &io::Error) -> bool {
+    if matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    ) {
+        return true;
+    }
+    return e.raw_os_error().is_some_and(|code| {
+        return [
+            libc::EIO,
+            libc::ESTALE,
+            libc::ETIMEDOUT,
+            libc::ECONNRESET,
+            libc::ECONNABORTED,
+            libc::ENOTCONN,
+            libc::EHOSTDOWN,
+            libc::EHOSTUNREACH,
+        ]
+        .contains(&code);
+    });
+}
+
+fn is_network_fs(path: &str) -> bool {
+    let Ok(c_path) = CString::new(Path::new(path).as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    unsafe {
+        if libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return false;
+        }
+        #[allow(clippy::useless_conversion)]
+        let fs_type = i64::from(stat.assume_init().f_type);
+        return NETWORK_FS_TYPES.contains(&fs_type);
+    }
+}
+
+// "\040" and the like in /proc/self/mounts
+fn unescape_mount_path(s: &str) -> String {
+    // the escaped bytes may be parts of a multi-byte UTF-8 character
+    let mut result = Vec::new();
+    let mut rest = s.as_bytes();
+    while let Some(pos) = rest.iter().position(|c| return *c == b'\\') {
+        result.extend_from_slice(&rest[..pos]);
+        let code = rest
+            .get(pos + 1..pos + 4)
+            .filter(|code| return code.iter().all(|c| return (b'0'..=b'7').contains(c)))
+            .and_then(|code| return u8::from_str_radix(std::str::from_utf8(code).ok()?, 8).ok());
+        if let Some(code) = code {
+            result.push(code);
+            rest = &rest[pos + 4..];
+        } else {
+            result.push(b'\\');
+            rest = &rest[pos + 1..];
+        }
+    }
+    result.extend_from_slice(rest);
+    return String::from_utf8_lossy(&result).into_owned();
+}
+
+// the mount table is read without touching the mounted file systems
+fn mount_point(path: &str) -> Option<PathBuf> {
+    let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+    return mounts
+        .lines()
+        .filter_map(|line| return line.split(' ').nth(1))
+        .map(|mount| return PathBuf::from(unescape_mount_path(mount)))
+        .filter(|mount| return Path::new(path).starts_with(mount))
+        .max_by_key(|mount| return mount.as_os_str().len());
+}
+
+// The files on the network mounts get a larger prebuffer.
+// A stalled mount blocks statfs as well, so such a mount counts as a network one.
+// The result is remembered for each mount, so a stalled one is waited for only once.
+pub fn is_on_network_mount(path: &str) -> bool {
+    static MOUNTS: OnceLock<Mutex<HashMap<PathBuf, bool>>> = OnceLock::new();
+    let mounts = MOUNTS.get_or_init(Mutex::default);
+    let mount = mount_point(path);
+    if let Some(mount) = &mount {
+        if let Some(is_network) = mounts.lock().unwrap().get(mount) {
+            return *is_network;
+        }
+    }
+
+    let (tx, rx) = channel();
+    let thread_path = path.to_string();
+    thread_util::thread("file system check", move || {
+        tx.send(is_network_fs(&thread_path)).ok();
+    });
+    let is_network = rx.recv_timeout(IO_TIMEOUT).unwrap_or(true);
+    if let Some(mount) = mount {
+        mounts.lock().unwrap().insert(mount, is_network);
+    }
+    return is_network;
+}
+
+fn io_thread(
+    path: &str,
+    open_tx: &Sender<io::Result<u64>>,
+    cmd_rx: &Receiver<IoCmd>,
+    result_tx: &Sender<io::Result<IoResult>>,
+    buf: &Mutex<Vec<u8>>,
+) {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            open_tx.send(Err(e)).ok();
+            return;
+        }
+    };
+    let len = file.metadata().map(|meta| meta.len());
+    if open_tx.send(len).is_err() {
+        return;
+    }
+
+    while let Ok(cmd) = cmd_rx.recv() {
+        let result = match cmd {
+            IoCmd::Read(size) => {
+                let mut buf = buf.lock().unwrap();
+                buf.resize(size, 0);
+                let result = file.read(&mut buf).map(IoResult::Read);
+                drop(buf);
+                result
+            }
+            IoCmd::Seek(pos) => file.seek(pos).map(IoResult::Seek),
+        };
+        if result_tx.send(result).is_err() {
+            return;
+        }
+    }
+}
+
+fn spawn_io_thread(path: &str, deadline: Instant) -> io::Result<(IoThread, u64)> {
+    let (cmd_tx, cmd_rx) = channel();
+    let (result_tx, result_rx) = channel();
+    let (open_tx, open_rx) = channel();
+    let buf = Arc::new(Mutex::new(Vec::new()));
+    let thread_path = path.to_string();
+    let thread_buf = buf.clone();
+    thread_util::thread("file I/O", move || {
+        io_thread(&thread_path, &open_tx, &cmd_rx, &result_tx, &thread_buf);
+    });
+
+    let len = match open_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(len) => len?,
+        Err(RecvTimeoutError::Timeout) => return Err(timed_out_error()),
+        Err(RecvTimeoutError::Disconnected) => return Err(thread_exited_error()),
+    };
+    let io = IoThread {
+        tx: cmd_tx,
+        rx: Mutex::new(result_rx),
+        buf,
+    };
+    return Ok((io, len));
+}
+
+impl TimeoutFile {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let (io, len) = spawn_io_thread(path, Instant::now() + IO_TIMEOUT)?;
+        return Ok(Self {
+            io,
+            path: path.to_string(),
+            len: Some(len),
+            is_stalled: false,
+            position: 0,
+        });
+    }
+
+    fn exec(&mut self, cmd: IoCmd, deadline: Instant) -> io::Result<IoResult> {
+        if self.is_stalled {
+            return Err(timed_out_error());
+        }
+        self.io.tx.send(cmd).map_err(|_| thread_exited_error())?;
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let result = self.io.rx.lock().unwrap().recv_timeout(timeout);
+        match result {
+            Ok(result) => return result,
+            Err(RecvTimeoutError::Timeout) => {
+                // the pending result would mix up with the next one
+                self.is_stalled = true;
+                return Err(timed_out_error());
+            }
+            Err(RecvTimeoutError::Disconnected) => return Err(thread_exited_error()),
+        }
+    }
+
+    // a new thread with a new file descriptor, the failed one is abandoned
+    fn reopen(&mut self, deadline: Instant) -> io::Result<()> {
+        let (io, len) = spawn_io_thread(&self.path, deadline)?;
+        self.io = io;
+        self.len = Some(len);
+        self.exec(IoCmd::Seek(SeekFrom::Start(self.position)), deadline)?;
+        return Ok(());
+    }
+
+    fn exec_with_retry(&mut self, cmd: IoCmd) -> io::Result<IoResult> {
+        let deadline = Instant::now() + IO_TIMEOUT;
+        let mut delay = FIRST_RETRY_DELAY;
+        for _ in 0..MAX_RETRIES {
+            let result = self.exec(cmd, deadline);
+            let Err(e) = &result else {
+                return result;
+            };
+            // the file is skipped right away if the file system does not respond
+            if self.is_stalled || !is_transient(e) || Instant::now() + delay >= deadline {
+                return result;
+            }
+            debugln_with_date(format!(
+                "{}: {e}, retrying in {}ms",
+                self.path,
+                delay.as_millis()
+            ));
+            thread::sleep(delay);
+            delay *= 2;
+            if let Err(e) = self.reopen(deadline) {
+                // the old thread may still have a pending result
+                self.is_stalled = true;
+                return Err(e);
+            }
+        }
+        return self.exec(cmd, deadline);
+    }
+}
+
+impl Read for TimeoutFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.exec_with_retry(IoCmd::Read(buf.len()))? {
+            IoResult::Read(len) => {
+                let data = self.io.buf.lock().unwrap();
+                buf[..len].copy_from_slice(&data[..len]);
+                drop(data);
+                self.position += len as u64;
+                return Ok(len);
+            }
+            IoResult::Seek(_) => unreachable!(),
+        }
+    }
+}
+
+impl Seek for TimeoutFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self.exec_with_retry(IoCmd::Seek(pos))? {
+            IoResult::Seek(position) => {
+                self.position = position;
+                return Ok(position);
+            }
+            IoResult::Read(_) => unreachable!(),
+        }
+    }
+}
+
+impl MediaSource for TimeoutFile {
+    fn is_seekable(&self) -> bool {
+        return true;
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        return self.len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_mount_paths() {
+        assert_eq!(unescape_mount_path(""), "");
+        assert_eq!(unescape_mount_path("/"), "/");
+        assert_eq!(unescape_mount_path("/mnt/music"), "/mnt/music");
+    }
+
+    #[test]
+    fn escaped_mount_paths() {
+        assert_eq!(unescape_mount_path("/mnt/my\\040music"), "/mnt/my music");
+        assert_eq!(unescape_mount_path("/mnt/a\\011b\\012c"), "/mnt/a\tb\nc");
+        assert_eq!(
+            unescape_mount_path("/mnt/back\\134slash"),
+            "/mnt/back\\slash"
+        );
+        assert_eq!(unescape_mount_path("\\040\\040"), "  ");
+    }
+
+    #[test]
+    fn escaped_utf8_mount_paths() {
+        assert_eq!(unescape_mount_path("/mnt/caf\\303\\251"), "/mnt/café");
+        assert_eq!(unescape_mount_path("/mnt/музыка"), "/mnt/музыка");
+    }
+
+    #[test]
+    fn invalid_mount_path_escapes() {
+        assert_eq!(unescape_mount_path("/mnt/a\\"), "/mnt/a\\");
+        assert_eq!(unescape_mount_path("/mnt/a\\04"), "/mnt/a\\04");
+        assert_eq!(unescape_mount_path("/mnt/a\\089"), "/mnt/a\\089");
+        assert_eq!(unescape_mount_path("/mnt/a\\+12"), "/mnt/a\\+12");
+    }
+}
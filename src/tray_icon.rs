@@ -1,12 +1,19 @@
 // SPDX-License-Identifier: GPL-3.0-only
 // 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
 
-use std::sync::Arc;
+use std::{env, fs, sync::Arc, thread, time::Duration};
 
 use anyhow::{Context, Result};
+use dbus::blocking::Connection;
+use directories::BaseDirs;
 use ksni::{self, menu::StandardItem, Handle, Icon, MenuItem, TrayService};
 use png::Decoder;
 
+use crate::{
+    err_util::{debugln_with_date, LogErr},
+    thread_util,
+};
+
 #[derive(Copy, Clone)]
 pub enum TrayIconImageType {
     Stop,
@@ -15,6 +22,65 @@ pub enum TrayIconImageType {
     Pause,
 }
 
+// the opaque part of a high-contrast icon
+const ALPHA_THRESHOLD: u8 = 128;
+const LUMA_THRESHOLD: u32 = 128;
+const OUTLINE_WIDTH: usize = 2;
+// e.g. the player is autostarted before the desktop session is ready
+const SESSION_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// Alternate looks of the tray icons.
+// They are generated from the regular images, so no extra assets are needed.
+#[derive(Copy, Clone, Default)]
+pub struct TrayIconStyle {
+    // only black and white, with an outline that is visible on any panel
+    pub high_contrast: bool,
+    // double resolution
+    pub large: bool,
+}
+
+impl TrayIconStyle {
+    // the values that are not set are guessed from the desktop settings
+    pub fn detect(high_contrast: Option<bool>, large: Option<bool>) -> Self {
+        return Self {
+            high_contrast: high_contrast.unwrap_or_else(desktop_has_high_contrast),
+            large: large.unwrap_or_else(desktop_has_hidpi),
+        };
+    }
+}
+
+fn is_high_contrast_name(name: &str) -> bool {
+    return name
+        .to_lowercase()
+        .replace(['-', '_', ' '], "")
+        .contains("highcontrast");
+}
+
+fn desktop_has_high_contrast() -> bool {
+    if env::var("GTK_THEME").is_ok_and(|theme| is_high_contrast_name(&theme)) {
+        return true;
+    }
+    // KDE keeps the color scheme in kdeglobals
+    let Some(dirs) = BaseDirs::new() else {
+        return false;
+    };
+    let kdeglobals = fs::read_to_string(dirs.config_dir().join("kdeglobals")).unwrap_or_default();
+    return kdeglobals.lines().any(|line| {
+        return line
+            .strip_prefix("ColorScheme=")
+            .is_some_and(is_high_contrast_name);
+    });
+}
+
+fn desktop_has_hidpi() -> bool {
+    return ["GDK_SCALE", "QT_SCALE_FACTOR"].iter().any(|name| {
+        return env::var(name)
+            .ok()
+            .and_then(|value| value.trim().parse::<f64>().ok())
+            .is_some_and(|scale| scale >= 2.0);
+    });
+}
+
 pub struct TrayMenuItem {
     label: String,
     func: Arc<dyn Fn() + Send + Sync + 'static>,
@@ -42,8 +108,9 @@ struct TrayIconData {
     menu_items: Vec<TrayMenuItem>,
 }
 
+// there's no tray in the headless mode, but the state is still tracked
 pub struct TrayIcon {
-    handle: Handle<TrayIconData>,
+    handle: Option<Handle<TrayIconData>>,
     image_type: TrayIconImageType,
 }
 
@@ -52,30 +119,81 @@ impl TrayIcon {
         p.copy_from_slice(&[p[3], p[0], p[1], p[2]]);
     }
 
-    fn create_ico(bytes: &[u8]) -> Result<Icon> {
+    // every opaque pixel becomes either black or white,
+    // and the transparent pixels around the shape become a black outline
+    fn make_high_contrast(bytes: &mut [u8], width: usize, height: usize) {
+        let is_opaque: Vec<bool> = bytes.chunks(4).map(|p| p[3] >= ALPHA_THRESHOLD).collect();
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let p = &mut bytes[i * 4..i * 4 + 4];
+                if is_opaque[i] {
+                    let luma =
+                        (u32::from(p[0]) * 299 + u32::from(p[1]) * 587 + u32::from(p[2]) * 114)
+                            / 1000;
+                    let value = if luma >= LUMA_THRESHOLD { 255 } else { 0 };
+                    p.copy_from_slice(&[value, value, value, 255]);
+                    continue;
+                }
+                let x_range = x.saturating_sub(OUTLINE_WIDTH)..=(x + OUTLINE_WIDTH).min(width - 1);
+                let y_range = y.saturating_sub(OUTLINE_WIDTH)..=(y + OUTLINE_WIDTH).min(height - 1);
+                let is_near_shape = y_range
+                    .flat_map(|ny| x_range.clone().map(move |nx| ny * width + nx))
+                    .any(|ni| is_opaque[ni]);
+                let alpha = if is_near_shape { 255 } else { 0 };
+                p.copy_from_slice(&[0, 0, 0, alpha]);
+            }
+        }
+    }
+
+    // nearest neighbour, so the edges stay sharp
+    fn double_size(bytes: &[u8], width: usize) -> Vec<u8> {
+        let mut doubled = Vec::with_capacity(bytes.len() * 4);
+        for row in bytes.chunks(width * 4) {
+            let doubled_row: Vec<u8> = row
+                .chunks(4)
+                .flat_map(|p| p.iter().chain(p.iter()).copied())
+                .collect();
+            doubled.extend_from_slice(&doubled_row);
+            doubled.extend_from_slice(&doubled_row);
+        }
+        return doubled;
+    }
+
+    fn create_ico(bytes: &[u8], style: TrayIconStyle) -> Result<Icon> {
         let decoder = Decoder::new(bytes);
         let mut reader = decoder.read_info().context("cannot read icon info")?;
         let mut buf = vec![0; reader.output_buffer_size()];
         let info = reader
             .next_frame(&mut buf)
             .context("cannot read icon frame")?;
+        let mut width = info.width as usize;
+        let mut height = info.height as usize;
         let mut bytes = buf[..info.buffer_size()].to_vec();
+        if style.high_contrast {
+            Self::make_high_contrast(&mut bytes, width, height);
+        }
+        if style.large {
+            bytes = Self::double_size(&bytes, width);
+            width *= 2;
+            height *= 2;
+        }
         bytes.chunks_mut(4).for_each(Self::rgba2argb);
         return Ok(Icon {
-            height: info.height as i32,
-            width: info.width as i32,
+            height: height as i32,
+            width: width as i32,
             data: bytes,
         });
     }
 
-    pub fn new() -> Result<Self> {
-        let stop_image = Self::create_ico(include_bytes!("../img/stop.png"))
+    pub fn new(style: TrayIconStyle) -> Result<Self> {
+        let stop_image = Self::create_ico(include_bytes!("../img/stop.png"), style)
             .context("cannot create stop icon")?;
-        let play_image = Self::create_ico(include_bytes!("../img/play.png"))
+        let play_image = Self::create_ico(include_bytes!("../img/play.png"), style)
             .context("cannot create play icon")?;
-        let play_hl_image = Self::create_ico(include_bytes!("../img/play_hl.png"))
+        let play_hl_image = Self::create_ico(include_bytes!("../img/play_hl.png"), style)
             .context("cannot create play_hl icon")?;
-        let pause_image = Self::create_ico(include_bytes!("../img/pause.png"))
+        let pause_image = Self::create_ico(include_bytes!("../img/pause.png"), style)
             .context("cannot create pause icon")?;
 
         let data = TrayIconData {
@@ -89,19 +207,51 @@ impl TrayIcon {
         };
         let service = TrayService::new(data);
         let handle = service.handle();
-        service.spawn();
+        Self::spawn(service);
 
         return Ok(Self {
-            handle,
+            handle: Some(handle),
             image_type: TrayIconImageType::Stop,
         });
     }
 
+    // The icon appears once there's a D-Bus session and a panel that shows it.
+    // Meanwhile the player works as usual.
+    fn spawn(service: TrayService<TrayIconData>) {
+        thread_util::thread("tray icon", move || {
+            if Connection::new_session().is_err() {
+                debugln_with_date("no D-Bus session yet, the tray icon is postponed");
+                while Connection::new_session().is_err() {
+                    thread::sleep(SESSION_RETRY_INTERVAL);
+                }
+            }
+            if let Err(e) = service.run() {
+                e.log_context("tray icon service has stopped");
+            }
+        });
+    }
+
+    pub const fn headless() -> Self {
+        return Self {
+            handle: None,
+            image_type: TrayIconImageType::Stop,
+        };
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut TrayIconData),
+    {
+        if let Some(handle) = &self.handle {
+            handle.update(f);
+        }
+    }
+
     pub fn add_menu_item<F>(&self, menu_item_func: F)
     where
         F: Fn() -> TrayMenuItem,
     {
-        self.handle.update(move |data| {
+        self.update(move |data| {
             data.menu_items.push(menu_item_func());
         });
     }
@@ -112,7 +262,7 @@ impl TrayIcon {
         }
         self.image_type = TrayIconImageType::Play;
 
-        self.handle.update(|data| {
+        self.update(|data| {
             data.image_type = TrayIconImageType::Play;
         });
     }
@@ -123,7 +273,7 @@ impl TrayIcon {
         }
         self.image_type = TrayIconImageType::PlayHL;
 
-        self.handle.update(|data| {
+        self.update(|data| {
             data.image_type = TrayIconImageType::PlayHL;
         });
     }
@@ -134,7 +284,7 @@ impl TrayIcon {
         }
         self.image_type = TrayIconImageType::Stop;
 
-        self.handle.update(|data| {
+        self.update(|data| {
             data.image_type = TrayIconImageType::Stop;
         });
     }
@@ -145,7 +295,7 @@ impl TrayIcon {
         }
         self.image_type = TrayIconImageType::Pause;
 
-        self.handle.update(|data| {
+        self.update(|data| {
             data.image_type = TrayIconImageType::Pause;
         });
     }
@@ -155,13 +305,15 @@ impl TrayIcon {
     }
 
     pub fn set_tooltip(&self, text: &str) {
-        self.handle.update(move |data| {
+        self.update(move |data| {
             data.tooltip = text.to_string();
         });
     }
 
     pub fn shutdown(&self) {
-        self.handle.shutdown();
+        if let Some(handle) = &self.handle {
+            handle.shutdown();
+        }
     }
 }
 
@@ -180,6 +332,11 @@ impl ksni::Tray for TrayIconData {
         return vec![image];
     }
 
+    // the panel may appear after the player has started (or restart)
+    fn watcher_online(&self) {
+        debugln_with_date("the tray icon is registered");
+    }
+
     fn menu(&self) -> Vec<MenuItem<Self>> {
         return self
             .menu_items
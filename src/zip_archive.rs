@@ -0,0 +1,182 @@
+// SPDX-License-Identifier: GPL-3.0-only
+// 🄯 2023, Alexey Parfenov <zxed@alkatrazstudio.net>
+
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::DeflateDecoder;
+
+use crate::timeout_file::TimeoutFile;
+
+// The files inside ZIP archives are addressed by virtual paths,
+// e.g. /music/album.zip/CD1/01.flac.
+// Only the stored and deflated entries of non-ZIP64 archives are supported.
+
+const EXT: &str = ".zip";
+const EOCD_SIGNATURE: u32 = 0x0605_4b50;
+const EOCD_SIZE: usize = 22;
+// the end of central directory record may be followed by a comment
+const EOCD_MAX_SEARCH: u64 = EOCD_SIZE as u64 + u16::MAX as u64;
+const CENTRAL_SIGNATURE: u32 = 0x0201_4b50;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const LOCAL_SIGNATURE: u32 = 0x0403_4b50;
+const LOCAL_HEADER_SIZE: usize = 30;
+const FLAG_ENCRYPTED: u16 = 1;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+// the sizes in the headers are not trusted for the allocation, the buffer grows if needed
+const MAX_PREALLOC_SIZE: u64 = 64 * 1024 * 1024;
+
+struct Entry {
+    name: String,
+    method: u16,
+    compressed_size: u64,
+    size: u64,
+    header_offset: u64,
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    return u16::from_le_bytes([buf[offset], buf[offset + 1]]);
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    return u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ]);
+}
+
+fn read_at(file: &mut TimeoutFile, offset: u64, len: usize) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; len];
+    file.read_exact(&mut buf)?;
+    return Ok(buf);
+}
+
+fn read_entries(file: &mut TimeoutFile) -> Result<Vec<Entry>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < EOCD_SIZE as u64 {
+        bail!("not a ZIP archive");
+    }
+    let tail_start = len.saturating_sub(EOCD_MAX_SEARCH);
+    let tail = read_at(file, tail_start, usize::try_from(len - tail_start)?)?;
+    let Some(eocd_pos) = (0..=tail.len() - EOCD_SIZE)
+        .rev()
+        .find(|pos| u32_at(&tail, *pos) == EOCD_SIGNATURE)
+    else {
+        bail!("not a ZIP archive");
+    };
+    let eocd = &tail[eocd_pos..];
+    if eocd.len() < EOCD_SIZE {
+        bail!("broken end of central directory");
+    }
+    let dir_size = u32_at(eocd, 12);
+    let dir_offset = u32_at(eocd, 16);
+    if dir_size == u32::MAX || dir_offset == u32::MAX {
+        bail!("ZIP64 archives are not supported");
+    }
+
+    let dir = read_at(file, u64::from(dir_offset), dir_size as usize)?;
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos + CENTRAL_HEADER_SIZE <= dir.len() && u32_at(&dir, pos) == CENTRAL_SIGNATURE {
+        let name_len = u16_at(&dir, pos + 28) as usize;
+        let extra_len = u16_at(&dir, pos + 30) as usize;
+        let comment_len = u16_at(&dir, pos + 32) as usize;
+        let name_start = pos + CENTRAL_HEADER_SIZE;
+        let Some(name) = dir.get(name_start..name_start + name_len) else {
+            bail!("broken central directory");
+        };
+        let name = String::from_utf8_lossy(name).to_string();
+        if u16_at(&dir, pos + 8) & FLAG_ENCRYPTED == 0 && !name.ends_with('/') {
+            entries.push(Entry {
+                name,
+                method: u16_at(&dir, pos + 10),
+                compressed_size: u64::from(u32_at(&dir, pos + 20)),
+                size: u64::from(u32_at(&dir, pos + 24)),
+                header_offset: u64::from(u32_at(&dir, pos + 42)),
+            });
+        }
+        pos = name_start + name_len + extra_len + comment_len;
+    }
+    return Ok(entries);
+}
+
+fn open(archive: &str) -> Result<(TimeoutFile, Vec<Entry>)> {
+    let mut file =
+        TimeoutFile::open(archive).with_context(|| format!("cannot open archive: {archive}"))?;
+    let entries =
+        read_entries(&mut file).with_context(|| format!("cannot read archive: {archive}"))?;
+    return Ok((file, entries));
+}
+
+pub fn is_archive(path: &str) -> bool {
+    return path.to_lowercase().ends_with(EXT);
+}
+
+// the archive and the name of the entry inside it
+pub fn split_path(path: &str) -> Option<(&str, &str)> {
+    // ASCII only, so the byte offsets stay the same as in the original path
+    let lower = path.to_ascii_lowercase();
+    let mut search_from = 0;
+    while let Some(pos) = lower[search_from..].find(".zip/") {
+        let archive_end = search_from + pos + EXT.len();
+        let archive = &path[..archive_end];
+        if fs::metadata(archive).is_ok_and(|meta| meta.is_file()) {
+            return Some((archive, &path[archive_end + 1..]));
+        }
+        search_from = archive_end;
+    }
+    return None;
+}
+
+// the real file that contains the given path
+pub fn file_path(path: &str) -> &str {
+    return split_path(path).map_or(path, |(archive, _)| archive);
+}
+
+// the virtual paths of all files inside the archive
+pub fn list(archive: &str) -> Result<Vec<String>> {
+    let (_, entries) = open(archive)?;
+    return Ok(entries
+        .into_iter()
+        .map(|entry| {
+            return Path::new(archive)
+                .join(entry.name)
+                .to_string_lossy()
+                .to_string();
+        })
+        .collect());
+}
+
+// the entry is unpacked into memory, so it can be seeked freely
+pub fn read(archive: &str, name: &str) -> Result<Vec<u8>> {
+    let (mut file, entries) = open(archive)?;
+    let Some(entry) = entries.into_iter().find(|entry| entry.name == name) else {
+        bail!("{name} is not found in {archive}");
+    };
+    let header = read_at(&mut file, entry.header_offset, LOCAL_HEADER_SIZE)?;
+    if u32_at(&header, 0) != LOCAL_SIGNATURE {
+        bail!("broken entry {name} in {archive}");
+    }
+    let data_offset = entry.header_offset
+        + LOCAL_HEADER_SIZE as u64
+        + u64::from(u16_at(&header, 26))
+        + u64::from(u16_at(&header, 28));
+    file.seek(SeekFrom::Start(data_offset))?;
+    let data = file.take(entry.compressed_size);
+    let mut buf = Vec::with_capacity(usize::try_from(entry.size.min(MAX_PREALLOC_SIZE))?);
+    match entry.method {
+        METHOD_STORED => data.take(entry.size).read_to_end(&mut buf),
+        METHOD_DEFLATED => DeflateDecoder::new(data).read_to_end(&mut buf),
+        method => bail!("unsupported compression method {method}: {name} in {archive}"),
+    }
+    .with_context(|| format!("cannot unpack {name} from {archive}"))?;
+    return Ok(buf);
+}